@@ -9,12 +9,12 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use futures::future;
 use rand::{distributions::Alphanumeric, Rng};
 use reqwest::{
-    header::{HeaderMap, HeaderValue, CONTENT_RANGE, CONTENT_TYPE},
-    Client, StatusCode,
+    header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_RANGE, CONTENT_TYPE},
+    Client, ClientBuilder, RequestBuilder, Response, StatusCode,
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -22,13 +22,10 @@ use thiserror::Error;
 use tokio::{io::AsyncRead, sync::Semaphore};
 use unicode_bom::Bom;
 
+use crate::backoff::Backoff;
 use crate::credentials::Credentials;
-use crate::util::read_chunk_async;
-
-/// The API version we implement.
-///
-/// <https://github.com/actions/toolkit/blob/0d44da2b87f9ed48ae889d15c6cc19667aa37ec0/packages/cache/src/internal/cacheHttpClient.ts>
-const API_VERSION: &str = "6.0-preview.1";
+use crate::util::{read_chunk_async, BufferPool};
+use crate::version::{self, ApiVersions};
 
 /// The User-Agent string for the client.
 ///
@@ -96,12 +93,24 @@ pub struct Api {
     /// The hasher of the version.
     version_hasher: Sha256,
 
+    /// Prepended to every key we read or write.
+    key_prefix: String,
+
     /// The HTTP client for authenticated requests.
     client: Client,
 
     /// The concurrent upload limit.
     concurrency_limit: Arc<Semaphore>,
 
+    /// Backs off when we get rate limited.
+    backoff: Arc<Backoff>,
+
+    /// Reusable chunk buffers for uploads.
+    chunk_buffers: Arc<BufferPool>,
+
+    /// The API version, negotiated with the server unless pinned.
+    api_versions: Arc<ApiVersions>,
+
     circuit_breaker_429_tripped: Arc<AtomicBool>,
 
     /// Backend request statistics.
@@ -243,6 +252,12 @@ impl fmt::Display for ApiErrorInfo {
 
 impl Api {
     pub fn new(credentials: Credentials) -> Result<Self> {
+        Self::with_client_builder(credentials, Client::builder().user_agent(USER_AGENT))
+    }
+
+    /// Creates a client whose HTTP requests are made with a client from `builder`,
+    /// e.g. to trust additional root certificates or to set the user agent.
+    pub fn with_client_builder(credentials: Credentials, builder: ClientBuilder) -> Result<Self> {
         let mut headers = HeaderMap::new();
         let auth_header = {
             let mut h = HeaderValue::from_str(&format!("Bearer {}", credentials.runtime_token))
@@ -251,14 +266,17 @@ impl Api {
             h
         };
         headers.insert("Authorization", auth_header);
-        headers.insert(
-            "Accept",
-            HeaderValue::from_str(&format!("application/json;api-version={}", API_VERSION))
-                .map_err(Error::init_error)?,
-        );
-
-        let client = Client::builder()
-            .user_agent(USER_AGENT)
+
+        let api_versions = match &credentials.api_version {
+            Some(api_version) => {
+                HeaderValue::from_str(&ApiVersions::accept_header(api_version))
+                    .map_err(Error::init_error)?;
+                ApiVersions::pinned(api_version)
+            }
+            None => ApiVersions::negotiate(),
+        };
+
+        let client = builder
             .default_headers(headers)
             .build()
             .map_err(Error::init_error)?;
@@ -266,18 +284,30 @@ impl Api {
         let version_hasher = Sha256::new_with_prefix(DEFAULT_VERSION.as_bytes());
         let initial_version = hex::encode(version_hasher.clone().finalize());
 
+        let concurrency_limit = Arc::new(Semaphore::new(MAX_CONCURRENCY));
+        let backoff = Arc::new(Backoff::new(concurrency_limit.clone(), MAX_CONCURRENCY));
+
         Ok(Self {
             credentials,
             version: initial_version,
             version_hasher,
+            key_prefix: String::new(),
             client,
-            concurrency_limit: Arc::new(Semaphore::new(MAX_CONCURRENCY)),
+            concurrency_limit,
+            backoff,
+            chunk_buffers: Arc::new(BufferPool::new(CHUNK_SIZE, MAX_CONCURRENCY)),
+            api_versions: Arc::new(api_versions),
             circuit_breaker_429_tripped: Arc::new(AtomicBool::from(false)),
             #[cfg(debug_assertions)]
             stats: Default::default(),
         })
     }
 
+    /// The API version in use.
+    pub fn api_version(&self) -> &str {
+        self.api_versions.current()
+    }
+
     pub fn circuit_breaker_tripped(&self) -> bool {
         self.circuit_breaker_429_tripped.load(Ordering::Relaxed)
     }
@@ -288,6 +318,11 @@ impl Api {
         self.version = hex::encode(self.version_hasher.clone().finalize());
     }
 
+    /// Prepends `prefix` to the keys of all caches read or written from now on.
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.to_owned();
+    }
+
     // Public
 
     /// Allocates a file.
@@ -344,12 +379,20 @@ impl Api {
         let mut offset = 0;
         let mut futures = Vec::new();
         loop {
-            let buf = BytesMut::with_capacity(CHUNK_SIZE);
-            let chunk = read_chunk_async(&mut stream, buf)
+            // Wait for an upload slot before reading the next chunk, so that we
+            // only hold as many chunks in memory as we can send at once.
+            let permit = self
+                .concurrency_limit
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("failed to acquire concurrency semaphore permit");
+
+            let chunk = read_chunk_async(&mut stream, self.chunk_buffers.take())
                 .await
                 .map_err(|e| Error::IoError(e, "Reading a chunk during upload".to_string()))?;
             if chunk.is_empty() {
-                offset += chunk.len();
+                self.chunk_buffers.give_back(chunk);
                 break;
             }
 
@@ -364,34 +407,32 @@ impl Api {
 
             futures.push({
                 let client = self.client.clone();
-                let concurrency_limit = self.concurrency_limit.clone();
+                let chunk_buffers = self.chunk_buffers.clone();
+                let backoff = self.backoff.clone();
+                let api_versions = self.api_versions.clone();
                 let circuit_breaker_429_tripped = self.circuit_breaker_429_tripped.clone();
                 let url = self.construct_url(&format!("caches/{}", allocation.0 .0));
 
                 tokio::task::spawn(async move {
-                    let permit = concurrency_limit
-                        .acquire()
-                        .await
-                        .expect("failed to acquire concurrency semaphore permit");
-
                     tracing::trace!(
                         "Starting uploading chunk {}-{}",
                         offset,
                         offset + chunk_len - 1
                     );
 
-                    let r = client
-                        .patch(url)
-                        .header(CONTENT_TYPE, "application/octet-stream")
-                        .header(
-                            CONTENT_RANGE,
-                            format!("bytes {}-{}/*", offset, offset + chunk.len() - 1),
-                        )
-                        .body(chunk)
-                        .send()
-                        .await?
-                        .check()
-                        .await;
+                    let r = send(&backoff, &api_versions, || {
+                        client
+                            .patch(&url)
+                            .header(CONTENT_TYPE, "application/octet-stream")
+                            .header(
+                                CONTENT_RANGE,
+                                format!("bytes {}-{}/*", offset, offset + chunk.len() - 1),
+                            )
+                            .body(chunk.clone())
+                    })
+                    .await?
+                    .check()
+                    .await;
 
                     tracing::trace!(
                         "Finished uploading chunk {}-{}: {:?}",
@@ -401,6 +442,7 @@ impl Api {
                     );
 
                     drop(permit);
+                    chunk_buffers.give_back(chunk);
 
                     circuit_breaker_429_tripped.check_result(&r);
 
@@ -456,14 +498,20 @@ impl Api {
         #[cfg(debug_assertions)]
         self.stats.get.fetch_add(1, Ordering::SeqCst);
 
-        let res = self
-            .client
-            .get(self.construct_url("cache"))
-            .query(&[("version", &self.version), ("keys", &keys.join(","))])
-            .send()
-            .await?
-            .check_json()
-            .await;
+        let url = self.construct_url("cache");
+        let keys = keys
+            .iter()
+            .map(|key| format!("{}{}", self.key_prefix, key))
+            .collect::<Vec<_>>()
+            .join(",");
+        let res = send(&self.backoff, &self.api_versions, || {
+            self.client
+                .get(&url)
+                .query(&[("version", &self.version), ("keys", &keys)])
+        })
+        .await?
+        .check_json()
+        .await;
 
         self.circuit_breaker_429_tripped.check_result(&res);
 
@@ -488,10 +536,12 @@ impl Api {
             return Err(Error::CircuitBreakerTripped);
         }
 
+        let key = format!("{}{}", self.key_prefix, key);
+
         tracing::debug!("Reserving cache for {}", key);
 
         let req = ReserveCacheRequest {
-            key,
+            key: &key,
             version: &self.version,
             cache_size,
         };
@@ -499,14 +549,13 @@ impl Api {
         #[cfg(debug_assertions)]
         self.stats.post.fetch_add(1, Ordering::SeqCst);
 
-        let res = self
-            .client
-            .post(self.construct_url("caches"))
-            .json(&req)
-            .send()
-            .await?
-            .check_json()
-            .await;
+        let url = self.construct_url("caches");
+        let res = send(&self.backoff, &self.api_versions, || {
+            self.client.post(&url).json(&req)
+        })
+        .await?
+        .check_json()
+        .await;
 
         self.circuit_breaker_429_tripped.check_result(&res);
 
@@ -526,14 +575,13 @@ impl Api {
         #[cfg(debug_assertions)]
         self.stats.post.fetch_add(1, Ordering::SeqCst);
 
-        if let Err(e) = self
-            .client
-            .post(self.construct_url(&format!("caches/{}", cache_id.0)))
-            .json(&req)
-            .send()
-            .await?
-            .check()
-            .await
+        let url = self.construct_url(&format!("caches/{}", cache_id.0));
+        if let Err(e) = send(&self.backoff, &self.api_versions, || {
+            self.client.post(&url).json(&req)
+        })
+        .await?
+        .check()
+        .await
         {
             self.circuit_breaker_429_tripped.check_err(&e);
             return Err(e);
@@ -547,12 +595,42 @@ impl Api {
         if !url.ends_with('/') {
             url.push('/');
         }
-        url.push_str("_apis/artifactcache/");
+        // Some GitHub Enterprise Server runners hand out the URL with the API path included.
+        if !url.ends_with("_apis/artifactcache/") {
+            url.push_str("_apis/artifactcache/");
+        }
         url.push_str(resource);
         url
     }
 }
 
+/// Sends the request built by `build` with the negotiated API version.
+///
+/// When the server rejects the version, the request is sent again with the
+/// next older one. Other failures are left to the caller.
+async fn send<F>(backoff: &Backoff, api_versions: &ApiVersions, build: F) -> Result<Response>
+where
+    F: Fn() -> RequestBuilder,
+{
+    loop {
+        let api_version = api_versions.current().to_owned();
+        let accept = ApiVersions::accept_header(&api_version);
+        let res = backoff.send(|| build().header(ACCEPT, &accept)).await?;
+
+        let status = res.status();
+        if status.is_success() || status == StatusCode::TOO_MANY_REQUESTS {
+            return Ok(res);
+        }
+
+        let bytes = strip_bom(res.bytes().await?);
+        if version::is_rejection(status, &bytes) && api_versions.fall_back(&api_version) {
+            continue;
+        }
+
+        return Err(decode_error(status, bytes));
+    }
+}
+
 #[async_trait]
 impl ResponseExt for reqwest::Response {
     async fn check(self) -> Result<()> {
@@ -588,16 +666,18 @@ impl ResponseExt for reqwest::Response {
 
 async fn handle_error(res: reqwest::Response) -> Error {
     let status = res.status();
-    let bytes = match res.bytes().await {
-        Ok(bytes) => {
-            let bom = Bom::from(bytes.as_ref());
-            bytes.slice(bom.len()..)
-        }
-        Err(e) => {
-            return e.into();
-        }
-    };
+    match res.bytes().await {
+        Ok(bytes) => decode_error(status, strip_bom(bytes)),
+        Err(e) => e.into(),
+    }
+}
+
+fn strip_bom(bytes: Bytes) -> Bytes {
+    let bom = Bom::from(bytes.as_ref());
+    bytes.slice(bom.len()..)
+}
 
+fn decode_error(status: StatusCode, bytes: Bytes) -> Error {
     let info = match serde_json::from_slice(&bytes) {
         Ok(structured) => ApiErrorInfo::Structured(structured),
         Err(e) => {
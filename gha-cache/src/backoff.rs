@@ -0,0 +1,177 @@
+//! Rate limit handling.
+//!
+//! When the GitHub Actions Cache API throttles us, we honor the `Retry-After`
+//! (or `X-RateLimit-Reset`) header before trying again, pause every other
+//! request in the meantime, and lower the number of concurrent chunk uploads.
+//! Concurrency recovers gradually as requests succeed again.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use tokio::sync::Semaphore;
+
+/// How many times a throttled request is retried before giving up.
+const MAX_RETRIES: u32 = 5;
+
+/// The delay for the first retry when the server doesn't tell us how long to wait.
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest we are willing to wait for a single retry.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How many successful requests it takes to give back one upload slot.
+const SUCCESSES_PER_RESTORED_PERMIT: usize = 16;
+
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    /// The concurrency limit shared by all uploads.
+    concurrency_limit: Arc<Semaphore>,
+
+    /// The configured concurrency limit.
+    max_concurrency: usize,
+
+    /// How many permits we have taken away from `concurrency_limit`.
+    withheld_permits: AtomicUsize,
+
+    /// Successful requests since the last throttle or restored permit.
+    successes: AtomicUsize,
+
+    /// No request is sent before this instant.
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl Backoff {
+    pub(crate) fn new(concurrency_limit: Arc<Semaphore>, max_concurrency: usize) -> Self {
+        Self {
+            concurrency_limit,
+            max_concurrency,
+            withheld_permits: AtomicUsize::new(0),
+            successes: AtomicUsize::new(0),
+            paused_until: Mutex::new(None),
+        }
+    }
+
+    /// Sends the request built by `build`, retrying while the server throttles us.
+    ///
+    /// Once the retries are exhausted, the last throttled response is returned.
+    pub(crate) async fn send<F>(&self, build: F) -> reqwest::Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut attempt = 0;
+
+        loop {
+            self.wait().await;
+
+            let response = build().send().await?;
+
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                self.record_success();
+                return Ok(response);
+            }
+
+            if attempt >= MAX_RETRIES {
+                return Ok(response);
+            }
+
+            let delay = retry_after(response.headers())
+                .unwrap_or_else(|| INITIAL_DELAY * 2u32.pow(attempt))
+                .min(MAX_DELAY);
+
+            tracing::info!(
+                "GitHub Actions Cache throttled us, retrying in {:?} (attempt {}/{})",
+                delay,
+                attempt + 1,
+                MAX_RETRIES
+            );
+
+            self.record_throttle(delay);
+            attempt += 1;
+        }
+    }
+
+    async fn wait(&self) {
+        let paused_until = *self
+            .paused_until
+            .lock()
+            .expect("backoff mutex was poisoned");
+
+        if let Some(paused_until) = paused_until {
+            tokio::time::sleep_until(paused_until.into()).await;
+        }
+    }
+
+    fn record_throttle(&self, delay: Duration) {
+        self.successes.store(0, Ordering::Relaxed);
+
+        {
+            let mut paused_until = self
+                .paused_until
+                .lock()
+                .expect("backoff mutex was poisoned");
+            let until = Instant::now() + delay;
+            if paused_until.map_or(true, |current| current < until) {
+                *paused_until = Some(until);
+            }
+        }
+
+        // Keep at least one upload slot.
+        let reduced = self
+            .withheld_permits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |withheld| {
+                (withheld + 1 < self.max_concurrency).then_some(withheld + 1)
+            })
+            .is_ok();
+
+        if reduced {
+            tracing::debug!("Reducing upload concurrency");
+
+            let concurrency_limit = self.concurrency_limit.clone();
+            tokio::task::spawn(async move {
+                if let Ok(permit) = concurrency_limit.acquire_owned().await {
+                    permit.forget();
+                }
+            });
+        }
+    }
+
+    fn record_success(&self) {
+        if self.withheld_permits.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        if self.successes.fetch_add(1, Ordering::Relaxed) + 1 < SUCCESSES_PER_RESTORED_PERMIT {
+            return;
+        }
+
+        self.successes.store(0, Ordering::Relaxed);
+
+        let restored = self
+            .withheld_permits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |withheld| {
+                withheld.checked_sub(1)
+            })
+            .is_ok();
+
+        if restored {
+            tracing::debug!("Restoring upload concurrency");
+            self.concurrency_limit.add_permits(1);
+        }
+    }
+}
+
+/// Determines how long the server wants us to wait from the response headers.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok();
+
+    if let Some(seconds) = header("retry-after") {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    // `X-RateLimit-Reset` is a UNIX timestamp.
+    let reset = header("x-ratelimit-reset")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Duration::from_secs(reset.saturating_sub(now)))
+}
@@ -21,6 +21,10 @@ pub struct Credentials {
     #[derivative(Debug = "ignore")]
     #[serde(alias = "ACTIONS_RUNTIME_TOKEN")]
     pub(crate) runtime_token: String,
+
+    /// The API version to use, instead of negotiating one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) api_version: Option<String>,
 }
 
 impl Credentials {
@@ -32,6 +36,19 @@ impl Credentials {
         Some(Self {
             cache_url,
             runtime_token,
+            api_version: None,
         })
     }
+
+    /// Uses another base URL, e.g. that of a GitHub Enterprise Server.
+    pub fn with_cache_url(mut self, cache_url: String) -> Self {
+        self.cache_url = cache_url;
+        self
+    }
+
+    /// Uses this API version instead of negotiating one with the server.
+    pub fn with_api_version(mut self, api_version: String) -> Self {
+        self.api_version = Some(api_version);
+        self
+    }
 }
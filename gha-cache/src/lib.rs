@@ -14,8 +14,10 @@
 )]
 
 pub mod api;
+mod backoff;
 pub mod credentials;
 mod util;
+mod version;
 
 pub use api::Api;
 pub use credentials::Credentials;
@@ -2,6 +2,8 @@
 //!
 //! Taken from <https://github.com/zhaofengli/attic>.
 
+use std::sync::Mutex;
+
 use bytes::{Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
@@ -20,3 +22,62 @@ pub async fn read_chunk_async<S: AsyncRead + Unpin + Send>(
 
     Ok(chunk.freeze())
 }
+
+/// A pool of chunk buffers.
+///
+/// Uploads take their chunk buffers from here and hand them back once the
+/// chunk is sent, so that large files don't allocate (and fault in) a fresh
+/// buffer for every chunk.
+#[derive(Debug)]
+pub struct BufferPool {
+    /// The capacity of every buffer.
+    capacity: usize,
+
+    /// How many idle buffers we keep around.
+    max_idle: usize,
+
+    idle: Mutex<Vec<BytesMut>>,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize, max_idle: usize) -> Self {
+        Self {
+            capacity,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Takes an empty buffer from the pool, or allocates one.
+    pub fn take(&self) -> BytesMut {
+        self.lock()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.capacity))
+    }
+
+    /// Hands a chunk back to the pool.
+    ///
+    /// The buffer is only reused if nobody else holds on to the chunk.
+    pub fn give_back(&self, chunk: Bytes) {
+        let Ok(mut buf) = chunk.try_into_mut() else {
+            return;
+        };
+
+        // `read_chunk_async` fills the whole capacity, so we only keep buffers
+        // that produce chunks of the right size.
+        if buf.capacity() != self.capacity {
+            return;
+        }
+
+        buf.clear();
+
+        let mut idle = self.lock();
+        if idle.len() < self.max_idle {
+            idle.push(buf);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<BytesMut>> {
+        self.idle.lock().expect("buffer pool mutex was poisoned")
+    }
+}
@@ -0,0 +1,97 @@
+//! API version negotiation.
+//!
+//! github.com implements the newest version of the cache API, but GitHub
+//! Enterprise Server lags behind and rejects versions it doesn't know with a
+//! 400 naming the versions it supports. We start with the newest version we
+//! implement and fall back to older ones when a request is rejected that way,
+//! sticking with the first version that is accepted.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use reqwest::StatusCode;
+
+/// The API versions we implement, newest first.
+///
+/// <https://github.com/actions/toolkit/blob/0d44da2b87f9ed48ae889d15c6cc19667aa37ec0/packages/cache/src/internal/cacheHttpClient.ts>
+const API_VERSIONS: &[&str] = &["6.0-preview.1", "5.1-preview.1"];
+
+#[derive(Debug)]
+pub(crate) struct ApiVersions {
+    /// The versions to try, in order.
+    candidates: Vec<String>,
+
+    /// The index of the version in use.
+    current: AtomicUsize,
+}
+
+impl ApiVersions {
+    /// Negotiates among the versions we implement.
+    pub(crate) fn negotiate() -> Self {
+        Self {
+            candidates: API_VERSIONS.iter().map(|v| v.to_string()).collect(),
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// Always uses `version`.
+    pub(crate) fn pinned(version: &str) -> Self {
+        Self {
+            candidates: vec![version.to_owned()],
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// The version in use.
+    pub(crate) fn current(&self) -> &str {
+        &self.candidates[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// The `Accept` header announcing `version`.
+    pub(crate) fn accept_header(version: &str) -> String {
+        format!("application/json;api-version={}", version)
+    }
+
+    /// Falls back to the version after `rejected`, if there is one.
+    ///
+    /// Returns whether there is a version left to try. Concurrent requests
+    /// rejected with the same version only move on once.
+    pub(crate) fn fall_back(&self, rejected: &str) -> bool {
+        let Some(index) = self.candidates.iter().position(|v| v == rejected) else {
+            return false;
+        };
+
+        if index + 1 >= self.candidates.len() {
+            return false;
+        }
+
+        if self
+            .current
+            .compare_exchange(index, index + 1, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            tracing::info!(
+                "The cache server doesn't support API version {}, falling back to {}",
+                rejected,
+                self.candidates[index + 1]
+            );
+        }
+
+        true
+    }
+}
+
+/// Whether a response says that the server doesn't support the API version of the request.
+pub(crate) fn is_rejection(status: StatusCode, body: &[u8]) -> bool {
+    if !matches!(
+        status,
+        StatusCode::BAD_REQUEST
+            | StatusCode::NOT_FOUND
+            | StatusCode::NOT_ACCEPTABLE
+            | StatusCode::UNSUPPORTED_MEDIA_TYPE
+    ) {
+        return false;
+    }
+
+    let body = String::from_utf8_lossy(body).to_lowercase();
+    body.contains("api-version") || body.contains("api version")
+}
@@ -2,12 +2,24 @@
 //!
 //! This API is intended to be used by nix-installer-action.
 
+use std::path::PathBuf;
+
 use attic::nix_store::StorePath;
-use axum::{extract::Extension, routing::post, Json, Router};
+use axum::{
+    extract::{Extension, Request},
+    http::header::AUTHORIZATION,
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
 use serde::{Deserialize, Serialize};
 
 use super::State;
 use crate::error::{Error, Result};
+use crate::preheat::PreheatRequest;
+use crate::queue::QueueEntry;
+use crate::Backend;
 
 #[derive(Debug, Clone, Serialize)]
 struct WorkflowStartResponse {
@@ -26,6 +38,37 @@ pub fn get_router() -> Router {
         .route("/api/workflow-start", post(workflow_start))
         .route("/api/workflow-finish", post(workflow_finish))
         .route("/api/enqueue-paths", post(post_enqueue_paths))
+        .route("/api/queue", get(get_queue))
+        .route("/api/status", get(crate::backends::status))
+        .route("/api/preheat", post(post_preheat))
+        .route("/api/events", get(crate::events::stream))
+        .route("/api/reload", post(post_reload))
+        .layer(middleware::from_fn(require_api_token))
+}
+
+/// Rejects requests that don't carry the token from `--api-token-file`, if one was configured.
+pub(crate) async fn require_api_token(
+    Extension(state): Extension<State>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    if let Some(api_token) = &state.api_token {
+        let authorized = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                crate::util::constant_time_eq(token.as_bytes(), api_token.as_bytes())
+            });
+
+        if !authorized {
+            tracing::warn!("Rejecting API request without a valid token");
+            return Err(Error::Unauthorized);
+        }
+    }
+
+    Ok(next.run(request).await)
 }
 
 /// Record existing paths.
@@ -94,9 +137,32 @@ async fn workflow_finish(
         }
     };
 
+    if let Some(flake) = &state.push_flake_inputs {
+        match crate::flake_inputs::query(&state.store, flake).await {
+            Ok(inputs) => {
+                tracing::info!("Uploading {} sources and inputs of {}", inputs.len(), flake);
+                state.metrics.flake_inputs_enqueued.add(inputs.len());
+                enqueue_paths(&state, inputs).await?;
+            }
+            Err(err) => tracing::warn!("Not uploading the inputs of {}: {}", flake, err),
+        }
+    }
+
+    state.fetcher.drain().await;
+
     if let Some(gha_cache) = &state.gha_cache {
         tracing::info!("Waiting for GitHub action cache uploads to finish");
         gha_cache.shutdown().await?;
+
+        let quarantined = gha_cache.quarantined_paths();
+        for (store_path, error) in &quarantined {
+            tracing::warn!(
+                "Gave up on uploading '{}' after repeated failures, the last one being: {}",
+                store_path.display(),
+                error
+            );
+        }
+        crate::gha_annotations::report_quarantined(&quarantined);
     }
 
     if let Some(sender) = state.shutdown_sender.lock().await.take() {
@@ -105,11 +171,16 @@ async fn workflow_finish(
             .map_err(|_| Error::Internal("Sending shutdown server message".to_owned()))?;
     }
 
+    state.backends.stop_retrying();
+
     if let Some(attic_state) = state.flakehub_state.write().await.take() {
         tracing::info!("Waiting for FlakeHub cache uploads to finish");
-        let _paths = attic_state.push_session.wait().await?;
+        attic_state.uploader.wait().await;
     }
 
+    state.substitutions.report();
+    crate::gha_annotations::finish(&state.metrics);
+
     // NOTE(cole-h): see `init_logging`
     if let Some(logfile) = &state.logfile {
         let logfile_contents = std::fs::read_to_string(logfile)
@@ -124,6 +195,11 @@ async fn workflow_finish(
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EnqueuePathsRequest {
     pub store_paths: Vec<String>,
+
+    /// The derivation that produced `store_paths`, if known, so that its
+    /// realisations and build log can be uploaded too.
+    #[serde(default)]
+    pub drv_path: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -145,19 +221,136 @@ async fn post_enqueue_paths(
 
     enqueue_paths(&state, store_paths).await?;
 
+    if let Some(drv_path) = req.drv_path {
+        enqueue_derivation(&state, drv_path).await?;
+    }
+
     Ok(Json(EnqueuePathsResponse {}))
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct QueueResponse {
+    /// Paths pending upload to the GitHub Actions Cache, if it's enabled.
+    ///
+    /// FlakeHub uploads are queued inside attic and aren't listed.
+    gha: Option<Vec<QueueEntry>>,
+}
+
+/// List the store paths that are still waiting to be uploaded.
+async fn get_queue(Extension(state): Extension<State>) -> Json<QueueResponse> {
+    Json(QueueResponse {
+        gha: state.gha_cache.as_ref().map(|gha| gha.pending_paths()),
+    })
+}
+
 pub async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
+    let store_paths = crate::builders::fetch_missing(state, store_paths).await;
+
+    let push_filter = state
+        .push_filter
+        .read()
+        .expect("push filter lock was poisoned")
+        .clone();
+    let store_paths = push_filter
+        .retain(&state.store, &state.metrics, store_paths)
+        .await;
+
     if let Some(gha_cache) = &state.gha_cache {
+        let store_paths = state
+            .policy
+            .retain_pushes(
+                &state.store,
+                &state.metrics,
+                Backend::Gha,
+                store_paths.clone(),
+            )
+            .await;
         gha_cache
-            .enqueue_paths(state.store.clone(), store_paths.clone())
+            .enqueue_paths(state.store.clone(), store_paths)
             .await?;
     }
 
-    if let Some(flakehub_state) = &*state.flakehub_state.read().await {
+    let store_paths = if state.flakehub_state.read().await.is_some() {
+        state
+            .policy
+            .retain_pushes(&state.store, &state.metrics, Backend::FlakeHub, store_paths)
+            .await
+    } else {
+        store_paths
+    };
+
+    if state.dry_run {
+        if state.flakehub_state.read().await.is_some() {
+            for path in &store_paths {
+                tracing::info!(
+                    "Dry run: would push the closure of '{}' to FlakeHub",
+                    state.store.get_full_path(path).display()
+                );
+            }
+        }
+    } else if state.disk_watchdog.is_paused() {
+        // Don't hold up the post-build hook while we wait for disk space to free up.
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            state.disk_watchdog.wait_for_space().await;
+            if let Some(flakehub_state) = &*state.flakehub_state.read().await {
+                if let Err(err) = crate::flakehub::enqueue_paths(flakehub_state, store_paths).await
+                {
+                    tracing::error!("Failed to enqueue paths for FlakeHub: {}", err);
+                }
+            }
+        });
+    } else if let Some(flakehub_state) = &*state.flakehub_state.read().await {
         crate::flakehub::enqueue_paths(flakehub_state, store_paths).await?;
     }
 
     Ok(())
 }
+
+/// Schedule the realisations and build log of a derivation for uploading.
+pub async fn enqueue_derivation(state: &State, drv_path: PathBuf) -> Result<()> {
+    if let Some(gha_cache) = &state.gha_cache {
+        gha_cache.enqueue_derivation(drv_path).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PreheatResponse {}
+
+/// Start looking up the narinfos of the closure of some paths in the background.
+async fn post_preheat(
+    Extension(state): Extension<State>,
+    Json(req): Json<PreheatRequest>,
+) -> Result<Json<PreheatResponse>> {
+    tracing::info!(
+        "Preheating {:?} and {:?}",
+        req.installables,
+        req.store_paths
+    );
+
+    if state.gha_cache.is_none() {
+        return Err(Error::GHADisabled);
+    }
+
+    tokio::task::spawn(async move {
+        if let Err(err) = crate::preheat::run(state, req).await {
+            tracing::warn!("Preheating failed: {}", err);
+        }
+    });
+
+    Ok(Json(PreheatResponse {}))
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ReloadResponse {}
+
+/// Reads the configuration again and applies what can change at runtime.
+async fn post_reload(Extension(state): Extension<State>) -> Result<Json<ReloadResponse>> {
+    tracing::info!("Reloading the configuration");
+
+    crate::reload::reload(&state).await?;
+
+    Ok(Json(ReloadResponse {}))
+}
@@ -0,0 +1,130 @@
+//! Starting the backends.
+//!
+//! The backends start concurrently, and one that fails to start doesn't take
+//! the daemon down as long as another one is up. FlakeHub, whose startup talks
+//! to the FlakeHub API, is retried in the background until it comes up. The
+//! GitHub Actions Cache only fails to start on configuration errors, which
+//! retrying wouldn't fix. `GET /api/status` reports where each backend stands.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use axum::{extract::Extension, Json};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use super::State;
+use crate::{flakehub, gha_annotations, Backend};
+
+/// How long to wait before the first retry.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(15);
+
+/// The longest we wait between retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum BackendStatus {
+    /// The backend is still starting.
+    Starting,
+
+    /// The backend is up.
+    Ready,
+
+    /// The backend failed to start.
+    Failed {
+        error: String,
+        attempts: u32,
+
+        /// Whether it's being retried in the background.
+        retrying: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct Backends {
+    /// The configured backends, and where they stand.
+    statuses: std::sync::Mutex<HashMap<Backend, BackendStatus>>,
+
+    /// Cancelled when we stop retrying, at the latest at shutdown.
+    stopped: CancellationToken,
+}
+
+impl Backends {
+    pub fn set(&self, backend: Backend, status: BackendStatus) {
+        self.lock().insert(backend, status);
+    }
+
+    /// Stops retrying failed backends, so that they don't start during shutdown.
+    pub fn stop_retrying(&self) {
+        self.stopped.cancel();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Backend, BackendStatus>> {
+        self.statuses
+            .lock()
+            .expect("backend statuses mutex was poisoned")
+    }
+}
+
+/// Retries starting FlakeHub until it succeeds or we stop retrying.
+pub fn retry_flakehub(state: State, mut attempts: u32, config: flakehub::Config) {
+    tokio::spawn(async move {
+        let mut delay = INITIAL_RETRY_DELAY;
+
+        loop {
+            tokio::select! {
+                _ = state.backends.stopped.cancelled() => return,
+                _ = tokio::time::sleep(delay) => {}
+            }
+
+            attempts += 1;
+            tracing::info!(
+                "Retrying to start the FlakeHub cache (attempt {})",
+                attempts
+            );
+
+            match config.init().await {
+                Ok(flakehub_state) => {
+                    // Checked under the lock, as shutdown takes the state right after stopping us.
+                    let mut slot = state.flakehub_state.write().await;
+                    if state.backends.stopped.is_cancelled() {
+                        return;
+                    }
+                    *slot = Some(flakehub_state);
+
+                    state.backends.set(Backend::FlakeHub, BackendStatus::Ready);
+                    tracing::info!("FlakeHub cache is enabled.");
+                    return;
+                }
+                Err(err) => {
+                    tracing::debug!("FlakeHub cache initialization failed: {}", err);
+                    gha_annotations::report(&err);
+                    state.backends.set(
+                        Backend::FlakeHub,
+                        BackendStatus::Failed {
+                            error: err.to_string(),
+                            attempts,
+                            retrying: true,
+                        },
+                    );
+                }
+            }
+
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    });
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusResponse {
+    /// The configured backends, and where they stand.
+    backends: HashMap<Backend, BackendStatus>,
+}
+
+/// Reports where each backend stands.
+pub async fn status(Extension(state): Extension<State>) -> Json<StatusResponse> {
+    Json(StatusResponse {
+        backends: state.backends.lock().clone(),
+    })
+}
@@ -1,16 +1,87 @@
 //! Binary Cache API.
+//!
+//! Besides serving the backends to Nix, this accepts uploads, so that
+//! `nix copy --to http://127.0.0.1:PORT` pushes arbitrary closures. The files
+//! go to the GitHub Actions Cache as is. FlakeHub pushes from the local store,
+//! so paths are forwarded to it when their narinfo arrives, if they're valid
+//! locally.
+//!
+//! Nix uploads a NAR before its narinfo, and only the narinfo says which path
+//! the NAR belongs to. So when the policy checks pushes, NARs are kept in a
+//! temporary file until their narinfo arrives, and are only uploaded if the
+//! path may be pushed.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use axum::{
+    body::Body,
     extract::{Extension, Path},
-    response::Redirect,
+    middleware,
+    response::{IntoResponse, Redirect, Response},
     routing::{get, put},
     Router,
 };
 use futures::StreamExt as _;
+use tokio::io::{AsyncRead, AsyncWriteExt as _};
 use tokio_util::io::StreamReader;
 
 use super::State;
+use crate::build_log;
+use crate::delta;
 use crate::error::{Error, Result};
+use crate::fallback;
+use crate::listing;
+use crate::narinfo_cache::Lookup;
+use crate::pack;
+use crate::policy::Operation;
+use crate::realisation;
+use crate::substitutions::Answer;
+use crate::Backend;
+
+/// How large an uploaded narinfo may be.
+const MAX_NARINFO_SIZE: usize = 1024 * 1024;
+
+/// How long an uploaded NAR waits for its narinfo before it's dropped.
+const STAGED_NAR_TTL: Duration = Duration::from_secs(3600);
+
+/// Uploaded NARs waiting for their narinfo, by the name they were uploaded as.
+#[derive(Debug, Default)]
+pub struct StagedNars {
+    nars: std::sync::Mutex<HashMap<String, (tempfile::TempPath, Instant)>>,
+}
+
+impl StagedNars {
+    async fn stage(&self, name: String, body: Body) -> Result<()> {
+        let path = tempfile::NamedTempFile::new()
+            .map_err(|e| Error::Io(e, "Creating a file for an uploaded NAR".to_owned()))?
+            .into_temp_path();
+
+        let mut file = tokio::fs::File::create(&path)
+            .await
+            .map_err(|e| Error::Io(e, format!("Opening {}", path.display())))?;
+        tokio::io::copy(&mut body_reader(body), &mut file)
+            .await
+            .map_err(|e| Error::Io(e, format!("Receiving the NAR {name}")))?;
+        file.flush()
+            .await
+            .map_err(|e| Error::Io(e, format!("Writing {}", path.display())))?;
+
+        let mut nars = self.lock();
+        nars.retain(|_, (_, staged)| staged.elapsed() < STAGED_NAR_TTL);
+        nars.insert(name, (path, Instant::now()));
+
+        Ok(())
+    }
+
+    fn take(&self, name: &str) -> Option<tempfile::TempPath> {
+        self.lock().remove(name).map(|(path, _)| path)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (tempfile::TempPath, Instant)>> {
+        self.nars.lock().expect("staged NARs mutex was poisoned")
+    }
+}
 
 pub fn get_router() -> Router {
     Router::new()
@@ -21,31 +92,82 @@ pub fn get_router() -> Router {
         // .nar
         .route("/nar/:path", get(get_nar))
         .route("/nar/:path", put(put_nar))
+        // .doi
+        .route("/realisations/:path", get(get_realisation))
+        .route(
+            "/realisations/:path",
+            put(put_realisation).route_layer(middleware::from_fn(crate::api::require_api_token)),
+        )
+        // build logs
+        .route("/log/:path", get(get_build_log))
+        .route(
+            "/log/:path",
+            put(put_build_log).route_layer(middleware::from_fn(crate::api::require_api_token)),
+        )
 }
 
-async fn get_nix_cache_info() -> &'static str {
-    // TODO: Make StoreDir configurable
-    r#"WantMassQuery: 1
-StoreDir: /nix/store
-Priority: 41
-"#
+async fn get_nix_cache_info(Extension(state): Extension<State>) -> String {
+    format!(
+        "WantMassQuery: 1\nStoreDir: {}\nPriority: {}\n",
+        state.store.store_dir().display(),
+        state.substituter_priority
+    )
 }
 
 async fn get_narinfo(
     Extension(state): Extension<State>,
     Path(path): Path<String>,
-) -> Result<Redirect> {
+) -> Result<Response> {
     let components: Vec<&str> = path.splitn(2, '.').collect();
 
     if components.len() != 2 {
         return Err(Error::NotFound);
     }
 
+    if components[1] == "ls" {
+        return Ok(get_listing(&state, components[0]).await?.into_response());
+    }
+
     if components[1] != "narinfo" {
         return Err(Error::NotFound);
     }
 
-    let store_path_hash = components[0].to_string();
+    let started = Instant::now();
+    let result = find_narinfo(&state, &path, components[0].to_string()).await;
+
+    let answer = match &result {
+        Ok((_, answer)) => *answer,
+        Err(Error::NotFound) => Answer::Miss,
+        Err(_) => Answer::Failed,
+    };
+    state
+        .substitutions
+        .record(components[0], answer, started.elapsed());
+
+    if let (Some(hooks), Answer::Hit(backend)) = (&state.hooks, answer) {
+        hooks.narinfo_hit(components[0], backend);
+    }
+
+    if let (Some(substituted), Answer::Hit(_)) = (&state.substituted, answer) {
+        substituted.record(components[0]);
+    }
+
+    result.map(|(narinfo, _)| narinfo)
+}
+
+/// Looks for a narinfo in the backends, and says who answered.
+async fn find_narinfo(
+    state: &State,
+    path: &str,
+    store_path_hash: String,
+) -> Result<(Response, Answer)> {
+    if state.fallback.flakehub_first() {
+        if let Some(narinfo) = fallback::from_flakehub(state, path).await {
+            state.metrics.narinfos_served_from_flakehub.incr();
+            return Ok((narinfo, Answer::Hit(Backend::FlakeHub)));
+        }
+    }
+
     let key = format!("{}.narinfo", store_path_hash);
 
     if state
@@ -54,66 +176,324 @@ async fn get_narinfo(
         .await
         .contains(&store_path_hash)
     {
-        state.metrics.narinfos_sent_upstream.incr();
         state.metrics.narinfos_negative_cache_hits.incr();
-        return pull_through(&state, &path);
+        return narinfo_miss(state, path).await;
+    }
+
+    match state.narinfo_cache.lookup(&store_path_hash).await {
+        Lookup::Fresh(url) => {
+            let Some(narinfo) = serve_gha_narinfo(state, &url).await? else {
+                return narinfo_miss(state, path).await;
+            };
+            state.metrics.narinfos_served.incr();
+            state.metrics.narinfos_cache_hits.incr();
+            return Ok((narinfo, Answer::Hit(Backend::Gha)));
+        }
+        Lookup::Stale(url) => {
+            let Some(narinfo) = serve_gha_narinfo(state, &url).await? else {
+                return narinfo_miss(state, path).await;
+            };
+            state.metrics.narinfos_served.incr();
+            state.metrics.narinfos_cache_hits.incr();
+
+            let state = state.clone();
+            tokio::task::spawn(async move {
+                if let Err(err) = revalidate_narinfo(&state, store_path_hash).await {
+                    tracing::debug!("Failed to revalidate a narinfo: {}", err);
+                }
+            });
+
+            return Ok((narinfo, Answer::Hit(Backend::Gha)));
+        }
+        Lookup::Miss => {}
     }
 
     if let Some(gha_cache) = &state.gha_cache {
         if let Some(url) = gha_cache.api.get_file_url(&[&key]).await? {
+            state
+                .narinfo_cache
+                .insert(store_path_hash, url.clone())
+                .await;
+            let Some(narinfo) = serve_gha_narinfo(state, &url).await? else {
+                return narinfo_miss(state, path).await;
+            };
             state.metrics.narinfos_served.incr();
-            return Ok(Redirect::temporary(&url));
+            return Ok((narinfo, Answer::Hit(Backend::Gha)));
+        }
+
+        match gha_cache
+            .handle_miss(state.store.clone(), &store_path_hash)
+            .await
+        {
+            Ok(true) => state.metrics.narinfos_evicted.incr(),
+            Ok(false) => {}
+            Err(err) => tracing::warn!("Failed to re-upload evicted path: {}", err),
         }
     }
 
-    let mut negative_cache = state.narinfo_negative_cache.write().await;
-    negative_cache.insert(store_path_hash);
+    state
+        .narinfo_negative_cache
+        .write()
+        .await
+        .insert(store_path_hash);
 
-    state.metrics.narinfos_sent_upstream.incr();
     state.metrics.narinfos_negative_cache_misses.incr();
-    pull_through(&state, &path)
+    narinfo_miss(state, path).await
+}
+
+/// Sends Nix to the narinfo at `url` in the GHA cache, or serves it ourselves
+/// if the policy has to look at it first. Returns `None` if the policy denies it.
+async fn serve_gha_narinfo(state: &State, url: &str) -> Result<Option<Response>> {
+    if !state.policy.checks(Operation::Substitute) {
+        return Ok(Some(Redirect::temporary(url).into_response()));
+    }
+
+    state
+        .policy
+        .fetch_narinfo(&state.metrics, Backend::Gha, url)
+        .await
+}
+
+/// Handles a narinfo that the GHA cache doesn't have.
+async fn narinfo_miss(state: &State, path: &str) -> Result<(Response, Answer)> {
+    if state.fallback.flakehub_last() {
+        if let Some(narinfo) = fallback::from_flakehub(state, path).await {
+            state.metrics.narinfos_served_from_flakehub.incr();
+            return Ok((narinfo, Answer::Hit(Backend::FlakeHub)));
+        }
+    }
+
+    state.metrics.narinfos_sent_upstream.incr();
+    Ok((pull_through(state, path)?.into_response(), Answer::Upstream))
+}
+
+/// Refreshes a stale narinfo cache entry from the backend.
+async fn revalidate_narinfo(state: &State, store_path_hash: String) -> Result<()> {
+    let Some(gha_cache) = &state.gha_cache else {
+        return Ok(());
+    };
+
+    let key = format!("{}.narinfo", store_path_hash);
+    match gha_cache.api.get_file_url(&[&key]).await? {
+        Some(url) => state.narinfo_cache.insert(store_path_hash, url).await,
+        None => state.narinfo_cache.remove(&store_path_hash).await,
+    }
+
+    Ok(())
+}
+
+async fn get_listing(state: &State, store_path_hash: &str) -> Result<Redirect> {
+    if let Some(gha_cache) = &state.gha_cache {
+        if let Some(url) = gha_cache
+            .api
+            .get_file_url(&[&listing::key(store_path_hash)])
+            .await?
+        {
+            state.metrics.listings_served.incr();
+            return Ok(Redirect::temporary(&url));
+        }
+    }
+
+    pull_through(state, &listing::key(store_path_hash))
 }
 
 async fn put_narinfo(
     Extension(state): Extension<State>,
     Path(path): Path<String>,
-    body: axum::body::Body,
+    body: Body,
 ) -> Result<()> {
+    if state.dry_run {
+        tracing::info!("Dry run: not uploading '{}'", path);
+        return Ok(());
+    }
+
     let components: Vec<&str> = path.splitn(2, '.').collect();
 
     if components.len() != 2 {
         return Err(Error::BadRequest);
     }
 
+    if components[1] == "ls" {
+        return put_listing(&state, components[0], body).await;
+    }
+
     if components[1] != "narinfo" {
         return Err(Error::BadRequest);
     }
 
-    let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
-
     let store_path_hash = components[0].to_string();
-    let key = format!("{}.narinfo", store_path_hash);
-    let allocation = gha_cache.api.allocate_file_with_random_suffix(&key).await?;
+    let narinfo = axum::body::to_bytes(body, MAX_NARINFO_SIZE)
+        .await
+        .map_err(|_| Error::BadRequest)?;
+    let store_path = narinfo_field(&narinfo, "StorePath");
 
-    let body_stream = body.into_data_stream();
-    let stream = StreamReader::new(
-        body_stream
-            .map(|r| r.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))),
-    );
+    let subject = state
+        .policy
+        .narinfo_subject(&String::from_utf8_lossy(&narinfo));
+    let allows = |backend| {
+        !state.policy.checks(Operation::Push)
+            || subject
+                .as_ref()
+                .is_some_and(|subject| state.policy.allows(Operation::Push, backend, subject))
+    };
+    let flakehub_allowed = state.flakehub_state.read().await.is_some() && allows(Backend::FlakeHub);
 
-    gha_cache.api.upload_file(allocation, stream).await?;
-    state.metrics.narinfos_uploaded.incr();
+    // Dropped if the path may not be pushed.
+    let nar_name = narinfo_field(&narinfo, "URL").and_then(|url| url.strip_prefix("nar/"));
+    let staged_nar = nar_name.and_then(|nar_name| state.staged_nars.take(nar_name));
 
-    state
-        .narinfo_negative_cache
-        .write()
-        .await
-        .remove(&store_path_hash);
+    match &state.gha_cache {
+        Some(_) if !allows(Backend::Gha) => {
+            tracing::info!("Not uploading '{}', as the policy denies pushing it", path);
+            state.metrics.paths_denied_policy.incr();
+            if !flakehub_allowed {
+                return Err(Error::Forbidden);
+            }
+        }
+        Some(gha_cache) => {
+            if let (Some(nar_name), Some(staged_nar)) = (nar_name, staged_nar) {
+                upload_nar(
+                    &state,
+                    nar_name,
+                    tokio::fs::File::open(&staged_nar)
+                        .await
+                        .map_err(|e| Error::Io(e, format!("Opening {}", staged_nar.display())))?,
+                )
+                .await?;
+            }
+
+            let key = format!("{}.narinfo", store_path_hash);
+            let allocation = gha_cache.api.allocate_file_with_random_suffix(&key).await?;
+
+            gha_cache
+                .api
+                .upload_file(allocation, narinfo.as_ref())
+                .await?;
+            state.metrics.narinfos_uploaded.incr();
+
+            state
+                .narinfo_negative_cache
+                .write()
+                .await
+                .remove(&store_path_hash);
+            state.narinfo_cache.remove(&store_path_hash).await;
+
+            // Keeps the post-build hook from uploading the path again.
+            if let Some(store_path) = &store_path {
+                gha_cache
+                    .record_upload(store_path_hash, store_path.into())
+                    .await;
+            }
+        }
+        None if state.flakehub_state.read().await.is_none() => return Err(Error::GHADisabled),
+        None if !flakehub_allowed => return Err(Error::Forbidden),
+        None => {}
+    }
+
+    if let Some(store_path) = store_path {
+        forward_to_flakehub(&state, store_path).await?;
+    }
 
     Ok(())
 }
 
-async fn get_nar(Extension(state): Extension<State>, Path(path): Path<String>) -> Result<Redirect> {
+/// Returns a field of a narinfo.
+fn narinfo_field<'a>(narinfo: &'a [u8], name: &str) -> Option<&'a str> {
+    std::str::from_utf8(narinfo)
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+        .map(str::trim)
+}
+
+/// Pushes a path that was uploaded to us to FlakeHub too, if it's in the local store.
+async fn forward_to_flakehub(state: &State, store_path: &str) -> Result<()> {
+    let flakehub_state = state.flakehub_state.read().await;
+    let Some(flakehub_state) = &*flakehub_state else {
+        return Ok(());
+    };
+
+    let valid_path = match state.store.follow_store_path(store_path) {
+        Ok(path) => state
+            .store
+            .query_path_info(path.clone())
+            .await
+            .ok()
+            .map(|_| path),
+        Err(_) => None,
+    };
+
+    let Some(path) = valid_path else {
+        tracing::debug!(
+            "Not pushing '{}' to FlakeHub, as it's not in the local store",
+            store_path
+        );
+        return Ok(());
+    };
+
+    let paths = state
+        .policy
+        .retain_pushes(&state.store, &state.metrics, Backend::FlakeHub, vec![path])
+        .await;
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    crate::flakehub::enqueue_paths(flakehub_state, paths).await?;
+    state.metrics.paths_forwarded_to_flakehub.incr();
+
+    Ok(())
+}
+
+async fn put_listing(state: &State, store_path_hash: &str, body: Body) -> Result<()> {
+    let Some(gha_cache) = &state.gha_cache else {
+        // Only the GitHub Actions Cache stores listings.
+        return Ok(());
+    };
+
+    let allocation = gha_cache
+        .api
+        .allocate_file_with_random_suffix(&listing::key(store_path_hash))
+        .await?;
+
+    gha_cache
+        .api
+        .upload_file(allocation, body_reader(body))
+        .await?;
+    state.metrics.listings_uploaded.incr();
+
+    Ok(())
+}
+
+async fn get_nar(Extension(state): Extension<State>, Path(path): Path<String>) -> Result<Response> {
+    if state.fallback.flakehub_first() {
+        if let Some(nar) = fallback::from_flakehub(&state, &format!("nar/{path}")).await {
+            return Ok(nar);
+        }
+    }
+
+    if state.delta_substitution {
+        match delta::substitute(&state, &path).await {
+            Ok(Some(nar)) => {
+                state.metrics.nars_served.incr();
+                state.metrics.nars_served_from_deltas.incr();
+                return Ok(nar.into_response());
+            }
+            Ok(None) => {}
+            Err(err) => tracing::debug!("Cannot substitute {} from a delta: {}", path, err),
+        }
+    }
+
+    match pack::substitute(&state, &path).await {
+        Ok(Some(nar)) => {
+            state.metrics.nars_served.incr();
+            state.metrics.nars_served_from_packs.incr();
+            return Ok(nar.into_response());
+        }
+        Ok(None) => {}
+        Err(err) => tracing::debug!("Cannot cut {} out of its pack: {}", path, err),
+    }
+
     if let Some(url) = state
         .gha_cache
         .as_ref()
@@ -123,12 +503,18 @@ async fn get_nar(Extension(state): Extension<State>, Path(path): Path<String>) -
         .await?
     {
         state.metrics.nars_served.incr();
-        return Ok(Redirect::temporary(&url));
+        return Ok(Redirect::temporary(&url).into_response());
+    }
+
+    if state.fallback.flakehub_last() {
+        if let Some(nar) = fallback::from_flakehub(&state, &format!("nar/{path}")).await {
+            return Ok(nar);
+        }
     }
 
     if let Some(upstream) = &state.upstream {
         state.metrics.nars_sent_upstream.incr();
-        Ok(Redirect::temporary(&format!("{}/nar/{}", upstream, path)))
+        Ok(Redirect::temporary(&format!("{}/nar/{}", upstream, path)).into_response())
     } else {
         Err(Error::NotFound)
     }
@@ -137,27 +523,153 @@ async fn get_nar(Extension(state): Extension<State>, Path(path): Path<String>) -
 async fn put_nar(
     Extension(state): Extension<State>,
     Path(path): Path<String>,
-    body: axum::body::Body,
+    body: Body,
+) -> Result<()> {
+    if state.dry_run {
+        tracing::info!("Dry run: not uploading '{}'", path);
+        return Ok(());
+    }
+
+    if state.gha_cache.is_none() {
+        // FlakeHub gets the path from the local store once its narinfo arrives.
+        if state.flakehub_state.read().await.is_some() {
+            return Ok(());
+        }
+        return Err(Error::GHADisabled);
+    }
+
+    if state.policy.checks(Operation::Push) {
+        return state.staged_nars.stage(path, body).await;
+    }
+
+    upload_nar(&state, &path, body_reader(body)).await
+}
+
+async fn upload_nar(state: &State, name: &str, nar: impl AsyncRead + Unpin + Send) -> Result<()> {
+    let Some(gha_cache) = &state.gha_cache else {
+        return Err(Error::GHADisabled);
+    };
+
+    let allocation = gha_cache.api.allocate_file_with_random_suffix(name).await?;
+
+    gha_cache.api.upload_file(allocation, nar).await?;
+    state.metrics.nars_uploaded.incr();
+
+    Ok(())
+}
+
+async fn get_realisation(
+    Extension(state): Extension<State>,
+    Path(path): Path<String>,
+) -> Result<Redirect> {
+    if !path.ends_with(".doi") {
+        return Err(Error::NotFound);
+    }
+
+    if let Some(gha_cache) = &state.gha_cache {
+        if let Some(url) = gha_cache
+            .api
+            .get_file_url(&[&realisation::key(&path)])
+            .await?
+        {
+            state.metrics.realisations_served.incr();
+            return Ok(Redirect::temporary(&url));
+        }
+    }
+
+    pull_through(&state, &format!("realisations/{}", path))
+}
+
+async fn get_build_log(
+    Extension(state): Extension<State>,
+    Path(path): Path<String>,
+) -> Result<Redirect> {
+    if !path.ends_with(".drv") {
+        return Err(Error::NotFound);
+    }
+
+    if let Some(gha_cache) = &state.gha_cache {
+        if let Some(url) = gha_cache
+            .api
+            .get_file_url(&[&build_log::key(&path)])
+            .await?
+        {
+            state.metrics.build_logs_served.incr();
+            return Ok(Redirect::temporary(&url));
+        }
+    }
+
+    pull_through(&state, &format!("log/{}", path))
+}
+
+async fn put_realisation(
+    Extension(state): Extension<State>,
+    Path(path): Path<String>,
+    body: Body,
 ) -> Result<()> {
+    if state.dry_run {
+        tracing::info!("Dry run: not uploading '{}'", path);
+        return Ok(());
+    }
+
+    if !path.ends_with(".doi") {
+        return Err(Error::BadRequest);
+    }
+
     let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
 
     let allocation = gha_cache
         .api
-        .allocate_file_with_random_suffix(&path)
+        .allocate_file_with_random_suffix(&realisation::key(&path))
         .await?;
 
-    let body_stream = body.into_data_stream();
-    let stream = StreamReader::new(
-        body_stream
-            .map(|r| r.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))),
-    );
+    gha_cache
+        .api
+        .upload_file(allocation, body_reader(body))
+        .await?;
+    state.metrics.realisations_uploaded.incr();
 
-    gha_cache.api.upload_file(allocation, stream).await?;
-    state.metrics.nars_uploaded.incr();
+    Ok(())
+}
+
+async fn put_build_log(
+    Extension(state): Extension<State>,
+    Path(path): Path<String>,
+    body: Body,
+) -> Result<()> {
+    if state.dry_run {
+        tracing::info!("Dry run: not uploading '{}'", path);
+        return Ok(());
+    }
+
+    if !path.ends_with(".drv") {
+        return Err(Error::BadRequest);
+    }
+
+    let gha_cache = state.gha_cache.as_ref().ok_or(Error::GHADisabled)?;
+
+    let allocation = gha_cache
+        .api
+        .allocate_file_with_random_suffix(&build_log::key(&path))
+        .await?;
+
+    gha_cache
+        .api
+        .upload_file(allocation, body_reader(body))
+        .await?;
+    state.metrics.build_logs_uploaded.incr();
 
     Ok(())
 }
 
+/// Reads an uploaded file.
+fn body_reader(body: Body) -> impl AsyncRead + Unpin + Send {
+    StreamReader::new(
+        body.into_data_stream()
+            .map(|r| r.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))),
+    )
+}
+
 fn pull_through(state: &State, path: &str) -> Result<Redirect> {
     if let Some(upstream) = &state.upstream {
         Ok(Redirect::temporary(&format!("{}/{}", upstream, path)))
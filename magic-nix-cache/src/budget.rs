@@ -0,0 +1,304 @@
+//! Checking closures against the upload budget before they are queued.
+//!
+//! A repository's GitHub Actions Cache holds 10 GiB by default. Past that,
+//! GitHub starts evicting entries and refusing uploads, so a large closure
+//! fails partway through, after pushing a random part of itself. Instead, we
+//! estimate how much each closure will take up once compressed before queueing
+//! it, and compare that with what's left of the quota and of `--upload-budget`.
+//! Closures that don't fit are warned about, or with `--over-budget trim`, left
+//! out root by root, as a path without its dependencies can't be substituted.
+//!
+//! The estimate is the NAR size of each path times the compression ratio seen
+//! so far. Getting the NAR sizes takes a query per path, so the check only runs
+//! when `--cache-quota` or `--upload-budget` is given.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use attic::nix_store::{NixStore, StorePath};
+use futures::stream::{self, StreamExt as _, TryStreamExt as _};
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+/// The compression ratio assumed until the first NAR has been compressed.
+const INITIAL_RATIO: f64 = 0.4;
+
+/// How much weight the most recent NAR gets in the ratio estimate.
+const SMOOTHING: f64 = 0.1;
+
+/// How many path infos we query at once.
+const CONCURRENT_QUERIES: usize = 16;
+
+/// What to do with a closure that doesn't fit in the budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverBudget {
+    /// Queue everything anyway, and warn.
+    Warn,
+
+    /// Queue the closures of as many of the paths as fit, the paths that were asked for first.
+    Trim,
+}
+
+#[derive(Debug)]
+pub struct UploadBudget {
+    /// How many compressed bytes we may upload in total.
+    limit: u64,
+
+    over_budget: OverBudget,
+
+    /// The estimated compressed size of what we queued so far, and the paths that make it up.
+    queued: std::sync::Mutex<(u64, HashSet<PathBuf>)>,
+
+    /// Moving average of the compressed size of a NAR over its uncompressed size.
+    ratio: std::sync::Mutex<f64>,
+}
+
+impl UploadBudget {
+    pub fn new(limit: u64, over_budget: OverBudget) -> Self {
+        Self {
+            limit,
+            over_budget,
+            queued: Default::default(),
+            ratio: std::sync::Mutex::new(INITIAL_RATIO),
+        }
+    }
+
+    /// Checks the paths in `closure` that weren't checked before against the
+    /// budget, returning the closure without the paths that were left out,
+    /// and their number.
+    ///
+    /// `roots` are the paths the closure was computed from, in order. When
+    /// trimming, the closures of the first ones are kept whole, and the rest
+    /// are left out along with what only they need.
+    pub async fn check(
+        &self,
+        store: &NixStore,
+        roots: &[StorePath],
+        mut closure: Vec<StorePath>,
+    ) -> Result<(Vec<StorePath>, usize)> {
+        let new_paths: Vec<PathBuf> = {
+            let queued = self.lock_queued();
+            closure
+                .iter()
+                .map(|path| store.get_full_path(path))
+                .filter(|full_path| !queued.1.contains(full_path))
+                .collect()
+        };
+
+        let ratio = self.ratio();
+        let mut sized: Vec<(PathBuf, u64)> = stream::iter(new_paths)
+            .map(|full_path| async move {
+                let path_info = store
+                    .query_path_info(store.follow_store_path(&full_path)?)
+                    .await?;
+                Ok::<_, Error>((full_path, (path_info.nar_size as f64 * ratio) as u64))
+            })
+            .buffered(CONCURRENT_QUERIES)
+            .try_collect()
+            .await?;
+
+        let estimate: u64 = sized.iter().map(|(_, size)| size).sum();
+
+        // Only needed when trimming, and the closures of most roots overlap a lot.
+        let root_closures = if self.over_budget == OverBudget::Trim
+            && estimate > self.limit.saturating_sub(self.lock_queued().0)
+        {
+            let mut root_closures = Vec::with_capacity(roots.len());
+            for root in roots {
+                let root_closure = store
+                    .compute_fs_closure_multi(vec![root.clone()], false, false, false)
+                    .await?;
+                root_closures.push((
+                    store.get_full_path(root),
+                    root_closure
+                        .iter()
+                        .map(|path| store.get_full_path(path))
+                        .collect::<Vec<_>>(),
+                ));
+            }
+            root_closures
+        } else {
+            Vec::new()
+        };
+
+        let mut queued = self.lock_queued();
+        let remaining = self.limit.saturating_sub(queued.0);
+
+        if estimate <= remaining || self.over_budget == OverBudget::Warn {
+            if estimate > remaining {
+                tracing::warn!(
+                    "The paths to upload take up about {} MiB compressed, but only {} MiB of the upload budget is left",
+                    estimate / (1024 * 1024),
+                    remaining / (1024 * 1024)
+                );
+            }
+
+            queued.0 += estimate;
+            queued
+                .1
+                .extend(sized.into_iter().map(|(full_path, _)| full_path));
+            return Ok((closure, 0));
+        }
+
+        let sizes: HashMap<PathBuf, u64> = sized.into_iter().collect();
+        let kept = trim(&sizes, &root_closures, remaining);
+        let kept_size: u64 = kept.iter().map(|full_path| sizes[full_path]).sum();
+        queued.0 += kept_size;
+        queued.1.extend(kept.iter().cloned());
+
+        let trimmed: HashSet<PathBuf> = sizes
+            .into_keys()
+            .filter(|full_path| !kept.contains(full_path))
+            .collect();
+
+        tracing::warn!(
+            "The paths to upload take up about {} MiB compressed, but only {} MiB of the upload budget is left; leaving out {} paths",
+            estimate / (1024 * 1024),
+            remaining / (1024 * 1024),
+            trimmed.len()
+        );
+
+        closure.retain(|path| !trimmed.contains(&store.get_full_path(path)));
+
+        Ok((closure, trimmed.len()))
+    }
+
+    /// Records that a NAR of `nar_size` bytes compressed to `compressed_size`.
+    pub fn record(&self, nar_size: u64, compressed_size: u64) {
+        if nar_size == 0 {
+            return;
+        }
+
+        let sample = compressed_size as f64 / nar_size as f64;
+        let mut ratio = self.ratio.lock().expect("ratio mutex was poisoned");
+        *ratio = *ratio * (1.0 - SMOOTHING) + sample * SMOOTHING;
+    }
+
+    fn ratio(&self) -> f64 {
+        *self.ratio.lock().expect("ratio mutex was poisoned")
+    }
+
+    fn lock_queued(&self) -> std::sync::MutexGuard<'_, (u64, HashSet<PathBuf>)> {
+        self.queued
+            .lock()
+            .expect("upload budget mutex was poisoned")
+    }
+}
+
+/// Picks the roots whose closures fit in `remaining` bytes, in order, and
+/// returns the paths of their closures that were sized.
+///
+/// Paths that aren't in `sizes` were queued before, and come for free.
+fn trim(
+    sizes: &HashMap<PathBuf, u64>,
+    root_closures: &[(PathBuf, Vec<PathBuf>)],
+    remaining: u64,
+) -> HashSet<PathBuf> {
+    let mut left = remaining;
+    let mut kept = HashSet::new();
+    for (root, root_closure) in root_closures {
+        let new_paths: HashSet<&PathBuf> = root_closure
+            .iter()
+            .filter(|full_path| sizes.contains_key(*full_path) && !kept.contains(*full_path))
+            .collect();
+        let size: u64 = new_paths.iter().map(|full_path| sizes[*full_path]).sum();
+
+        if size <= left {
+            left -= size;
+            kept.extend(new_paths.into_iter().cloned());
+        } else {
+            tracing::debug!(
+                "Not uploading the closure of '{}' (about {} bytes compressed): over budget",
+                root.display(),
+                size
+            );
+        }
+    }
+
+    kept
+}
+
+#[derive(Deserialize)]
+struct CacheUsage {
+    active_caches_size_in_bytes: u64,
+}
+
+/// How much of the repository's `quota` is still free.
+///
+/// GitHub only tells us how much is in use given a token, so without
+/// `GITHUB_TOKEN` the whole quota is assumed to be free.
+pub async fn remaining_quota(quota: u64) -> u64 {
+    match cache_usage().await {
+        Ok(Some(usage)) => {
+            tracing::debug!(
+                "The GitHub Actions Cache of this repository holds {} bytes",
+                usage
+            );
+            quota.saturating_sub(usage)
+        }
+        Ok(None) => quota,
+        Err(err) => {
+            tracing::warn!("Failed to query the GitHub Actions Cache usage: {}", err);
+            quota
+        }
+    }
+}
+
+async fn cache_usage() -> Result<Option<u64>> {
+    let (Ok(token), Ok(repository)) = (
+        std::env::var("GITHUB_TOKEN"),
+        std::env::var("GITHUB_REPOSITORY"),
+    ) else {
+        return Ok(None);
+    };
+    let api_url =
+        std::env::var("GITHUB_API_URL").unwrap_or_else(|_| "https://api.github.com".to_owned());
+
+    let usage: CacheUsage = crate::http_client::client()
+        .get(format!("{api_url}/repos/{repository}/actions/cache/usage"))
+        .bearer_auth(token)
+        .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Error::Internal(format!("Querying the cache usage: {e}")))?
+        .json()
+        .await
+        .map_err(|e| Error::Internal(format!("Parsing the cache usage: {e}")))?;
+
+    Ok(Some(usage.active_caches_size_in_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trimming_keeps_whole_closures() {
+        let path = |name: &str| PathBuf::from(format!("/nix/store/{name}"));
+        let sizes = HashMap::from([
+            (path("app"), 10),
+            (path("lib"), 50),
+            (path("tool"), 10),
+            (path("big"), 100),
+        ]);
+        let root_closures = vec![
+            (path("app"), vec![path("app"), path("lib"), path("glibc")]),
+            (path("big"), vec![path("big"), path("lib")]),
+            (path("tool"), vec![path("tool"), path("lib")]),
+        ];
+
+        // `big` doesn't fit, but `tool` does, as it shares `lib` with `app`.
+        // `glibc` was queued before.
+        let kept = trim(&sizes, &root_closures, 80);
+        assert_eq!(
+            kept,
+            HashSet::from([path("app"), path("lib"), path("tool")])
+        );
+
+        // Without room for `lib`, nothing that needs it is kept.
+        let kept = trim(&sizes, &root_closures, 40);
+        assert!(kept.is_empty());
+    }
+}
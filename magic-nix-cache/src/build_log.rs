@@ -0,0 +1,47 @@
+//! Build logs.
+//!
+//! With `--upload-build-logs`, the log of each derivation we build is pushed
+//! next to its outputs, so that `nix log` on another machine can show how a
+//! substituted path was built. Binary caches serve them as `/log/<drv name>`.
+
+use std::path::Path;
+
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+
+/// Turns the file name of a derivation into the GitHub Actions Cache key of its log.
+pub fn key(drv_name: &str) -> String {
+    format!("log/{drv_name}")
+}
+
+/// Reads the build log of `drv_path` from the local store.
+///
+/// Returns `None` if Nix has no log for it, e.g. because it was substituted.
+pub async fn query(drv_path: &Path) -> Result<Option<Vec<u8>>> {
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "log",
+            // Don't ask the substituters, which include ourselves.
+            "--option",
+            "substituters",
+            "",
+        ])
+        .arg(drv_path)
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix log".to_owned()))?;
+
+    if !output.status.success() {
+        tracing::debug!(
+            "No build log for {}: {}",
+            drv_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(output.stdout))
+}
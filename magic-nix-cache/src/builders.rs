@@ -0,0 +1,189 @@
+//! Fetching outputs from remote builders.
+//!
+//! When a build runs on a remote builder, its outputs aren't necessarily copied
+//! back to the local store by the time they're enqueued. With `--fetch-from`,
+//! enqueued paths that are missing locally are copied from the given stores
+//! (e.g. `ssh-ng://builder`) before they're pushed. The copying happens in the
+//! background, so the paths that are already here are pushed right away.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use attic::nix_store::StorePath;
+use futures::future::BoxFuture;
+use tokio::process::Command;
+use tokio::task::JoinSet;
+
+use crate::error::{Error, Result};
+use crate::State;
+
+/// Fetches missing paths in the background, so that enqueueing never waits for a builder.
+#[derive(Default)]
+pub struct Fetcher {
+    /// The stores to fetch from, e.g. `ssh-ng://builder`.
+    remotes: Vec<String>,
+
+    /// The fetches that are still running.
+    fetches: std::sync::Mutex<JoinSet<()>>,
+}
+
+impl Fetcher {
+    pub fn new(remotes: Vec<String>) -> Self {
+        Self {
+            remotes,
+            fetches: Default::default(),
+        }
+    }
+
+    pub fn remotes(&self) -> &[String] {
+        &self.remotes
+    }
+
+    /// Waits for the running fetches, and for what they fetched to be enqueued.
+    pub async fn drain(&self) {
+        loop {
+            let mut fetches = std::mem::take(&mut *self.lock());
+            if fetches.is_empty() {
+                return;
+            }
+
+            while fetches.join_next().await.is_some() {}
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JoinSet<()>> {
+        self.fetches.lock().expect("fetches mutex was poisoned")
+    }
+}
+
+/// Returns the paths among `store_paths` that are valid locally. The others are
+/// copied from the remote stores in the background, and enqueued once they're here.
+pub async fn fetch_missing(state: &State, store_paths: Vec<StorePath>) -> Vec<StorePath> {
+    if state.fetcher.remotes.is_empty() {
+        return store_paths;
+    }
+
+    let missing = missing_paths(state, &store_paths).await;
+    if missing.is_empty() {
+        return store_paths;
+    }
+
+    let missing_full_paths: HashSet<_> = missing
+        .iter()
+        .map(|p| state.store.get_full_path(p))
+        .collect();
+    let present = store_paths
+        .into_iter()
+        .filter(|p| !missing_full_paths.contains(&state.store.get_full_path(p)))
+        .collect();
+
+    state
+        .fetcher
+        .lock()
+        .spawn(fetch_and_enqueue(state.clone(), missing));
+
+    present
+}
+
+/// Boxed, because enqueueing the fetched paths is what got us here.
+fn fetch_and_enqueue(state: State, missing: Vec<StorePath>) -> BoxFuture<'static, ()> {
+    Box::pin(async move {
+        let fetched = fetch(&state, missing).await;
+        if fetched.is_empty() {
+            return;
+        }
+
+        if let Err(err) = crate::api::enqueue_paths(&state, fetched).await {
+            tracing::error!("Failed to enqueue the paths fetched from builders: {}", err);
+        }
+    })
+}
+
+/// Copies `missing` from the remote stores, returning the paths that arrived.
+async fn fetch(state: &State, missing: Vec<StorePath>) -> Vec<StorePath> {
+    let requested = missing.clone();
+    let mut missing = missing;
+
+    for remote in &state.fetcher.remotes {
+        let full_paths: Vec<_> = missing
+            .iter()
+            .map(|p| state.store.get_full_path(p))
+            .collect();
+
+        tracing::info!("Fetching {} paths from {}", full_paths.len(), remote);
+
+        // Some of the paths may be on another builder, so failures are expected.
+        if let Err(err) = copy_from(remote, &full_paths).await {
+            tracing::debug!("Could not fetch all paths from {}: {}", remote, err);
+        }
+
+        let still_missing = missing_paths(state, &missing).await;
+        state
+            .metrics
+            .paths_fetched_from_builders
+            .add(missing.len() - still_missing.len());
+        missing = still_missing;
+
+        if missing.is_empty() {
+            break;
+        }
+    }
+
+    for path in &missing {
+        tracing::warn!(
+            "'{}' is neither in the local store nor on any builder",
+            state.store.get_full_path(path).display()
+        );
+    }
+
+    let still_missing: HashSet<_> = missing
+        .iter()
+        .map(|p| state.store.get_full_path(p))
+        .collect();
+    requested
+        .into_iter()
+        .filter(|p| !still_missing.contains(&state.store.get_full_path(p)))
+        .collect()
+}
+
+async fn missing_paths(state: &State, store_paths: &[StorePath]) -> Vec<StorePath> {
+    let mut missing = Vec::new();
+    for store_path in store_paths {
+        if state
+            .store
+            .query_path_info(store_path.clone())
+            .await
+            .is_err()
+        {
+            missing.push(store_path.clone());
+        }
+    }
+
+    missing
+}
+
+async fn copy_from(remote: &str, full_paths: &[PathBuf]) -> Result<()> {
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "copy",
+            // Builders don't sign what they build.
+            "--no-check-sigs",
+            "--from",
+            remote,
+        ])
+        .args(full_paths)
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix copy".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Internal(format!(
+            "nix copy failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
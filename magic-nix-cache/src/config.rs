@@ -0,0 +1,40 @@
+//! Reading arguments from a file.
+//!
+//! `--config-file` names a file with more arguments for the daemon, separated
+//! by whitespace, where lines starting with `#` are comments. They are placed
+//! before those on the command line, which thus take precedence. The file is
+//! read again when the daemon reloads its configuration.
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// Returns our command line with the arguments from `path` spliced in after
+/// the program name.
+pub fn args_with_file(path: &Path) -> Result<Vec<OsString>> {
+    let mut args = std::env::args_os();
+    let mut spliced: Vec<OsString> = args.next().into_iter().collect();
+    spliced.extend(read_args(path)?);
+    spliced.extend(args);
+    Ok(spliced)
+}
+
+/// Reads the arguments in `path`. A missing file holds no arguments.
+fn read_args(path: &Path) -> Result<Vec<OsString>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::Io(e, format!("Reading {}", path.display()))),
+    };
+
+    tracing::debug!("Reading arguments from {}", path.display());
+
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .flat_map(str::split_whitespace)
+        .map(OsString::from)
+        .collect())
+}
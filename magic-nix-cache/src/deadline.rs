@@ -0,0 +1,70 @@
+//! Deadline-aware upload scheduling.
+//!
+//! GitHub kills jobs that run past their timeout. A NAR that was cut off halfway
+//! still counts against the cache quota, so as the job's deadline approaches we
+//! stop starting uploads that aren't expected to finish in time.
+
+use std::time::{Duration, SystemTime};
+
+/// Time kept in reserve for the narinfo upload and shutting down.
+const SAFETY_MARGIN: Duration = Duration::from_secs(30);
+
+/// The NAR throughput assumed until the first upload has been measured, in bytes per second.
+const INITIAL_THROUGHPUT: f64 = 10.0 * 1024.0 * 1024.0;
+
+/// How much weight the most recent upload gets in the throughput estimate.
+const SMOOTHING: f64 = 0.3;
+
+pub struct UploadDeadline {
+    /// When the job is going to be killed, if known.
+    deadline: Option<SystemTime>,
+
+    /// Moving average of the NAR bytes we upload per second.
+    throughput: std::sync::Mutex<f64>,
+}
+
+impl UploadDeadline {
+    pub fn new(deadline: Option<SystemTime>) -> Self {
+        Self {
+            deadline,
+            throughput: std::sync::Mutex::new(INITIAL_THROUGHPUT),
+        }
+    }
+
+    /// Whether a NAR of `nar_size` bytes is expected to be uploaded before the deadline.
+    pub fn allows(&self, nar_size: u64) -> bool {
+        let Some(deadline) = self.deadline else {
+            return true;
+        };
+
+        let Ok(remaining) = deadline.duration_since(SystemTime::now()) else {
+            return false;
+        };
+
+        let expected = Duration::try_from_secs_f64(nar_size as f64 / self.throughput().max(1.0))
+            .unwrap_or(Duration::MAX);
+
+        expected.saturating_add(SAFETY_MARGIN) <= remaining
+    }
+
+    /// Records that a NAR of `nar_size` bytes took `elapsed` to upload.
+    pub fn record(&self, nar_size: u64, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
+
+        let sample = nar_size as f64 / elapsed.as_secs_f64();
+        let mut throughput = self
+            .throughput
+            .lock()
+            .expect("throughput mutex was poisoned");
+        *throughput = *throughput * (1.0 - SMOOTHING) + sample * SMOOTHING;
+    }
+
+    fn throughput(&self) -> f64 {
+        *self
+            .throughput
+            .lock()
+            .expect("throughput mutex was poisoned")
+    }
+}
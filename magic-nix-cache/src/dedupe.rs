@@ -0,0 +1,155 @@
+//! Remembering uploads across jobs on persistent runners.
+//!
+//! Every job pushes the closures of what it built, so back-to-back jobs on the
+//! same self-hosted runner upload thousands of unchanged paths again. With
+//! `--dedupe-index`, successful uploads are recorded in an SQLite database that
+//! outlives the daemon, and paths found there are not enqueued again.
+//!
+//! Uploads are recorded per scope: the repository and the part of the GitHub
+//! Actions Cache that was written to, as the same store path has to be uploaded
+//! again for another repository or `--cache-version`. SQLite takes care of jobs
+//! on the same machine sharing the database at the same time, which means a
+//! query may wait for another job, so queries run on the blocking thread pool.
+//!
+//! Only the GitHub Actions Cache uses the index. attic asks FlakeHub which paths
+//! it's missing before pushing anyway.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension as _};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug)]
+pub struct DedupeIndex {
+    connection: Arc<std::sync::Mutex<Connection>>,
+}
+
+impl DedupeIndex {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| Error::Io(e, format!("Creating {}", parent.display())))?;
+        }
+
+        let connection = Connection::open(path).map_err(|e| sqlite_error(path, e))?;
+
+        // Other jobs may hold the database for a moment.
+        connection
+            .busy_timeout(std::time::Duration::from_secs(5))
+            .and_then(|_| {
+                connection.execute_batch(
+                    "PRAGMA journal_mode = WAL;
+                     CREATE TABLE IF NOT EXISTS uploads (
+                         scope TEXT NOT NULL,
+                         store_path TEXT NOT NULL,
+                         uploaded_at INTEGER NOT NULL,
+                         PRIMARY KEY (scope, store_path)
+                     );",
+                )
+            })
+            .map_err(|e| sqlite_error(path, e))?;
+
+        Ok(Self {
+            connection: Arc::new(std::sync::Mutex::new(connection)),
+        })
+    }
+
+    /// The paths of `store_paths` that were uploaded in `scope` before.
+    pub async fn uploaded(&self, scope: &str, store_paths: Vec<PathBuf>) -> HashSet<PathBuf> {
+        let scope = scope.to_owned();
+        let result = self
+            .with_connection(move |connection| {
+                let mut statement = connection
+                    .prepare_cached("SELECT 1 FROM uploads WHERE scope = ?1 AND store_path = ?2")?;
+
+                let mut uploaded = HashSet::new();
+                for store_path in store_paths {
+                    let found = statement
+                        .query_row(params![scope, store_path.to_string_lossy()], |_| Ok(()))
+                        .optional()?;
+                    if found.is_some() {
+                        uploaded.insert(store_path);
+                    }
+                }
+
+                Ok(uploaded)
+            })
+            .await;
+
+        match result {
+            Ok(uploaded) => uploaded,
+            Err(err) => {
+                tracing::debug!("Failed to query the dedupe index: {}", err);
+                HashSet::new()
+            }
+        }
+    }
+
+    /// Records that `store_path` was uploaded in `scope`.
+    pub async fn record(&self, scope: &str, store_path: &Path) {
+        let uploaded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+
+        let scope = scope.to_owned();
+        let store_path = store_path.to_string_lossy().into_owned();
+        let result = self
+            .with_connection(move |connection| {
+                connection.execute(
+                    "INSERT OR REPLACE INTO uploads (scope, store_path, uploaded_at) VALUES (?1, ?2, ?3)",
+                    params![scope, store_path, uploaded_at],
+                )
+            })
+            .await;
+
+        if let Err(err) = result {
+            tracing::warn!("Failed to record an upload in the dedupe index: {}", err);
+        }
+    }
+
+    /// Forgets an upload, e.g. because GitHub evicted it.
+    pub async fn forget(&self, scope: &str, store_path: &Path) {
+        let scope = scope.to_owned();
+        let store_path = store_path.to_string_lossy().into_owned();
+        let result = self
+            .with_connection(move |connection| {
+                connection.execute(
+                    "DELETE FROM uploads WHERE scope = ?1 AND store_path = ?2",
+                    params![scope, store_path],
+                )
+            })
+            .await;
+
+        if let Err(err) = result {
+            tracing::warn!("Failed to remove an upload from the dedupe index: {}", err);
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool, as it may wait for another job.
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            f(&connection.lock().expect("dedupe index mutex was poisoned"))
+        })
+        .await
+        .map_err(|e| Error::Internal(format!("Querying the dedupe index: {e}")))?
+        .map_err(|e| Error::Internal(e.to_string()))
+    }
+}
+
+fn sqlite_error(path: &Path, err: rusqlite::Error) -> Error {
+    Error::Internal(format!(
+        "Opening the dedupe index at {}: {err}",
+        path.display()
+    ))
+}
@@ -0,0 +1,186 @@
+//! Delta substitution.
+//!
+//! Bumping a dependency usually changes only a small part of each affected
+//! store path. When we upload a path and an earlier version of it (same name,
+//! different hash) that we uploaded before is still in the local store, we also
+//! upload the new NAR compressed with the old NAR as a zstd reference prefix
+//! (what `zstd --patch-from` does). Substituting daemons that have the old
+//! version locally fetch the much smaller delta and rebuild the NAR from it,
+//! falling back to the full NAR otherwise.
+//!
+//! A delta is stored as two cache entries:
+//! - `<nar hash>.delta`, a small JSON [`DeltaInfo`] saying which base it needs;
+//! - `<nar hash>.from-<base nar hash>.nar.zstd-delta`, the delta itself.
+
+use std::io::{Read as _, Write as _};
+use std::path::{Path, PathBuf};
+
+use attic::nix_store::NixStore;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::nar;
+use crate::State;
+
+/// NARs bigger than this are never diffed, as both versions are held in memory.
+pub const MAX_NAR_SIZE: u64 = 512 * 1024 * 1024;
+
+/// The zstd level used for deltas. Deltas are uploaded once and fetched many times.
+const DELTA_LEVEL: i32 = 19;
+
+/// The zstd level used when handing a rebuilt NAR to Nix.
+const SERVE_LEVEL: i32 = 1;
+
+/// Describes the delta available for a NAR.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaInfo {
+    /// The name of the store path, without the hash.
+    pub name: String,
+
+    /// The NAR hash of the version the delta applies to.
+    pub base_nar_hash: String,
+}
+
+pub fn info_key(nar_hash: &str) -> String {
+    format!("{nar_hash}.delta")
+}
+
+pub fn blob_key(nar_hash: &str, base_nar_hash: &str) -> String {
+    format!("{nar_hash}.from-{base_nar_hash}.nar.zstd-delta")
+}
+
+/// Returns the name of a store path, i.e. the part after the hash.
+pub fn path_name(store_path: &Path) -> Option<&str> {
+    let (_, name) = store_path.file_name()?.to_str()?.split_once('-')?;
+    Some(name)
+}
+
+/// Compresses `target` using `base` as a reference.
+pub fn make(base: &[u8], target: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(Vec::new(), DELTA_LEVEL, base)?;
+    encoder.window_log(window_log(base.len() + target.len()))?;
+    encoder.long_distance_matching(true)?;
+    encoder.write_all(target)?;
+    encoder.finish()
+}
+
+/// Rebuilds the target of a delta made with [`make`] from its base.
+pub fn apply(base: &[u8], delta: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(delta, base)?;
+    decoder.window_log_max(31)?;
+
+    let mut target = Vec::new();
+    decoder.read_to_end(&mut target)?;
+    Ok(target)
+}
+
+/// The smallest zstd window that covers both the reference and the data.
+fn window_log(len: usize) -> u32 {
+    (usize::BITS - len.leading_zeros()).clamp(10, 31)
+}
+
+/// Lists the local store paths called `name`.
+async fn local_versions(store: &NixStore, name: &str) -> Result<Vec<PathBuf>> {
+    let store_dir = store.store_dir();
+    let mut listing = tokio::fs::read_dir(store_dir)
+        .await
+        .map_err(|e| Error::Io(e, format!("Listing {}", store_dir.display())))?;
+
+    let mut versions = Vec::new();
+    while let Some(entry) = listing
+        .next_entry()
+        .await
+        .map_err(|e| Error::Io(e, format!("Listing {}", store_dir.display())))?
+    {
+        let path = entry.path();
+        if path_name(&path) == Some(name) {
+            versions.push(path);
+        }
+    }
+
+    Ok(versions)
+}
+
+async fn download(url: &str) -> Result<Vec<u8>> {
+    let body = crate::http_client::client()
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Error::Internal(format!("Downloading {url}: {e}")))?
+        .bytes()
+        .await
+        .map_err(|e| Error::Internal(format!("Downloading {url}: {e}")))?;
+
+    Ok(body.to_vec())
+}
+
+/// Tries to rebuild the NAR `nar_file` (e.g. `<nar hash>.nar.zstd`) from a delta
+/// against a path in the local store.
+///
+/// Returns the zstd-compressed NAR, or `None` if there's no usable delta.
+pub async fn substitute(state: &State, nar_file: &str) -> Result<Option<Vec<u8>>> {
+    let Some(gha_cache) = &state.gha_cache else {
+        return Ok(None);
+    };
+
+    let Some(nar_hash) = nar_file.strip_suffix(".nar.zstd") else {
+        return Ok(None);
+    };
+
+    let Some(info_url) = gha_cache.api.get_file_url(&[&info_key(nar_hash)]).await? else {
+        return Ok(None);
+    };
+
+    let info: DeltaInfo = serde_json::from_slice(&download(&info_url).await?)
+        .map_err(|e| Error::Internal(format!("Parsing the delta info of {nar_hash}: {e}")))?;
+
+    let mut base = None;
+    for candidate in local_versions(&state.store, &info.name).await? {
+        let Ok(store_path) = state.store.follow_store_path(&candidate) else {
+            continue;
+        };
+        let Ok(path_info) = state.store.query_path_info(store_path.clone()).await else {
+            continue;
+        };
+
+        if path_info.nar_hash.to_base32() == info.base_nar_hash
+            && path_info.nar_size <= MAX_NAR_SIZE
+        {
+            base = Some(store_path);
+            break;
+        }
+    }
+
+    let Some(base) = base else {
+        tracing::debug!(
+            "A delta exists for {}, but its base isn't in the local store",
+            info.name
+        );
+        return Ok(None);
+    };
+
+    let Some(delta_url) = gha_cache
+        .api
+        .get_file_url(&[&blob_key(nar_hash, &info.base_nar_hash)])
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    let delta = download(&delta_url).await?;
+    let delta_size = delta.len();
+    let base_nar = nar::read(&state.store, base).await?;
+
+    let compressed = tokio::task::spawn_blocking(move || {
+        let nar = apply(&base_nar, &delta)?;
+        zstd::bulk::compress(&nar, SERVE_LEVEL)
+    })
+    .await
+    .map_err(|e| Error::Internal(format!("Applying a delta: {e}")))?
+    .map_err(|e| Error::Io(e, format!("Applying the delta for {nar_hash}")))?;
+
+    tracing::debug!("Rebuilt {} from a {} byte delta", info.name, delta_size);
+
+    Ok(Some(compressed))
+}
@@ -0,0 +1,153 @@
+//! Running on a developer machine.
+//!
+//! With `--dev-mode`, the daemon runs as a long-lived service rather than for
+//! the length of a CI job:
+//! - `--config-file` defaults to `magic-nix-cache/args` in the user's config
+//!   directory;
+//! - new paths are found by watching the store, so that whatever gets built
+//!   while the daemon runs is pushed;
+//! - the daemon's state is saved every `--flush-interval` seconds, not just at
+//!   shutdown, which may never come cleanly.
+//!
+//! `magic-nix-cache install-service` writes a systemd user unit (Linux) or a
+//! launchd agent (macOS) that starts the daemon in dev mode at login.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::State;
+
+/// The name of the systemd unit and the label of the launchd agent.
+const SERVICE_NAME: &str = "magic-nix-cache";
+
+/// The default `--config-file` in dev mode.
+pub fn config_file() -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("magic-nix-cache")
+        .ok()
+        .map(|dirs| dirs.get_config_home().join("args"))
+}
+
+/// Saves the daemon's state every `interval`, for as long as it runs.
+pub fn spawn_flush(state: State, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately, and there's nothing to save yet.
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            if let Err(err) = state.narinfo_cache.save().await {
+                tracing::warn!("Failed to save the narinfo cache: {}", err);
+            }
+
+            tracing::debug!(
+                "Flushed; {} paths uploaded so far",
+                state.metrics.nars_uploaded.get()
+            );
+        }
+    });
+}
+
+/// Writes a service definition that runs this executable in dev mode, or
+/// prints it if `print` is set.
+pub fn install_service(print: bool) -> Result<()> {
+    let exe = std::env::current_exe()
+        .map_err(|e| Error::Io(e, "Finding the path of magic-nix-cache".to_owned()))?;
+
+    let (path, contents, enable) = if cfg!(target_os = "macos") {
+        let path = home_dir()?
+            .join("Library/LaunchAgents")
+            .join(format!("{SERVICE_NAME}.plist"));
+        let enable = format!("launchctl load -w {}", path.display());
+        (path, launchd_agent(&exe), enable)
+    } else {
+        let path = xdg::BaseDirectories::new()
+            .map_err(|e| Error::Config(format!("Finding the config directory: {e}")))?
+            .get_config_home()
+            .join("systemd/user")
+            .join(format!("{SERVICE_NAME}.service"));
+        let enable = format!("systemctl --user enable --now {SERVICE_NAME}");
+        (path, systemd_unit(&exe), enable)
+    };
+
+    if print {
+        print!("{contents}");
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| Error::Io(e, format!("Creating {}", parent.display())))?;
+    }
+    std::fs::write(&path, contents)
+        .map_err(|e| Error::Io(e, format!("Writing {}", path.display())))?;
+
+    println!("Wrote {}", path.display());
+    if let Some(config_file) = config_file() {
+        println!(
+            "Put any arguments for the daemon in {}",
+            config_file.display()
+        );
+    }
+    println!("Start it with: {enable}");
+
+    Ok(())
+}
+
+fn home_dir() -> Result<PathBuf> {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::Config("HOME is not set".to_owned()))
+}
+
+fn systemd_unit(exe: &Path) -> String {
+    format!(
+        "[Unit]
+Description=Magic Nix Cache
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+ExecStart=\"{}\" --dev-mode
+Restart=on-failure
+RestartSec=10
+
+[Install]
+WantedBy=default.target
+",
+        exe.display()
+    )
+}
+
+fn launchd_agent(exe: &Path) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{SERVICE_NAME}</string>
+  <key>ProgramArguments</key>
+  <array>
+    <string>{}</string>
+    <string>--dev-mode</string>
+  </array>
+  <key>RunAtLoad</key>
+  <true/>
+  <key>KeepAlive</key>
+  <true/>
+</dict>
+</plist>
+"#,
+        xml_escape(&exe.display().to_string())
+    )
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -19,6 +19,27 @@ pub enum Error {
     #[error("Bad Request")]
     BadRequest,
 
+    #[error("Unauthorized")]
+    Unauthorized,
+
+    #[error("Forbidden")]
+    Forbidden,
+
+    #[error("Too Many Requests")]
+    RateLimited,
+
+    #[error("Method Not Allowed")]
+    MethodNotAllowed,
+
+    #[error("The upload queue is full")]
+    QueueFull,
+
+    #[error("Too many scopes are in use")]
+    TooManyScopes,
+
+    #[error("Timed out after {0:?}")]
+    TimedOut(std::time::Duration),
+
     #[error("I/O error: {0}. Context: {1}")]
     Io(std::io::Error, String),
 
@@ -53,8 +74,154 @@ pub enum Error {
     Internal(String),
 }
 
+/// What kind of failure an [`Error`] represents.
+///
+/// This is what the Action wrapper keys its messages off, via the
+/// `X-Magic-Nix-Cache-Error` response header and the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The backend rejected our credentials, or we don't have any.
+    AuthFailed,
+
+    /// The backend is out of storage for this repository or organization.
+    QuotaExceeded,
+
+    /// The backend doesn't know about the requested cache.
+    CacheNotFound,
+
+    /// The backend is throttling us.
+    RateLimited,
+
+    /// The daemon's upload queue is full.
+    Overloaded,
+
+    /// The backend refused an upload because it is too big.
+    PayloadTooLarge,
+
+    /// We couldn't talk to the backend at all.
+    Network,
+
+    /// The daemon was misconfigured.
+    Config,
+
+    /// The local Nix store or file system failed us.
+    Store,
+
+    /// The request to the daemon was invalid.
+    BadRequest,
+
+    /// The requested object doesn't exist.
+    NotFound,
+
+    /// Anything else.
+    Internal,
+}
+
+impl ErrorKind {
+    /// A short, stable identifier for the error kind.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AuthFailed => "auth-failed",
+            Self::QuotaExceeded => "quota-exceeded",
+            Self::CacheNotFound => "cache-not-found",
+            Self::RateLimited => "rate-limited",
+            Self::Overloaded => "overloaded",
+            Self::PayloadTooLarge => "payload-too-large",
+            Self::Network => "network",
+            Self::Config => "config",
+            Self::Store => "store",
+            Self::BadRequest => "bad-request",
+            Self::NotFound => "not-found",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// The exit code used when the daemon fails with this kind of error.
+    ///
+    /// These follow `sysexits.h` where there is a fitting code.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::AuthFailed => 77,                                           // EX_NOPERM
+            Self::QuotaExceeded | Self::RateLimited | Self::Overloaded => 75, // EX_TEMPFAIL
+            Self::CacheNotFound => 68,                                        // EX_NOHOST
+            Self::Network => 69,                                              // EX_UNAVAILABLE
+            Self::Config | Self::BadRequest => 78,                            // EX_CONFIG
+            Self::Store => 74,                                                // EX_IOERR
+            Self::PayloadTooLarge | Self::NotFound | Self::Internal => 70,    // EX_SOFTWARE
+        }
+    }
+
+    fn from_status(status: StatusCode) -> Option<Self> {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(Self::AuthFailed),
+            StatusCode::NOT_FOUND => Some(Self::CacheNotFound),
+            StatusCode::TOO_MANY_REQUESTS => Some(Self::RateLimited),
+            StatusCode::PAYLOAD_TOO_LARGE => Some(Self::PayloadTooLarge),
+            StatusCode::INSUFFICIENT_STORAGE => Some(Self::QuotaExceeded),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        use gha_cache::api::Error as ApiError;
+
+        match self {
+            Self::Api(ApiError::ApiError { status, info }) => {
+                // GitHub reports running out of cache storage with a generic status.
+                if info.to_string().to_lowercase().contains("quota") {
+                    ErrorKind::QuotaExceeded
+                } else {
+                    ErrorKind::from_status(*status).unwrap_or(ErrorKind::Internal)
+                }
+            }
+            Self::Api(ApiError::CircuitBreakerTripped) => ErrorKind::RateLimited,
+            Self::Api(ApiError::RequestError(_)) => ErrorKind::Network,
+            Self::Api(ApiError::IoError(..)) => ErrorKind::Store,
+            Self::Api(_) => ErrorKind::Internal,
+            Self::FlakeHubHttp(e) => e
+                .status()
+                .and_then(ErrorKind::from_status)
+                .unwrap_or(ErrorKind::Network),
+            Self::GetCacheName(status, _) => {
+                ErrorKind::from_status(*status).unwrap_or(ErrorKind::Internal)
+            }
+            Self::Netrc(_) | Self::MissingCreds(_) | Self::Unauthorized => ErrorKind::AuthFailed,
+            Self::Config(_) | Self::BadUrl(_) | Self::GHADisabled => ErrorKind::Config,
+            Self::Io(..) | Self::Attic(_) => ErrorKind::Store,
+            Self::RateLimited => ErrorKind::RateLimited,
+            Self::QueueFull | Self::TooManyScopes => ErrorKind::Overloaded,
+            Self::NotFound => ErrorKind::NotFound,
+            Self::BadRequest | Self::Forbidden | Self::MethodNotAllowed => ErrorKind::BadRequest,
+            Self::FlakeHub(_) | Self::TimedOut(_) | Self::Internal(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Picks the exit code for a failure of the daemon.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    err.chain()
+        .find_map(|e| {
+            e.downcast_ref::<Error>().map(Error::kind).or_else(|| {
+                e.downcast_ref::<crate::selftest::SelfTestError>()
+                    .map(|e| e.kind())
+            })
+        })
+        .map(|kind| kind.exit_code())
+        .unwrap_or(1)
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
+        crate::gha_annotations::report(&self);
+
         let code = match &self {
             Self::Api(gha_cache::api::Error::ApiError {
                 status: StatusCode::TOO_MANY_REQUESTS,
@@ -64,9 +231,20 @@ impl IntoResponse for Error {
             Self::Api(_) => StatusCode::IM_A_TEAPOT,
             Self::NotFound => StatusCode::NOT_FOUND,
             Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::RateLimited | Self::QueueFull | Self::TooManyScopes => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        (code, format!("{}", self)).into_response()
+        (
+            code,
+            [("X-Magic-Nix-Cache-Error", self.kind().as_str())],
+            format!("{}", self),
+        )
+            .into_response()
     }
 }
@@ -0,0 +1,164 @@
+//! Live progress of uploads.
+//!
+//! Progress bars are useless in CI logs, so instead the backends publish what
+//! they're doing to an [`EventBus`], which `GET /api/events` streams as
+//! server-sent events. The Action tails that stream and renders it as grouped
+//! log lines. Events are only kept while somebody is listening.
+
+use std::convert::Infallible;
+use std::path::PathBuf;
+
+use axum::{
+    extract::Extension,
+    response::sse::{self, KeepAlive, Sse},
+};
+use futures::stream::Stream;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+use super::State;
+use crate::Backend;
+
+/// How many events a slow subscriber may fall behind before it misses some.
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event {
+    /// A path was queued for upload.
+    UploadQueued {
+        backend: Backend,
+        store_path: PathBuf,
+    },
+
+    /// The upload of a path started.
+    UploadStarted {
+        backend: Backend,
+        store_path: PathBuf,
+        nar_size: u64,
+    },
+
+    /// An upload got through one of its stages.
+    UploadProgress {
+        backend: Backend,
+        store_path: PathBuf,
+        stage: UploadStage,
+
+        /// The number of bytes the stage produced.
+        bytes: u64,
+    },
+
+    /// A path was uploaded.
+    UploadCompleted {
+        backend: Backend,
+        store_path: PathBuf,
+        nar_size: u64,
+
+        /// Unknown for FlakeHub, as attic compresses the NAR while it streams it.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compressed_size: Option<u64>,
+
+        elapsed_ms: u64,
+    },
+
+    /// The upload of a path failed.
+    UploadFailed {
+        backend: Backend,
+        store_path: PathBuf,
+        error: String,
+    },
+
+    /// The subscriber fell behind, and this many events were dropped.
+    EventsMissed { count: u64 },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UploadStage {
+    /// The NAR was compressed.
+    Compressed,
+
+    /// The compressed NAR was uploaded, the narinfo is next.
+    NarUploaded,
+}
+
+/// Where the backends publish their events.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+
+    /// The bus of the daemon, if this is the bus of a scope.
+    parent: Option<broadcast::Sender<Event>>,
+
+    /// Cancelled when the daemon shuts down, which ends the streams, as the
+    /// server would otherwise wait for them to finish.
+    closed: CancellationToken,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            parent: None,
+            closed: CancellationToken::new(),
+        }
+    }
+}
+
+impl EventBus {
+    /// A bus whose events only its own subscribers and those of this bus see.
+    pub fn child(&self) -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            parent: Some(self.sender.clone()),
+            closed: self.closed.clone(),
+        }
+    }
+
+    /// Publishes an event to the current subscribers, if any.
+    pub fn publish(&self, event: Event) {
+        // Failing only means that nobody is listening.
+        if let Some(parent) = &self.parent {
+            let _ = parent.send(event.clone());
+        }
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Ends the streams of the current subscribers.
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+}
+
+/// Streams the events published from now on.
+pub async fn stream(
+    Extension(state): Extension<State>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let closed = state.events.closed.clone();
+    let receiver = state.events.sender.subscribe();
+
+    let events = futures::stream::unfold(receiver, move |mut receiver| {
+        let closed = closed.clone();
+        async move {
+            let event = tokio::select! {
+                _ = closed.cancelled() => return None,
+                event = receiver.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(count)) => Event::EventsMissed { count },
+                    Err(RecvError::Closed) => return None,
+                },
+            };
+
+            let data = serde_json::to_string(&event).expect("events are serializable");
+            Some((Ok(sse::Event::default().data(data)), receiver))
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
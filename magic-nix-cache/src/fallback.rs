@@ -0,0 +1,117 @@
+//! Substituting from several backends through one URL.
+//!
+//! With `--substitution-order flakehub,gha`, Nix only knows about the daemon,
+//! which asks FlakeHub and the GitHub Actions Cache in that order. When FlakeHub
+//! fails or is slow to answer, it's skipped for a while, so an outage degrades
+//! to GHA cache hits instead of Nix timing out on every path.
+
+use std::time::{Duration, Instant};
+
+use axum::{http::StatusCode, response::Response};
+
+use super::State;
+use crate::error::Error;
+use crate::flakehub_proxy;
+use crate::Backend;
+
+/// How long FlakeHub has to start answering before we move on.
+const FLAKEHUB_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long FlakeHub is skipped after failing.
+const FLAKEHUB_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct Fallback {
+    /// The backends to substitute from, in order. Empty when only the GHA cache is used.
+    order: Vec<Backend>,
+
+    /// When FlakeHub failed, the time until which it's skipped.
+    flakehub_down_until: std::sync::Mutex<Option<Instant>>,
+}
+
+impl Fallback {
+    pub fn new(order: Vec<Backend>) -> Self {
+        Self {
+            order,
+            flakehub_down_until: Default::default(),
+        }
+    }
+
+    /// Whether FlakeHub is asked before the GHA cache.
+    pub fn flakehub_first(&self) -> bool {
+        matches!(
+            (self.position(Backend::FlakeHub), self.position(Backend::Gha)),
+            (Some(flakehub), Some(gha)) if flakehub < gha
+        )
+    }
+
+    /// Whether FlakeHub is asked when the GHA cache doesn't have a path.
+    pub fn flakehub_last(&self) -> bool {
+        matches!(
+            (self.position(Backend::FlakeHub), self.position(Backend::Gha)),
+            (Some(flakehub), Some(gha)) if flakehub > gha
+        )
+    }
+
+    fn position(&self, backend: Backend) -> Option<usize> {
+        self.order.iter().position(|b| *b == backend)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.flakehub_down_until
+            .lock()
+            .expect("fallback mutex was poisoned")
+    }
+
+    fn flakehub_is_down(&self) -> bool {
+        self.lock().is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Skips FlakeHub for a while, returning whether it was considered up until now.
+    fn mark_flakehub_down(&self) -> bool {
+        let was_up = !self.flakehub_is_down();
+        *self.lock() = Some(Instant::now() + FLAKEHUB_RETRY_AFTER);
+        was_up
+    }
+}
+
+/// Asks FlakeHub for `path`, returning `None` when the next backend should be tried.
+pub async fn from_flakehub(state: &State, path: &str) -> Option<Response> {
+    if state.fallback.flakehub_is_down() {
+        state.metrics.flakehub_fallbacks.incr();
+        return None;
+    }
+
+    let failure =
+        match tokio::time::timeout(FLAKEHUB_TIMEOUT, flakehub_proxy::forward(state, path)).await {
+            Ok(Ok(response)) if response.status() == StatusCode::NOT_FOUND => return None,
+            Ok(Ok(response))
+                if response.status().is_success() || response.status().is_redirection() =>
+            {
+                if !path.ends_with(".narinfo") {
+                    return Some(response);
+                }
+
+                return state
+                    .policy
+                    .check_narinfo(&state.metrics, Backend::FlakeHub, response)
+                    .await;
+            }
+            Ok(Ok(response)) => format!("HTTP {}", response.status()),
+            // FlakeHub isn't set up, e.g. because its initialization failed.
+            Ok(Err(Error::NotFound)) => return None,
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => format!("no response within {FLAKEHUB_TIMEOUT:?}"),
+        };
+
+    state.metrics.flakehub_fallbacks.incr();
+    if state.fallback.mark_flakehub_down() {
+        tracing::warn!(
+            "FlakeHub is unavailable ({}), substituting from the next backend for {:?}",
+            failure,
+            FLAKEHUB_RETRY_AFTER
+        );
+    }
+
+    None
+}
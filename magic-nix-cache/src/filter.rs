@@ -0,0 +1,285 @@
+//! Filtering the store paths to push by their derivation.
+//!
+//! Some outputs only eat into the cache quota:
+//! - cross-compiled outputs for platforms nobody substitutes on. With
+//!   `--push-systems`, paths whose derivation targets another system are not
+//!   pushed;
+//! - outputs of fixed-output derivations, like fetched sources, which are cheap
+//!   to fetch again. They are not pushed unless `--skip-fixed-output=false` is
+//!   passed or their name matches a `--push-fixed-output` pattern.
+//!
+//! Paths whose derivation isn't available locally are pushed, as we can't tell
+//! what they are.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use attic::nix_store::{NixStore, StorePath};
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+use crate::telemetry;
+
+/// Decides which store paths get pushed.
+#[derive(Debug, Clone, Default)]
+pub struct PushFilter {
+    /// The systems whose paths get pushed. Empty means all of them.
+    pub systems: Vec<String>,
+
+    /// Whether to skip the outputs of fixed-output derivations.
+    pub skip_fixed_output: bool,
+
+    /// Names of fixed outputs to push anyway, where `*` matches anything.
+    pub fixed_output_patterns: Vec<String>,
+}
+
+/// What we need to know about a derivation.
+struct Derivation {
+    system: String,
+
+    /// Whether any of the outputs has a fixed hash.
+    fixed_output: bool,
+}
+
+impl PushFilter {
+    /// Drops the paths that shouldn't be pushed.
+    pub async fn retain(
+        &self,
+        store: &NixStore,
+        metrics: &telemetry::TelemetryReport,
+        store_paths: Vec<StorePath>,
+    ) -> Vec<StorePath> {
+        if (self.systems.is_empty() && !self.skip_fixed_output) || store_paths.is_empty() {
+            return store_paths;
+        }
+
+        let full_paths: Vec<_> = store_paths.iter().map(|p| store.get_full_path(p)).collect();
+
+        let derivers = match query_derivers(&full_paths).await {
+            Ok(derivers) => derivers,
+            Err(err) => {
+                tracing::warn!(
+                    "Cannot determine the derivers of the paths to push: {}",
+                    err
+                );
+                return store_paths;
+            }
+        };
+
+        let mut retained = Vec::with_capacity(store_paths.len());
+        for (store_path, full_path) in store_paths.into_iter().zip(full_paths) {
+            let derivation = match derivers.get(&full_path) {
+                Some(drv_path) => tokio::fs::read_to_string(drv_path)
+                    .await
+                    .ok()
+                    .and_then(|drv| parse_derivation(&drv)),
+                None => None,
+            };
+
+            let Some(derivation) = derivation else {
+                retained.push(store_path);
+                continue;
+            };
+
+            if !self.systems.is_empty() && !self.systems.contains(&derivation.system) {
+                tracing::debug!(
+                    "Not pushing '{}', which was built for {}",
+                    full_path.display(),
+                    derivation.system
+                );
+                metrics.paths_skipped_other_system.incr();
+                continue;
+            }
+
+            if derivation.fixed_output && self.skip_fixed_output {
+                let name = crate::delta::path_name(&full_path).unwrap_or_default();
+                if !self
+                    .fixed_output_patterns
+                    .iter()
+                    .any(|pattern| matches(pattern, name))
+                {
+                    tracing::debug!(
+                        "Not pushing '{}', which is the output of a fixed-output derivation",
+                        full_path.display()
+                    );
+                    metrics.paths_skipped_fixed_output.incr();
+                    continue;
+                }
+            }
+
+            retained.push(store_path);
+        }
+
+        retained
+    }
+}
+
+/// Matches `name` against a pattern in which `*` stands for any number of characters.
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = name.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all.
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Asks Nix for the derivers of `full_paths`.
+async fn query_derivers(full_paths: &[PathBuf]) -> Result<HashMap<PathBuf, PathBuf>> {
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "path-info",
+            "--json",
+        ])
+        .args(full_paths)
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix path-info".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Internal(format!(
+            "nix path-info failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::Internal(format!("Parsing nix path-info: {e}")))?;
+
+    let deriver = |info: &serde_json::Value| info.get("deriver")?.as_str().map(PathBuf::from);
+
+    // Nix 2.19 turned the list of objects into an object keyed by store path.
+    let derivers = match json {
+        serde_json::Value::Array(infos) => infos
+            .iter()
+            .filter_map(|info| Some((PathBuf::from(info.get("path")?.as_str()?), deriver(info)?)))
+            .collect(),
+        serde_json::Value::Object(infos) => infos
+            .iter()
+            .filter_map(|(path, info)| Some((PathBuf::from(path), deriver(info)?)))
+            .collect(),
+        _ => HashMap::new(),
+    };
+
+    Ok(derivers)
+}
+
+/// Returns the system a derivation in ATerm format targets.
+pub(crate) fn derivation_system(drv: &str) -> Option<String> {
+    parse_derivation(drv).map(|derivation| derivation.system)
+}
+
+/// Parses a derivation in ATerm format, i.e.
+/// `Derive(outputs,inputDrvs,inputSrcs,"system",...)`.
+fn parse_derivation(drv: &str) -> Option<Derivation> {
+    let (fixed_output, rest) = parse_outputs(drv.strip_prefix("Derive(")?)?;
+
+    let mut rest = rest.strip_prefix(',')?;
+    for _ in 0..2 {
+        rest = skip_term(rest)?.strip_prefix(',')?;
+    }
+
+    let (system, _) = parse_string(rest)?;
+
+    Some(Derivation {
+        system,
+        fixed_output,
+    })
+}
+
+/// Parses the list of outputs at the start of `s`, returning whether any of
+/// them has a fixed hash and what follows the list.
+fn parse_outputs(s: &str) -> Option<(bool, &str)> {
+    let mut rest = s.strip_prefix('[')?;
+    let mut fixed_output = false;
+
+    loop {
+        if let Some(rest) = rest.strip_prefix(']') {
+            return Some((fixed_output, rest));
+        }
+
+        // Each output is `("name","path","hash algorithm","hash")`.
+        let mut fields = Vec::new();
+        let mut field = rest.strip_prefix(',').unwrap_or(rest).strip_prefix('(')?;
+        loop {
+            let (value, after) = parse_string(field)?;
+            fields.push(value);
+            match after.strip_prefix(',') {
+                Some(after) => field = after,
+                None => {
+                    rest = after.strip_prefix(')')?;
+                    break;
+                }
+            }
+        }
+
+        fixed_output |= fields.get(3).is_some_and(|hash| !hash.is_empty());
+    }
+}
+
+/// Skips a string, list or tuple at the start of `s`, returning what follows it.
+fn skip_term(s: &str) -> Option<&str> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+                if depth == 0 {
+                    return Some(&s[i + 1..]);
+                }
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '[' | '(' => depth += 1,
+            ']' | ')' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(&s[i + 1..]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Parses the string at the start of `s`, returning it and what follows it.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let rest = s.strip_prefix('"')?;
+    let mut parsed = String::new();
+    let mut chars = rest.char_indices();
+
+    loop {
+        match chars.next()? {
+            (i, '"') => return Some((parsed, &rest[i + 1..])),
+            (_, '\\') => parsed.push(chars.next()?.1),
+            (_, c) => parsed.push(c),
+        }
+    }
+}
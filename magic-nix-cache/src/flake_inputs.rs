@@ -0,0 +1,67 @@
+//! Pushing the sources of a flake.
+//!
+//! Evaluating a flake fetches its source and inputs, typically GitHub
+//! tarballs, into the store. Nothing builds them, so the post-build hook never
+//! sees them and the next run downloads them again. With `--push-flake-inputs`,
+//! the daemon asks Nix for these paths when the workflow finishes and pushes
+//! the ones that are in the local store.
+
+use std::path::PathBuf;
+
+use attic::nix_store::{NixStore, StorePath};
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+
+/// Returns the source and input paths of `flake` that are in the local store.
+pub async fn query(store: &NixStore, flake: &str) -> Result<Vec<StorePath>> {
+    // `--dry-run` keeps Nix from fetching inputs that evaluation never needed.
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "flake",
+            "archive",
+            "--json",
+            "--dry-run",
+        ])
+        .arg(flake)
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix flake archive".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Internal(format!(
+            "nix flake archive failed for {}: {}",
+            flake,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let archive: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::Internal(format!("Parsing nix flake archive: {e}")))?;
+
+    let mut paths = Vec::new();
+    collect_paths(&archive, &mut paths);
+    paths.sort();
+    paths.dedup();
+
+    Ok(paths
+        .into_iter()
+        .filter(|path| path.exists())
+        .filter_map(|path| store.follow_store_path(&path).ok())
+        .collect())
+}
+
+/// Collects the `path` of a flake and, recursively, of its `inputs`.
+fn collect_paths(node: &serde_json::Value, paths: &mut Vec<PathBuf>) {
+    if let Some(path) = node.get("path").and_then(|path| path.as_str()) {
+        paths.push(PathBuf::from(path));
+    }
+
+    if let Some(inputs) = node.get("inputs").and_then(|inputs| inputs.as_object()) {
+        for input in inputs.values() {
+            collect_paths(input, paths);
+        }
+    }
+}
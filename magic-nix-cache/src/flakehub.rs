@@ -3,12 +3,7 @@ use crate::error::{Error, Result};
 use anyhow::Context;
 use attic::cache::CacheName;
 use attic::nix_store::{NixStore, StorePath};
-use attic_client::push::{PushSession, PushSessionConfig};
-use attic_client::{
-    api::ApiClient,
-    config::ServerConfig,
-    push::{PushConfig, Pusher},
-};
+use attic_client::{api::ApiClient, config::ServerConfig};
 
 use reqwest::header::HeaderValue;
 use reqwest::Url;
@@ -17,25 +12,91 @@ use std::path::Path;
 use std::sync::Arc;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, Semaphore};
 use uuid::Uuid;
 
-const USER_AGENT: &str = "magic-nix-cache";
+use crate::events::EventBus;
+use crate::flakehub_push::Uploader;
+use crate::secrets::SecretScanner;
+use crate::telemetry;
 
 pub struct State {
-    #[allow(dead_code)]
     pub substituter: Url,
 
-    pub push_session: PushSession,
+    pub uploader: Arc<Uploader>,
+
+    /// The credentials for the cache server, kept current as the token is refreshed.
+    pub cache_server_auth: CacheServerAuth,
+}
+
+/// How to authenticate to the FlakeHub cache server, for proxying substitutions.
+#[derive(Clone)]
+pub struct CacheServerAuth {
+    login: String,
+    password: Arc<RwLock<String>>,
+}
+
+impl CacheServerAuth {
+    /// Adds the current credentials to a request.
+    pub async fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request.basic_auth(&self.login, Some(self.password.read().await.as_str()))
+    }
+}
+
+/// How to start the FlakeHub cache, kept to retry when it fails.
+#[derive(Clone)]
+pub struct Config {
+    pub environment: Environment,
+    pub api_server: Url,
+    pub cache_server: Url,
+    pub flake_name: Option<String>,
+    pub cache_name: Option<String>,
+    pub store: Arc<NixStore>,
+    pub auth_method: super::FlakeHubAuthSource,
+    pub upload_jobs: usize,
+    /// Caps the number of uploads across backends, if set.
+    pub upload_limit: Option<Arc<Semaphore>>,
+    pub secret_scanner: Arc<SecretScanner>,
+    pub metrics: Arc<telemetry::TelemetryReport>,
+    pub events: EventBus,
+    pub proxy: bool,
 }
 
-pub async fn init_cache(
+impl Config {
+    pub async fn init(&self) -> Result<State> {
+        init_cache(
+            self.environment,
+            &self.api_server,
+            &self.cache_server,
+            self.flake_name.clone(),
+            self.cache_name.clone(),
+            self.store.clone(),
+            &self.auth_method,
+            self.upload_jobs,
+            self.upload_limit.clone(),
+            self.secret_scanner.clone(),
+            self.metrics.clone(),
+            self.events.clone(),
+            self.proxy,
+        )
+        .await
+    }
+}
+
+async fn init_cache(
     environment: Environment,
     flakehub_api_server: &Url,
     flakehub_cache_server: &Url,
     flakehub_flake_name: Option<String>,
+    flakehub_cache_name: Option<String>,
     store: Arc<NixStore>,
     auth_method: &super::FlakeHubAuthSource,
+    upload_jobs: usize,
+    upload_limit: Option<Arc<Semaphore>>,
+    secret_scanner: Arc<SecretScanner>,
+    metrics: Arc<telemetry::TelemetryReport>,
+    events: EventBus,
+    proxy: bool,
 ) -> Result<State> {
     // Parse netrc to get the credentials for api.flakehub.com.
     let netrc = {
@@ -87,7 +148,13 @@ pub async fn init_cache(
         ))
     })?;
 
-    if let super::FlakeHubAuthSource::Netrc(netrc_path) = auth_method {
+    let cache_server_auth = CacheServerAuth {
+        login: flakehub_login.to_owned(),
+        password: Arc::new(RwLock::new(flakehub_password.clone())),
+    };
+
+    // When proxying, Nix never talks to the cache server, so it doesn't need the credentials.
+    if let (super::FlakeHubAuthSource::Netrc(netrc_path), false) = (auth_method, proxy) {
         // Append an entry for the FlakeHub cache server to netrc.
         if !netrc
             .machines
@@ -148,18 +215,22 @@ pub async fn init_cache(
             let initial_github_jwt_clone = flakehub_password.clone();
             let flakehub_cache_server_clone = flakehub_cache_server.to_string();
             let api_clone = api.clone();
+            let cache_server_auth_clone = cache_server_auth.clone();
 
             tokio::task::spawn(refresh_github_actions_jwt_worker(
                 netrc_path_clone,
                 initial_github_jwt_clone,
                 flakehub_cache_server_clone,
                 api_clone,
+                cache_server_auth_clone,
             ));
         }
     }
 
-    // Get the cache UUID for this project.
-    let cache_name = {
+    // Get the cache UUID for this project, unless we were told which cache to use.
+    let cache_name = if let Some(flakehub_cache_name) = flakehub_cache_name {
+        flakehub_cache_name
+    } else {
         let mut url = flakehub_api_server
             .join("project")
             .map_err(|_| Error::Config(format!("bad URL '{}'", flakehub_api_server)))?;
@@ -172,9 +243,8 @@ pub async fn init_cache(
             }
         }
 
-        let response = reqwest::Client::new()
+        let response = crate::http_client::client()
             .get(url.to_owned())
-            .header("User-Agent", USER_AGENT)
             .basic_auth(flakehub_login, Some(&flakehub_password))
             .send()
             .await?;
@@ -206,36 +276,72 @@ pub async fn init_cache(
 
     let cache_config = api.read().await.get_cache_config(&cache).await?;
 
-    let push_config = PushConfig {
-        num_workers: 5, // FIXME: use number of CPUs?
-        force_preamble: false,
-    };
+    // Progress is reported through `GET /api/events` instead, as bars are useless in CI logs.
+    let mp = indicatif::MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
 
-    let mp = indicatif::MultiProgress::new();
-
-    let push_session = Pusher::new(
+    let uploader = Uploader::new(
         store.clone(),
         api.clone(),
         cache.to_owned(),
-        cache_config,
+        cache_config.upstream_cache_key_names.unwrap_or_default(),
         mp,
-        push_config,
-    )
-    .into_push_session(PushSessionConfig {
-        no_closure: false,
-        ignore_upstream_cache_filter: false,
-    });
+        upload_jobs,
+        upload_limit,
+        secret_scanner,
+        metrics,
+        events,
+    );
 
     let state = State {
         substituter: flakehub_cache_server.to_owned(),
-        push_session,
+        uploader,
+        cache_server_auth,
     };
 
     Ok(state)
 }
 
+/// Writes a `netrc` file that authenticates to FlakeHub with the OIDC token in `token_file`.
+///
+/// This is how the FlakeHub cache is used outside of GitHub Actions, e.g. with a GitLab CI
+/// `id_tokens` entry. The `netrc` file holds the token, so it is removed when the
+/// returned path is dropped.
+pub async fn write_oidc_netrc(
+    token_file: &Path,
+    flakehub_api_server: &Url,
+) -> Result<tempfile::TempPath> {
+    let token = tokio::fs::read_to_string(token_file)
+        .await
+        .map_err(|e| Error::Io(e, format!("Reading {}", token_file.display())))?
+        .trim()
+        .to_owned();
+
+    if token.is_empty() {
+        return Err(Error::Config(format!(
+            "the FlakeHub OIDC token in {} is empty",
+            token_file.display()
+        )));
+    }
+
+    let flakehub_api_server_hostname = flakehub_api_server
+        .host()
+        .ok_or_else(|| Error::BadUrl(flakehub_api_server.to_owned()))?
+        .to_string();
+
+    let mut file = tempfile::NamedTempFile::with_prefix("magic-nix-cache-flakehub-netrc-")
+        .map_err(|e| Error::Io(e, "Creating a temporary netrc file".to_owned()))?;
+    std::io::Write::write_all(
+        &mut file,
+        format!("machine {flakehub_api_server_hostname} login flakehub password {token}\n")
+            .as_bytes(),
+    )
+    .map_err(|e| Error::Io(e, "Writing the FlakeHub netrc file".to_owned()))?;
+
+    Ok(file.into_temp_path())
+}
+
 pub async fn enqueue_paths(state: &State, store_paths: Vec<StorePath>) -> Result<()> {
-    state.push_session.queue_many(store_paths)?;
+    state.uploader.queue_many(store_paths);
 
     Ok(())
 }
@@ -248,6 +354,7 @@ async fn refresh_github_actions_jwt_worker(
     mut github_jwt: String,
     flakehub_cache_server_clone: String,
     api: Arc<RwLock<ApiClient>>,
+    cache_server_auth: CacheServerAuth,
 ) -> Result<()> {
     // TODO(cole-h): this should probably be half of the token's lifetime ((exp - iat) / 2), but
     // getting this is nontrivial so I'm not going to do it until GitHub changes the lifetime and
@@ -269,8 +376,7 @@ async fn refresh_github_actions_jwt_worker(
         HeaderValue::from_static("application/json"),
     );
 
-    let github_client = reqwest::Client::builder()
-        .user_agent(USER_AGENT)
+    let github_client = crate::http_client::client_builder()
         .default_headers(headers)
         .build()?;
 
@@ -292,8 +398,10 @@ async fn refresh_github_actions_jwt_worker(
                     *api_client = new_api;
                 }
 
+                *cache_server_auth.password.write().await = github_jwt.clone();
+
                 tracing::debug!(
-                    "Stored new token in netrc and API client, sleeping for {next_refresh:?}"
+                    "Stored new token in netrc, API client and proxy, sleeping for {next_refresh:?}"
                 );
                 tokio::time::sleep(next_refresh).await;
             }
@@ -0,0 +1,148 @@
+//! Substituting from FlakeHub through the daemon.
+//!
+//! With `--flakehub-proxy`, Nix uses `http://<listen>/flakehub` as the FlakeHub
+//! substituter instead of the cache server itself. The daemon adds the
+//! credentials to each request on the way through, so the token never ends up
+//! in a netrc file that Nix reads, and refreshing it only happens here.
+
+use std::sync::OnceLock;
+
+use axum::{
+    body::Body,
+    extract::{Extension, Path},
+    http::header,
+    response::Response,
+    routing::get,
+    Router,
+};
+
+use super::State;
+use crate::error::{Error, Result};
+use crate::Backend;
+
+/// The headers of the cache server's responses that are passed on to Nix.
+const FORWARDED_HEADERS: &[header::HeaderName] = &[
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::CACHE_CONTROL,
+    header::LOCATION,
+];
+
+pub fn get_router() -> Router {
+    Router::new().route("/flakehub/*path", get(proxy))
+}
+
+/// Redirects aren't followed, so that Nix downloads NARs from wherever the
+/// cache server sends it without going through the daemon.
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+    CLIENT.get_or_init(|| {
+        crate::http_client::client_builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("failed to build the FlakeHub proxy client")
+    })
+}
+
+async fn proxy(Extension(state): Extension<State>, Path(path): Path<String>) -> Result<Response> {
+    let response = forward(&state, &path).await?;
+
+    if !path.ends_with(".narinfo") {
+        return Ok(response);
+    }
+
+    state
+        .policy
+        .check_narinfo(&state.metrics, Backend::FlakeHub, response)
+        .await
+        .ok_or(Error::NotFound)
+}
+
+/// Requests `path` from the FlakeHub cache server with our credentials, and
+/// passes the response on as is.
+pub async fn forward(state: &State, path: &str) -> Result<Response> {
+    let (url, auth) = {
+        let flakehub_state = state.flakehub_state.read().await;
+        let flakehub_state = flakehub_state.as_ref().ok_or(Error::NotFound)?;
+
+        let url = cache_server_url(&flakehub_state.substituter, path)?;
+
+        (url, flakehub_state.cache_server_auth.clone())
+    };
+
+    let upstream = auth.apply(client().get(url)).await.send().await?;
+    state.metrics.flakehub_requests_proxied.incr();
+
+    let mut response = Response::builder().status(upstream.status());
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = upstream.headers().get(name) {
+            response = response.header(name, value.clone());
+        }
+    }
+
+    response
+        .body(Body::from_stream(upstream.bytes_stream()))
+        .map_err(|e| Error::Internal(format!("Building the proxied response: {e}")))
+}
+
+/// Resolves `path` against the cache server.
+///
+/// The request carries our credentials, so anything that would send it
+/// elsewhere, like an absolute URL or `//host/...`, is rejected.
+fn cache_server_url(substituter: &reqwest::Url, path: &str) -> Result<reqwest::Url> {
+    let has_scheme = path
+        .split_once(':')
+        .is_some_and(|(scheme, _)| !scheme.contains('/'));
+    if path.starts_with('/') || path.starts_with('\\') || has_scheme {
+        return Err(Error::BadRequest);
+    }
+
+    let url = substituter.join(path).map_err(|_| Error::BadRequest)?;
+
+    if url.origin() != substituter.origin() || !url.path().starts_with(substituter.path()) {
+        return Err(Error::BadRequest);
+    }
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_on_the_cache_server() {
+        let substituter = reqwest::Url::parse("https://cache.flakehub.com/").unwrap();
+        let resolve = |path| {
+            cache_server_url(&substituter, path)
+                .ok()
+                .map(|url| url.to_string())
+        };
+
+        assert_eq!(
+            resolve("abc.narinfo"),
+            Some("https://cache.flakehub.com/abc.narinfo".to_owned())
+        );
+        assert_eq!(
+            resolve("nar/abc.nar.zst"),
+            Some("https://cache.flakehub.com/nar/abc.nar.zst".to_owned())
+        );
+
+        assert_eq!(resolve("https://evil.example/abc.narinfo"), None);
+        assert_eq!(resolve("//evil.example/abc.narinfo"), None);
+        assert_eq!(resolve("/\\evil.example/abc.narinfo"), None);
+        assert_eq!(resolve("\\\\evil.example/abc.narinfo"), None);
+        assert_eq!(resolve("http:evil.example"), None);
+    }
+
+    #[test]
+    fn paths_stay_below_the_substituter() {
+        let substituter = reqwest::Url::parse("https://cache.example/flakehub/").unwrap();
+
+        assert!(cache_server_url(&substituter, "abc.narinfo").is_ok());
+        assert!(cache_server_url(&substituter, "../abc.narinfo").is_err());
+        assert!(cache_server_url(&substituter, "nar/../../abc.narinfo").is_err());
+    }
+}
@@ -0,0 +1,247 @@
+//! Pushing paths to FlakeHub.
+//!
+//! attic's push session runs its own pool of workers, and doesn't say when an
+//! upload finished. So the daemon computes the closures itself and hands the
+//! paths to attic one at a time, from its own workers. That way FlakeHub
+//! uploads take their turn under `--max-concurrent-uploads` like GHA uploads do,
+//! and publish the same events.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+
+use attic::cache::CacheName;
+use attic::nix_store::{NixStore, StorePath, ValidPathInfo};
+use attic_client::api::ApiClient;
+use indicatif::MultiProgress;
+use tokio::sync::{RwLock, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::error::{Error, Result};
+use crate::events::{Event, EventBus};
+use crate::secrets::SecretScanner;
+use crate::telemetry;
+use crate::Backend;
+
+pub struct Uploader {
+    store: Arc<NixStore>,
+
+    api: Arc<RwLock<ApiClient>>,
+
+    cache: CacheName,
+
+    /// Paths signed by one of these keys are in an upstream cache already.
+    upstream_cache_key_names: Vec<String>,
+
+    /// Unused, attic just insists on drawing somewhere.
+    mp: MultiProgress,
+
+    /// Caps the number of uploads to FlakeHub.
+    jobs: Semaphore,
+
+    /// Caps the number of uploads across backends, if set.
+    upload_limit: Option<Arc<Semaphore>>,
+
+    secret_scanner: Arc<SecretScanner>,
+
+    metrics: Arc<telemetry::TelemetryReport>,
+
+    events: EventBus,
+
+    /// The hashes of the paths that were queued already.
+    queued: std::sync::Mutex<HashSet<String>>,
+
+    /// The closures being computed and the paths being uploaded.
+    tasks: std::sync::Mutex<JoinSet<()>>,
+}
+
+impl Uploader {
+    pub fn new(
+        store: Arc<NixStore>,
+        api: Arc<RwLock<ApiClient>>,
+        cache: CacheName,
+        upstream_cache_key_names: Vec<String>,
+        mp: MultiProgress,
+        upload_jobs: usize,
+        upload_limit: Option<Arc<Semaphore>>,
+        secret_scanner: Arc<SecretScanner>,
+        metrics: Arc<telemetry::TelemetryReport>,
+        events: EventBus,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            store,
+            api,
+            cache,
+            upstream_cache_key_names,
+            mp,
+            jobs: Semaphore::new(upload_jobs),
+            upload_limit,
+            secret_scanner,
+            metrics,
+            events,
+            queued: Default::default(),
+            tasks: Default::default(),
+        })
+    }
+
+    /// Queues the closures of `store_paths` for upload.
+    pub fn queue_many(self: &Arc<Self>, store_paths: Vec<StorePath>) {
+        let uploader = self.clone();
+        self.lock_tasks().spawn(async move {
+            if let Err(err) = uploader.queue_closure(store_paths).await {
+                tracing::error!("Failed to queue paths for FlakeHub: {}", err);
+            }
+        });
+    }
+
+    /// Waits for everything that was queued to be uploaded.
+    pub async fn wait(&self) {
+        loop {
+            let mut tasks = std::mem::take(&mut *self.lock_tasks());
+            if tasks.is_empty() {
+                return;
+            }
+
+            while tasks.join_next().await.is_some() {}
+        }
+    }
+
+    async fn queue_closure(self: &Arc<Self>, store_paths: Vec<StorePath>) -> Result<()> {
+        let closure = self
+            .store
+            .compute_fs_closure_multi(store_paths, false, false, false)
+            .await?;
+
+        let closure: Vec<StorePath> = {
+            let mut queued = self.lock_queued();
+            closure
+                .into_iter()
+                .filter(|path| queued.insert(path.to_hash().as_str().to_owned()))
+                .collect()
+        };
+        if closure.is_empty() {
+            return Ok(());
+        }
+
+        let mut path_infos = Vec::with_capacity(closure.len());
+        for path in closure {
+            let path_info = self.store.query_path_info(path).await?;
+            if !self.is_upstream(&path_info) {
+                path_infos.push(path_info);
+            }
+        }
+
+        let missing: HashSet<String> = self
+            .api
+            .read()
+            .await
+            .get_missing_paths(
+                &self.cache,
+                path_infos.iter().map(|p| p.path.to_hash()).collect(),
+            )
+            .await
+            .map_err(Error::FlakeHub)?
+            .missing_paths
+            .into_iter()
+            .map(|hash| hash.as_str().to_owned())
+            .collect();
+
+        let mut tasks = self.lock_tasks();
+        for path_info in path_infos {
+            if !missing.contains(path_info.path.to_hash().as_str()) {
+                continue;
+            }
+
+            self.events.publish(Event::UploadQueued {
+                backend: Backend::FlakeHub,
+                store_path: self.store.get_full_path(&path_info.path),
+            });
+
+            let uploader = self.clone();
+            tasks.spawn(async move { uploader.upload(path_info).await });
+        }
+
+        Ok(())
+    }
+
+    async fn upload(&self, path_info: ValidPathInfo) {
+        let _job = self.jobs.acquire().await.ok();
+
+        if !self
+            .secret_scanner
+            .allows(&self.store, &self.metrics, &path_info.path)
+            .await
+        {
+            return;
+        }
+
+        let _permit = match &self.upload_limit {
+            Some(upload_limit) => upload_limit.acquire().await.ok(),
+            None => None,
+        };
+
+        let full_path = self.store.get_full_path(&path_info.path);
+        let nar_size = path_info.nar_size;
+        self.events.publish(Event::UploadStarted {
+            backend: Backend::FlakeHub,
+            store_path: full_path.clone(),
+            nar_size,
+        });
+
+        let started = Instant::now();
+
+        let api = self.api.read().await.clone();
+        match attic_client::push::upload_path(
+            path_info,
+            self.store.clone(),
+            api,
+            &self.cache,
+            self.mp.clone(),
+            false,
+        )
+        .await
+        {
+            Ok(()) => {
+                self.events.publish(Event::UploadCompleted {
+                    backend: Backend::FlakeHub,
+                    store_path: full_path,
+                    nar_size,
+                    compressed_size: None,
+                    elapsed_ms: started.elapsed().as_millis() as u64,
+                });
+            }
+            Err(err) => {
+                tracing::error!(
+                    "Failed to push '{}' to FlakeHub: {}",
+                    full_path.display(),
+                    err
+                );
+                self.events.publish(Event::UploadFailed {
+                    backend: Backend::FlakeHub,
+                    store_path: full_path,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+
+    fn is_upstream(&self, path_info: &ValidPathInfo) -> bool {
+        path_info.sigs.iter().any(|sig| {
+            self.upstream_cache_key_names.iter().any(|key_name| {
+                sig.split_once(':').map(|(name, _)| name) == Some(key_name.as_str())
+            })
+        })
+    }
+
+    fn lock_queued(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.queued
+            .lock()
+            .expect("queued FlakeHub paths mutex was poisoned")
+    }
+
+    fn lock_tasks(&self) -> std::sync::MutexGuard<'_, JoinSet<()>> {
+        self.tasks
+            .lock()
+            .expect("FlakeHub upload tasks mutex was poisoned")
+    }
+}
@@ -1,17 +1,42 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
+use crate::budget::UploadBudget;
+use crate::build_log;
+use crate::deadline::UploadDeadline;
+use crate::dedupe::DedupeIndex;
+use crate::delta;
 use crate::error::{Error, Result};
+use crate::events::{Event, EventBus, UploadStage};
+use crate::gha_annotations;
+use crate::index::UploadIndex;
+use crate::listing;
+use crate::nar;
+use crate::pack::{self, Pack};
+use crate::persist;
+use crate::quarantine::Quarantine;
+use crate::queue::{EnqueueOverflow, QueueEntry, UploadQueue};
+use crate::realisation;
+use crate::resume;
+use crate::secrets::SecretScanner;
+use crate::signing::SigningKeys;
 use crate::telemetry;
-use async_compression::tokio::bufread::ZstdEncoder;
+use crate::verify::{self, Digest256};
+use crate::watchdog::DiskSpaceWatchdog;
+use crate::Backend;
 use attic::nix_store::{NixStore, StorePath, ValidPathInfo};
 use attic_server::narinfo::{Compression, NarInfo};
-use futures::stream::TryStreamExt;
 use gha_cache::{Api, Credentials};
+use tokio::io::AsyncReadExt as _;
 use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    RwLock,
+    mpsc::{channel, Receiver, Sender},
+    Mutex, RwLock, Semaphore,
 };
-use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio::task::JoinSet;
 
 pub struct GhaCache {
     /// The GitHub Actions Cache API.
@@ -20,61 +45,437 @@ pub struct GhaCache {
     /// The future from the completion of the worker.
     worker_result: RwLock<Option<tokio::task::JoinHandle<Result<()>>>>,
 
-    channel_tx: UnboundedSender<Request>,
+    channel_tx: Sender<Request>,
+
+    uploader: Arc<Uploader>,
+
+    /// Keeps the journal of pending uploads up to date, if we keep one.
+    journal_writer: Option<tokio::task::JoinHandle<()>>,
+
+    /// The indexes of the packs we substituted from.
+    pub pack_indexes: pack::IndexCache,
+
+    /// Counters from earlier jobs, if the state is carried between jobs.
+    persisted_totals: std::sync::Mutex<Option<persist::Totals>>,
+
+    /// Hashes of the store paths that earlier jobs uploaded and that weren't evicted since.
+    persisted_uploads: std::sync::RwLock<HashSet<String>>,
+}
+
+/// Everything an upload needs, shared between the worker and [`GhaCache`].
+struct Uploader {
+    api: Arc<Api>,
+
+    store: Arc<NixStore>,
+
+    /// Store paths we have uploaded in this or previous runs.
+    index: Arc<UploadIndex>,
+
+    /// Store paths we still have to upload.
+    queue: UploadQueue,
+
+    /// Keeps us from starting uploads that won't finish before the job ends.
+    deadline: UploadDeadline,
+
+    /// Keeps us from queueing more than fits in the cache, if set.
+    budget: Option<Arc<UploadBudget>>,
+
+    /// How long compressing or uploading a path may take.
+    upload_timeout: Option<Duration>,
+
+    /// Paths that failed too often to be tried again.
+    quarantine: Quarantine,
+
+    /// Paths that went down the pipeline in this run.
+    sent: SentPaths,
+
+    /// Paths uploaded by earlier jobs on this machine, if enabled.
+    dedupe_index: Option<Arc<DedupeIndex>>,
+
+    /// The part of `dedupe_index` that applies to this cache.
+    dedupe_scope: String,
+
+    upload_deltas: bool,
+
+    upload_build_logs: bool,
+
+    upload_listings: bool,
+
+    /// NARs that compress to at most this many bytes are packed, if set.
+    pack_threshold: Option<u64>,
+
+    /// The pack that small NARs are added to.
+    pending_pack: Mutex<Option<Pack<PackedNar>>>,
+
+    /// The packs being uploaded.
+    pack_uploads: std::sync::Mutex<JoinSet<()>>,
+
+    /// Where the paths still to be uploaded are recorded, if anywhere.
+    journal: Option<resume::Journal>,
+
+    signing_keys: Option<Arc<SigningKeys>>,
+
+    verify_after_push: bool,
+
+    dry_run: bool,
+
+    /// Caps the number of uploads across backends, if set.
+    upload_limit: Option<Arc<Semaphore>>,
+
+    secret_scanner: Arc<SecretScanner>,
+
+    compression_pool: WorkerPool,
+
+    upload_pool: WorkerPool,
+
+    metrics: Arc<telemetry::TelemetryReport>,
+
+    events: EventBus,
+
+    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+}
+
+/// How many requests may wait for the worker before senders have to wait too.
+const REQUEST_CAPACITY: usize = 1024;
+
+/// How the upload pipeline is set up.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    pub compression_workers: usize,
+    pub upload_jobs: usize,
+
+    /// Shared with the other backends to enforce `--max-concurrent-uploads`.
+    pub upload_limit: Option<Arc<Semaphore>>,
+
+    /// Whether to also upload deltas against earlier versions of each path.
+    pub upload_deltas: bool,
+
+    /// Whether to upload the build logs of derivations.
+    pub upload_build_logs: bool,
+
+    /// Whether to upload a listing of each path.
+    pub upload_listings: bool,
+
+    /// NARs that compress to at most this many bytes are bundled into packs, if set.
+    pub pack_threshold: Option<u64>,
+
+    /// The keys to sign narinfos with, if any.
+    pub signing_keys: Option<Arc<SigningKeys>>,
+
+    /// Whether to read each upload back and check it.
+    pub verify_after_push: bool,
+
+    /// Whether to stop short of compressing and uploading.
+    pub dry_run: bool,
+
+    /// How many paths may wait for upload before `enqueue_overflow` applies.
+    pub max_queued_uploads: usize,
+
+    /// What to do with new paths when the queue is full.
+    pub enqueue_overflow: EnqueueOverflow,
+
+    /// How long compressing or uploading a path may take, if limited.
+    pub upload_timeout: Option<Duration>,
+
+    /// After how many failures a path isn't tried again.
+    pub quarantine_after: u32,
+
+    /// Where uploads are remembered across jobs, if anywhere.
+    pub dedupe_index: Option<Arc<DedupeIndex>>,
+
+    /// Where the paths still to be uploaded are recorded for the next daemon, if anywhere.
+    pub pending_uploads_file: Option<PathBuf>,
+
+    /// What closures are checked against before they are queued, if anything.
+    pub budget: Option<Arc<UploadBudget>>,
+
+    /// Keeps paths that look like they contain credentials out of the cache.
+    pub secret_scanner: Arc<SecretScanner>,
+}
+
+/// Which part of the GitHub Actions Cache we read and write.
+///
+/// GitHub only returns entries whose version matches the one in the request,
+/// and matches keys by prefix. The version starts out as a hash of the
+/// `gha-cache` version, and is extended with each of the following that are
+/// set, in this order:
+///
+/// 1. `--cache-version`, as is, so that existing caches stay readable;
+/// 2. `--cache-version-salt`, so bumping it invalidates everything at once;
+/// 3. the scope of the request, with `--multi-tenant`.
+///
+/// `--cache-key-prefix` is prepended to every key instead. Unlike the version,
+/// it shows up in the cache list of the repository, which makes it the better
+/// choice for partitions that users want to tell apart, e.g. per branch.
+#[derive(Debug, Clone, Default)]
+pub struct CacheNamespace {
+    pub cache_version: Option<String>,
+    pub salt: Option<String>,
+    pub scope: Option<String>,
+    pub key_prefix: Option<String>,
+}
+
+impl CacheNamespace {
+    /// This namespace, narrowed down to a scope.
+    pub fn scoped(&self, scope: &str) -> Self {
+        Self {
+            scope: Some(scope.to_owned()),
+            ..self.clone()
+        }
+    }
+
+    /// Identifies the cache entries this namespace leads to, for the dedupe index.
+    fn dedupe_scope(&self) -> String {
+        [
+            std::env::var("GITHUB_REPOSITORY").ok(),
+            self.cache_version.clone(),
+            self.salt.clone(),
+            self.scope.clone(),
+            self.key_prefix.clone(),
+        ]
+        .map(Option::unwrap_or_default)
+        .join("\0")
+    }
+
+    fn apply(&self, api: &mut Api) {
+        if let Some(cache_version) = &self.cache_version {
+            api.mutate_version(cache_version.as_bytes());
+        }
+
+        // The separators keep a salt from being mistaken for the end of the version.
+        if let Some(salt) = &self.salt {
+            api.mutate_version(format!("\0salt:{salt}").as_bytes());
+        }
+
+        if let Some(scope) = &self.scope {
+            api.mutate_version(format!("\0scope:{scope}").as_bytes());
+        }
+
+        if let Some(key_prefix) = &self.key_prefix {
+            api.set_key_prefix(key_prefix);
+        }
+    }
+}
+
+/// A NAR that has been compressed and is waiting to be uploaded.
+struct CompressedNar {
+    path: StorePath,
+    path_info: ValidPathInfo,
+    file: tokio::fs::File,
+    compressed_size: u64,
+
+    /// When compression started.
+    started: Instant,
+}
+
+/// A NAR that has been added to a pack, and whose narinfo is uploaded with it.
+struct PackedNar {
+    path: StorePath,
+    path_info: ValidPathInfo,
+    compressed_size: u64,
+    started: Instant,
 }
 
 #[derive(Debug)]
 enum Request {
     Shutdown,
     Upload(StorePath),
+    Derivation(PathBuf),
+    Resize {
+        compression_workers: usize,
+        upload_jobs: usize,
+    },
+}
+
+/// How many workers of a kind should run, and how many do.
+#[derive(Default)]
+struct WorkerPool(std::sync::Mutex<PoolSize>);
+
+#[derive(Default)]
+struct PoolSize {
+    target: usize,
+    running: usize,
+}
+
+impl WorkerPool {
+    /// Sets the number of workers, returning how many have to be spawned.
+    ///
+    /// Excess workers exit as they finish their current item.
+    fn resize(&self, target: usize) -> usize {
+        let mut size = self.lock();
+        size.target = target;
+        let missing = target.saturating_sub(size.running);
+        size.running += missing;
+        missing
+    }
+
+    /// Whether the calling worker should exit because the pool shrank.
+    fn retire(&self) -> bool {
+        let mut size = self.lock();
+        if size.running > size.target {
+            size.running -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolSize> {
+        self.0.lock().expect("worker pool mutex was poisoned")
+    }
+}
+
+/// The paths that went down the pipeline in this run, so that duplicates are skipped.
+#[derive(Default)]
+struct SentPaths(std::sync::Mutex<HashSet<PathBuf>>);
+
+impl SentPaths {
+    /// Whether `full_path` should go down the pipeline: the first time it's
+    /// queued, and again if its upload failed.
+    fn admit(&self, full_path: &Path, failed: bool) -> bool {
+        self.lock().insert(full_path.to_owned()) || failed
+    }
+
+    /// Lets `full_path` go down the pipeline again, e.g. after it was evicted.
+    fn forget(&self, full_path: &Path) {
+        self.lock().remove(full_path);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<PathBuf>> {
+        self.0.lock().expect("sent paths mutex was poisoned")
+    }
 }
 
 impl GhaCache {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         credentials: Credentials,
-        cache_version: Option<String>,
+        namespace: &CacheNamespace,
         store: Arc<NixStore>,
         metrics: Arc<telemetry::TelemetryReport>,
+        events: EventBus,
         narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+        index: Arc<UploadIndex>,
+        disk_watchdog: DiskSpaceWatchdog,
+        job_deadline: Option<SystemTime>,
+        pipeline: Pipeline,
     ) -> Result<GhaCache> {
-        let mut api = Api::new(credentials)?;
-
-        if let Some(cache_version) = &cache_version {
-            api.mutate_version(cache_version.as_bytes());
-        }
+        let mut api = Api::with_client_builder(credentials, crate::http_client::client_builder())?;
+        namespace.apply(&mut api);
 
-        let (channel_tx, channel_rx) = unbounded_channel();
+        let (channel_tx, channel_rx) = channel(REQUEST_CAPACITY);
 
         let api = Arc::new(api);
 
-        let api2 = api.clone();
-
-        let worker_result = tokio::task::spawn(async move {
-            worker(
-                &api2,
-                store,
-                channel_rx,
-                metrics,
-                narinfo_negative_cache.clone(),
-            )
-            .await
+        let uploader = Arc::new(Uploader {
+            api: api.clone(),
+            store,
+            index,
+            queue: UploadQueue::new(pipeline.max_queued_uploads, pipeline.enqueue_overflow),
+            deadline: UploadDeadline::new(job_deadline),
+            budget: pipeline.budget.clone(),
+            upload_timeout: pipeline.upload_timeout,
+            quarantine: Quarantine::new(pipeline.quarantine_after),
+            sent: SentPaths::default(),
+            dedupe_index: pipeline.dedupe_index.clone(),
+            dedupe_scope: namespace.dedupe_scope(),
+            upload_deltas: pipeline.upload_deltas,
+            upload_build_logs: pipeline.upload_build_logs,
+            upload_listings: pipeline.upload_listings,
+            pack_threshold: pipeline.pack_threshold,
+            pending_pack: Mutex::new(None),
+            pack_uploads: Default::default(),
+            journal: pipeline
+                .pending_uploads_file
+                .as_deref()
+                .filter(|_| !pipeline.dry_run)
+                .and_then(|path| resume::Journal::open(path, &namespace.dedupe_scope())),
+            signing_keys: pipeline.signing_keys.clone(),
+            verify_after_push: pipeline.verify_after_push,
+            dry_run: pipeline.dry_run,
+            upload_limit: pipeline.upload_limit.clone(),
+            secret_scanner: pipeline.secret_scanner.clone(),
+            compression_pool: WorkerPool::default(),
+            upload_pool: WorkerPool::default(),
+            metrics,
+            events,
+            narinfo_negative_cache,
         });
 
+        let journal_writer = uploader
+            .journal
+            .is_some()
+            .then(|| tokio::task::spawn(write_journal(uploader.clone())));
+
+        let worker_result = tokio::task::spawn(worker(
+            uploader.clone(),
+            channel_rx,
+            disk_watchdog,
+            pipeline,
+        ));
+
         Ok(GhaCache {
             api,
             worker_result: RwLock::new(Some(worker_result)),
             channel_tx,
+            uploader,
+            journal_writer,
+            pack_indexes: Default::default(),
+            persisted_totals: Default::default(),
+            persisted_uploads: Default::default(),
         })
     }
 
     pub async fn shutdown(&self) -> Result<()> {
         if let Some(worker_result) = self.worker_result.write().await.take() {
+            // In case we get killed before the queue is drained.
+            self.uploader.write_journal().await;
+
             self.channel_tx
                 .send(Request::Shutdown)
-                .expect("Cannot send shutdown message");
-            worker_result
                 .await
-                .expect("failed to read result from gha worker")
+                .map_err(|_| Error::Internal("Cannot send shutdown message".to_owned()))?;
+            worker_result.await.map_err(|e| {
+                Error::Internal(format!("Failed to read result from the GHA worker: {e}"))
+            })??;
+
+            if let Some(journal_writer) = &self.journal_writer {
+                journal_writer.abort();
+            }
+            self.uploader.write_journal().await;
+
+            if self.uploader.dry_run {
+                tracing::info!(
+                    "Dry run: would have uploaded {} paths ({} bytes of uncompressed NARs)",
+                    self.uploader.metrics.dry_run_nars.get(),
+                    self.uploader.metrics.dry_run_nar_bytes.get()
+                );
+                return Ok(());
+            }
+
+            self.uploader.index.save().await?;
+
+            let persisted_totals = *self
+                .persisted_totals
+                .lock()
+                .expect("persisted totals mutex was poisoned");
+            if let Some(totals) = persisted_totals {
+                if let Err(err) = persist::save(
+                    &self.api,
+                    &self.uploader.index,
+                    totals,
+                    &self.uploader.metrics,
+                )
+                .await
+                {
+                    tracing::warn!(
+                        "Failed to save the state to the GitHub Actions Cache: {}",
+                        err
+                    );
+                }
+            }
+
+            Ok(())
         } else {
             Ok(())
         }
@@ -92,130 +493,1163 @@ impl GhaCache {
         // FIXME: compute_fs_closure_multi doesn't return a
         // toposort, though it doesn't really matter for the GHA
         // cache.
-        let closure = store
+        let roots = store_paths.clone();
+        let mut closure = store
             .compute_fs_closure_multi(store_paths, false, false, false)
             .await?;
 
+        if let Some(dedupe_index) = &self.uploader.dedupe_index {
+            let before = closure.len();
+            let uploaded = dedupe_index
+                .uploaded(
+                    &self.uploader.dedupe_scope,
+                    closure
+                        .iter()
+                        .map(|path| store.get_full_path(path))
+                        .collect(),
+                )
+                .await;
+            closure.retain(|path| !uploaded.contains(&store.get_full_path(path)));
+            self.uploader
+                .metrics
+                .uploads_skipped_dedupe
+                .add(before - closure.len());
+        }
+
+        {
+            let persisted_uploads = self
+                .persisted_uploads
+                .read()
+                .expect("persisted uploads lock was poisoned");
+            if !persisted_uploads.is_empty() {
+                let before = closure.len();
+                closure.retain(|path| !persisted_uploads.contains(path.to_hash().as_str()));
+                self.uploader
+                    .metrics
+                    .uploads_skipped_persisted
+                    .add(before - closure.len());
+            }
+        }
+
+        if let Some(budget) = &self.uploader.budget {
+            let trimmed;
+            (closure, trimmed) = budget.check(&store, &roots, closure).await?;
+            self.uploader.metrics.uploads_skipped_budget.add(trimmed);
+        }
+
+        match self.uploader.queue.make_room(closure.len()).await {
+            Ok(0) => {}
+            Ok(dropped) => self
+                .uploader
+                .metrics
+                .uploads_dropped_queue_full
+                .add(dropped),
+            Err(err) => {
+                self.uploader.metrics.enqueues_rejected_queue_full.incr();
+                return Err(err);
+            }
+        }
+
         for p in closure {
+            let full_path = store.get_full_path(&p);
+            self.uploader.queue.push(full_path.clone());
+            self.uploader.events.publish(Event::UploadQueued {
+                backend: Backend::Gha,
+                store_path: full_path,
+            });
             self.channel_tx
                 .send(Request::Upload(p))
+                .await
                 .map_err(|_| Error::Internal("Cannot send upload message".to_owned()))?;
         }
 
         Ok(())
     }
+
+    /// Restores the state saved by earlier jobs, and saves it again at shutdown.
+    pub async fn restore_state(&self) {
+        let restored = match persist::restore(&self.api, &self.uploader.index).await {
+            Ok(restored) => restored,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to restore the state from the GitHub Actions Cache: {}",
+                    err
+                );
+                persist::Restored::default()
+            }
+        };
+
+        *self
+            .persisted_totals
+            .lock()
+            .expect("persisted totals mutex was poisoned") = Some(restored.totals);
+        *self
+            .persisted_uploads
+            .write()
+            .expect("persisted uploads lock was poisoned") = restored.uploaded;
+    }
+
+    /// The paths that an earlier daemon on this machine didn't get to upload.
+    pub async fn pending_uploads(&self) -> Vec<StorePath> {
+        let Some(journal) = &self.uploader.journal else {
+            return Vec::new();
+        };
+
+        let pending: Vec<StorePath> = journal
+            .load()
+            .await
+            .into_iter()
+            .filter_map(|full_path| self.uploader.store.follow_store_path(full_path).ok())
+            .collect();
+
+        if !pending.is_empty() {
+            tracing::info!(
+                "Resuming the upload of {} paths left over by an earlier run",
+                pending.len()
+            );
+            self.uploader.metrics.uploads_resumed.add(pending.len());
+        }
+
+        pending
+    }
+
+    /// Changes the number of compression workers and upload jobs.
+    pub async fn resize(&self, compression_workers: usize, upload_jobs: usize) -> Result<()> {
+        self.channel_tx
+            .send(Request::Resize {
+                compression_workers,
+                upload_jobs,
+            })
+            .await
+            .map_err(|_| Error::Internal("Cannot send resize message".to_owned()))
+    }
+
+    /// Queues the realisations and build log of a derivation for upload.
+    ///
+    /// They are uploaded as soon as the paths queued before them are, so a
+    /// realisation is never visible before the output it points to.
+    pub async fn enqueue_derivation(&self, drv_path: PathBuf) -> Result<()> {
+        self.channel_tx
+            .send(Request::Derivation(drv_path))
+            .await
+            .map_err(|_| Error::Internal("Cannot send upload message".to_owned()))
+    }
+
+    /// Uploads a single path right away, bypassing the queue.
+    pub async fn upload_now(&self, store: Arc<NixStore>, path: &StorePath) -> Result<()> {
+        let result = match self.uploader.compress(path).await {
+            Ok(Some(nar)) => self.uploader.upload(nar).await,
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+
+        self.uploader.queue.finish(&store.get_full_path(path));
+
+        result
+    }
+
+    /// Records a path that was uploaded some other way, e.g. with `nix copy`.
+    pub async fn record_upload(&self, store_path_hash: String, store_path: PathBuf) {
+        if let Some(dedupe_index) = &self.uploader.dedupe_index {
+            dedupe_index
+                .record(&self.uploader.dedupe_scope, &store_path)
+                .await;
+        }
+
+        self.uploader
+            .index
+            .record_upload(store_path_hash, store_path)
+            .await;
+    }
+
+    /// The store paths that are queued or being uploaded.
+    pub fn pending_paths(&self) -> Vec<QueueEntry> {
+        self.uploader.queue.snapshot()
+    }
+
+    /// The paths that failed too often to be tried again, with their last error.
+    pub fn quarantined_paths(&self) -> Vec<(PathBuf, String)> {
+        self.uploader.quarantine.paths()
+    }
+
+    /// Handles a narinfo cache miss.
+    ///
+    /// If we uploaded the path in an earlier run, GitHub has evicted it. When the path is
+    /// still present in the local store, it's queued for upload again. Returns whether the
+    /// miss was an eviction.
+    pub async fn handle_miss(&self, store: Arc<NixStore>, store_path_hash: &str) -> Result<bool> {
+        let Some(full_path) = self.uploader.index.record_eviction(store_path_hash).await else {
+            return Ok(false);
+        };
+
+        self.persisted_uploads
+            .write()
+            .expect("persisted uploads lock was poisoned")
+            .remove(store_path_hash);
+
+        if let Some(dedupe_index) = &self.uploader.dedupe_index {
+            dedupe_index
+                .forget(&self.uploader.dedupe_scope, &full_path)
+                .await;
+        }
+
+        if !full_path.exists() {
+            tracing::debug!(
+                "'{}' was evicted from the GitHub Action Cache, but it isn't in the local store",
+                full_path.display()
+            );
+            return Ok(true);
+        }
+
+        tracing::info!(
+            "'{}' was evicted from the GitHub Action Cache, uploading it again",
+            full_path.display()
+        );
+
+        let store_path = store.follow_store_path(&full_path)?;
+        self.uploader.sent.forget(&full_path);
+        self.uploader.queue.push(full_path);
+        self.channel_tx
+            .send(Request::Upload(store_path))
+            .await
+            .map_err(|_| Error::Internal("Cannot send upload message".to_owned()))?;
+
+        Ok(true)
+    }
 }
 
 async fn worker(
-    api: &Api,
-    store: Arc<NixStore>,
-    mut channel_rx: UnboundedReceiver<Request>,
-    metrics: Arc<telemetry::TelemetryReport>,
-    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+    uploader: Arc<Uploader>,
+    mut channel_rx: Receiver<Request>,
+    disk_watchdog: DiskSpaceWatchdog,
+    pipeline: Pipeline,
 ) -> Result<()> {
-    let mut done = HashSet::new();
+    // Compression is CPU-bound and uploading is IO-bound, so they get separate
+    // pools of workers, connected by a bounded channel.
+    let (compress_tx, compress_rx) = channel::<StorePath>(pipeline.compression_workers);
+    let (upload_tx, upload_rx) = channel::<CompressedNar>(pipeline.upload_jobs);
+    let compress_rx = Arc::new(Mutex::new(compress_rx));
+    let upload_rx = Arc::new(Mutex::new(upload_rx));
+
+    let mut stages = JoinSet::new();
+    let mut resize = |compression_workers, upload_jobs| {
+        for _ in 0..uploader.compression_pool.resize(compression_workers) {
+            stages.spawn(compression_worker(
+                uploader.clone(),
+                compress_rx.clone(),
+                upload_tx.clone(),
+            ));
+        }
+        for _ in 0..uploader.upload_pool.resize(upload_jobs) {
+            stages.spawn(upload_worker(uploader.clone(), upload_rx.clone()));
+        }
+    };
+    resize(pipeline.compression_workers, pipeline.upload_jobs);
+
+    let pack_flusher = pipeline
+        .pack_threshold
+        .is_some()
+        .then(|| tokio::task::spawn(flush_packs(uploader.clone())));
+
+    // Derivations wait for their outputs, which were queued before them.
+    let mut derivations = JoinSet::new();
+    let derivation_jobs = Arc::new(Semaphore::new(pipeline.upload_jobs));
+    let pipeline_drained = tokio_util::sync::CancellationToken::new();
+
+    // Paths that wait for disk space, in the order they were queued. They wait
+    // here rather than in the loop, so that shutdown isn't held up by them.
+    let mut waiting = VecDeque::new();
+
+    loop {
+        let req = tokio::select! {
+            biased;
+            _ = disk_watchdog.wait_for_space(), if !waiting.is_empty() => {
+                while !disk_watchdog.is_paused() {
+                    let Some(path) = waiting.pop_front() else {
+                        break;
+                    };
+                    send_to_compression(&compress_tx, path).await?;
+                }
+                continue;
+            }
+            req = channel_rx.recv() => req,
+        };
+        let Some(req) = req else {
+            break;
+        };
 
-    while let Some(req) = channel_rx.recv().await {
         match req {
             Request::Shutdown => {
                 break;
             }
             Request::Upload(path) => {
-                if api.circuit_breaker_tripped() {
+                let full_path = uploader.store.get_full_path(&path);
+
+                // Paths that were dropped to make room for newer ones are gone, and
+                // duplicates of paths in the pipeline are taken care of by the original.
+                if !uploader.queue.claim(&full_path) {
+                    continue;
+                }
+
+                if uploader.api.circuit_breaker_tripped() {
                     tracing::trace!("GitHub Actions gave us a 429, so we're done.",);
+                    uploader.queue.finish(&full_path);
                     continue;
                 }
 
-                if !done.insert(path.clone()) {
+                if uploader.quarantine.contains(&full_path) {
+                    tracing::debug!("Not uploading quarantined path '{}'", full_path.display());
+                    uploader.metrics.uploads_skipped_quarantined.incr();
+                    uploader.queue.finish(&full_path);
                     continue;
                 }
 
-                if let Err(err) = upload_path(
-                    api,
-                    store.clone(),
-                    &path,
-                    metrics.clone(),
-                    narinfo_negative_cache.clone(),
-                )
-                .await
-                {
-                    tracing::error!(
-                        "Upload of path '{}' failed: {}",
-                        store.get_full_path(&path).display(),
-                        err
-                    );
+                // Paths whose upload failed get another chance.
+                let failed = uploader.quarantine.has_failed(&full_path);
+                if !uploader.sent.admit(&full_path, failed) {
+                    uploader.queue.finish(&full_path);
+                    continue;
+                }
+
+                if disk_watchdog.is_paused() || !waiting.is_empty() {
+                    waiting.push_back(path);
+                    continue;
                 }
+
+                send_to_compression(&compress_tx, path).await?;
+            }
+            Request::Derivation(drv_path) => {
+                let pending = uploader
+                    .queue
+                    .snapshot()
+                    .into_iter()
+                    .map(|entry| entry.store_path)
+                    .collect();
+                let uploader = uploader.clone();
+                let derivation_jobs = derivation_jobs.clone();
+                let pipeline_drained = pipeline_drained.clone();
+
+                derivations.spawn(async move {
+                    tokio::select! {
+                        _ = uploader.queue.wait_for(pending) => {}
+                        // Paths that never made it into the pipeline don't hold anything up.
+                        _ = pipeline_drained.cancelled() => {}
+                    }
+                    let _permit = derivation_jobs.acquire().await;
+                    uploader.upload_derivation(&drv_path).await;
+                });
             }
+            Request::Resize {
+                compression_workers,
+                upload_jobs,
+            } => {
+                tracing::debug!(
+                    "Using {} compression workers and {} upload jobs",
+                    compression_workers,
+                    upload_jobs
+                );
+                resize(compression_workers, upload_jobs);
+            }
+        }
+    }
+
+    if disk_watchdog.is_paused() {
+        // They stay in the queue, so the journal hands them to the next run.
+        if !waiting.is_empty() {
+            tracing::warn!(
+                "Not uploading {} paths because the disk is still low on space",
+                waiting.len()
+            );
         }
+    } else {
+        for path in waiting {
+            send_to_compression(&compress_tx, path).await?;
+        }
+    }
+
+    // Let the pipeline drain.
+    drop(compress_tx);
+    drop(upload_tx);
+    while let Some(result) = stages.join_next().await {
+        result.map_err(|e| Error::Internal(format!("An upload worker failed: {e}")))?;
+    }
+
+    if let Some(pack_flusher) = pack_flusher {
+        pack_flusher.abort();
+    }
+    if let Some(pack) = uploader.pending_pack.lock().await.take() {
+        uploader.spawn_pack_upload(pack);
+    }
+    uploader.wait_for_packs().await;
+
+    pipeline_drained.cancel();
+    while let Some(result) = derivations.join_next().await {
+        result.map_err(|e| Error::Internal(format!("A derivation upload failed: {e}")))?;
     }
 
     Ok(())
 }
 
-async fn upload_path(
-    api: &Api,
-    store: Arc<NixStore>,
-    path: &StorePath,
-    metrics: Arc<telemetry::TelemetryReport>,
-    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
-) -> Result<()> {
-    let path_info = store.query_path_info(path.clone()).await?;
+async fn send_to_compression(compress_tx: &Sender<StorePath>, path: StorePath) -> Result<()> {
+    compress_tx
+        .send(path)
+        .await
+        .map_err(|_| Error::Internal("The compression workers exited early".to_owned()))
+}
+
+/// Keeps the journal of pending uploads up to date.
+async fn write_journal(uploader: Arc<Uploader>) {
+    let mut interval = tokio::time::interval(resume::WRITE_INTERVAL);
+    loop {
+        interval.tick().await;
+        uploader.write_journal().await;
+    }
+}
 
-    // Upload the NAR.
-    let nar_path = format!("{}.nar.zstd", path_info.nar_hash.to_base32());
+/// Uploads the pending pack once it's waited long enough for more members.
+async fn flush_packs(uploader: Arc<Uploader>) {
+    let mut interval = tokio::time::interval(pack::FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
 
-    let nar_allocation = api.allocate_file_with_random_suffix(&nar_path).await?;
+        let due = {
+            let mut pending_pack = uploader.pending_pack.lock().await;
+            if pending_pack
+                .as_ref()
+                .is_some_and(|pack| pack.is_due(Instant::now()))
+            {
+                pending_pack.take()
+            } else {
+                None
+            }
+        };
 
-    let nar_stream = store.nar_from_path(path.clone());
+        if let Some(pack) = due {
+            uploader.spawn_pack_upload(pack);
+        }
+    }
+}
+
+async fn compression_worker(
+    uploader: Arc<Uploader>,
+    paths: Arc<Mutex<Receiver<StorePath>>>,
+    compressed: Sender<CompressedNar>,
+) {
+    loop {
+        if uploader.compression_pool.retire() {
+            break;
+        }
 
-    let nar_reader = nar_stream
-        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
-        .into_async_read();
+        let Some(path) = paths.lock().await.recv().await else {
+            break;
+        };
 
-    let nar_compressor = ZstdEncoder::new(nar_reader.compat());
+        match uploader.timed(uploader.compress(&path)).await {
+            Ok(Some(nar)) => {
+                if compressed.send(nar).await.is_err() {
+                    break;
+                }
+            }
+            Ok(None) => uploader.queue.finish(&uploader.store.get_full_path(&path)),
+            Err(err) => {
+                let full_path = uploader.store.get_full_path(&path);
+                tracing::error!(
+                    "Compression of path '{}' failed: {}",
+                    full_path.display(),
+                    err
+                );
+                uploader.record_failure(&full_path, &err);
+                uploader.events.publish(Event::UploadFailed {
+                    backend: Backend::Gha,
+                    store_path: full_path.clone(),
+                    error: err.to_string(),
+                });
+                uploader.queue.finish(&full_path);
+            }
+        }
+    }
+}
 
-    let compressed_nar_size = api.upload_file(nar_allocation, nar_compressor).await?;
-    metrics.nars_uploaded.incr();
+async fn upload_worker(uploader: Arc<Uploader>, nars: Arc<Mutex<Receiver<CompressedNar>>>) {
+    loop {
+        if uploader.upload_pool.retire() {
+            break;
+        }
 
-    tracing::debug!(
-        "Uploaded '{}' (size {} -> {})",
-        nar_path,
-        path_info.nar_size,
-        compressed_nar_size
-    );
+        let Some(nar) = nars.lock().await.recv().await else {
+            break;
+        };
 
-    // Upload the narinfo.
-    let narinfo_path = format!("{}.narinfo", path.to_hash().as_str());
+        let full_path = uploader.store.get_full_path(&nar.path);
+        match uploader.timed(uploader.pack_or_upload(nar)).await {
+            // Finished along with its pack.
+            Ok(true) => continue,
+            Ok(false) => uploader.quarantine.record_success(&full_path),
+            Err(err) => uploader.upload_failed(&full_path, &err),
+        }
 
-    let narinfo_allocation = api.allocate_file_with_random_suffix(&narinfo_path).await?;
+        uploader.queue.finish(&full_path);
+    }
+}
 
-    let narinfo = path_info_to_nar_info(store.clone(), &path_info, format!("nar/{}", nar_path))
-        .to_string()
-        .expect("failed to convert path into to nar info");
+impl Uploader {
+    /// Runs one stage of an upload, cancelling it after `--upload-timeout`.
+    async fn timed<T>(&self, stage: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+        match self.upload_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, stage)
+                .await
+                .unwrap_or(Err(Error::TimedOut(timeout))),
+            None => stage.await,
+        }
+    }
 
-    tracing::debug!("Uploading '{}'", narinfo_path);
+    /// Records the paths that are still to be uploaded, if we keep a journal.
+    async fn write_journal(&self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
 
-    api.upload_file(narinfo_allocation, narinfo.as_bytes())
-        .await?;
+        // Packed paths stay in the queue until their pack is uploaded.
+        let pending: BTreeSet<PathBuf> = self
+            .queue
+            .snapshot()
+            .into_iter()
+            .map(|entry| entry.store_path)
+            .collect();
 
-    metrics.narinfos_uploaded.incr();
+        if let Err(err) = journal.write(pending).await {
+            tracing::debug!("Failed to record the pending uploads: {}", err);
+        }
+    }
+
+    /// Reports a failed upload.
+    fn upload_failed(&self, full_path: &Path, err: &Error) {
+        tracing::error!("Upload of path '{}' failed: {}", full_path.display(), err);
+        gha_annotations::report(err);
+        self.record_failure(full_path, err);
+        self.events.publish(Event::UploadFailed {
+            backend: Backend::Gha,
+            store_path: full_path.to_owned(),
+            error: err.to_string(),
+        });
+    }
+
+    /// Records a failed upload, quarantining the path if it failed too often.
+    fn record_failure(&self, full_path: &Path, err: &Error) {
+        if self.quarantine.record_failure(full_path, err.to_string()) {
+            tracing::warn!(
+                "Not trying to upload '{}' again, as it failed too often",
+                full_path.display()
+            );
+            self.metrics.uploads_quarantined.incr();
+        }
+    }
+
+    /// Uploads the realisations and, if enabled, the build log of a derivation.
+    async fn upload_derivation(&self, drv_path: &Path) {
+        if self.api.circuit_breaker_tripped() {
+            return;
+        }
+
+        if let Err(err) = self.upload_realisations(drv_path).await {
+            tracing::error!(
+                "Upload of the realisations of '{}' failed: {}",
+                drv_path.display(),
+                err
+            );
+            gha_annotations::report(&err);
+        }
+
+        if self.upload_build_logs {
+            if let Err(err) = self.upload_build_log(drv_path).await {
+                tracing::error!(
+                    "Upload of the build log of '{}' failed: {}",
+                    drv_path.display(),
+                    err
+                );
+                gha_annotations::report(&err);
+            }
+        }
+    }
+
+    async fn upload_realisations(&self, drv_path: &Path) -> Result<()> {
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: would upload the realisations of '{}'",
+                drv_path.display()
+            );
+            return Ok(());
+        }
+
+        // Most derivations aren't content-addressed, and Nix refuses to show realisations
+        // unless `ca-derivations` is enabled, so failing here is the common case.
+        let realisations = match realisation::query(drv_path).await {
+            Ok(realisations) => realisations,
+            Err(err) => {
+                tracing::debug!("Not uploading realisations: {}", err);
+                return Ok(());
+            }
+        };
+
+        for realisation in realisations {
+            let allocation = self
+                .api
+                .allocate_file_with_random_suffix(&realisation.key())
+                .await?;
+
+            let doi = serde_json::to_vec(&realisation.json).map_err(|e| {
+                Error::Internal(format!("Failed to serialize the realisation: {e}"))
+            })?;
+
+            self.api.upload_file(allocation, doi.as_slice()).await?;
+            self.metrics.realisations_uploaded.incr();
+
+            tracing::debug!("Uploaded realisation '{}'", realisation.id);
+        }
+
+        Ok(())
+    }
 
-    narinfo_negative_cache
-        .write()
+    async fn upload_build_log(&self, drv_path: &Path) -> Result<()> {
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: would upload the build log of '{}'",
+                drv_path.display()
+            );
+            return Ok(());
+        }
+
+        let Some(drv_name) = drv_path.file_name().and_then(|n| n.to_str()) else {
+            return Err(Error::Internal(format!(
+                "Invalid derivation path '{}'",
+                drv_path.display()
+            )));
+        };
+
+        let Some(log) = build_log::query(drv_path).await? else {
+            return Ok(());
+        };
+
+        let allocation = self
+            .api
+            .allocate_file_with_random_suffix(&build_log::key(drv_name))
+            .await?;
+        self.api.upload_file(allocation, log.as_slice()).await?;
+        self.metrics.build_logs_uploaded.incr();
+
+        tracing::debug!("Uploaded the build log of '{}'", drv_path.display());
+
+        Ok(())
+    }
+
+    /// Compresses the NAR of a path into a temporary file.
+    ///
+    /// Returns `None` if the path shouldn't be uploaded after all.
+    async fn compress(&self, path: &StorePath) -> Result<Option<CompressedNar>> {
+        let store = &self.store;
+
+        let path_info = store.query_path_info(path.clone()).await?;
+
+        if !self.deadline.allows(path_info.nar_size) {
+            tracing::info!(
+                "Not uploading '{}': it wouldn't finish before the job deadline",
+                store.get_full_path(path).display()
+            );
+            self.metrics.uploads_skipped_deadline.incr();
+            return Ok(None);
+        }
+
+        if !self.secret_scanner.allows(store, &self.metrics, path).await {
+            return Ok(None);
+        }
+
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: would upload '{}' ({} bytes uncompressed)",
+                store.get_full_path(path).display(),
+                path_info.nar_size
+            );
+            self.metrics.dry_run_nars.incr();
+            self.metrics
+                .dry_run_nar_bytes
+                .add(path_info.nar_size as usize);
+            return Ok(None);
+        }
+
+        self.queue
+            .start(&store.get_full_path(path), path_info.nar_size);
+        self.events.publish(Event::UploadStarted {
+            backend: Backend::Gha,
+            store_path: store.get_full_path(path),
+            nar_size: path_info.nar_size,
+        });
+
+        let started = Instant::now();
+
+        let (file, compressed_size) = nar::compress(store, path).await?;
+        if let Some(budget) = &self.budget {
+            budget.record(path_info.nar_size, compressed_size);
+        }
+
+        self.events.publish(Event::UploadProgress {
+            backend: Backend::Gha,
+            store_path: store.get_full_path(path),
+            stage: UploadStage::Compressed,
+            bytes: compressed_size,
+        });
+
+        Ok(Some(CompressedNar {
+            path: path.clone(),
+            path_info,
+            file,
+            compressed_size,
+            started,
+        }))
+    }
+
+    /// Uploads a compressed NAR and its narinfo.
+    async fn upload(&self, nar: CompressedNar) -> Result<()> {
+        let _permit = match &self.upload_limit {
+            Some(upload_limit) => upload_limit.acquire().await.ok(),
+            None => None,
+        };
+
+        let api = &self.api;
+        let store = &self.store;
+        let CompressedNar {
+            path,
+            path_info,
+            mut file,
+            compressed_size: _,
+            started,
+        } = nar;
+
+        let nar_digest = if self.verify_after_push {
+            Some(verify::digest_file(&mut file).await?)
+        } else {
+            None
+        };
+
+        // Upload the NAR.
+        let nar_path = format!("{}.nar.zstd", path_info.nar_hash.to_base32());
+
+        let nar_allocation = api.allocate_file_with_random_suffix(&nar_path).await?;
+
+        let compressed_nar_size = api.upload_file(nar_allocation, file).await?;
+        self.deadline.record(path_info.nar_size, started.elapsed());
+        self.metrics.nars_uploaded.incr();
+        self.metrics.nar_bytes_uploaded.add(compressed_nar_size);
+        self.events.publish(Event::UploadProgress {
+            backend: Backend::Gha,
+            store_path: store.get_full_path(&path),
+            stage: UploadStage::NarUploaded,
+            bytes: compressed_nar_size as u64,
+        });
+
+        tracing::debug!(
+            "Uploaded '{}' (size {} -> {})",
+            nar_path,
+            path_info.nar_size,
+            compressed_nar_size
+        );
+
+        self.publish(
+            &path,
+            &path_info,
+            format!("nar/{}", nar_path),
+            nar_digest
+                .as_ref()
+                .map(|digest| (nar_path.as_str(), digest)),
+            compressed_nar_size,
+            started,
+        )
         .await
-        .remove(&path.to_hash().to_string());
+    }
 
-    tracing::info!(
-        "Uploaded '{}' to the GitHub Action Cache",
-        store.get_full_path(path).display()
-    );
+    /// Adds a compressed NAR to the pending pack if it's small enough, and
+    /// uploads it on its own otherwise.
+    ///
+    /// Returns whether the NAR was packed, in which case the path is finished
+    /// when the pack is uploaded.
+    async fn pack_or_upload(self: &Arc<Self>, nar: CompressedNar) -> Result<bool> {
+        if !self
+            .pack_threshold
+            .is_some_and(|threshold| nar.compressed_size <= threshold)
+        {
+            return self.upload(nar).await.map(|()| false);
+        }
 
-    Ok(())
+        let CompressedNar {
+            path,
+            path_info,
+            mut file,
+            compressed_size,
+            started,
+        } = nar;
+
+        let mut compressed_nar = Vec::with_capacity(compressed_size as usize);
+        file.read_to_end(&mut compressed_nar)
+            .await
+            .map_err(|e| Error::Io(e, "Reading the compressed NAR".to_owned()))?;
+
+        let full_pack = {
+            let mut pending_pack = self.pending_pack.lock().await;
+            let pack = pending_pack.get_or_insert_with(Pack::default);
+            let full = pack.add(
+                path_info.nar_hash.to_base32(),
+                &compressed_nar,
+                PackedNar {
+                    path,
+                    path_info,
+                    compressed_size,
+                    started,
+                },
+            );
+            full.then(|| pending_pack.take()).flatten()
+        };
+
+        if let Some(pack) = full_pack {
+            self.spawn_pack_upload(pack);
+        }
+
+        Ok(true)
+    }
+
+    /// Uploads a pack in the background, so that the upload worker that filled it can move on.
+    fn spawn_pack_upload(self: &Arc<Self>, pack: Pack<PackedNar>) {
+        let uploader = self.clone();
+        self.lock_pack_uploads()
+            .spawn(async move { uploader.upload_pack(pack).await });
+    }
+
+    /// Waits for the packs being uploaded.
+    async fn wait_for_packs(&self) {
+        loop {
+            let mut pack_uploads = std::mem::take(&mut *self.lock_pack_uploads());
+            if pack_uploads.is_empty() {
+                return;
+            }
+
+            while pack_uploads.join_next().await.is_some() {}
+        }
+    }
+
+    fn lock_pack_uploads(&self) -> std::sync::MutexGuard<'_, JoinSet<()>> {
+        self.pack_uploads
+            .lock()
+            .expect("pack uploads mutex was poisoned")
+    }
+
+    /// Uploads a pack, then the narinfos of its members, and finishes the members.
+    async fn upload_pack(&self, pack: Pack<PackedNar>) {
+        let Pack {
+            id,
+            data,
+            index,
+            members,
+            ..
+        } = pack;
+
+        if let Err(err) = pack::retry(pack::RETRY_DELAY, || {
+            self.timed(self.upload_pack_entries(&id, &data, &index))
+        })
+        .await
+        {
+            tracing::error!("Upload of pack {} failed: {}", id, err);
+            for member in members {
+                let full_path = self.store.get_full_path(&member.path);
+                self.upload_failed(&full_path, &err);
+                self.queue.finish(&full_path);
+            }
+            return;
+        }
+
+        for member in members {
+            let full_path = self.store.get_full_path(&member.path);
+            let nar_url = pack::nar_url(&id, &member.path_info.nar_hash.to_base32());
+
+            match self
+                .timed(self.publish(
+                    &member.path,
+                    &member.path_info,
+                    nar_url,
+                    None,
+                    member.compressed_size as usize,
+                    member.started,
+                ))
+                .await
+            {
+                Ok(()) => {
+                    self.metrics.nars_packed.incr();
+                    self.quarantine.record_success(&full_path);
+                }
+                Err(err) => self.upload_failed(&full_path, &err),
+            }
+
+            self.queue.finish(&full_path);
+        }
+    }
+
+    async fn upload_pack_entries(
+        &self,
+        id: &str,
+        data: &[u8],
+        index: &pack::PackIndex,
+    ) -> Result<()> {
+        let _permit = match &self.upload_limit {
+            Some(upload_limit) => upload_limit.acquire().await.ok(),
+            None => None,
+        };
+
+        let allocation = self
+            .api
+            .allocate_file_with_random_suffix(&pack::blob_key(id))
+            .await?;
+        let size = self.api.upload_file(allocation, data).await?;
+
+        let index = serde_json::to_vec(index)
+            .map_err(|e| Error::Internal(format!("Failed to serialize the pack index: {e}")))?;
+        let allocation = self
+            .api
+            .allocate_file_with_random_suffix(&pack::index_key(id))
+            .await?;
+        self.api.upload_file(allocation, index.as_slice()).await?;
+
+        self.metrics.packs_uploaded.incr();
+        self.metrics.nar_bytes_uploaded.add(size);
+
+        tracing::debug!("Uploaded pack {} ({} bytes)", id, size);
+
+        Ok(())
+    }
+
+    /// Uploads the narinfo of a path whose NAR is at `nar_url`, and records the upload.
+    ///
+    /// The upload is verified if the key and digest of the NAR are given.
+    async fn publish(
+        &self,
+        path: &StorePath,
+        path_info: &ValidPathInfo,
+        nar_url: String,
+        nar_digest: Option<(&str, &Digest256)>,
+        compressed_nar_size: usize,
+        started: Instant,
+    ) -> Result<()> {
+        let api = &self.api;
+        let store = &self.store;
+
+        // Before the narinfo makes it visible, so that a corrupt NAR is never served.
+        if let Some((nar_path, nar_digest)) = nar_digest {
+            if let Err(err) = verify::check_nar(api, nar_path, nar_digest).await {
+                self.verification_failed(path, &err);
+                return Err(err);
+            }
+        }
+
+        // Upload the narinfo.
+        let narinfo_path = format!("{}.narinfo", path.to_hash().as_str());
+
+        let narinfo_allocation = api.allocate_file_with_random_suffix(&narinfo_path).await?;
+
+        let mut narinfo = path_info_to_nar_info(store.clone(), path_info, nar_url)?;
+
+        let secondary_signatures = match &self.signing_keys {
+            Some(signing_keys) => signing_keys.sign(&mut narinfo),
+            None => Vec::new(),
+        };
+
+        let mut narinfo = narinfo
+            .to_string()
+            .map_err(|e| Error::Internal(format!("Failed to serialize the narinfo: {e}")))?;
+
+        // The narinfo format allows any number of signatures, but `NarInfo` only has room for one.
+        for signature in secondary_signatures {
+            if !narinfo.ends_with('\n') {
+                narinfo.push('\n');
+            }
+            narinfo.push_str(&format!("Sig: {signature}\n"));
+        }
+
+        tracing::debug!("Uploading '{}'", narinfo_path);
+
+        api.upload_file(narinfo_allocation, narinfo.as_bytes())
+            .await?;
+
+        self.metrics.narinfos_uploaded.incr();
+
+        if nar_digest.is_some() {
+            if let Err(err) = verify::check_narinfo(api, &narinfo_path, &narinfo).await {
+                self.verification_failed(path, &err);
+                return Err(err);
+            }
+        }
+
+        self.narinfo_negative_cache
+            .write()
+            .await
+            .remove(&path.to_hash().to_string());
+
+        self.index
+            .record_upload(path.to_hash().to_string(), store.get_full_path(path))
+            .await;
+
+        if let Some(dedupe_index) = &self.dedupe_index {
+            dedupe_index
+                .record(&self.dedupe_scope, &store.get_full_path(path))
+                .await;
+        }
+
+        tracing::info!(
+            "Uploaded '{}' to the GitHub Action Cache",
+            store.get_full_path(path).display()
+        );
+        self.events.publish(Event::UploadCompleted {
+            backend: Backend::Gha,
+            store_path: store.get_full_path(path),
+            nar_size: path_info.nar_size,
+            compressed_size: Some(compressed_nar_size as u64),
+            elapsed_ms: started.elapsed().as_millis() as u64,
+        });
+
+        if self.upload_listings {
+            if let Err(err) = self.upload_listing(path).await {
+                tracing::debug!(
+                    "Not uploading a listing for '{}': {}",
+                    store.get_full_path(path).display(),
+                    err
+                );
+            }
+        }
+
+        if self.upload_deltas {
+            if let Err(err) = self
+                .upload_delta(path, path_info, compressed_nar_size)
+                .await
+            {
+                tracing::debug!(
+                    "Not uploading a delta for '{}': {}",
+                    store.get_full_path(&path).display(),
+                    err
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn verification_failed(&self, path: &StorePath, err: &Error) {
+        self.metrics.uploads_failed_verification.incr();
+        tracing::error!(
+            "Verifying the upload of '{}' failed: {}",
+            self.store.get_full_path(path).display(),
+            err
+        );
+    }
+
+    async fn upload_listing(&self, path: &StorePath) -> Result<()> {
+        let listing = listing::generate(&self.store.get_full_path(path)).await?;
+
+        let allocation = self
+            .api
+            .allocate_file_with_random_suffix(&listing::key(path.to_hash().as_str()))
+            .await?;
+        self.api.upload_file(allocation, listing.as_slice()).await?;
+
+        self.metrics.listings_uploaded.incr();
+
+        Ok(())
+    }
+
+    /// Uploads a delta against an earlier version of the path, if there is one
+    /// and the delta is meaningfully smaller than the full NAR.
+    async fn upload_delta(
+        &self,
+        path: &StorePath,
+        path_info: &ValidPathInfo,
+        compressed_nar_size: usize,
+    ) -> Result<()> {
+        let store = &self.store;
+
+        let Some(base) = self
+            .index
+            .previous_version(&store.get_full_path(path))
+            .await
+        else {
+            return Ok(());
+        };
+
+        let base_path = store.follow_store_path(&base)?;
+        let base_info = store.query_path_info(base_path.clone()).await?;
+
+        if path_info.nar_size > delta::MAX_NAR_SIZE || base_info.nar_size > delta::MAX_NAR_SIZE {
+            return Ok(());
+        }
+
+        let base_nar = nar::read(store, base_path).await?;
+        let target_nar = nar::read(store, path.clone()).await?;
+
+        let delta = tokio::task::spawn_blocking(move || delta::make(&base_nar, &target_nar))
+            .await
+            .map_err(|e| Error::Internal(format!("Computing a delta: {e}")))?
+            .map_err(|e| Error::Io(e, "Computing a delta".to_owned()))?;
+
+        // Deltas count against the quota too, so they have to pay for themselves.
+        if delta.len() * 2 > compressed_nar_size {
+            tracing::debug!(
+                "The delta for '{}' isn't worth it ({} -> {} bytes)",
+                store.get_full_path(path).display(),
+                compressed_nar_size,
+                delta.len()
+            );
+            return Ok(());
+        }
+
+        let nar_hash = path_info.nar_hash.to_base32();
+        let base_nar_hash = base_info.nar_hash.to_base32();
+
+        let delta_allocation = self
+            .api
+            .allocate_file_with_random_suffix(&delta::blob_key(&nar_hash, &base_nar_hash))
+            .await?;
+        self.api
+            .upload_file(delta_allocation, delta.as_slice())
+            .await?;
+
+        let info = serde_json::to_vec(&delta::DeltaInfo {
+            name: delta::path_name(&base).unwrap_or_default().to_owned(),
+            base_nar_hash,
+        })
+        .map_err(|e| Error::Internal(format!("Failed to serialize the delta info: {e}")))?;
+
+        let info_allocation = self
+            .api
+            .allocate_file_with_random_suffix(&delta::info_key(&nar_hash))
+            .await?;
+        self.api
+            .upload_file(info_allocation, info.as_slice())
+            .await?;
+
+        self.metrics.deltas_uploaded.incr();
+
+        tracing::info!(
+            "Uploaded a delta for '{}' against '{}' ({} -> {} bytes)",
+            store.get_full_path(path).display(),
+            base.display(),
+            compressed_nar_size,
+            delta.len()
+        );
+
+        Ok(())
+    }
 }
 
 // FIXME: move to attic.
-fn path_info_to_nar_info(store: Arc<NixStore>, path_info: &ValidPathInfo, url: String) -> NarInfo {
-    NarInfo {
+fn path_info_to_nar_info(
+    store: Arc<NixStore>,
+    path_info: &ValidPathInfo,
+    url: String,
+) -> Result<NarInfo> {
+    Ok(NarInfo {
         store_path: store.get_full_path(&path_info.path),
         url,
         compression: Compression::Zstd,
@@ -229,18 +1663,40 @@ fn path_info_to_nar_info(store: Arc<NixStore>, path_info: &ValidPathInfo, url: S
             .map(|r| {
                 r.file_name()
                     .and_then(|n| n.to_str())
-                    .unwrap_or_else(|| {
-                        panic!(
+                    .map(ToOwned::to_owned)
+                    .ok_or_else(|| {
+                        Error::Internal(format!(
                             "failed to convert nar_info reference to string: {}",
                             r.display()
-                        )
+                        ))
                     })
-                    .to_owned()
             })
-            .collect(),
+            .collect::<Result<_>>()?,
         system: None,
         deriver: None,
         signature: None,
         ca: path_info.ca.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicted_paths_are_sent_again() {
+        let sent = SentPaths::default();
+        let path = Path::new("/nix/store/00000000000000000000000000000000-hello");
+
+        assert!(sent.admit(path, false));
+        assert!(!sent.admit(path, false));
+
+        // A failed upload gets another chance.
+        assert!(sent.admit(path, true));
+
+        // The cache evicted the path, and Nix missed it.
+        sent.forget(path);
+        assert!(sent.admit(path, false));
+        assert!(!sent.admit(path, false));
     }
 }
@@ -0,0 +1,155 @@
+//! GitHub Actions annotations and step outputs.
+//!
+//! Failures that stop a backend from working for the rest of the job (bad
+//! credentials, a full cache, ...) are collected as they happen and shown as
+//! `::warning::` or `::error::` annotations at workflow-finish, once per kind.
+//! The caching results are also written to `GITHUB_OUTPUT`, so that later
+//! steps can branch on them.
+
+use std::io::Write as _;
+use std::path::PathBuf;
+
+use crate::env::Environment;
+use crate::error::{Error, ErrorKind};
+use crate::telemetry;
+
+/// The failures reported so far, at most one per kind.
+static FAILURES: std::sync::Mutex<Vec<Failure>> = std::sync::Mutex::new(Vec::new());
+
+struct Failure {
+    kind: ErrorKind,
+    level: Level,
+    message: String,
+}
+
+#[derive(Clone, Copy)]
+enum Level {
+    Warning,
+    Error,
+}
+
+impl Level {
+    fn command(&self) -> &'static str {
+        match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Records a failure, if it's one that will keep failing for the rest of the job.
+pub fn report(err: &Error) {
+    let kind = err.kind();
+    let level = match kind {
+        ErrorKind::AuthFailed | ErrorKind::CacheNotFound => Level::Error,
+        ErrorKind::QuotaExceeded | ErrorKind::RateLimited => Level::Warning,
+        _ => return,
+    };
+
+    let mut failures = FAILURES.lock().expect("failures mutex was poisoned");
+    if failures.iter().any(|failure| failure.kind == kind) {
+        return;
+    }
+
+    failures.push(Failure {
+        kind,
+        level,
+        message: err.to_string(),
+    });
+}
+
+/// Records the paths that failed to upload too often to be tried again.
+pub fn report_quarantined(paths: &[(PathBuf, String)]) {
+    if paths.is_empty() {
+        return;
+    }
+
+    let mut failures = FAILURES.lock().expect("failures mutex was poisoned");
+    failures.push(Failure {
+        kind: ErrorKind::Internal,
+        level: Level::Warning,
+        message: format!(
+            "Gave up on uploading {} paths after repeated failures:\n{}",
+            paths.len(),
+            paths
+                .iter()
+                .map(|(store_path, _)| store_path.display().to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    });
+}
+
+/// Prints the annotations for the recorded failures and writes the step outputs.
+pub fn finish(metrics: &telemetry::TelemetryReport) {
+    if !Environment::determine().is_github_actions() {
+        return;
+    }
+
+    for failure in FAILURES.lock().expect("failures mutex was poisoned").iter() {
+        println!(
+            "::{} title=Magic Nix Cache ({})::{}",
+            failure.level.command(),
+            failure.kind,
+            escape(&failure.message)
+        );
+    }
+
+    if let Err(err) = write_outputs(metrics) {
+        tracing::warn!("Failed to write the step outputs: {}", err);
+    }
+}
+
+fn write_outputs(metrics: &telemetry::TelemetryReport) -> std::io::Result<()> {
+    let Some(output_file) = std::env::var_os("GITHUB_OUTPUT") else {
+        return Ok(());
+    };
+
+    let mut outputs = vec![
+        (
+            "uploaded_paths",
+            metrics.narinfos_uploaded.get().to_string(),
+        ),
+        (
+            "uploaded_bytes",
+            metrics.nar_bytes_uploaded.get().to_string(),
+        ),
+    ];
+
+    let served = metrics.narinfos_served.get();
+    let requested = served + metrics.narinfos_sent_upstream.get();
+    if requested > 0 {
+        outputs.push((
+            "cache_hit_rate",
+            format!("{:.3}", served as f64 / requested as f64),
+        ));
+    }
+
+    let failures = FAILURES.lock().expect("failures mutex was poisoned");
+    outputs.push((
+        "failures",
+        failures
+            .iter()
+            .map(|failure| failure.kind.as_str())
+            .collect::<Vec<_>>()
+            .join(","),
+    ));
+
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(output_file)?;
+    for (name, value) in outputs {
+        writeln!(file, "{name}={value}")?;
+    }
+
+    Ok(())
+}
+
+/// Escapes the message of a workflow command.
+fn escape(message: &str) -> String {
+    message
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
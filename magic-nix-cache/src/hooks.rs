@@ -0,0 +1,582 @@
+//! Running commands on the paths that enter and leave the machine.
+//!
+//! Compliance tooling may have to scan or record every artifact that comes out
+//! of a cache or goes into one. With `--on-substitute-exec CMD`, `CMD` is run
+//! for every path substituted through the daemon, and with `--on-push-exec
+//! CMD`, for every path pushed. The command is run by `sh -c`, with the store
+//! path as its argument, and `MNC_HOOK` (`substitute` or `push`),
+//! `MNC_BACKEND` and `MNC_STORE_PATH` in its environment.
+//!
+//! Hooks never hold up Nix or the uploads: they run on `--hook-workers`
+//! workers, fed by a bounded queue. When the queue is full, the path is dropped
+//! with a warning. Each hook runs in its own process group, so that killing it
+//! also kills whatever it started. At shutdown, the queued hooks get a while to
+//! finish before they're killed.
+//!
+//! The daemon only sees Nix ask for narinfos, not whether it went on to
+//! substitute the path. So a path counts as substituted once it becomes valid
+//! in the local store after we answered for it. The store is only searched for
+//! the paths we answered for, and only again once something was added to it.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::process::{Child, Command};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+use crate::events::Event;
+use crate::telemetry;
+use crate::{Backend, State};
+
+/// How many paths may wait for a hook worker.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How long a hook may run before it is killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long the queued hooks get to finish at shutdown.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the store is checked for the paths we answered narinfos for.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long we wait for a path we answered for to show up in the store.
+const SUBSTITUTION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How many answered paths we wait for at most.
+const MAX_AWAITED: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+enum Hook {
+    Substitute,
+    Push,
+}
+
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Hook::Substitute => "substitute",
+            Hook::Push => "push",
+        }
+    }
+}
+
+struct Job {
+    hook: Hook,
+    command: Arc<str>,
+    backend: Backend,
+    store_path: PathBuf,
+}
+
+/// A path we answered the narinfo of.
+struct Awaited {
+    backend: Backend,
+
+    answered: Instant,
+
+    /// Where the path is in the store, once it showed up there.
+    full_path: Option<PathBuf>,
+}
+
+pub struct Hooks {
+    on_substitute: Option<Arc<str>>,
+
+    on_push: Option<Arc<str>>,
+
+    /// Taken at shutdown, so that the workers stop once the queue is empty.
+    queue: std::sync::Mutex<Option<Sender<Job>>>,
+
+    workers: std::sync::Mutex<JoinSet<()>>,
+
+    /// Makes the workers kill their hooks, when they don't drain in time.
+    stop: CancellationToken,
+
+    /// The paths we answered narinfos for, by store path hash.
+    awaited: std::sync::Mutex<HashMap<String, Awaited>>,
+
+    metrics: Arc<telemetry::TelemetryReport>,
+}
+
+impl Hooks {
+    /// Sets up the hooks, if any are configured, and starts their workers.
+    pub fn new(
+        on_substitute: Option<String>,
+        on_push: Option<String>,
+        workers: usize,
+        metrics: Arc<telemetry::TelemetryReport>,
+    ) -> Option<Arc<Self>> {
+        if on_substitute.is_none() && on_push.is_none() {
+            return None;
+        }
+
+        Some(Self::start(
+            on_substitute,
+            on_push,
+            workers,
+            HOOK_TIMEOUT,
+            metrics,
+        ))
+    }
+
+    fn start(
+        on_substitute: Option<String>,
+        on_push: Option<String>,
+        workers: usize,
+        hook_timeout: Duration,
+        metrics: Arc<telemetry::TelemetryReport>,
+    ) -> Arc<Self> {
+        let (queue, jobs) = mpsc::channel(QUEUE_CAPACITY);
+        let jobs = Arc::new(Mutex::new(jobs));
+        let stop = CancellationToken::new();
+
+        let mut worker_tasks = JoinSet::new();
+        for _ in 0..workers {
+            worker_tasks.spawn(worker(
+                jobs.clone(),
+                hook_timeout,
+                stop.clone(),
+                metrics.clone(),
+            ));
+        }
+
+        Arc::new(Self {
+            on_substitute: on_substitute.map(Arc::from),
+            on_push: on_push.map(Arc::from),
+            queue: std::sync::Mutex::new(Some(queue)),
+            workers: std::sync::Mutex::new(worker_tasks),
+            stop,
+            awaited: Default::default(),
+            metrics,
+        })
+    }
+
+    /// Notes that we answered the narinfo of `store_path_hash` from `backend`.
+    pub fn narinfo_hit(&self, store_path_hash: &str, backend: Backend) {
+        if self.on_substitute.is_none() {
+            return;
+        }
+
+        let mut awaited = self.lock_awaited();
+        if awaited.len() >= MAX_AWAITED {
+            self.metrics.hooks_dropped.incr();
+            return;
+        }
+        awaited.insert(
+            store_path_hash.to_owned(),
+            Awaited {
+                backend,
+                answered: Instant::now(),
+                full_path: None,
+            },
+        );
+    }
+
+    /// Stops taking paths and waits for the queued hooks to run, killing them
+    /// if they take longer than `DRAIN_TIMEOUT`.
+    pub async fn shutdown(&self) {
+        self.lock_queue().take();
+
+        let mut workers = std::mem::take(&mut *self.lock_workers());
+        let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+            while workers.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            tracing::warn!(
+                "The hooks didn't finish within {} seconds, killing them",
+                DRAIN_TIMEOUT.as_secs()
+            );
+            self.stop.cancel();
+            while workers.join_next().await.is_some() {}
+        }
+    }
+
+    fn run(&self, hook: Hook, backend: Backend, store_path: PathBuf) {
+        let command = match hook {
+            Hook::Substitute => &self.on_substitute,
+            Hook::Push => &self.on_push,
+        };
+        let Some(command) = command.clone() else {
+            return;
+        };
+
+        let queue = self.lock_queue();
+        let Some(queue) = &*queue else {
+            tracing::debug!(
+                "Not running the {} hook for '{}': shutting down",
+                hook.name(),
+                store_path.display()
+            );
+            return;
+        };
+
+        let job = Job {
+            hook,
+            command,
+            backend,
+            store_path,
+        };
+        if let Err(TrySendError::Full(job)) = queue.try_send(job) {
+            tracing::warn!(
+                "Not running the {} hook for '{}': too many are waiting",
+                job.hook.name(),
+                job.store_path.display()
+            );
+            self.metrics.hooks_dropped.incr();
+        }
+    }
+
+    /// Notes where the awaited paths that showed up in `store_dir` are, and
+    /// stops looking once all of them did.
+    async fn find_awaited(&self, store_dir: &Path) -> std::io::Result<()> {
+        let mut missing = self
+            .lock_awaited()
+            .values()
+            .filter(|awaited| awaited.full_path.is_none())
+            .count();
+
+        let mut listing = tokio::fs::read_dir(store_dir).await?;
+        while missing > 0 {
+            let Some(entry) = listing.next_entry().await? else {
+                break;
+            };
+
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+
+            // Nix locks the paths it's substituting next to them.
+            if file_name.ends_with(".lock") {
+                continue;
+            }
+
+            let Some((hash, _)) = file_name.split_once('-') else {
+                continue;
+            };
+
+            if let Some(awaited) = self.lock_awaited().get_mut(hash) {
+                if awaited.full_path.is_none() {
+                    awaited.full_path = Some(entry.path());
+                    missing -= 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lock_queue(&self) -> std::sync::MutexGuard<'_, Option<Sender<Job>>> {
+        self.queue.lock().expect("hook queue mutex was poisoned")
+    }
+
+    fn lock_workers(&self) -> std::sync::MutexGuard<'_, JoinSet<()>> {
+        self.workers
+            .lock()
+            .expect("hook workers mutex was poisoned")
+    }
+
+    fn lock_awaited(&self) -> std::sync::MutexGuard<'_, HashMap<String, Awaited>> {
+        self.awaited
+            .lock()
+            .expect("awaited substitutions mutex was poisoned")
+    }
+}
+
+/// Starts feeding the hooks with pushed and substituted paths.
+pub fn spawn(state: State) {
+    let Some(hooks) = state.hooks.clone() else {
+        return;
+    };
+
+    if hooks.on_push.is_some() {
+        tokio::task::spawn(watch_pushes(hooks.clone(), state.clone()));
+    }
+
+    if hooks.on_substitute.is_some() {
+        tokio::task::spawn(watch_substitutions(hooks, state));
+    }
+}
+
+async fn watch_pushes(hooks: Arc<Hooks>, state: State) {
+    let mut events = state.events.subscribe();
+
+    loop {
+        match events.recv().await {
+            Ok(Event::UploadCompleted {
+                backend,
+                store_path,
+                ..
+            }) => hooks.run(Hook::Push, backend, store_path),
+            Ok(_) => {}
+            // Most of the missed events aren't completed uploads, so they
+            // can't be counted as dropped hooks.
+            Err(RecvError::Lagged(count)) => {
+                tracing::warn!("The push hook missed {} events", count);
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn watch_substitutions(hooks: Arc<Hooks>, state: State) {
+    let store_dir = state.store.store_dir().to_owned();
+
+    // When the store directory last changed as of our last look at it.
+    let mut searched: Option<SystemTime> = None;
+
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let searching = {
+            let mut awaited = hooks.lock_awaited();
+            awaited.retain(|_, awaited| awaited.answered.elapsed() < SUBSTITUTION_TIMEOUT);
+            awaited.values().any(|awaited| awaited.full_path.is_none())
+        };
+
+        if searching {
+            // Paths showing up in the store change its directory, so it only
+            // has to be searched again then.
+            let modified = tokio::fs::metadata(&store_dir)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok();
+
+            if modified.is_none() || modified != searched {
+                match hooks.find_awaited(&store_dir).await {
+                    Ok(()) => searched = modified,
+                    Err(err) => tracing::warn!("Failed to search the store: {}", err),
+                }
+            }
+        }
+
+        let found: Vec<(String, PathBuf, Backend)> = hooks
+            .lock_awaited()
+            .iter()
+            .filter_map(|(hash, awaited)| {
+                Some((hash.clone(), awaited.full_path.clone()?, awaited.backend))
+            })
+            .collect();
+
+        for (hash, full_path, backend) in found {
+            let Ok(store_path) = state.store.follow_store_path(&full_path) else {
+                continue;
+            };
+
+            // Paths that are being substituted show up before they're valid.
+            if state.store.query_path_info(store_path).await.is_err() {
+                continue;
+            }
+
+            hooks.lock_awaited().remove(&hash);
+            hooks.run(Hook::Substitute, backend, full_path);
+        }
+    }
+}
+
+async fn worker(
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    hook_timeout: Duration,
+    stop: CancellationToken,
+    metrics: Arc<telemetry::TelemetryReport>,
+) {
+    loop {
+        let job = tokio::select! {
+            biased;
+            _ = stop.cancelled() => None,
+            job = async { jobs.lock().await.recv().await } => job,
+        };
+        let Some(job) = job else {
+            break;
+        };
+
+        let backend = match job.backend {
+            Backend::Gha => "gha",
+            Backend::FlakeHub => "flakehub",
+        };
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("{} \"$1\"", job.command))
+            .arg("sh")
+            .arg(&job.store_path)
+            .env("MNC_HOOK", job.hook.name())
+            .env("MNC_BACKEND", backend)
+            .env("MNC_STORE_PATH", &job.store_path)
+            .kill_on_drop(true);
+
+        // So that `kill` gets whatever the hook started too.
+        #[cfg(unix)]
+        command.process_group(0);
+
+        metrics.hooks_run.incr();
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                metrics.hooks_failed.incr();
+                tracing::warn!("Failed to run the {} hook: {}", job.hook.name(), err);
+                continue;
+            }
+        };
+
+        let status = tokio::select! {
+            status = child.wait() => Some(status),
+            _ = tokio::time::sleep(hook_timeout) => None,
+            _ = stop.cancelled() => None,
+        };
+
+        match status {
+            Some(Ok(status)) if status.success() => {}
+            Some(Ok(status)) => {
+                metrics.hooks_failed.incr();
+                tracing::warn!(
+                    "The {} hook for '{}' failed: {}",
+                    job.hook.name(),
+                    job.store_path.display(),
+                    status
+                );
+            }
+            Some(Err(err)) => {
+                metrics.hooks_failed.incr();
+                tracing::warn!("Failed to wait for the {} hook: {}", job.hook.name(), err);
+            }
+            None => {
+                kill(&mut child).await;
+                metrics.hooks_failed.incr();
+                if stop.is_cancelled() {
+                    tracing::warn!(
+                        "The {} hook for '{}' was killed at shutdown",
+                        job.hook.name(),
+                        job.store_path.display()
+                    );
+                } else {
+                    tracing::warn!(
+                        "The {} hook for '{}' took more than {} seconds and was killed",
+                        job.hook.name(),
+                        job.store_path.display(),
+                        hook_timeout.as_secs()
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Kills the hook's process group, and reaps the hook.
+#[cfg(unix)]
+async fn kill(child: &mut Child) {
+    use nix::sys::signal::{killpg, Signal};
+    use nix::unistd::Pid;
+
+    if let Some(pid) = child.id() {
+        if let Err(err) = killpg(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+            tracing::warn!("Failed to kill a hook: {}", err);
+        }
+    }
+
+    if let Err(err) = child.kill().await {
+        tracing::warn!("Failed to kill a hook: {}", err);
+    }
+}
+
+#[cfg(not(unix))]
+async fn kill(child: &mut Child) {
+    if let Err(err) = child.kill().await {
+        tracing::warn!("Failed to kill a hook: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics() -> Arc<telemetry::TelemetryReport> {
+        Arc::new(telemetry::TelemetryReport::new())
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_paths() {
+        let metrics = metrics();
+        // Without workers, nothing takes paths off the queue.
+        let hooks = Hooks::start(
+            None,
+            Some("true".to_owned()),
+            0,
+            HOOK_TIMEOUT,
+            metrics.clone(),
+        );
+
+        for i in 0..=QUEUE_CAPACITY {
+            hooks.run(
+                Hook::Push,
+                Backend::Gha,
+                PathBuf::from(format!("/nix/store/{i}")),
+            );
+        }
+
+        assert_eq!(metrics.hooks_dropped.get(), 1);
+        assert_eq!(metrics.hooks_run.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn shutdown_runs_queued_hooks() {
+        let metrics = metrics();
+        let hooks = Hooks::start(
+            None,
+            Some("true".to_owned()),
+            1,
+            HOOK_TIMEOUT,
+            metrics.clone(),
+        );
+
+        for i in 0..3 {
+            hooks.run(
+                Hook::Push,
+                Backend::Gha,
+                PathBuf::from(format!("/nix/store/{i}")),
+            );
+        }
+        hooks.shutdown().await;
+
+        assert_eq!(metrics.hooks_run.get(), 3);
+        assert_eq!(metrics.hooks_failed.get(), 0);
+
+        // Paths that come in after shutdown are ignored.
+        hooks.run(Hook::Push, Backend::Gha, PathBuf::from("/nix/store/late"));
+        assert_eq!(metrics.hooks_dropped.get(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn timeout_kills_what_the_hook_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("marker");
+
+        let metrics = metrics();
+        // The inner shell outlives `sh -c` unless its process group is killed.
+        let hooks = Hooks::start(
+            Some("sh -c 'sleep 1; touch \"$0\"'".to_owned()),
+            None,
+            1,
+            Duration::from_millis(100),
+            metrics.clone(),
+        );
+
+        hooks.run(Hook::Substitute, Backend::Gha, marker.clone());
+        hooks.shutdown().await;
+
+        assert_eq!(metrics.hooks_run.get(), 1);
+        assert_eq!(metrics.hooks_failed.get(), 1);
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!marker.exists());
+    }
+}
@@ -0,0 +1,136 @@
+//! HTTP clients.
+//!
+//! Every client the daemon uses to talk to the outside world comes from
+//! [`client_builder`], so that they all trust the CAs from `--extra-ca-cert`,
+//! which corporate runners behind intercepting proxies need. The proxies in
+//! `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` are picked up by reqwest itself,
+//! unless `--proxy` overrides them (see [`crate::tunnel`]).
+//!
+//! They also identify us the same way: the user agent names the repository and
+//! workflow run, plus `--user-agent-suffix`, so that the logs of the backends
+//! can attribute traffic to specific workflows.
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::error::{Error, Result};
+
+const BASE_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The CAs from `--extra-ca-cert`, once loaded.
+static EXTRA_CA_CERTS: OnceLock<Vec<reqwest::Certificate>> = OnceLock::new();
+
+/// The user agent, once the suffix is known.
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+
+/// The proxy from `--proxy`, if any.
+static PROXY: OnceLock<reqwest::Proxy> = OnceLock::new();
+
+/// Loads the PEM bundles in `paths`, to be trusted by all clients made afterwards,
+/// and sets up the user agent and proxy.
+pub fn init(
+    paths: &[PathBuf],
+    user_agent_suffix: Option<&str>,
+    proxy_url: Option<&str>,
+) -> Result<()> {
+    let user_agent = build_user_agent(user_agent_suffix);
+    reqwest::header::HeaderValue::from_str(&user_agent)
+        .map_err(|_| Error::Config(format!("invalid user agent '{user_agent}'")))?;
+    USER_AGENT
+        .set(user_agent)
+        .map_err(|_| Error::Internal("The HTTP clients were already set up".to_owned()))?;
+
+    let mut certs = Vec::new();
+    for path in paths {
+        let pem = std::fs::read(path)
+            .map_err(|e| Error::Io(e, format!("Reading the CA bundle {}", path.display())))?;
+        certs
+            .extend(reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| {
+                Error::Config(format!("invalid CA bundle {}: {e}", path.display()))
+            })?);
+    }
+
+    if !certs.is_empty() {
+        tracing::info!("Trusting {} extra CA certificates", certs.len());
+    }
+
+    if let Some(proxy_url) = proxy_url {
+        let proxy = reqwest::Proxy::all(proxy_url)
+            .map_err(|e| Error::Config(format!("invalid proxy '{proxy_url}': {e}")))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        PROXY
+            .set(proxy)
+            .map_err(|_| Error::Internal("The HTTP clients were already set up".to_owned()))?;
+    }
+
+    EXTRA_CA_CERTS
+        .set(certs)
+        .map_err(|_| Error::Internal("The HTTP clients were already set up".to_owned()))
+}
+
+/// The user agent of all requests, e.g.
+/// `magic-nix-cache/0.2.0 (owner/repo; run 1234/1) suffix`.
+pub fn user_agent() -> &'static str {
+    USER_AGENT.get().map_or(BASE_USER_AGENT, String::as_str)
+}
+
+fn build_user_agent(suffix: Option<&str>) -> String {
+    let mut user_agent = BASE_USER_AGENT.to_owned();
+
+    if let Ok(repository) = std::env::var("GITHUB_REPOSITORY") {
+        let run = match (
+            std::env::var("GITHUB_RUN_ID"),
+            std::env::var("GITHUB_RUN_ATTEMPT"),
+        ) {
+            (Ok(id), Ok(attempt)) => format!("; run {id}/{attempt}"),
+            (Ok(id), Err(_)) => format!("; run {id}"),
+            _ => String::new(),
+        };
+        user_agent.push_str(&format!(" ({repository}{run})"));
+    }
+
+    if let Some(suffix) = suffix.filter(|suffix| !suffix.is_empty()) {
+        user_agent.push(' ');
+        user_agent.push_str(suffix);
+    }
+
+    user_agent
+}
+
+/// A client builder that trusts the extra CAs and goes through the proxy.
+pub fn client_builder() -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder().user_agent(user_agent());
+
+    if let Some(proxy) = PROXY.get() {
+        builder = builder.proxy(proxy.clone());
+    }
+
+    EXTRA_CA_CERTS
+        .get()
+        .into_iter()
+        .flatten()
+        .fold(builder, |builder, cert| {
+            builder.add_root_certificate(cert.clone())
+        })
+}
+
+/// A shared client for requests that need no special setup.
+pub fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+    CLIENT.get_or_init(|| {
+        client_builder()
+            .build()
+            .expect("failed to build the HTTP client")
+    })
+}
+
+/// A client for talking to the daemon and the Action on this machine, which
+/// must not go through a proxy.
+pub fn local_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(user_agent())
+        .no_proxy()
+        .build()
+        .expect("failed to build the HTTP client")
+}
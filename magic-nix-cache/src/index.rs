@@ -0,0 +1,157 @@
+//! Persistent index of store paths uploaded to the GitHub Actions Cache.
+//!
+//! GitHub evicts cache entries that haven't been accessed in a while, or when the
+//! repository exceeds its storage quota. Without remembering what we uploaded, an
+//! evicted path looks exactly like one we never had, and Nix keeps missing on it.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::error::{Error, Result};
+
+/// An entry in the upload index, keyed by store path hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// The full path of the uploaded store path.
+    pub store_path: PathBuf,
+
+    /// How many times we noticed that GitHub evicted this path.
+    #[serde(default)]
+    pub evictions: usize,
+}
+
+pub struct UploadIndex {
+    /// Where the index is persisted, if anywhere.
+    path: Option<PathBuf>,
+
+    entries: RwLock<HashMap<String, IndexEntry>>,
+}
+
+impl UploadIndex {
+    /// Loads the index from `path`, starting empty if it doesn't exist or can't be parsed.
+    pub async fn load(path: Option<PathBuf>) -> Self {
+        let entries = match &path {
+            Some(path) => match read_entries(path).await {
+                Ok(entries) => {
+                    tracing::debug!(
+                        "Loaded {} entries from the upload index at {}",
+                        entries.len(),
+                        path.display()
+                    );
+                    entries
+                }
+                Err(err) => {
+                    tracing::warn!("Ignoring the upload index at {}: {}", path.display(), err);
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        Self {
+            path,
+            entries: RwLock::new(entries),
+        }
+    }
+
+    /// Records that a store path was successfully uploaded.
+    pub async fn record_upload(&self, store_path_hash: String, store_path: PathBuf) {
+        let mut entries = self.entries.write().await;
+        let entry = entries
+            .entry(store_path_hash)
+            .or_insert_with(|| IndexEntry {
+                store_path: store_path.clone(),
+                evictions: 0,
+            });
+        entry.store_path = store_path;
+    }
+
+    /// Records a cache miss for a store path hash.
+    ///
+    /// If we previously uploaded the path, the cache evicted it, and we return
+    /// the full store path so it can be uploaded again.
+    pub async fn record_eviction(&self, store_path_hash: &str) -> Option<PathBuf> {
+        let mut entries = self.entries.write().await;
+        let entry = entries.get_mut(store_path_hash)?;
+        entry.evictions += 1;
+
+        Some(entry.store_path.clone())
+    }
+
+    /// Finds another version of `store_path` (same name, different hash) that we
+    /// uploaded before and that is still in the local store.
+    pub async fn previous_version(&self, store_path: &Path) -> Option<PathBuf> {
+        let name = crate::delta::path_name(store_path)?;
+
+        self.entries
+            .read()
+            .await
+            .values()
+            .map(|entry| &entry.store_path)
+            .find(|candidate| {
+                *candidate != store_path
+                    && crate::delta::path_name(candidate) == Some(name)
+                    && candidate.exists()
+            })
+            .cloned()
+    }
+
+    /// Adds entries from another index, e.g. one saved by an earlier job.
+    ///
+    /// Entries we already have win, but keep the higher eviction count.
+    pub async fn merge(&self, other: HashMap<String, IndexEntry>) {
+        let mut entries = self.entries.write().await;
+        for (store_path_hash, other_entry) in other {
+            entries
+                .entry(store_path_hash)
+                .and_modify(|entry| entry.evictions = entry.evictions.max(other_entry.evictions))
+                .or_insert(other_entry);
+        }
+    }
+
+    /// Returns a copy of all entries.
+    pub async fn entries(&self) -> HashMap<String, IndexEntry> {
+        self.entries.read().await.clone()
+    }
+
+    /// Writes the index back to disk.
+    pub async fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let serialized = serde_json::to_vec(&*self.entries.read().await)
+            .map_err(|e| Error::Internal(format!("Serializing the upload index: {e}")))?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| Error::Io(e, format!("Creating {}", parent.display())))?;
+        }
+
+        // NOTE: write next to the real file and rename, so a crash never leaves a truncated index
+        let path_tmp = path.with_extension("tmp");
+        tokio::fs::write(&path_tmp, serialized)
+            .await
+            .map_err(|e| Error::Io(e, format!("Writing {}", path_tmp.display())))?;
+        tokio::fs::rename(&path_tmp, path)
+            .await
+            .map_err(|e| Error::Io(e, format!("Renaming {}", path_tmp.display())))?;
+
+        Ok(())
+    }
+}
+
+async fn read_entries(path: &Path) -> Result<HashMap<String, IndexEntry>> {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(Error::Io(e, format!("Reading {}", path.display()))),
+    };
+
+    serde_json::from_slice(&contents)
+        .map_err(|e| Error::Internal(format!("Parsing the upload index: {e}")))
+}
@@ -0,0 +1,1840 @@
+//! A Nix binary cache backed by the GitHub Actions Cache and FlakeHub.
+//!
+//! The `magic-nix-cache` daemon is a thin wrapper around [`run`]. To serve the
+//! GitHub Actions Cache as a binary cache from another program instead, build a
+//! [`Server`] with a [`ServerBuilder`], mount its [`Server::router`] and push
+//! paths through its [`PushQueue`].
+//!
+//! It needs the Nix store libraries, so it's only built on Unix.
+
+#![cfg(unix)]
+#![deny(
+    asm_sub_register,
+    deprecated,
+    missing_abi,
+    unused_macros,
+    unused_must_use,
+    unused_unsafe
+)]
+#![deny(clippy::from_over_into, clippy::needless_question_mark)]
+#![cfg_attr(
+    not(debug_assertions),
+    deny(unused_imports, unused_mut, unused_variables,)
+)]
+
+mod api;
+mod backends;
+mod binary_cache;
+mod budget;
+mod build_log;
+mod builders;
+mod config;
+mod deadline;
+mod dedupe;
+mod delta;
+mod dev;
+mod env;
+mod error;
+mod events;
+mod fallback;
+mod filter;
+mod flake_inputs;
+mod flakehub;
+mod flakehub_proxy;
+mod flakehub_push;
+mod gha;
+mod gha_annotations;
+mod hooks;
+mod http_client;
+mod index;
+mod listing;
+mod nar;
+mod narinfo_cache;
+mod nix_conf;
+mod pack;
+mod pbh;
+mod persist;
+mod policy;
+mod preheat;
+mod quarantine;
+mod queue;
+mod realisation;
+mod reload;
+mod remote;
+mod resume;
+mod secrets;
+mod selftest;
+mod server;
+mod signing;
+mod substitutions;
+mod telemetry;
+mod tenants;
+mod tunnel;
+mod util;
+mod verify;
+mod watch_store;
+mod watchdog;
+
+use std::collections::HashSet;
+use std::fs::create_dir_all;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ::attic::nix_store::NixStore;
+use anyhow::{anyhow, Context, Result};
+use axum::extract::Extension;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+pub use error::{Error, ErrorKind};
+pub use gha_cache::Credentials;
+pub use queue::{EnqueueOverflow, QueueEntry, UploadState};
+pub use server::{PushQueue, Server, ServerBuilder};
+
+const DETERMINATE_STATE_DIR: &str = "/nix/var/determinate";
+const DETERMINATE_NIXD_SOCKET_NAME: &str = "determinate-nixd.socket";
+
+// TODO(colemickens): refactor, move with other UDS stuff (or all PBH stuff) to new file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "c", rename_all = "kebab-case")]
+pub(crate) struct BuiltPathResponseEventV1 {
+    pub drv: PathBuf,
+    pub outputs: Vec<PathBuf>,
+}
+
+type State = Arc<StateInner>;
+
+/// GitHub Actions-powered Nix binary cache
+#[derive(Parser, Debug)]
+#[command(args_override_self = true)]
+struct Args {
+    /// Addresses to listen on, separated by commas, e.g. `127.0.0.1:3000,[::1]:3000`.
+    ///
+    /// Nix is pointed at the first one.
+    #[arg(
+        short = 'l',
+        long,
+        default_value = "127.0.0.1:3000",
+        value_delimiter = ','
+    )]
+    listen: Vec<SocketAddr>,
+
+    /// File to write a freshly generated API token to.
+    ///
+    /// When set, all `/api/*` requests and uploads of realisations and build
+    /// logs must carry the token as a bearer token.
+    #[arg(long)]
+    api_token_file: Option<PathBuf>,
+
+    /// Address on which to serve the binary cache to other machines.
+    ///
+    /// Only the read-only substituter endpoints are exposed, and clients must
+    /// present the token from `--remote-token-file`.
+    #[arg(long)]
+    remote_listen: Option<SocketAddr>,
+
+    /// File containing the token that remote clients must present, either as a
+    /// bearer token or as the password in their `netrc`.
+    #[arg(long)]
+    remote_token_file: Option<PathBuf>,
+
+    /// Number of requests per second each remote client may make. 0 disables rate limiting.
+    #[arg(long, default_value_t = 50)]
+    remote_rate_limit: u32,
+
+    /// The cache version.
+    ///
+    /// Only caches with the same version string are visible.
+    /// Using another version string allows you to "bust" the cache.
+    #[arg(long)]
+    cache_version: Option<String>,
+
+    /// Mixed into the cache version. Bump it to invalidate everything cached so far.
+    #[arg(long)]
+    cache_version_salt: Option<String>,
+
+    /// Prepended to the key of everything stored in the GHA cache, to partition it,
+    /// e.g. per branch or nixpkgs pin.
+    #[arg(long)]
+    cache_key_prefix: Option<String>,
+
+    /// The base URL of the GHA cache, instead of `ACTIONS_CACHE_URL`,
+    /// e.g. that of the cache service of a GitHub Enterprise Server.
+    #[arg(long)]
+    gha_cache_url: Option<String>,
+
+    /// The GHA cache API version to use, e.g. `5.1-preview.1`.
+    ///
+    /// By default, the newest version the server accepts is negotiated.
+    #[arg(long)]
+    gha_api_version: Option<String>,
+
+    /// The upstream cache.
+    ///
+    /// Requests for unknown NARs are redirected to this cache
+    /// instead.
+    #[arg(long)]
+    upstream: Option<String>,
+
+    /// Diagnostic endpoint to send diagnostics and performance data.
+    ///
+    /// Set it to an empty string to disable reporting.
+    /// See the README for details.
+    #[arg(
+        long,
+        default_value = "https://install.determinate.systems/magic-nix-cache/perf"
+    )]
+    diagnostic_endpoint: String,
+
+    /// How long, in seconds, to remember narinfo locations before asking the backend again.
+    ///
+    /// Set it to 0 to disable the narinfo cache.
+    #[arg(long, default_value_t = 300)]
+    narinfo_cache_ttl: u64,
+
+    /// How long, in seconds, an expired narinfo location may still be served
+    /// while it's refreshed in the background.
+    #[arg(long, default_value_t = 3600)]
+    narinfo_cache_stale: u64,
+
+    /// Where to persist the narinfo cache between runs.
+    #[arg(long)]
+    narinfo_cache_file: Option<PathBuf>,
+
+    /// The FlakeHub API server.
+    #[arg(long, default_value = "https://api.flakehub.com")]
+    flakehub_api_server: reqwest::Url,
+
+    /// The path of the `netrc` file that contains the FlakeHub JWT token.
+    #[arg(long)]
+    flakehub_api_server_netrc: Option<PathBuf>,
+
+    /// The FlakeHub binary cache server.
+    #[arg(long, default_value = "https://cache.flakehub.com")]
+    flakehub_cache_server: reqwest::Url,
+
+    /// The FlakeHub project whose cache to use, e.g. `my-org/my-flake`.
+    ///
+    /// Defaults to the project FlakeHub associates with the credentials.
+    #[arg(long, visible_alias = "flakehub-project")]
+    flakehub_flake_name: Option<String>,
+
+    /// The FlakeHub cache to use, skipping the project lookup altogether.
+    #[arg(long)]
+    flakehub_cache_name: Option<String>,
+
+    /// File containing an OIDC token for `api.flakehub.com`, for authenticating to
+    /// FlakeHub outside of GitHub Actions, e.g. from a GitLab CI `id_tokens` entry.
+    #[arg(long)]
+    flakehub_oidc_token_file: Option<PathBuf>,
+
+    /// Substitute from FlakeHub through the daemon, which adds the credentials to
+    /// each request, instead of giving Nix a netrc file for the cache server.
+    #[arg(long, default_value_t = false)]
+    flakehub_proxy: bool,
+
+    /// The Nix store to push from, e.g. `/home/runner/nix` or `local?root=/mnt/chroot`.
+    ///
+    /// Defaults to the store Nix itself would use.
+    #[arg(long)]
+    store: Option<String>,
+
+    /// The location of `nix.conf`.
+    #[arg(long, default_value_os_t = default_nix_conf())]
+    nix_conf: PathBuf,
+
+    /// Whether to use the GHA cache.
+    #[arg(long)]
+    use_gha_cache: bool,
+
+    /// Where to persist the index of paths uploaded to the GHA cache.
+    ///
+    /// The index is used to notice paths that GitHub evicted and upload them again.
+    #[arg(long, default_value_os_t = default_gha_index_file())]
+    gha_index_file: PathBuf,
+
+    /// Carry the index of uploaded paths over to later jobs by storing it in the
+    /// GitHub Actions Cache, for ephemeral runners.
+    #[arg(long, default_value_t = false)]
+    persist_state: bool,
+
+    /// Remember uploaded paths in this SQLite database, and don't upload them again,
+    /// for persistent runners. Can be shared between runners on the same machine.
+    #[arg(long)]
+    dedupe_index: Option<PathBuf>,
+
+    /// Where to record the paths that are still to be uploaded, so that the next
+    /// daemon on a persistent runner uploads them if this one is killed first.
+    #[arg(long, default_value_os_t = default_pending_uploads_file())]
+    pending_uploads_file: PathBuf,
+
+    /// Whether to use the FlakeHub binary cache.
+    #[arg(long)]
+    use_flakehub: Option<Option<FlakeHubArg>>,
+
+    /// URL to which to post startup notification.
+    #[arg(long)]
+    startup_notification_url: Option<reqwest::Url>,
+
+    /// File to write to when indicating startup.
+    #[arg(long)]
+    startup_notification_file: Option<PathBuf>,
+
+    /// Push a tiny store path and read it back at startup, failing if that doesn't work.
+    #[arg(long, default_value_t = false, conflicts_with = "dry_run")]
+    startup_selftest: bool,
+
+    /// Go through the motions of uploading, logging and reporting what would be
+    /// pushed, without actually pushing anything.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Watch the store for new paths instead of installing a post-build hook.
+    #[arg(long, default_value_t = false)]
+    watch_store: bool,
+
+    /// Run as a long-lived daemon on a developer machine rather than in CI.
+    /// `--config-file` defaults to `magic-nix-cache/args` in the user's config
+    /// directory, and `--watch-store` is implied.
+    #[arg(long, default_value_t = false)]
+    dev_mode: bool,
+
+    /// In dev mode, how often to save the daemon's state, in seconds.
+    #[arg(long, default_value_t = 300)]
+    flush_interval: u64,
+
+    /// Read more arguments from this file, separated by whitespace, ahead of
+    /// those on the command line. Lines starting with `#` are ignored.
+    ///
+    /// The file is read again on SIGHUP and `POST /api/reload`.
+    #[arg(long)]
+    config_file: Option<PathBuf>,
+
+    /// Which log messages to show, in `RUST_LOG` syntax, e.g. `info,magic_nix_cache=debug`.
+    #[arg(long)]
+    log_filter: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Copy enqueued paths that are missing from the local store from this
+    /// remote store first, e.g. `ssh-ng://builder`. Can be given multiple times.
+    #[arg(long)]
+    fetch_from: Vec<String>,
+
+    /// Also push the source and inputs of this flake when the workflow finishes.
+    /// Without a value, the flake in the current directory.
+    #[arg(long, num_args = 0..=1, default_missing_value = ".")]
+    push_flake_inputs: Option<String>,
+
+    /// Whether or not to diff the store before and after Magic Nix Cache runs
+    #[arg(long, default_value_t = false)]
+    diff_store: bool,
+
+    /// Serve requests with an `X-Magic-Nix-Cache-Scope` header from a separate
+    /// GHA cache namespace per scope, for jobs sharing a persistent runner.
+    #[arg(long, default_value_t = false)]
+    multi_tenant: bool,
+
+    /// Mount points to watch for free disk space.
+    #[arg(long = "disk-watchdog-path", default_values_os_t = default_disk_watchdog_paths())]
+    disk_watchdog_paths: Vec<PathBuf>,
+
+    /// Pause new uploads when a watched mount point has fewer than this many MiB free.
+    ///
+    /// Set it to 0 to disable the disk space watchdog.
+    #[arg(long, default_value = "1024", value_parser = parse_mib)]
+    min_free_disk_space: u64,
+
+    /// When the job will be killed, in seconds since the Unix epoch.
+    ///
+    /// As the deadline approaches, uploads that aren't expected to finish in time are skipped.
+    #[arg(long)]
+    job_deadline: Option<u64>,
+
+    /// How much compressed NAR data to upload to the GitHub Actions Cache at
+    /// most, in MiB.
+    #[arg(long, value_parser = parse_mib)]
+    upload_budget: Option<u64>,
+
+    /// Check uploads against this GitHub Actions Cache quota of the repository,
+    /// in MiB, usually 10240. What's in use is subtracted when `GITHUB_TOKEN` is set.
+    #[arg(long, value_parser = parse_mib)]
+    cache_quota: Option<u64>,
+
+    /// What to do with paths that are estimated not to fit in the upload
+    /// budget or the remaining quota.
+    #[arg(long, value_enum, default_value_t = budget::OverBudget::Warn)]
+    over_budget: budget::OverBudget,
+
+    /// How many store paths to compress in parallel. Defaults to the number of CPUs.
+    #[arg(long, default_value_t = default_compression_workers())]
+    compression_workers: usize,
+
+    /// How many compressed store paths to upload to the GHA cache in parallel.
+    #[arg(long, visible_alias = "upload-workers", default_value_t = 4)]
+    gha_upload_jobs: usize,
+
+    /// How many store paths to push to FlakeHub in parallel.
+    #[arg(long, default_value_t = 5)]
+    flakehub_upload_jobs: usize,
+
+    /// The most uploads to run at once, across all backends. Unlimited by default.
+    #[arg(long)]
+    max_concurrent_uploads: Option<usize>,
+
+    /// How many store paths may wait for upload to the GHA cache before `--enqueue-overflow` applies.
+    #[arg(long, default_value_t = 10_000)]
+    max_queued_uploads: usize,
+
+    /// What to do with newly enqueued paths when the upload queue is full.
+    #[arg(long, value_enum, default_value_t = EnqueueOverflow::Block)]
+    enqueue_overflow: EnqueueOverflow,
+
+    /// How many seconds compressing or uploading a store path may take before it's cancelled.
+    /// 0 means no limit.
+    #[arg(long, default_value_t = 3600)]
+    upload_timeout: u64,
+
+    /// After how many failed uploads a store path isn't tried again for the rest of the job.
+    #[arg(long, default_value_t = 3)]
+    quarantine_after: u32,
+
+    /// Upload deltas against earlier versions of store paths, and rebuild NARs
+    /// from deltas when an earlier version is in the local store.
+    #[arg(long, default_value_t = false)]
+    delta_substitution: bool,
+
+    /// Upload the build log of each derivation along with its outputs.
+    #[arg(long, default_value_t = false)]
+    upload_build_logs: bool,
+
+    /// Upload a listing of the contents of each store path, for tools like nix-index.
+    #[arg(long, default_value_t = false)]
+    upload_listings: bool,
+
+    /// Bundle NARs that compress to at most this many KiB into pack files,
+    /// instead of giving each its own GitHub Actions Cache entry.
+    #[arg(long, value_name = "KIB", value_parser = parse_kib)]
+    pack_threshold: Option<u64>,
+
+    /// Sign uploaded narinfos with the secret key in this file.
+    ///
+    /// Can be given multiple times to rotate keys: the first key is the primary one,
+    /// and the others add secondary signatures.
+    #[arg(long = "signing-key-file")]
+    signing_key_files: Vec<PathBuf>,
+
+    /// Download each path again right after uploading it, and check that it arrived intact.
+    #[arg(long, default_value_t = false)]
+    verify_after_push: bool,
+
+    /// Trust the CA certificates in this PEM file, e.g. for a proxy that intercepts TLS.
+    ///
+    /// Can be given multiple times. `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` are honored too.
+    #[arg(long = "extra-ca-cert")]
+    extra_ca_certs: Vec<PathBuf>,
+
+    /// Appended to the user agent of all requests, after the repository and workflow run,
+    /// to tell the traffic of a workflow apart in the logs of the backends.
+    #[arg(long)]
+    user_agent_suffix: Option<String>,
+
+    /// Send traffic to cache servers through this proxy: `socks5://host:port`,
+    /// `socks5h://host:port`, `http://host:port`, or `ssh://user@bastion` to
+    /// have the daemon open an SSH tunnel itself.
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Substituter priorities per backend, e.g. `gha=50,flakehub=30`.
+    ///
+    /// Nix tries substituters with lower values first; cache.nixos.org has priority 40.
+    #[arg(long, value_delimiter = ',', value_parser = parse_substituter_priority)]
+    substituter_priority: Vec<(Backend, u32)>,
+
+    /// Substitute from these backends through the daemon, in this order, e.g. `flakehub,gha`.
+    ///
+    /// Nix is then only given the daemon as a substituter, and the daemon moves on to the
+    /// next backend when FlakeHub is unavailable.
+    #[arg(long, value_delimiter = ',', value_parser = parse_backend)]
+    substitution_order: Vec<Backend>,
+
+    /// Only push paths built for these systems, e.g. `x86_64-linux,aarch64-darwin`.
+    ///
+    /// Defaults to pushing paths for every system.
+    #[arg(long, value_delimiter = ',')]
+    push_systems: Vec<String>,
+
+    /// Don't push the outputs of fixed-output derivations, such as fetched sources.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    skip_fixed_output: bool,
+
+    /// Push fixed outputs whose name matches this pattern anyway, e.g. `node-modules-*`.
+    /// Can be given multiple times.
+    #[arg(long)]
+    push_fixed_output: Vec<String>,
+
+    /// Scan the contents of paths for credentials before pushing them.
+    #[arg(long, value_enum, default_value_t = secrets::SecretScan::Off)]
+    secret_scan: secrets::SecretScan,
+
+    /// An additional secret scan rule, as `NAME=REGEX`.
+    /// Can be given multiple times.
+    #[arg(long, value_parser = secrets::Pattern::parse)]
+    secret_scan_pattern: Vec<secrets::Pattern>,
+
+    /// A JSON file with rules that decide per path and backend whether it may be
+    /// substituted and pushed (see README.md).
+    #[arg(long)]
+    policy_file: Option<PathBuf>,
+
+    /// Log which backend answered each narinfo request, and how quickly, and
+    /// summarize the answers at workflow-finish.
+    #[arg(long, default_value_t = false)]
+    log_substitutions: bool,
+
+    /// Run this command, with the store path as its argument, for every path
+    /// substituted through the daemon.
+    #[arg(long)]
+    on_substitute_exec: Option<String>,
+
+    /// Run this command, with the store path as its argument, for every path pushed.
+    #[arg(long)]
+    on_push_exec: Option<String>,
+
+    /// How many hook commands may run in parallel.
+    #[arg(long, default_value_t = 4)]
+    hook_workers: usize,
+}
+
+/// A backend that Nix substitutes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Gha,
+    FlakeHub,
+}
+
+fn parse_substituter_priority(s: &str) -> std::result::Result<(Backend, u32), String> {
+    let (backend, priority) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected BACKEND=PRIORITY, got '{s}'"))?;
+
+    let backend = parse_backend(backend)?;
+
+    let priority = priority
+        .parse()
+        .map_err(|e| format!("invalid priority '{priority}': {e}"))?;
+
+    Ok((backend, priority))
+}
+
+/// Parses a number of KiB, returning it in bytes.
+fn parse_kib(s: &str) -> std::result::Result<u64, String> {
+    parse_size(s, "KiB", 1024)
+}
+
+/// Parses a number of MiB, returning it in bytes.
+fn parse_mib(s: &str) -> std::result::Result<u64, String> {
+    parse_size(s, "MiB", 1024 * 1024)
+}
+
+fn parse_size(s: &str, unit: &str, unit_bytes: u64) -> std::result::Result<u64, String> {
+    let count: u64 = s
+        .parse()
+        .map_err(|e| format!("invalid number of {unit} '{s}': {e}"))?;
+
+    count
+        .checked_mul(unit_bytes)
+        .ok_or_else(|| format!("{count} {unit} is too large"))
+}
+
+fn parse_backend(s: &str) -> std::result::Result<Backend, String> {
+    match s {
+        "gha" => Ok(Backend::Gha),
+        "flakehub" => Ok(Backend::FlakeHub),
+        _ => Err(format!("unknown backend '{s}', expected gha or flakehub")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub(crate) enum FlakeHubArg {
+    NoPreference,
+    Enabled,
+    Disabled,
+}
+
+impl From<Option<Option<FlakeHubArg>>> for FlakeHubArg {
+    fn from(b: Option<Option<FlakeHubArg>>) -> Self {
+        match b {
+            None => FlakeHubArg::NoPreference,
+            Some(None) => FlakeHubArg::Enabled,
+            Some(Some(v)) => v,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub(crate) enum Dnixd {
+    Available,
+    Missing,
+}
+
+impl From<bool> for Dnixd {
+    fn from(b: bool) -> Self {
+        if b {
+            Dnixd::Available
+        } else {
+            Dnixd::Missing
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Install a systemd user unit (Linux) or launchd agent (macOS) that runs
+    /// the daemon in dev mode.
+    InstallService {
+        /// Print the service definition instead of installing it.
+        #[arg(long, default_value_t = false)]
+        print: bool,
+    },
+}
+
+impl Args {
+    fn validate(&self, environment: env::Environment) -> Result<(), error::Error> {
+        if self.dev_mode && self.use_gha_cache {
+            return Err(error::Error::Config(String::from(
+                "the GitHub Actions Cache is not available in --dev-mode",
+            )));
+        }
+
+        if self.flush_interval == 0 {
+            return Err(error::Error::Config(String::from(
+                "--flush-interval must be at least 1 second",
+            )));
+        }
+
+        if environment.is_gitlab_ci() && self.use_gha_cache {
+            return Err(error::Error::Config(String::from(
+                "the --use-gha-cache flag should not be applied in GitLab CI",
+            )));
+        }
+
+        if environment.is_gitlab_ci() && self.flakehub_preference() != FlakeHubArg::Enabled {
+            return Err(error::Error::Config(String::from(
+                "you must set --use-flakehub in GitLab CI",
+            )));
+        }
+
+        if self.listen.is_empty() {
+            return Err(error::Error::Config(String::from(
+                "--listen needs at least one address",
+            )));
+        }
+
+        if self.flakehub_oidc_token_file.is_some() && self.flakehub_api_server_netrc.is_some() {
+            return Err(error::Error::Config(String::from(
+                "--flakehub-oidc-token-file and --flakehub-api-server-netrc are mutually exclusive",
+            )));
+        }
+
+        if self.compression_workers == 0
+            || self.gha_upload_jobs == 0
+            || self.flakehub_upload_jobs == 0
+            || self.max_concurrent_uploads == Some(0)
+            || self.max_queued_uploads == 0
+            || self.quarantine_after == 0
+            || self.hook_workers == 0
+        {
+            return Err(error::Error::Config(String::from(
+                "--compression-workers, --gha-upload-jobs, --flakehub-upload-jobs, --max-concurrent-uploads, --max-queued-uploads, --quarantine-after and --hook-workers must be at least 1",
+            )));
+        }
+
+        if !self.substitution_order.is_empty() {
+            if !self.substitution_order.contains(&Backend::Gha) {
+                return Err(error::Error::Config(String::from(
+                    "--substitution-order must include gha",
+                )));
+            }
+
+            if self.substitution_order.len()
+                != self.substitution_order.iter().collect::<HashSet<_>>().len()
+            {
+                return Err(error::Error::Config(String::from(
+                    "--substitution-order must not name a backend twice",
+                )));
+            }
+
+            if !self.use_gha_cache {
+                return Err(error::Error::Config(String::from(
+                    "--substitution-order requires --use-gha-cache",
+                )));
+            }
+        }
+
+        if self.multi_tenant && !self.use_gha_cache {
+            return Err(error::Error::Config(String::from(
+                "--multi-tenant requires --use-gha-cache",
+            )));
+        }
+
+        if self.remote_listen.is_some() && self.remote_token_file.is_none() {
+            return Err(error::Error::Config(String::from(
+                "--remote-listen requires --remote-token-file",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Where to read more arguments from, if anywhere.
+    fn config_file(&self) -> Option<PathBuf> {
+        self.config_file
+            .clone()
+            .or_else(|| self.dev_mode.then(dev::config_file).flatten())
+    }
+
+    fn push_filter(&self) -> filter::PushFilter {
+        filter::PushFilter {
+            systems: self.push_systems.clone(),
+            skip_fixed_output: self.skip_fixed_output,
+            fixed_output_patterns: self.push_fixed_output.clone(),
+        }
+    }
+
+    /// How many uploads to a backend may run in parallel.
+    fn upload_jobs(&self, backend: Backend) -> usize {
+        let jobs = match backend {
+            Backend::Gha => self.gha_upload_jobs,
+            Backend::FlakeHub => self.flakehub_upload_jobs,
+        };
+
+        self.max_concurrent_uploads
+            .map_or(jobs, |max_concurrent_uploads| {
+                jobs.min(max_concurrent_uploads)
+            })
+    }
+
+    /// The priority the user asked for for `backend`, if any.
+    fn substituter_priority(&self, backend: Backend) -> Option<u32> {
+        self.substituter_priority
+            .iter()
+            .rev()
+            .find(|(b, _)| *b == backend)
+            .map(|(_, priority)| *priority)
+    }
+
+    /// The address that Nix and the post-build hook talk to.
+    fn primary_listen(&self) -> SocketAddr {
+        self.listen[0]
+    }
+
+    fn flakehub_preference(&self) -> FlakeHubArg {
+        self.use_flakehub.into()
+    }
+}
+
+fn default_nix_conf() -> PathBuf {
+    xdg::BaseDirectories::new()
+        .with_context(|| "identifying XDG base directories")
+        .expect(
+            "Could not identify your home directory. Try setting the HOME environment variable.",
+        )
+        .get_config_file("nix/nix.conf")
+}
+
+fn default_disk_watchdog_paths() -> Vec<PathBuf> {
+    vec![PathBuf::from("/nix"), std::env::temp_dir()]
+}
+
+fn default_compression_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+fn default_gha_index_file() -> PathBuf {
+    xdg::BaseDirectories::with_prefix("magic-nix-cache")
+        .map(|dirs| dirs.get_state_home())
+        .unwrap_or_else(|_| std::env::temp_dir().join("magic-nix-cache"))
+        .join("gha-index.json")
+}
+
+fn default_pending_uploads_file() -> PathBuf {
+    xdg::BaseDirectories::with_prefix("magic-nix-cache")
+        .map(|dirs| dirs.get_state_home())
+        .unwrap_or_else(|_| std::env::temp_dir().join("magic-nix-cache"))
+        .join("pending-uploads.json")
+}
+
+/// The global server state.
+struct StateInner {
+    /// State for uploading to the GHA cache.
+    gha_cache: Option<gha::GhaCache>,
+
+    /// The upstream cache.
+    upstream: Option<String>,
+
+    /// The sender half of the oneshot channel to trigger a shutdown.
+    shutdown_sender: Mutex<Option<oneshot::Sender<()>>>,
+
+    /// Where recently requested narinfos live.
+    narinfo_cache: narinfo_cache::NarinfoCache,
+
+    /// Set of store path hashes that are not present in GHAC.
+    narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+
+    /// Metrics for sending to perf at shutdown
+    metrics: Arc<telemetry::TelemetryReport>,
+
+    /// Where the backends publish the progress of uploads.
+    events: events::EventBus,
+
+    /// Connection to the local Nix store.
+    store: Arc<NixStore>,
+
+    /// FlakeHub cache state.
+    flakehub_state: RwLock<Option<flakehub::State>>,
+
+    /// Where the backends stand, and the retries of those that failed to start.
+    backends: backends::Backends,
+
+    /// Pauses uploads when the disk runs low.
+    disk_watchdog: watchdog::DiskSpaceWatchdog,
+
+    /// Whether to rebuild NARs from deltas against paths in the local store.
+    delta_substitution: bool,
+
+    /// The backends to substitute from, and which of them are currently down.
+    fallback: fallback::Fallback,
+
+    /// The priority advertised in `nix-cache-info`.
+    substituter_priority: u32,
+
+    /// Decides which paths get pushed. Replaced on reload.
+    push_filter: Arc<std::sync::RwLock<filter::PushFilter>>,
+
+    /// Keeps paths that look like they contain credentials out of the cache.
+    secret_scanner: Arc<secrets::SecretScanner>,
+
+    /// Decides per path and backend what may be substituted and pushed.
+    policy: Arc<policy::Policy>,
+
+    /// Uploaded NARs waiting for their narinfo, while the policy checks pushes.
+    staged_nars: binary_cache::StagedNars,
+
+    /// Records who answered each narinfo request, with `--log-substitutions`.
+    substitutions: Arc<substitutions::SubstitutionLog>,
+
+    /// Where reloads read the configuration from.
+    reloader: reload::Reloader,
+
+    /// The commands run on substituted and pushed paths, if any.
+    hooks: Option<Arc<hooks::Hooks>>,
+
+    /// Whether to only log what would be pushed.
+    dry_run: bool,
+
+    /// Copies missing paths from remote stores before pushing them.
+    fetcher: builders::Fetcher,
+
+    /// The flake whose source and inputs to push when the workflow finishes.
+    push_flake_inputs: Option<String>,
+
+    /// The token required by the `/api/*` routes, if any.
+    api_token: Option<String>,
+
+    /// Where all of tracing will log to when GitHub Actions is run in debug mode
+    logfile: Option<PathBuf>,
+
+    /// The paths in the Nix store when Magic Nix Cache started, if store diffing is enabled.
+    original_paths: Option<Mutex<HashSet<PathBuf>>>,
+
+    /// The paths substituted from our own backends, if the store is watched.
+    substituted: Option<watch_store::Substituted>,
+
+    /// The netrc written for `--flakehub-oidc-token-file`, removed at shutdown.
+    flakehub_oidc_netrc: std::sync::Mutex<Option<tempfile::TempPath>>,
+
+    /// The states of the scopes of other jobs, with `--multi-tenant`.
+    tenants: Option<tenants::Tenants>,
+}
+
+impl StateInner {
+    /// A state around the given store and caches, with every optional feature
+    /// turned off. Callers fill in the rest with struct update syntax.
+    fn new(
+        store: Arc<NixStore>,
+        metrics: Arc<telemetry::TelemetryReport>,
+        events: events::EventBus,
+        narinfo_cache: narinfo_cache::NarinfoCache,
+        narinfo_negative_cache: Arc<RwLock<HashSet<String>>>,
+        disk_watchdog: watchdog::DiskSpaceWatchdog,
+    ) -> Self {
+        Self {
+            gha_cache: None,
+            upstream: None,
+            shutdown_sender: Mutex::new(None),
+            narinfo_cache,
+            narinfo_negative_cache,
+            metrics,
+            events,
+            store,
+            flakehub_state: RwLock::new(None),
+            backends: Default::default(),
+            disk_watchdog,
+            delta_substitution: false,
+            fallback: Default::default(),
+            substituter_priority: 41,
+            push_filter: Default::default(),
+            secret_scanner: Default::default(),
+            policy: Default::default(),
+            staged_nars: Default::default(),
+            substitutions: Default::default(),
+            reloader: Default::default(),
+            hooks: None,
+            dry_run: false,
+            fetcher: Default::default(),
+            push_flake_inputs: None,
+            api_token: None,
+            logfile: None,
+            original_paths: None,
+            substituted: None,
+            flakehub_oidc_netrc: Default::default(),
+            tenants: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum FlakeHubAuthSource {
+    DeterminateNixd,
+    Netrc(PathBuf),
+}
+
+impl FlakeHubAuthSource {
+    pub(crate) fn as_path_buf(&self) -> PathBuf {
+        match &self {
+            Self::Netrc(path) => path.clone(),
+            Self::DeterminateNixd => {
+                let mut path = PathBuf::from(DETERMINATE_STATE_DIR);
+                path.push("netrc");
+
+                path
+            }
+        }
+    }
+}
+
+async fn main_cli() -> Result<()> {
+    let guard = init_logging()?;
+    let _tracing_guard = guard.appender_guard;
+
+    let mut args = Args::parse();
+    if let Some(Command::InstallService { print }) = args.command {
+        dev::install_service(print)?;
+        return Ok(());
+    }
+    let config_file = args.config_file();
+    if let Some(config_file) = &config_file {
+        args = Args::parse_from(config::args_with_file(config_file)?);
+    }
+    if args.log_filter.is_some() {
+        reload::set_log_filter(args.log_filter.as_deref())?;
+    }
+    let environment = env::Environment::determine();
+    tracing::debug!("Running in {}", environment.to_string());
+    args.validate(environment)?;
+
+    if let Some(store) = &args.store {
+        if std::env::var_os("NIX_REMOTE").as_deref() != Some(std::ffi::OsStr::new(store)) {
+            return Err(reexec_with_store(store));
+        }
+    }
+
+    let tunnel = match &args.proxy {
+        Some(proxy) => Some(tunnel::start(proxy).await?),
+        None => None,
+    };
+    http_client::init(
+        &args.extra_ca_certs,
+        args.user_agent_suffix.as_deref(),
+        tunnel.as_ref().map(|tunnel| tunnel.url.as_str()),
+    )?;
+
+    let policy = match &args.policy_file {
+        Some(policy_file) => policy::Policy::load(policy_file)?,
+        None => policy::Policy::default(),
+    };
+
+    let metrics = Arc::new(telemetry::TelemetryReport::new());
+    let events = events::EventBus::default();
+
+    let dnixd_uds_socket_dir: &Path = Path::new(&DETERMINATE_STATE_DIR);
+    let dnixd_uds_socket_path = dnixd_uds_socket_dir.join(DETERMINATE_NIXD_SOCKET_NAME);
+    let dnixd_available: Dnixd = dnixd_uds_socket_path.exists().into();
+
+    // NOTE: we expect this to point to a user nix.conf
+    // our settings always go to a file it includes, to be able to add the extra-substituter for github-actions cache
+    // but we don't write to it for initializing flakehub_cache unless dnixd is unavailable
+    let mut nix_conf = nix_conf::NixConf::new(args.nix_conf.clone());
+
+    // always enable fallback, first
+    nix_conf
+        .write_all(b"fallback = true\n")
+        .with_context(|| "Setting fallback in nix.conf")?;
+
+    let store = Arc::new(NixStore::connect()?);
+
+    let narinfo_negative_cache = Arc::new(RwLock::new(HashSet::new()));
+
+    let disk_watchdog = watchdog::DiskSpaceWatchdog::spawn(
+        args.disk_watchdog_paths.clone(),
+        args.min_free_disk_space,
+        metrics.clone(),
+    );
+
+    let flakehub_oidc_netrc = match &args.flakehub_oidc_token_file {
+        Some(token_file) => {
+            Some(flakehub::write_oidc_netrc(token_file, &args.flakehub_api_server).await?)
+        }
+        None => None,
+    };
+    let flakehub_api_server_netrc = match &flakehub_oidc_netrc {
+        Some(netrc) => Some(netrc.to_path_buf()),
+        None => args.flakehub_api_server_netrc.clone(),
+    };
+
+    let flakehub_auth_method: Option<FlakeHubAuthSource> = match (
+        args.flakehub_preference(),
+        &flakehub_api_server_netrc,
+        dnixd_available,
+    ) {
+        // User has explicitly pyassed --use-flakehub=disabled, so just straight up don't
+        (FlakeHubArg::Disabled, _, _) => {
+            tracing::info!("Disabling FlakeHub cache.");
+            None
+        }
+
+        // User has no preference, did not pass a netrc, and determinate-nixd is not available
+        (FlakeHubArg::NoPreference, None, Dnixd::Missing) => None,
+
+        // Use it when determinate-nixd is available, and let the user know what's going on
+        (pref, user_netrc_path, Dnixd::Available) => {
+            if pref == FlakeHubArg::NoPreference {
+                tracing::info!("Enabling FlakeHub cache because determinate-nixd is available.");
+            }
+
+            if user_netrc_path.is_some() {
+                tracing::info!("Ignoring the user-specified --flakehub-api-server-netrc, in favor of the determinate-nixd netrc");
+            }
+
+            Some(FlakeHubAuthSource::DeterminateNixd)
+        }
+
+        // When determinate-nixd is not available, but the user specified a netrc
+        (_, Some(path), Dnixd::Missing) => Some(FlakeHubAuthSource::Netrc(path.to_owned())),
+
+        // User explicitly turned on flakehub cache, but we have no netrc and determinate-nixd is not present
+        (FlakeHubArg::Enabled, None, Dnixd::Missing) => {
+            return Err(anyhow!(
+                "--flakehub-api-server-netrc or --flakehub-oidc-token-file is required when determinate-nixd is unavailable"
+            ));
+        }
+    };
+
+    // Shared by the backends, so that they take turns under the one ceiling.
+    let upload_limit = args.max_concurrent_uploads.map(|max_concurrent_uploads| {
+        Arc::new(tokio::sync::Semaphore::new(max_concurrent_uploads))
+    });
+
+    let secret_scanner = Arc::new(secrets::SecretScanner::new(
+        args.secret_scan,
+        args.secret_scan_pattern
+            .iter()
+            .cloned()
+            .map(|pattern| Box::new(pattern) as Box<dyn secrets::Rule>)
+            .collect(),
+    ));
+
+    let flakehub_config = flakehub_auth_method.map(|auth_method| flakehub::Config {
+        environment,
+        api_server: args.flakehub_api_server.clone(),
+        cache_server: args.flakehub_cache_server.clone(),
+        flake_name: args.flakehub_flake_name.clone(),
+        cache_name: args.flakehub_cache_name.clone(),
+        store: store.clone(),
+        auth_method,
+        upload_jobs: args.upload_jobs(Backend::FlakeHub),
+        upload_limit: upload_limit.clone(),
+        secret_scanner: secret_scanner.clone(),
+        metrics: metrics.clone(),
+        events: events.clone(),
+        proxy: args.flakehub_proxy || args.substitution_order.contains(&Backend::FlakeHub),
+    });
+
+    if flakehub_config.is_none() {
+        tracing::info!("FlakeHub cache is disabled.");
+    }
+
+    let diagnostic_endpoint = match args.diagnostic_endpoint.as_str() {
+        "" => {
+            tracing::info!("Diagnostics disabled.");
+            None
+        }
+        url => Some(url),
+    };
+
+    let backends = backends::Backends::default();
+
+    if flakehub_config.is_some() {
+        backends.set(Backend::FlakeHub, backends::BackendStatus::Starting);
+    }
+
+    if args.use_gha_cache {
+        backends.set(Backend::Gha, backends::BackendStatus::Starting);
+    }
+
+    let start_gha = async {
+        tracing::info!("Loading credentials from environment");
+
+        let mut credentials = Credentials::load_from_env()
+            .with_context(|| "Failed to load credentials from environment (see README.md)")?;
+
+        if let Some(cache_url) = &args.gha_cache_url {
+            credentials = credentials.with_cache_url(cache_url.clone());
+        }
+
+        if let Some(api_version) = &args.gha_api_version {
+            credentials = credentials.with_api_version(api_version.clone());
+        }
+
+        if let Ok(server_url) = std::env::var("GITHUB_SERVER_URL") {
+            if server_url.trim_end_matches('/') != "https://github.com" {
+                tracing::info!("Using the cache of GitHub Enterprise Server {}", server_url);
+            }
+        }
+
+        let index = Arc::new(index::UploadIndex::load(Some(args.gha_index_file.clone())).await);
+
+        let signing_keys = signing::SigningKeys::load(&args.signing_key_files)
+            .await
+            .with_context(|| "Failed to load the signing keys")?
+            .map(Arc::new);
+
+        let dedupe_index = args
+            .dedupe_index
+            .as_deref()
+            .map(dedupe::DedupeIndex::open)
+            .transpose()
+            .with_context(|| "Failed to open the dedupe index")?
+            .map(Arc::new);
+
+        let remaining_quota = match args.cache_quota {
+            Some(cache_quota) => Some(budget::remaining_quota(cache_quota).await),
+            None => None,
+        };
+        let budget_limit = [remaining_quota, args.upload_budget]
+            .into_iter()
+            .flatten()
+            .min();
+
+        let pipeline = gha::Pipeline {
+            compression_workers: args.compression_workers,
+            upload_jobs: args.upload_jobs(Backend::Gha),
+            upload_limit,
+            upload_deltas: args.delta_substitution,
+            upload_build_logs: args.upload_build_logs,
+            upload_listings: args.upload_listings,
+            pack_threshold: args.pack_threshold,
+            signing_keys,
+            verify_after_push: args.verify_after_push,
+            dry_run: args.dry_run,
+            max_queued_uploads: args.max_queued_uploads,
+            enqueue_overflow: args.enqueue_overflow,
+            upload_timeout: (args.upload_timeout > 0)
+                .then(|| std::time::Duration::from_secs(args.upload_timeout)),
+            quarantine_after: args.quarantine_after,
+            dedupe_index,
+            pending_uploads_file: Some(args.pending_uploads_file.clone()),
+            budget: budget_limit
+                .map(|limit| Arc::new(budget::UploadBudget::new(limit, args.over_budget))),
+            secret_scanner: secret_scanner.clone(),
+        };
+
+        let namespace = gha::CacheNamespace {
+            cache_version: args.cache_version.clone(),
+            salt: args.cache_version_salt.clone(),
+            scope: None,
+            key_prefix: args.cache_key_prefix.clone(),
+        };
+
+        let gha_cache = gha::GhaCache::new(
+            credentials.clone(),
+            &namespace,
+            store.clone(),
+            metrics.clone(),
+            events.clone(),
+            narinfo_negative_cache.clone(),
+            index,
+            disk_watchdog.clone(),
+            args.job_deadline
+                .map(|secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)),
+            pipeline.clone(),
+        )
+        .with_context(|| "Failed to initialize GitHub Actions Cache API")?;
+
+        if args.persist_state {
+            gha_cache.restore_state().await;
+        }
+
+        let pending_uploads = gha_cache.pending_uploads().await;
+
+        let public_keys = pipeline
+            .signing_keys
+            .as_ref()
+            .map(|signing_keys| signing_keys.public_keys().join(" "));
+
+        let tenants = args.multi_tenant.then(|| {
+            tracing::info!("Serving scoped requests from separate namespaces.");
+            tenants::Tenants::new(
+                credentials,
+                namespace,
+                // The journal of pending uploads belongs to the main namespace.
+                gha::Pipeline {
+                    pending_uploads_file: None,
+                    ..pipeline
+                },
+                std::time::Duration::from_secs(args.narinfo_cache_ttl),
+                std::time::Duration::from_secs(args.narinfo_cache_stale),
+                diagnostic_endpoint.map(ToOwned::to_owned),
+            )
+        });
+
+        anyhow::Ok((gha_cache, tenants, public_keys, pending_uploads))
+    };
+
+    // Neither backend waits for the other to start, and neither failing to
+    // start takes the daemon down while the other is up.
+    let (flakehub_result, gha_result) = tokio::join!(
+        async {
+            if let Some(config) = &flakehub_config {
+                Some(config.init().await)
+            } else {
+                None
+            }
+        },
+        async {
+            if args.use_gha_cache {
+                Some(start_gha.await)
+            } else {
+                None
+            }
+        },
+    );
+
+    let flakehub_state = match flakehub_result {
+        Some(Ok(state)) => {
+            let auth_method = &flakehub_config
+                .as_ref()
+                .expect("FlakeHub started without a config")
+                .auth_method;
+            let flakehub_cache_server = &args.flakehub_cache_server;
+            let flakehub_priority = args.substituter_priority(Backend::FlakeHub);
+
+            if args.substitution_order.contains(&Backend::FlakeHub) {
+                tracing::info!("Substituting from FlakeHub through the daemon.");
+            } else if args.flakehub_proxy && matches!(auth_method, FlakeHubAuthSource::Netrc(_)) {
+                nix_conf
+                    .write_all(
+                        format!(
+                            "extra-substituters = http://{}/flakehub?trusted=1{}\n",
+                            args.primary_listen(),
+                            flakehub_priority
+                                .map(|p| format!("&priority={p}"))
+                                .unwrap_or_default(),
+                        )
+                        .as_bytes(),
+                    )
+                    .with_context(|| "Writing to nix.conf")?;
+            } else if let FlakeHubAuthSource::Netrc(ref path) = auth_method {
+                nix_conf
+                    .write_all(
+                        format!(
+                            "extra-substituters = {}?trusted=1{}\nnetrc-file = {}\n",
+                            &flakehub_cache_server,
+                            flakehub_priority
+                                .map(|p| format!("&priority={p}"))
+                                .unwrap_or_default(),
+                            path.display()
+                        )
+                        .as_bytes(),
+                    )
+                    .with_context(|| "Writing to nix.conf")?;
+            }
+
+            backends.set(Backend::FlakeHub, backends::BackendStatus::Ready);
+            tracing::info!("FlakeHub cache is enabled.");
+            Some(state)
+        }
+        Some(Err(err)) => {
+            tracing::debug!("FlakeHub cache initialization failed: {}", err);
+            gha_annotations::report(&err);
+            backends.set(
+                Backend::FlakeHub,
+                backends::BackendStatus::Failed {
+                    error: err.to_string(),
+                    attempts: 1,
+                    retrying: true,
+                },
+            );
+            None
+        }
+        None => None,
+    };
+
+    let (gha_cache, tenants, pending_uploads) = match gha_result {
+        Some(Ok((gha_cache, tenants, public_keys, pending_uploads))) => {
+            if let Some(public_keys) = public_keys {
+                nix_conf
+                    .write_all(format!("extra-trusted-public-keys = {public_keys}\n").as_bytes())
+                    .with_context(|| "Writing to nix.conf")?;
+            }
+
+            nix_conf
+                .write_all(format!("extra-substituters = http://{}?trusted=1&compression=zstd&parallel-compression=true&priority={}\n", args.primary_listen(), args.substituter_priority(Backend::Gha).unwrap_or(1)).as_bytes())
+                .with_context(|| "Writing to nix.conf")?;
+
+            backends.set(Backend::Gha, backends::BackendStatus::Ready);
+            tracing::info!("Native GitHub Action cache is enabled.");
+            (Some(gha_cache), tenants, pending_uploads)
+        }
+        Some(Err(err)) if flakehub_config.is_some() => {
+            tracing::error!(
+                "GitHub Actions Cache failed to start, continuing with FlakeHub: {:#}",
+                err
+            );
+            backends.set(
+                Backend::Gha,
+                backends::BackendStatus::Failed {
+                    error: format!("{err:#}"),
+                    attempts: 1,
+                    retrying: false,
+                },
+            );
+            (None, None, Vec::new())
+        }
+        Some(Err(err)) => return Err(err),
+        None => {
+            if environment.is_github_actions() {
+                tracing::info!("Native GitHub Action cache is disabled.");
+            }
+
+            (None, None, Vec::new())
+        }
+    };
+
+    let api_token = match &args.api_token_file {
+        Some(api_token_file) => Some(write_api_token(api_token_file)?),
+        None => None,
+    };
+
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
+    let narinfo_cache = narinfo_cache::NarinfoCache::load(
+        std::time::Duration::from_secs(args.narinfo_cache_ttl),
+        std::time::Duration::from_secs(args.narinfo_cache_stale),
+        args.narinfo_cache_file.clone(),
+    )
+    .await;
+
+    let hooks = hooks::Hooks::new(
+        args.on_substitute_exec.clone(),
+        args.on_push_exec.clone(),
+        args.hook_workers,
+        metrics.clone(),
+    );
+
+    let state = Arc::new(StateInner {
+        gha_cache,
+        upstream: args.upstream.clone(),
+        shutdown_sender: Mutex::new(Some(shutdown_sender)),
+        flakehub_state: RwLock::new(flakehub_state),
+        backends,
+        delta_substitution: args.delta_substitution,
+        fallback: fallback::Fallback::new(args.substitution_order.clone()),
+        substituter_priority: args.substituter_priority(Backend::Gha).unwrap_or(41),
+        push_filter: Arc::new(std::sync::RwLock::new(args.push_filter())),
+        secret_scanner,
+        policy: Arc::new(policy),
+        substitutions: Arc::new(substitutions::SubstitutionLog::new(args.log_substitutions)),
+        reloader: reload::Reloader {
+            config_file,
+            remote_access: Default::default(),
+        },
+        hooks,
+        dry_run: args.dry_run,
+        fetcher: builders::Fetcher::new(args.fetch_from.clone()),
+        push_flake_inputs: args.push_flake_inputs.clone(),
+        api_token,
+        logfile: guard.logfile,
+        original_paths: args.diff_store.then_some(Mutex::new(HashSet::new())),
+        substituted: (args.watch_store || args.dev_mode).then(Default::default),
+        flakehub_oidc_netrc: std::sync::Mutex::new(flakehub_oidc_netrc),
+        tenants,
+        ..StateInner::new(
+            store,
+            metrics,
+            events,
+            narinfo_cache,
+            narinfo_negative_cache,
+            disk_watchdog,
+        )
+    });
+
+    hooks::spawn(state.clone());
+
+    // Queued before anything else, so the leftovers go first. They take the same
+    // route as any other path, so filters and policies apply to them too.
+    if !pending_uploads.is_empty() {
+        if let Err(err) = api::enqueue_paths(&state, pending_uploads).await {
+            tracing::warn!("Failed to resume the uploads of an earlier run: {}", err);
+        }
+    }
+
+    if let Some(flakehub_config) = flakehub_config {
+        if state.flakehub_state.read().await.is_none() {
+            backends::retry_flakehub(state.clone(), 1, flakehub_config);
+        }
+    }
+
+    if args.startup_selftest {
+        selftest::run(&state).await?;
+    }
+
+    if args.dev_mode {
+        dev::spawn_flush(
+            state.clone(),
+            std::time::Duration::from_secs(args.flush_interval),
+        );
+    }
+
+    if args.watch_store || args.dev_mode {
+        tracing::info!("Watching the store for new paths.");
+        watch_store::spawn(state.clone()).await?;
+    } else if dnixd_available == Dnixd::Available {
+        tracing::info!("Subscribing to Determinate Nixd build events.");
+        crate::pbh::subscribe_uds_post_build_hook(dnixd_uds_socket_path, state.clone()).await?;
+    } else {
+        tracing::info!("Patching nix.conf to use a post-build-hook.");
+        crate::pbh::setup_legacy_post_build_hook(
+            &args.primary_listen(),
+            args.api_token_file.as_deref(),
+            &mut nix_conf,
+        )
+        .await?;
+    }
+
+    let mut nix_conf_rollback = nix_conf
+        .commit()
+        .with_context(|| "Writing the Nix settings")?;
+
+    let app = server::routes();
+
+    #[cfg(debug_assertions)]
+    let app = app
+        .layer(tower_http::trace::TraceLayer::new_for_http())
+        .layer(axum::middleware::from_fn(dump_api_stats));
+
+    let app = app.layer(Extension(state.clone()));
+
+    tracing::info!(
+        "Listening on {}",
+        args.listen
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    // Notify of startup via HTTP
+    if let Some(startup_notification_url) = args.startup_notification_url {
+        tracing::debug!("Startup notification via HTTP POST to {startup_notification_url}");
+
+        let response = http_client::local_client()
+            .post(startup_notification_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body("{}")
+            .send()
+            .await;
+        match response {
+            Ok(response) => {
+                if !response.status().is_success() {
+                    Err(anyhow!(
+                        "Startup notification returned an error: {}\n{}",
+                        response.status(),
+                        response
+                            .text()
+                            .await
+                            .unwrap_or_else(|_| "<no response text>".to_owned())
+                    ))?;
+                }
+            }
+            err @ Err(_) => {
+                err.with_context(|| "Startup notification failed")?;
+            }
+        }
+    }
+
+    // Notify of startup by writing "1" to the specified file
+    if let Some(startup_notification_file_path) = args.startup_notification_file {
+        let file_contents: &[u8] = b"1";
+
+        tracing::debug!("Startup notification via file at {startup_notification_file_path:?}");
+
+        if let Some(parent_dir) = startup_notification_file_path.parent() {
+            tokio::fs::create_dir_all(parent_dir)
+                .await
+                .with_context(|| {
+                    format!(
+                        "failed to create parent directory for startup notification file path: {}",
+                        startup_notification_file_path.display()
+                    )
+                })?;
+        }
+        let mut notification_file = File::create(&startup_notification_file_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to create startup notification file to path: {}",
+                    startup_notification_file_path.display()
+                )
+            })?;
+        notification_file
+            .write_all(file_contents)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to write startup notification file to path: {}",
+                    startup_notification_file_path.display()
+                )
+            })?;
+
+        tracing::debug!("Created startup notification file at {startup_notification_file_path:?}");
+    }
+
+    let remote_server = match (args.remote_listen, &args.remote_token_file) {
+        (Some(remote_listen), Some(remote_token_file)) => {
+            let remote_access = Arc::new(
+                remote::RemoteAccess::load(remote_token_file, args.remote_rate_limit).await?,
+            );
+            state.reloader.remote_access.set(remote_access.clone()).ok();
+            let remote_app = remote::get_router(remote_access).layer(Extension(state.clone()));
+            let remote_listener = tokio::net::TcpListener::bind(&remote_listen).await?;
+
+            tracing::info!("Serving the binary cache to other machines on {remote_listen}");
+
+            Some(tokio::task::spawn(async move {
+                axum::serve(
+                    remote_listener,
+                    remote_app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await
+            }))
+        }
+        _ => None,
+    };
+
+    #[cfg(unix)]
+    reload::spawn_sighup_handler(state.clone())?;
+
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    tokio::task::spawn({
+        let shutdown = shutdown.clone();
+        let state = state.clone();
+        async move {
+            tokio::select! {
+                _ = shutdown_receiver => {}
+                _ = shutdown_signal() => {}
+            }
+            tracing::info!("Shutting down");
+            state.events.close();
+            shutdown.cancel();
+        }
+    });
+
+    let mut servers = Vec::new();
+    for listen in &args.listen {
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Binding to {listen}"))?;
+        let app = app.clone();
+        let shutdown = shutdown.clone();
+
+        servers.push(tokio::task::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .with_graceful_shutdown(async move { shutdown.cancelled().await })
+                .await
+        }));
+    }
+
+    let ret: Result<()> = async {
+        for server in servers {
+            server.await??;
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Some(remote_server) = remote_server {
+        remote_server.abort();
+    }
+
+    // Nix shouldn't try to reach us once we stop serving.
+    nix_conf_rollback.rollback();
+
+    // If we were interrupted instead of being told that the workflow finished,
+    // still give the pending uploads a chance to complete.
+    if let Some(gha_cache) = &state.gha_cache {
+        if let Err(err) = gha_cache.shutdown().await {
+            tracing::error!("Failed to finish GitHub Actions Cache uploads: {}", err);
+        }
+    }
+
+    if let Some(tenants) = &state.tenants {
+        tenants.shutdown().await;
+    }
+
+    state.backends.stop_retrying();
+
+    if let Some(flakehub_state) = state.flakehub_state.write().await.take() {
+        flakehub_state.uploader.wait().await;
+    }
+
+    let flakehub_oidc_netrc = state
+        .flakehub_oidc_netrc
+        .lock()
+        .expect("FlakeHub netrc mutex was poisoned")
+        .take();
+    if let Some(netrc) = flakehub_oidc_netrc {
+        if let Err(err) = netrc.close() {
+            tracing::warn!("Failed to remove the FlakeHub netrc file: {}", err);
+        }
+    }
+
+    if let Some(hooks) = &state.hooks {
+        hooks.shutdown().await;
+    }
+
+    if let Err(err) = state.narinfo_cache.save().await {
+        tracing::warn!("Failed to save the narinfo cache: {}", err);
+    }
+
+    // Notify diagnostics endpoint
+    if let Some(diagnostic_endpoint) = diagnostic_endpoint {
+        state.metrics.send(diagnostic_endpoint).await;
+    }
+
+    if let Some(tunnel) = &tunnel {
+        tunnel.stop();
+    }
+
+    ret?;
+
+    Ok(())
+}
+
+/// Generates a random API token and writes it to `path`, readable only by us
+/// (and root, which runs the post-build hook).
+fn write_api_token(path: &Path) -> Result<String> {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .with_context(|| format!("Creating parent directories of {}", path.display()))?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+        .with_context(|| format!("Writing the API token to {}", path.display()))?;
+
+    Ok(token)
+}
+
+/// Restarts the daemon with `NIX_REMOTE` pointing at `store`.
+///
+/// The Nix store library reads `NIX_REMOTE` once, when it is loaded, so setting
+/// it in our own environment would have no effect. Only returns on failure.
+#[cfg(unix)]
+fn reexec_with_store(store: &str) -> anyhow::Error {
+    use std::os::unix::process::CommandExt as _;
+
+    tracing::debug!("Restarting with NIX_REMOTE={store}");
+
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => return anyhow::Error::new(err).context("Getting the path of magic-nix-cache"),
+    };
+
+    let err = std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .env("NIX_REMOTE", store)
+        .exec();
+
+    anyhow::Error::new(err).context("Restarting magic-nix-cache with a custom --store")
+}
+
+#[cfg(not(unix))]
+fn reexec_with_store(_store: &str) -> anyhow::Error {
+    anyhow!("--store is only supported on Unix")
+}
+
+/// Resolves when the process is asked to terminate: SIGTERM or Ctrl-C on Unix,
+/// Ctrl-Break or Ctrl-C on Windows.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate());
+    #[cfg(windows)]
+    let terminate = tokio::signal::windows::ctrl_break();
+
+    match terminate {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(err) => {
+            tracing::warn!("Cannot listen for termination signals: {}", err);
+            tokio::signal::ctrl_c().await.ok();
+        }
+    }
+}
+
+/// Runs the `magic-nix-cache` daemon, or the legacy post-build hook when
+/// invoked as one.
+pub async fn run() -> std::process::ExitCode {
+    let result = match std::env::var("OUT_PATHS") {
+        Ok(out_paths) => pbh::handle_legacy_post_build_hook(&out_paths).await,
+        Err(_) => main_cli().await,
+    };
+
+    match result {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            std::process::ExitCode::from(error::exit_code(&err))
+        }
+    }
+}
+
+pub(crate) fn debug_logfile() -> PathBuf {
+    std::env::temp_dir().join("magic-nix-cache-tracing.log")
+}
+
+pub(crate) struct LogGuard {
+    appender_guard: Option<tracing_appender::non_blocking::WorkerGuard>,
+    logfile: Option<PathBuf>,
+}
+
+/// The log filter from `RUST_LOG`, or our defaults.
+fn default_log_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        #[cfg(debug_assertions)]
+        return EnvFilter::new("info")
+            .add_directive(
+                "magic_nix_cache=debug"
+                    .parse()
+                    .expect("failed to parse magix_nix_cache directive"),
+            )
+            .add_directive(
+                "gha_cache=debug"
+                    .parse()
+                    .expect("failed to parse gha_cahce directive"),
+            );
+
+        #[cfg(not(debug_assertions))]
+        return EnvFilter::new("info");
+    })
+}
+
+fn init_logging() -> Result<LogGuard> {
+    let (filter, filter_handle) = tracing_subscriber::reload::Layer::new(default_log_filter());
+    reload::LOG_FILTER.set(filter_handle).ok();
+
+    let stderr_layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .pretty();
+
+    let (guard, file_layer) = match std::env::var("RUNNER_DEBUG") {
+        Ok(val) if val == "1" => {
+            let logfile = debug_logfile();
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&logfile)?;
+            let (nonblocking, guard) = tracing_appender::non_blocking(file);
+            let file_layer = tracing_subscriber::fmt::layer()
+                .with_writer(nonblocking)
+                .pretty();
+
+            (
+                LogGuard {
+                    appender_guard: Some(guard),
+                    logfile: Some(logfile),
+                },
+                Some(file_layer),
+            )
+        }
+        _ => (
+            LogGuard {
+                appender_guard: None,
+                logfile: None,
+            },
+            None,
+        ),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stderr_layer)
+        .with(file_layer)
+        .init();
+
+    Ok(guard)
+}
+
+#[cfg(debug_assertions)]
+async fn dump_api_stats(
+    Extension(state): Extension<State>,
+    request: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if let Some(gha_cache) = &state.gha_cache {
+        gha_cache.api.dump_stats();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,95 @@
+//! NAR listings.
+//!
+//! Binary caches can serve a `<hash>.ls` file describing the contents of each
+//! NAR, which tools like nix-index and `nix why-depends --precise` use to look
+//! inside store paths without downloading them. With `--upload-listings`, one
+//! is uploaded next to each narinfo.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+/// The key under which the listing of a store path is stored.
+pub fn key(store_path_hash: &str) -> String {
+    format!("{store_path_hash}.ls")
+}
+
+/// A `.ls` file, in the format Nix writes them.
+#[derive(Debug, Serialize)]
+struct Listing {
+    version: u32,
+    root: Entry,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Entry {
+    Regular {
+        size: u64,
+
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        executable: bool,
+    },
+    Directory {
+        entries: BTreeMap<String, Entry>,
+    },
+    Symlink {
+        target: String,
+    },
+}
+
+/// Generates the listing of a store path from the file system.
+pub async fn generate(full_path: &Path) -> Result<Vec<u8>> {
+    let full_path = full_path.to_owned();
+
+    let root = tokio::task::spawn_blocking(move || list(&full_path))
+        .await
+        .map_err(|e| Error::Internal(format!("Generating a listing: {e}")))??;
+
+    serde_json::to_vec(&Listing { version: 1, root })
+        .map_err(|e| Error::Internal(format!("Failed to serialize the listing: {e}")))
+}
+
+fn list(path: &Path) -> Result<Entry> {
+    let io_error = |e| Error::Io(e, format!("Listing {}", path.display()));
+
+    let metadata = std::fs::symlink_metadata(path).map_err(io_error)?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+        let target = std::fs::read_link(path).map_err(io_error)?;
+        Ok(Entry::Symlink {
+            target: target.to_string_lossy().into_owned(),
+        })
+    } else if file_type.is_dir() {
+        let mut entries = BTreeMap::new();
+        for entry in std::fs::read_dir(path).map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            entries.insert(
+                entry.file_name().to_string_lossy().into_owned(),
+                list(&entry.path())?,
+            );
+        }
+        Ok(Entry::Directory { entries })
+    } else {
+        Ok(Entry::Regular {
+            size: metadata.len(),
+            executable: is_executable(&metadata),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
@@ -0,0 +1,94 @@
+//! Streaming NARs out of the local Nix store.
+//!
+//! The daemon hands us a NAR in chunks. Rather than wrapping those in an
+//! `AsyncRead` and copying them through the adapter's, the encoder's and
+//! `tokio::fs::File`'s buffers on the runtime, we move each chunk as is to a
+//! blocking thread that compresses it straight into the file.
+//!
+//! Zero-copy transfers (`sendfile`/`splice`) don't apply here: every NAR is
+//! compressed before it leaves the machine, and uploads go through TLS.
+
+use std::io::{Seek as _, Write as _};
+
+use attic::nix_store::{NixStore, StorePath};
+use futures::stream::TryStreamExt as _;
+use tokio::sync::mpsc;
+
+use crate::error::{Error, Result};
+
+/// How many chunks may wait for the compressor.
+const QUEUED_CHUNKS: usize = 16;
+
+/// Compresses the NAR of `path` with zstd into a temporary file.
+///
+/// Returns the file, rewound, and its size.
+pub async fn compress(store: &NixStore, path: &StorePath) -> Result<(tokio::fs::File, u64)> {
+    let full_path = store.get_full_path(path);
+
+    let file = tempfile::tempfile()
+        .map_err(|e| Error::Io(e, "Creating a temporary file for the NAR".to_owned()))?;
+
+    let (chunks_tx, chunks_rx) = mpsc::channel(QUEUED_CHUNKS);
+    let compressor = tokio::task::spawn_blocking(move || compress_chunks(chunks_rx, file));
+
+    let mut nar_stream = store.nar_from_path(path.clone());
+    let read = async {
+        while let Some(chunk) = nar_stream.try_next().await.map_err(|e| {
+            Error::Internal(format!("Reading the NAR of {}: {e}", full_path.display()))
+        })? {
+            // The compressor only goes away if it failed, which we report below.
+            if chunks_tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, Error>(())
+    }
+    .await;
+    drop(chunks_tx);
+
+    let compressed = compressor
+        .await
+        .map_err(|e| Error::Internal(format!("Compressing a NAR: {e}")))?;
+
+    read?;
+
+    let (file, size) =
+        compressed.map_err(|e| Error::Io(e, format!("Compressing {}", full_path.display())))?;
+
+    Ok((tokio::fs::File::from_std(file), size))
+}
+
+/// Reads the whole NAR of `path` into memory.
+pub async fn read(store: &NixStore, path: StorePath) -> Result<Vec<u8>> {
+    let full_path = store.get_full_path(&path);
+    let mut nar_stream = store.nar_from_path(path);
+    let mut nar = Vec::new();
+
+    while let Some(chunk) = nar_stream
+        .try_next()
+        .await
+        .map_err(|e| Error::Internal(format!("Reading the NAR of {}: {e}", full_path.display())))?
+    {
+        let chunk: &[u8] = chunk.as_ref();
+        nar.extend_from_slice(chunk);
+    }
+
+    Ok(nar)
+}
+
+fn compress_chunks<C: AsRef<[u8]>>(
+    mut chunks: mpsc::Receiver<C>,
+    file: std::fs::File,
+) -> std::io::Result<(std::fs::File, u64)> {
+    let mut encoder = zstd::stream::write::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+
+    while let Some(chunk) = chunks.blocking_recv() {
+        encoder.write_all(chunk.as_ref())?;
+    }
+
+    let mut file = encoder.finish()?;
+    let size = file.stream_position()?;
+    file.rewind()?;
+
+    Ok((file, size))
+}
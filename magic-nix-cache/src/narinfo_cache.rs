@@ -0,0 +1,265 @@
+//! Positive narinfo cache.
+//!
+//! Remembers where the backends told us a narinfo lives, so that repeated
+//! queries for the same store path don't have to go to the backend again.
+//! Entries older than the TTL are still served for a while, but trigger a
+//! refresh in the background (stale-while-revalidate).
+//!
+//! The URLs are usually presigned, so an entry is never served past the
+//! expiry its URL carries, however young it is.
+
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// Where the narinfo can be downloaded from.
+    url: String,
+
+    /// When the backend gave us the URL.
+    fetched_at: SystemTime,
+
+    /// When the URL stops working, if it says.
+    #[serde(default)]
+    expires_at: Option<SystemTime>,
+}
+
+pub enum Lookup {
+    /// The entry is younger than the TTL.
+    Fresh(String),
+
+    /// The entry is past its TTL but may still be served while it's refreshed.
+    Stale(String),
+
+    Miss,
+}
+
+pub struct NarinfoCache {
+    ttl: Duration,
+    stale_for: Duration,
+
+    /// Where the cache is persisted, if anywhere.
+    path: Option<PathBuf>,
+
+    entries: RwLock<HashMap<String, Entry>>,
+}
+
+impl NarinfoCache {
+    /// Creates the cache, loading previous entries from `path` if given.
+    ///
+    /// A TTL of zero disables the cache.
+    pub async fn load(ttl: Duration, stale_for: Duration, path: Option<PathBuf>) -> Self {
+        let cache = Self {
+            ttl,
+            stale_for,
+            path,
+            entries: Default::default(),
+        };
+
+        if let Some(path) = &cache.path {
+            match tokio::fs::read(path).await {
+                Ok(contents) => match serde_json::from_slice::<HashMap<String, Entry>>(&contents) {
+                    Ok(entries) => {
+                        let mut live = cache.entries.write().await;
+                        live.extend(entries.into_iter().filter(|(_, e)| cache.is_usable(e)));
+                        tracing::debug!("Loaded {} narinfo cache entries", live.len());
+                    }
+                    Err(err) => {
+                        tracing::warn!("Ignoring the narinfo cache at {}: {}", path.display(), err);
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    tracing::warn!(
+                        "Cannot read the narinfo cache at {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+            }
+        }
+
+        cache
+    }
+
+    pub async fn lookup(&self, store_path_hash: &str) -> Lookup {
+        if self.ttl.is_zero() {
+            return Lookup::Miss;
+        }
+
+        let entries = self.entries.read().await;
+        let Some(entry) = entries.get(store_path_hash) else {
+            return Lookup::Miss;
+        };
+
+        if !self.is_usable(entry) {
+            Lookup::Miss
+        } else if age(entry) < self.ttl {
+            Lookup::Fresh(entry.url.clone())
+        } else {
+            Lookup::Stale(entry.url.clone())
+        }
+    }
+
+    pub async fn insert(&self, store_path_hash: String, url: String) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        self.entries.write().await.insert(
+            store_path_hash,
+            Entry {
+                expires_at: url_expiry(&url),
+                url,
+                fetched_at: SystemTime::now(),
+            },
+        );
+    }
+
+    pub async fn remove(&self, store_path_hash: &str) {
+        self.entries.write().await.remove(store_path_hash);
+    }
+
+    /// Writes the cache back to disk, dropping expired entries.
+    pub async fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let entries: HashMap<_, _> = self
+            .entries
+            .read()
+            .await
+            .iter()
+            .filter(|(_, e)| self.is_usable(e))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        let serialized = serde_json::to_vec(&entries)
+            .map_err(|e| Error::Internal(format!("Serializing the narinfo cache: {e}")))?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| Error::Io(e, format!("Creating {}", parent.display())))?;
+        }
+
+        // Through a temporary file, so that a crash never leaves half a cache behind.
+        let context = format!("Writing {}", path.display());
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            let dir = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => std::path::Path::new("."),
+            };
+            let mut file = tempfile::NamedTempFile::new_in(dir)?;
+            file.write_all(&serialized)?;
+            file.persist(&path).map_err(|e| e.error)?;
+            Ok::<_, std::io::Error>(())
+        })
+        .await
+        .map_err(|e| Error::Internal(format!("Saving the narinfo cache: {e}")))?
+        .map_err(|e| Error::Io(e, context))
+    }
+
+    fn is_usable(&self, entry: &Entry) -> bool {
+        let expired = entry
+            .expires_at
+            .is_some_and(|expires_at| SystemTime::now() + EXPIRY_MARGIN >= expires_at);
+
+        !expired && age(entry) < self.ttl + self.stale_for
+    }
+}
+
+/// How long before its URL expires we stop serving an entry, so that Nix
+/// still gets to use the URL we send it to.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// When a presigned URL stops working, for the kinds of URLs the backends hand out:
+/// Azure SAS (`se`), S3 SigV4 (`X-Amz-Date` plus `X-Amz-Expires`) and S3 SigV2 (`Expires`).
+fn url_expiry(url: &str) -> Option<SystemTime> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let query: HashMap<_, _> = url.query_pairs().collect();
+
+    if let Some(se) = query.get("se") {
+        return parse_timestamp(se);
+    }
+
+    if let (Some(date), Some(expires)) = (query.get("X-Amz-Date"), query.get("X-Amz-Expires")) {
+        let signed = parse_timestamp(date)?;
+        return Some(signed + Duration::from_secs(expires.parse().ok()?));
+    }
+
+    let expires: u64 = query.get("Expires")?.parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(expires))
+}
+
+/// Parses a UTC timestamp like `2024-05-01T12:34:56Z` or `20240501T123456Z`.
+fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let digits: String = s
+        .strip_suffix('Z')?
+        .chars()
+        .filter(|c| !matches!(c, '-' | ':' | 'T'))
+        .collect();
+    // Drop fractional seconds, which some SAS tokens carry.
+    let digits = digits.split('.').next()?;
+    if digits.len() != 14 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let field = |range: std::ops::Range<usize>| digits[range].parse::<i64>().ok();
+    let days = days_from_civil(field(0..4)?, field(4..6)?, field(6..8)?);
+    let seconds = days * 86400 + field(8..10)? * 3600 + field(10..12)? * 60 + field(12..14)?;
+
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(seconds).ok()?))
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn age(entry: &Entry) -> Duration {
+    // Entries from the future (clock changes) are treated as brand new.
+    SystemTime::now()
+        .duration_since(entry.fetched_at)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_expiry_of_presigned_urls() {
+        let at = |seconds| Some(UNIX_EPOCH + Duration::from_secs(seconds));
+
+        assert_eq!(
+            url_expiry("https://acc.blob.core.windows.net/c/x?sv=2019-12-12&se=2024-05-01T12%3A34%3A56Z&sig=abc"),
+            at(1714566896)
+        );
+        assert_eq!(
+            url_expiry(
+                "https://bucket.s3.amazonaws.com/x?X-Amz-Date=20240501T123456Z&X-Amz-Expires=600"
+            ),
+            at(1714567496)
+        );
+        assert_eq!(
+            url_expiry("https://bucket.s3.amazonaws.com/x?Expires=1714566896&Signature=abc"),
+            at(1714566896)
+        );
+        assert_eq!(url_expiry("https://cache.example/x.narinfo"), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Configuring Nix to use the daemon.
+//!
+//! Instead of appending to the user's `nix.conf`, the daemon writes its
+//! settings (substituters, trusted keys, the post-build hook, ...) to a file of
+//! its own next to it, and adds a single `!include` line for that file to
+//! `nix.conf`. At shutdown the file is removed, so Nix stops talking to a daemon
+//! that's gone, while the `!include` line stays: Nix ignores it when the file
+//! doesn't exist, which also keeps `nix.conf` valid if the daemon is killed
+//! before it can clean up. The next start replaces whatever a crashed run left
+//! behind.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+/// The name of the file with our settings, next to `nix.conf`.
+const INCLUDE_FILE_NAME: &str = "magic-nix-cache.conf";
+
+/// Collects the settings to write to the include file.
+pub struct NixConf {
+    nix_conf_path: PathBuf,
+    settings: Vec<u8>,
+}
+
+impl NixConf {
+    pub fn new(nix_conf_path: PathBuf) -> Self {
+        Self {
+            nix_conf_path,
+            settings: Vec::new(),
+        }
+    }
+
+    fn include_path(&self) -> PathBuf {
+        self.nix_conf_path.with_file_name(INCLUDE_FILE_NAME)
+    }
+
+    /// Writes the include file and makes sure that `nix.conf` includes it.
+    pub fn commit(self) -> Result<Rollback> {
+        let include_path = self.include_path();
+        let dir = include_path.parent().unwrap_or(Path::new("."));
+
+        std::fs::create_dir_all(dir)
+            .map_err(|e| Error::Io(e, format!("Creating {}", dir.display())))?;
+
+        // Written next to its final location and renamed, so Nix never reads half of it.
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .map_err(|e| Error::Io(e, format!("Creating a temporary file in {}", dir.display())))?;
+        file.write_all(&self.settings)
+            .map_err(|e| Error::Io(e, format!("Writing {}", include_path.display())))?;
+        file.persist(&include_path)
+            .map_err(|e| Error::Io(e.error, format!("Writing {}", include_path.display())))?;
+
+        let include_line = format!("!include {}", include_path.display());
+        let nix_conf = match std::fs::read_to_string(&self.nix_conf_path) {
+            Ok(nix_conf) => nix_conf,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(Error::Io(
+                    err,
+                    format!("Reading {}", self.nix_conf_path.display()),
+                ))
+            }
+        };
+
+        if !nix_conf.lines().any(|line| line.trim() == include_line) {
+            let mut nix_conf_file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.nix_conf_path)
+                .map_err(|e| Error::Io(e, format!("Opening {}", self.nix_conf_path.display())))?;
+
+            let separator = if nix_conf.is_empty() || nix_conf.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            nix_conf_file
+                .write_all(format!("{separator}{include_line}\n").as_bytes())
+                .map_err(|e| Error::Io(e, format!("Writing {}", self.nix_conf_path.display())))?;
+        }
+
+        tracing::debug!("Wrote the Nix settings to {}", include_path.display());
+
+        Ok(Rollback {
+            include_path: Some(include_path),
+        })
+    }
+}
+
+impl Write for NixConf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.settings.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Removes our settings again, at the latest when dropped.
+pub struct Rollback {
+    include_path: Option<PathBuf>,
+}
+
+impl Rollback {
+    pub fn rollback(&mut self) {
+        let Some(include_path) = self.include_path.take() else {
+            return;
+        };
+
+        match std::fs::remove_file(&include_path) {
+            Ok(()) => tracing::debug!("Removed {}", include_path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => tracing::warn!("Failed to remove {}: {}", include_path.display(), err),
+        }
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        self.rollback();
+    }
+}
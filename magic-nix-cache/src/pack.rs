@@ -0,0 +1,307 @@
+//! Packing small paths.
+//!
+//! Every cache entry costs three requests to create and counts against the
+//! entry limit of the repository, which adds up for the thousands of tiny
+//! paths a typical closure contains (man pages, `doc` outputs, ...). With
+//! `--pack-threshold`, NARs that compress to at most that size are bundled
+//! into packs instead of getting an entry each.
+//!
+//! A pack is stored as two cache entries:
+//! - `pack-<id>.pack`, the compressed NARs, one after the other;
+//! - `pack-<id>.pack-index`, a small JSON [`PackIndex`] saying where each is.
+//!
+//! Packed paths still get a narinfo of their own, uploaded once the pack is,
+//! whose URL (`nar/pack-<id>-<nar hash>.nar.zstd`) tells us which pack to cut
+//! the NAR from on substitution.
+//!
+//! A pack is uploaded when it's full or its first member has waited for
+//! [`MAX_PACK_AGE`], in the background, and is tried again if that fails. Its
+//! members only count as uploaded, or as failed, once the pack is.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::{distributions::Alphanumeric, Rng as _};
+use reqwest::header::RANGE;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::error::{Error, Result};
+use crate::State;
+
+/// Packs are uploaded once they reach this size.
+pub const MAX_PACK_SIZE: usize = 32 * 1024 * 1024;
+
+/// Packs are uploaded once they're this old, full or not.
+pub const MAX_PACK_AGE: Duration = Duration::from_secs(30);
+
+/// How often the pending pack is checked for its age.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many times the upload of a pack is tried.
+pub const UPLOAD_ATTEMPTS: u32 = 3;
+
+/// How long to wait before trying the upload of a pack again, doubling each time.
+pub const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Where the NARs in a pack are.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PackIndex {
+    /// The members of the pack, by NAR hash.
+    pub members: HashMap<String, Member>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Member {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A pack that is being filled.
+///
+/// `T` is whatever the uploader needs to finish the upload of a member once
+/// the pack is uploaded.
+pub struct Pack<T> {
+    pub id: String,
+    pub data: Vec<u8>,
+    pub index: PackIndex,
+    pub members: Vec<T>,
+    pub created: Instant,
+}
+
+impl<T> Default for Pack<T> {
+    fn default() -> Self {
+        Self {
+            id: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(16)
+                .map(char::from)
+                .collect::<String>()
+                .to_lowercase(),
+            data: Vec::new(),
+            index: PackIndex::default(),
+            members: Vec::new(),
+            created: Instant::now(),
+        }
+    }
+}
+
+impl<T> Pack<T> {
+    /// Adds a compressed NAR. Returns whether the pack is full.
+    pub fn add(&mut self, nar_hash: String, compressed_nar: &[u8], member: T) -> bool {
+        self.index.members.insert(
+            nar_hash,
+            Member {
+                offset: self.data.len() as u64,
+                length: compressed_nar.len() as u64,
+            },
+        );
+        self.data.extend_from_slice(compressed_nar);
+        self.members.push(member);
+
+        self.data.len() >= MAX_PACK_SIZE
+    }
+
+    /// Whether the pack has waited long enough for more members at `now`.
+    pub fn is_due(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.created) >= MAX_PACK_AGE
+    }
+}
+
+/// Runs `upload` until it succeeds, at most [`UPLOAD_ATTEMPTS`] times, waiting
+/// `delay` after the first failure and twice as long after each one after that.
+pub async fn retry<F, Fut>(delay: Duration, mut upload: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut delay = delay;
+    for _ in 1..UPLOAD_ATTEMPTS {
+        match upload().await {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                tracing::debug!("Upload of a pack failed, trying again: {}", err);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+
+    upload().await
+}
+
+/// The pack indexes we have downloaded, by pack ID.
+#[derive(Debug, Default)]
+pub struct IndexCache {
+    indexes: RwLock<HashMap<String, Arc<PackIndex>>>,
+}
+
+impl IndexCache {
+    async fn get(&self, pack_id: &str) -> Option<Arc<PackIndex>> {
+        self.indexes.read().await.get(pack_id).cloned()
+    }
+
+    async fn insert(&self, pack_id: &str, index: PackIndex) -> Arc<PackIndex> {
+        let index = Arc::new(index);
+        self.indexes
+            .write()
+            .await
+            .insert(pack_id.to_owned(), index.clone());
+        index
+    }
+}
+
+pub fn blob_key(pack_id: &str) -> String {
+    format!("pack-{pack_id}.pack")
+}
+
+pub fn index_key(pack_id: &str) -> String {
+    format!("pack-{pack_id}.pack-index")
+}
+
+/// The URL of a packed NAR, relative to the binary cache.
+pub fn nar_url(pack_id: &str, nar_hash: &str) -> String {
+    format!("nar/pack-{pack_id}-{nar_hash}.nar.zstd")
+}
+
+/// Splits `pack-<id>-<nar hash>.nar.zstd` into the pack ID and the NAR hash.
+fn parse_nar_file(nar_file: &str) -> Option<(&str, &str)> {
+    nar_file
+        .strip_prefix("pack-")?
+        .strip_suffix(".nar.zstd")?
+        .split_once('-')
+}
+
+/// Cuts the NAR `nar_file` (e.g. `pack-<id>-<nar hash>.nar.zstd`) out of its pack.
+///
+/// Returns the zstd-compressed NAR, or `None` if `nar_file` isn't packed.
+pub async fn substitute(state: &State, nar_file: &str) -> Result<Option<Vec<u8>>> {
+    let Some((pack_id, nar_hash)) = parse_nar_file(nar_file) else {
+        return Ok(None);
+    };
+
+    let Some(gha_cache) = &state.gha_cache else {
+        return Ok(None);
+    };
+
+    let index = match gha_cache.pack_indexes.get(pack_id).await {
+        Some(index) => index,
+        None => {
+            let Some(index_url) = gha_cache.api.get_file_url(&[&index_key(pack_id)]).await? else {
+                return Ok(None);
+            };
+
+            let index: PackIndex = serde_json::from_slice(&download(&index_url, None).await?)
+                .map_err(|e| {
+                    Error::Internal(format!("Parsing the index of pack {pack_id}: {e}"))
+                })?;
+
+            gha_cache.pack_indexes.insert(pack_id, index).await
+        }
+    };
+
+    let Some(member) = index.members.get(nar_hash).copied() else {
+        return Ok(None);
+    };
+
+    let Some(blob_url) = gha_cache.api.get_file_url(&[&blob_key(pack_id)]).await? else {
+        return Ok(None);
+    };
+
+    let nar = download(&blob_url, Some(member)).await?;
+
+    tracing::debug!("Cut {} out of pack {}", nar_hash, pack_id);
+
+    Ok(Some(nar))
+}
+
+/// Downloads `url`, or just the part of it that holds `member`.
+async fn download(url: &str, member: Option<Member>) -> Result<Vec<u8>> {
+    let mut request = crate::http_client::client().get(url);
+    if let Some(member) = member {
+        request = request.header(
+            RANGE,
+            format!(
+                "bytes={}-{}",
+                member.offset,
+                member.offset + member.length - 1
+            ),
+        );
+    }
+
+    let response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Error::Internal(format!("Downloading {url}: {e}")))?;
+
+    // Servers are free to ignore the range and send everything.
+    let partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| Error::Internal(format!("Downloading {url}: {e}")))?;
+
+    match member {
+        Some(member) if !partial => body
+            .get(member.offset as usize..(member.offset + member.length) as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| Error::Internal(format!("The pack at {url} is truncated"))),
+        _ => Ok(body.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn packs_fill_up() {
+        let mut pack = Pack::default();
+
+        assert!(!pack.add("a".to_owned(), &[1; 16], 'a'));
+        assert!(pack.add("b".to_owned(), &vec![2; MAX_PACK_SIZE], 'b'));
+
+        assert_eq!(pack.members, vec!['a', 'b']);
+        assert_eq!(pack.index.members["b"].offset, 16);
+        assert_eq!(pack.index.members["b"].length, MAX_PACK_SIZE as u64);
+    }
+
+    #[test]
+    fn packs_are_due_after_a_while() {
+        let pack = Pack::<()>::default();
+
+        assert!(!pack.is_due(pack.created));
+        assert!(!pack.is_due(pack.created + MAX_PACK_AGE / 2));
+        assert!(pack.is_due(pack.created + MAX_PACK_AGE));
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_are_retried() {
+        let attempts = AtomicU32::new(0);
+        let result = retry(Duration::ZERO, || async {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(Error::Internal("flaky".to_owned()))
+            } else {
+                Ok(())
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let attempts = AtomicU32::new(0);
+        let result = retry(Duration::ZERO, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(Error::Internal("down".to_owned()))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), UPLOAD_ATTEMPTS);
+    }
+}
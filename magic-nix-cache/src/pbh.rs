@@ -1,7 +1,7 @@
 use std::io::Write as _;
 use std::net::SocketAddr;
 use std::os::unix::fs::PermissionsExt as _;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::anyhow;
 use anyhow::Context as _;
@@ -15,6 +15,7 @@ use tempfile::NamedTempFile;
 use tokio::net::UnixStream;
 use tokio::process::Command;
 
+use crate::util::shell_quote;
 use crate::BuiltPathResponseEventV1;
 use crate::State;
 
@@ -115,6 +116,14 @@ pub async fn subscribe_uds_post_build_hook(
                     );
                     continue;
                 }
+
+                if let Err(e) = crate::api::enqueue_derivation(&state, event.drv.clone()).await {
+                    tracing::error!(
+                        "built-paths: failed to enqueue derivation ({}): {}",
+                        event.drv.display(),
+                        e
+                    );
+                }
             }
         }
     });
@@ -124,8 +133,14 @@ pub async fn subscribe_uds_post_build_hook(
 
 pub async fn setup_legacy_post_build_hook(
     listen: &SocketAddr,
-    nix_conf: &mut std::fs::File,
+    api_token_file: Option<&Path>,
+    nix_conf: &mut impl std::io::Write,
 ) -> Result<()> {
+    let token_file_arg = match api_token_file {
+        Some(path) => format!(" --token-file {}", shell_quote(&path.display().to_string())),
+        None => String::new(),
+    };
+
     /* Write the post-build hook script. Note that the shell script
      * ignores errors, to avoid the Nix build from failing. */
     let post_build_hook_script = {
@@ -135,11 +150,15 @@ pub async fn setup_legacy_post_build_hook(
             format!(
                 // NOTE(cole-h): We want to exit 0 even if the hook failed, otherwise it'll fail the
                 // build itself
-                "#! /bin/sh\nRUST_LOG=trace RUST_BACKTRACE=full {} --server {} || :\n",
-                std::env::current_exe()
-                    .with_context(|| "Getting the path of magic-nix-cache")?
-                    .display(),
-                listen
+                "#! /bin/sh\nRUST_LOG=trace RUST_BACKTRACE=full {} --server {}{} || :\n",
+                shell_quote(
+                    &std::env::current_exe()
+                        .with_context(|| "Getting the path of magic-nix-cache")?
+                        .display()
+                        .to_string()
+                ),
+                listen,
+                token_file_arg,
             )
             .as_bytes(),
         )
@@ -198,6 +217,10 @@ pub async fn handle_legacy_post_build_hook(out_paths: &str) -> Result<()> {
         /// `magic-nix-cache` daemon to connect to.
         #[arg(short = 'l', long, default_value = "127.0.0.1:3000")]
         server: SocketAddr,
+
+        /// File containing the daemon's API token.
+        #[arg(long)]
+        token_file: Option<PathBuf>,
     }
 
     let args = Args::parse();
@@ -207,11 +230,23 @@ pub async fn handle_legacy_post_build_hook(out_paths: &str) -> Result<()> {
         .map(|s| s.trim().to_owned())
         .collect();
 
-    let request = crate::api::EnqueuePathsRequest { store_paths };
+    let request = crate::api::EnqueuePathsRequest {
+        store_paths,
+        drv_path: std::env::var_os("DRV_PATH").map(PathBuf::from),
+    };
 
-    let response = reqwest::Client::new()
+    let mut request_builder = crate::http_client::local_client()
         .post(format!("http://{}/api/enqueue-paths", &args.server))
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+
+    if let Some(token_file) = &args.token_file {
+        let token = tokio::fs::read_to_string(token_file)
+            .await
+            .with_context(|| format!("Reading the API token from {}", token_file.display()))?;
+        request_builder = request_builder.bearer_auth(token.trim());
+    }
+
+    let response = request_builder
         .body(
             serde_json::to_string(&request)
                 .with_context(|| "Decoding the response from the magic-nix-cache server")?,
@@ -0,0 +1,173 @@
+//! Carrying the daemon's state from one job to the next.
+//!
+//! Runners are usually ephemeral, so the upload index on disk is lost after
+//! every job. With `--persist-state`, the index is also stored as a small entry
+//! in the GitHub Actions Cache at shutdown, and merged into the local one at
+//! startup. Paths it lists aren't uploaded again until GitHub evicts them.
+//!
+//! The entry is keyed by its contents, so jobs that upload nothing new don't
+//! add another one.
+//!
+//! The negative narinfo cache is deliberately left out: other jobs may have
+//! uploaded those paths since.
+
+use std::collections::{HashMap, HashSet};
+
+use gha_cache::Api;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::index::{IndexEntry, UploadIndex};
+use crate::telemetry;
+
+/// The version of the snapshot format. Bump it on incompatible changes.
+const VERSION: u32 = 1;
+
+/// The key the snapshot is stored under, minus the hash of its contents.
+fn key() -> String {
+    format!("magic-nix-cache-state-v{VERSION}")
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+
+    /// Store paths uploaded by the jobs that contributed to the snapshot.
+    #[serde(default)]
+    uploaded: HashMap<String, IndexEntry>,
+
+    /// Totals across those jobs.
+    #[serde(default)]
+    totals: Totals,
+}
+
+/// Counters summed over all jobs that contributed to the snapshot.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Totals {
+    #[serde(default)]
+    jobs: u64,
+
+    #[serde(default)]
+    nars_uploaded: u64,
+
+    #[serde(default)]
+    narinfos_evicted: u64,
+}
+
+/// What the snapshot carried over from earlier jobs.
+#[derive(Debug, Default)]
+pub struct Restored {
+    pub totals: Totals,
+
+    /// The hashes of the store paths those jobs uploaded.
+    pub uploaded: HashSet<String>,
+}
+
+/// Restores the most recent snapshot into `index`.
+pub async fn restore(api: &Api, index: &UploadIndex) -> Result<Restored> {
+    // The trailing dash keeps e.g. `v1` from matching `v10`.
+    let Some(url) = api.get_file_url(&[&format!("{}-", key())]).await? else {
+        tracing::debug!("No saved state in the GitHub Actions Cache");
+        return Ok(Restored::default());
+    };
+
+    let contents = crate::http_client::client()
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Error::Internal(format!("Downloading the saved state: {e}")))?
+        .bytes()
+        .await
+        .map_err(|e| Error::Internal(format!("Downloading the saved state: {e}")))?;
+
+    let snapshot: Snapshot = serde_json::from_slice(&contents)
+        .map_err(|e| Error::Internal(format!("Parsing the saved state: {e}")))?;
+
+    if snapshot.version != VERSION {
+        tracing::debug!(
+            "Ignoring saved state with version {} (expected {})",
+            snapshot.version,
+            VERSION
+        );
+        return Ok(Restored::default());
+    }
+
+    let uploaded = snapshot.uploaded.keys().cloned().collect::<HashSet<_>>();
+    index.merge(snapshot.uploaded).await;
+
+    tracing::info!(
+        "Restored {} uploaded paths from {} earlier jobs",
+        uploaded.len(),
+        snapshot.totals.jobs
+    );
+
+    Ok(Restored {
+        totals: snapshot.totals,
+        uploaded,
+    })
+}
+
+/// Stores the index as a new snapshot, adding this job's counters to `totals`.
+///
+/// Does nothing if this job neither uploaded nor lost anything.
+pub async fn save(
+    api: &Api,
+    index: &UploadIndex,
+    totals: Totals,
+    metrics: &telemetry::TelemetryReport,
+) -> Result<()> {
+    if metrics.nars_uploaded.get() == 0 && metrics.narinfos_evicted.get() == 0 {
+        tracing::debug!("Nothing changed since the state was restored, not saving it");
+        return Ok(());
+    }
+
+    let snapshot = Snapshot {
+        version: VERSION,
+        uploaded: index.entries().await,
+        totals: Totals {
+            jobs: totals.jobs + 1,
+            nars_uploaded: totals.nars_uploaded + metrics.nars_uploaded.get() as u64,
+            narinfos_evicted: totals.narinfos_evicted + metrics.narinfos_evicted.get() as u64,
+        },
+    };
+
+    let serialized = serde_json::to_vec(&snapshot)
+        .map_err(|e| Error::Internal(format!("Serializing the state: {e}")))?;
+
+    let allocation = match api.allocate_file(&snapshot_key(&snapshot)).await {
+        Ok(allocation) => allocation,
+        Err(gha_cache::api::Error::ApiError {
+            status: StatusCode::CONFLICT,
+            ..
+        }) => {
+            tracing::debug!("Another job already saved the same state");
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    api.upload_file(allocation, serialized.as_slice()).await?;
+
+    tracing::debug!(
+        "Saved the state of {} uploaded paths to the GitHub Actions Cache",
+        snapshot.uploaded.len()
+    );
+
+    Ok(())
+}
+
+/// The key of `snapshot`, which only changes when the set of uploaded paths does.
+fn snapshot_key(snapshot: &Snapshot) -> String {
+    let mut hashes: Vec<_> = snapshot.uploaded.keys().collect();
+    hashes.sort();
+
+    let mut hasher = Sha256::new();
+    for hash in hashes {
+        hasher.update(hash);
+        hasher.update(b"\n");
+    }
+
+    format!("{}-{:x}", key(), hasher.finalize())
+}
@@ -0,0 +1,726 @@
+//! Deciding per path what to substitute and push.
+//!
+//! `--policy-file` points to a JSON file like
+//!
+//! ```json
+//! {
+//!   "rules": [
+//!     { "action": "deny", "operation": "push", "backend": "flakehub", "name": "*-internal-*" },
+//!     { "action": "allow", "operation": "substitute", "signers": ["cache.example.com-1"] },
+//!     { "action": "deny", "operation": "substitute", "min_size": 1073741824 }
+//!   ],
+//!   "trusted_keys": ["cache.example.com-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ="],
+//!   "default": "allow"
+//! }
+//! ```
+//!
+//! Every narinfo a backend serves through the daemon, and every path about to
+//! be pushed, is checked against the rules in order: the first rule whose
+//! conditions all hold decides, and `default` applies when none does. The
+//! conditions are the operation, the backend, the keys the path is signed with,
+//! a pattern for its name where `*` matches anything, the size of its NAR, and
+//! the system of its derivation. `signers` names keys from `trusted_keys`, and
+//! only signatures that verify against those keys count, as anybody can put any
+//! key name in a `Sig:` line. A condition on something we
+//! don't know about a path, e.g. the system of a path without a local
+//! derivation, doesn't hold, so an `allow` rule never lets a path through on a
+//! technicality.
+//!
+//! A path is only pushed if its whole closure may be, as both backends push
+//! closures. Nix can't be stopped from substituting from FlakeHub directly,
+//! only when it goes through the daemon.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use attic::hash::Hash;
+use attic::nix_store::{NixStore, StorePath};
+use attic::signing::NixPublicKey;
+use axum::{body::Body, http::header, response::Response};
+use base64::Engine as _;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+use crate::{telemetry, Backend};
+
+/// How large a narinfo we check may be.
+const MAX_NARINFO_SIZE: usize = 1024 * 1024;
+
+/// How many paths we ask `nix path-info` about at once, to stay below the
+/// limit on the size of a command line.
+const PATH_INFO_CHUNK: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Substitute,
+    Push,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    action: Action,
+
+    #[serde(default)]
+    operation: Option<Operation>,
+
+    #[serde(default)]
+    backend: Option<Backend>,
+
+    /// The names of the keys in `trusted_keys` of which any one must have signed the path.
+    #[serde(default)]
+    signers: Vec<String>,
+
+    /// A pattern for the name of the path, without the hash.
+    #[serde(default)]
+    name: Option<String>,
+
+    #[serde(default)]
+    min_size: Option<u64>,
+
+    #[serde(default)]
+    max_size: Option<u64>,
+
+    /// The systems of which the derivation of the path must target one.
+    #[serde(default)]
+    systems: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    rules: Vec<Rule>,
+
+    /// The public keys that `signers` refer to, as in `trusted-public-keys`.
+    #[serde(default)]
+    trusted_keys: Vec<String>,
+
+    #[serde(default)]
+    default: Action,
+
+    /// `trusted_keys`, parsed.
+    #[serde(skip)]
+    keys: Vec<TrustedKey>,
+}
+
+/// A public key whose signatures count for `signers`.
+struct TrustedKey {
+    name: String,
+    key: NixPublicKey,
+}
+
+impl std::fmt::Debug for TrustedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TrustedKey").field(&self.name).finish()
+    }
+}
+
+/// What we know about a path.
+#[derive(Debug, Default)]
+pub struct Subject {
+    pub name: String,
+    pub nar_size: Option<u64>,
+
+    /// The names of the trusted keys whose signatures of the path verified.
+    pub signers: Vec<String>,
+
+    pub system: Option<String>,
+}
+
+impl Rule {
+    fn matches(&self, operation: Operation, backend: Backend, subject: &Subject) -> bool {
+        self.operation.map_or(true, |o| o == operation)
+            && self.backend.map_or(true, |b| b == backend)
+            && (self.signers.is_empty() || subject.signers.iter().any(|s| self.signers.contains(s)))
+            && self.name.as_ref().map_or(true, |pattern| {
+                crate::filter::matches(pattern, &subject.name)
+            })
+            && self
+                .min_size
+                .map_or(true, |min| subject.nar_size.is_some_and(|size| size >= min))
+            && self
+                .max_size
+                .map_or(true, |max| subject.nar_size.is_some_and(|size| size <= max))
+            && (self.systems.is_empty()
+                || subject
+                    .system
+                    .as_ref()
+                    .is_some_and(|system| self.systems.contains(system)))
+    }
+
+    fn applies_to(&self, operation: Operation) -> bool {
+        self.operation.map_or(true, |o| o == operation)
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let policy = std::fs::read(path)
+            .map_err(|e| Error::Io(e, format!("Reading the policy {}", path.display())))?;
+
+        Self::parse(&policy)
+            .map_err(|e| Error::Config(format!("invalid policy {}: {e}", path.display())))
+    }
+
+    fn parse(policy: &[u8]) -> std::result::Result<Self, String> {
+        let mut policy: Self = serde_json::from_slice(policy).map_err(|e| e.to_string())?;
+
+        policy.keys = policy
+            .trusted_keys
+            .iter()
+            .map(|trusted_key| {
+                let (name, _) = trusted_key
+                    .split_once(':')
+                    .ok_or_else(|| format!("'{trusted_key}' is not a public key"))?;
+                let key = NixPublicKey::from_str(trusted_key)
+                    .map_err(|e| format!("'{trusted_key}' is not a public key: {e}"))?;
+                Ok(TrustedKey {
+                    name: name.to_owned(),
+                    key,
+                })
+            })
+            .collect::<std::result::Result<_, String>>()?;
+
+        for rule in &policy.rules {
+            if let Some(signer) = rule
+                .signers
+                .iter()
+                .find(|signer| !policy.keys.iter().any(|key| &key.name == *signer))
+            {
+                return Err(format!("'{signer}' is not in trusted_keys"));
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Whether anything could be denied for `operation`.
+    pub fn checks(&self, operation: Operation) -> bool {
+        self.default == Action::Deny || self.rules.iter().any(|rule| rule.applies_to(operation))
+    }
+
+    pub fn allows(&self, operation: Operation, backend: Backend, subject: &Subject) -> bool {
+        let action = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(operation, backend, subject))
+            .map_or(self.default, |rule| rule.action);
+
+        action == Action::Allow
+    }
+
+    /// The names of the trusted keys that made one of `signatures` of `fingerprint`.
+    fn verified_signers<'a>(
+        &self,
+        fingerprint: Option<&str>,
+        signatures: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<String> {
+        let Some(fingerprint) = fingerprint else {
+            return Vec::new();
+        };
+
+        let mut signers = Vec::new();
+        for signature in signatures {
+            let signature = signature.trim();
+            let Some((name, _)) = signature.split_once(':') else {
+                continue;
+            };
+
+            let verified = self.keys.iter().any(|key| {
+                key.name == name && key.key.verify(fingerprint.as_bytes(), signature).is_ok()
+            });
+            if verified && !signers.iter().any(|signer| signer == name) {
+                signers.push(name.to_owned());
+            }
+        }
+
+        signers
+    }
+
+    /// Reads what a narinfo says about its path.
+    pub fn narinfo_subject(&self, narinfo: &str) -> Option<Subject> {
+        let mut subject = Subject::default();
+        let mut store_path = None;
+        let mut nar_hash = None;
+        let mut references = Vec::new();
+        let mut signatures = Vec::new();
+
+        for line in narinfo.lines() {
+            let Some((field, value)) = line.split_once(": ") else {
+                continue;
+            };
+
+            match field {
+                "StorePath" => store_path = Some(PathBuf::from(value.trim())),
+                "NarHash" => nar_hash = Some(value.trim()),
+                "NarSize" => subject.nar_size = value.trim().parse().ok(),
+                "References" => references.extend(value.split_whitespace()),
+                "Sig" => signatures.push(value),
+                "System" => subject.system = Some(value.trim().to_owned()),
+                _ => {}
+            }
+        }
+
+        let store_path = store_path?;
+        let store_dir = store_path.parent()?;
+        let references: Vec<PathBuf> = references
+            .into_iter()
+            .map(|reference| store_dir.join(reference))
+            .collect();
+
+        let fingerprint = fingerprint(&store_path, nar_hash?, subject.nar_size?, &references);
+        subject.signers = self.verified_signers(fingerprint.as_deref(), signatures);
+        subject.name = crate::delta::path_name(&store_path)?.to_owned();
+        Some(subject)
+    }
+
+    /// Whether the systems of derivations are needed to decide on `operation`.
+    fn needs_system(&self, operation: Operation) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.applies_to(operation) && !rule.systems.is_empty())
+    }
+
+    /// Drops the paths whose closure may not be pushed to `backend`.
+    ///
+    /// When we can't tell, nothing is pushed.
+    pub async fn retain_pushes(
+        &self,
+        store: &NixStore,
+        metrics: &telemetry::TelemetryReport,
+        backend: Backend,
+        store_paths: Vec<StorePath>,
+    ) -> Vec<StorePath> {
+        if !self.checks(Operation::Push) || store_paths.is_empty() {
+            return store_paths;
+        }
+
+        match self.denied_pushes(store, backend, &store_paths).await {
+            Ok(denied) => {
+                let mut retained = Vec::with_capacity(store_paths.len());
+                for (store_path, closure) in store_paths.into_iter().zip(denied) {
+                    match closure {
+                        None => retained.push(store_path),
+                        Some(denied) => {
+                            tracing::info!(
+                                "Not pushing '{}' to {:?}, as the policy denies pushing '{}'",
+                                store.get_full_path(&store_path).display(),
+                                backend,
+                                denied.display()
+                            );
+                            metrics.paths_denied_policy.incr();
+                        }
+                    }
+                }
+                retained
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Not pushing {} paths to {:?}, as the policy can't be checked: {}",
+                    store_paths.len(),
+                    backend,
+                    err
+                );
+                metrics.paths_denied_policy.add(store_paths.len());
+                Vec::new()
+            }
+        }
+    }
+
+    /// Returns, for each of `store_paths`, a path in its closure that may not be pushed, if any.
+    async fn denied_pushes(
+        &self,
+        store: &NixStore,
+        backend: Backend,
+        store_paths: &[StorePath],
+    ) -> Result<Vec<Option<PathBuf>>> {
+        let mut closures = Vec::with_capacity(store_paths.len());
+        for store_path in store_paths {
+            let closure = store
+                .compute_fs_closure_multi(vec![store_path.clone()], false, false, false)
+                .await
+                .map_err(Error::Attic)?;
+            closures.push(
+                closure
+                    .iter()
+                    .map(|path| store.get_full_path(path))
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        let all_paths: HashSet<_> = closures.iter().flatten().cloned().collect();
+        let infos = self
+            .query_path_infos(&all_paths.into_iter().collect::<Vec<_>>())
+            .await?;
+
+        let needs_system = self.needs_system(Operation::Push);
+        let mut subjects = HashMap::with_capacity(infos.len());
+        for (path, (mut subject, deriver)) in infos {
+            if let (true, Some(deriver)) = (needs_system, deriver) {
+                subject.system = tokio::fs::read_to_string(deriver)
+                    .await
+                    .ok()
+                    .and_then(|drv| crate::filter::derivation_system(&drv));
+            }
+            subjects.insert(path, subject);
+        }
+
+        let unknown = Subject::default();
+        Ok(closures
+            .into_iter()
+            .map(|closure| {
+                closure.into_iter().find(|path| {
+                    let subject = subjects.get(path).unwrap_or(&unknown);
+                    !self.allows(Operation::Push, backend, subject)
+                })
+            })
+            .collect())
+    }
+
+    /// Passes on a narinfo served by `backend` if it may be substituted, and
+    /// returns `None` if it may not.
+    pub async fn check_narinfo(
+        &self,
+        metrics: &telemetry::TelemetryReport,
+        backend: Backend,
+        response: Response,
+    ) -> Option<Response> {
+        if !self.checks(Operation::Substitute) {
+            return Some(response);
+        }
+
+        // We can't look behind a redirect.
+        if response.status().is_redirection() {
+            metrics.narinfos_denied_policy.incr();
+            return None;
+        }
+
+        if !response.status().is_success() {
+            return Some(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let narinfo = match axum::body::to_bytes(body, MAX_NARINFO_SIZE).await {
+            Ok(narinfo) => narinfo,
+            Err(err) => {
+                tracing::warn!("Failed to read a narinfo from {:?}: {}", backend, err);
+                metrics.narinfos_denied_policy.incr();
+                return None;
+            }
+        };
+
+        let subject = self.narinfo_subject(&String::from_utf8_lossy(&narinfo));
+        if !subject.is_some_and(|subject| self.allows(Operation::Substitute, backend, &subject)) {
+            metrics.narinfos_denied_policy.incr();
+            return None;
+        }
+
+        Some(Response::from_parts(parts, Body::from(narinfo)))
+    }
+
+    /// Downloads the narinfo at `url` if it may be substituted from `backend`.
+    pub async fn fetch_narinfo(
+        &self,
+        metrics: &telemetry::TelemetryReport,
+        backend: Backend,
+        url: &str,
+    ) -> Result<Option<Response>> {
+        let narinfo = async {
+            crate::http_client::client()
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await
+        }
+        .await
+        .map_err(|e| Error::Internal(format!("Downloading a narinfo: {e}")))?;
+
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "text/x-nix-narinfo")
+            .body(Body::from(narinfo))
+            .map_err(|e| Error::Internal(format!("Building the narinfo response: {e}")))?;
+
+        Ok(self.check_narinfo(metrics, backend, response).await)
+    }
+}
+
+/// What the signatures of a path are made over, as in Nix's `ValidPathInfo::fingerprint`.
+///
+/// `None` if the NAR hash is in a form we don't know.
+fn fingerprint(
+    store_path: &Path,
+    nar_hash: &str,
+    nar_size: u64,
+    references: &[PathBuf],
+) -> Option<String> {
+    let references: Vec<String> = references
+        .iter()
+        .map(|reference| reference.display().to_string())
+        .collect();
+
+    Some(format!(
+        "1;{};{};{};{}",
+        store_path.display(),
+        nar_hash_base32(nar_hash)?,
+        nar_size,
+        references.join(",")
+    ))
+}
+
+/// Turns a NAR hash into the `sha256:<base32>` form that signatures are made over.
+///
+/// Newer versions of `nix path-info` print SRI hashes instead.
+fn nar_hash_base32(nar_hash: &str) -> Option<String> {
+    if let Some(base32) = nar_hash.strip_prefix("sha256:") {
+        return (base32.len() == 52).then(|| nar_hash.to_owned());
+    }
+
+    let digest = base64::engine::general_purpose::STANDARD
+        .decode(nar_hash.strip_prefix("sha256-")?)
+        .ok()?;
+    Some(format!(
+        "sha256:{}",
+        Hash::Sha256(digest.try_into().ok()?).to_base32()
+    ))
+}
+
+impl Policy {
+    /// Asks Nix about `full_paths`, returning what we know about each and its deriver.
+    async fn query_path_infos(
+        &self,
+        full_paths: &[PathBuf],
+    ) -> Result<HashMap<PathBuf, (Subject, Option<PathBuf>)>> {
+        let mut infos = HashMap::with_capacity(full_paths.len());
+        for chunk in full_paths.chunks(PATH_INFO_CHUNK) {
+            infos.extend(self.query_path_infos_chunk(chunk).await?);
+        }
+
+        Ok(infos)
+    }
+
+    async fn query_path_infos_chunk(
+        &self,
+        full_paths: &[PathBuf],
+    ) -> Result<HashMap<PathBuf, (Subject, Option<PathBuf>)>> {
+        let output = Command::new("nix")
+            .args([
+                "--extra-experimental-features",
+                "nix-command",
+                "path-info",
+                "--json",
+            ])
+            .args(full_paths)
+            .output()
+            .await
+            .map_err(|e| Error::Io(e, "Running nix path-info".to_owned()))?;
+
+        if !output.status.success() {
+            return Err(Error::Internal(format!(
+                "nix path-info failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| Error::Internal(format!("Parsing nix path-info: {e}")))?;
+
+        let path_info = |path: &Path, info: &serde_json::Value| {
+            let nar_size = info.get("narSize").and_then(|size| size.as_u64());
+            let references: Vec<PathBuf> = info
+                .get("references")
+                .and_then(|references| references.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|reference| reference.as_str())
+                .map(PathBuf::from)
+                .collect();
+            let fingerprint = info
+                .get("narHash")
+                .and_then(|nar_hash| nar_hash.as_str())
+                .zip(nar_size)
+                .and_then(|(nar_hash, nar_size)| {
+                    fingerprint(path, nar_hash, nar_size, &references)
+                });
+            let signatures = info
+                .get("signatures")
+                .and_then(|sigs| sigs.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|sig| sig.as_str());
+
+            let subject = Subject {
+                name: crate::delta::path_name(path).unwrap_or_default().to_owned(),
+                nar_size,
+                signers: self.verified_signers(fingerprint.as_deref(), signatures),
+                system: None,
+            };
+            let deriver = info
+                .get("deriver")
+                .and_then(|deriver| deriver.as_str())
+                .map(PathBuf::from);
+            (subject, deriver)
+        };
+
+        // Nix 2.19 turned the list of objects into an object keyed by store path.
+        let infos = match json {
+            serde_json::Value::Array(infos) => infos
+                .iter()
+                .filter_map(|info| {
+                    let path = PathBuf::from(info.get("path")?.as_str()?);
+                    let info = path_info(&path, info);
+                    Some((path, info))
+                })
+                .collect(),
+            serde_json::Value::Object(infos) => infos
+                .iter()
+                .map(|(path, info)| {
+                    let path = PathBuf::from(path);
+                    let info = path_info(&path, info);
+                    (path, info)
+                })
+                .collect(),
+            _ => HashMap::new(),
+        };
+
+        Ok(infos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use attic::signing::NixKeypair;
+
+    use super::*;
+
+    const STORE_PATH: &str = "/nix/store/7xd5l2wzqd3dmvrp4pgmk2r7mrx0gsrh-hello-2.12.1";
+    const NAR_HASH: &str = "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+
+    fn policy(trusted: &NixKeypair) -> Policy {
+        let policy = serde_json::json!({
+            "rules": [
+                { "action": "allow", "operation": "substitute", "signers": ["trusted-1"] },
+                { "action": "deny", "operation": "substitute" },
+                { "action": "deny", "operation": "push", "name": "*-internal-*" },
+                { "action": "deny", "operation": "push", "min_size": 1000 }
+            ],
+            "trusted_keys": [trusted.export_public_key()]
+        });
+        Policy::parse(policy.to_string().as_bytes()).unwrap()
+    }
+
+    fn narinfo(signatures: &[String]) -> String {
+        let mut narinfo = format!(
+            "StorePath: {STORE_PATH}\nURL: nar/abc.nar.zst\nCompression: zstd\n\
+             NarHash: {NAR_HASH}\nNarSize: 100\n\
+             References: 7xd5l2wzqd3dmvrp4pgmk2r7mrx0gsrh-hello-2.12.1 3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.38\n"
+        );
+        for signature in signatures {
+            narinfo.push_str(&format!("Sig: {signature}\n"));
+        }
+        narinfo
+    }
+
+    fn sign(key: &NixKeypair) -> String {
+        let fingerprint = fingerprint(
+            Path::new(STORE_PATH),
+            NAR_HASH,
+            100,
+            &[
+                PathBuf::from(STORE_PATH),
+                PathBuf::from("/nix/store/3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.38"),
+            ],
+        )
+        .unwrap();
+        key.sign(fingerprint.as_bytes())
+    }
+
+    #[test]
+    fn signers_are_verified() {
+        let trusted = NixKeypair::generate("trusted-1").unwrap();
+        let policy = policy(&trusted);
+
+        let subject = policy.narinfo_subject(&narinfo(&[sign(&trusted)])).unwrap();
+        assert_eq!(subject.signers, vec!["trusted-1".to_owned()]);
+        assert!(policy.allows(Operation::Substitute, Backend::Gha, &subject));
+
+        // Anybody can claim to be `trusted-1`.
+        let forger = NixKeypair::generate("trusted-1").unwrap();
+        let subject = policy.narinfo_subject(&narinfo(&[sign(&forger)])).unwrap();
+        assert!(subject.signers.is_empty());
+        assert!(!policy.allows(Operation::Substitute, Backend::Gha, &subject));
+
+        let other = NixKeypair::generate("other-1").unwrap();
+        let subject = policy.narinfo_subject(&narinfo(&[sign(&other)])).unwrap();
+        assert!(subject.signers.is_empty());
+
+        // A signature of another path doesn't count either.
+        let tampered = narinfo(&[sign(&trusted)]).replace("NarSize: 100", "NarSize: 101");
+        let subject = policy.narinfo_subject(&tampered).unwrap();
+        assert!(subject.signers.is_empty());
+    }
+
+    #[test]
+    fn signers_must_be_trusted_keys() {
+        let policy = r#"{ "rules": [{ "action": "allow", "signers": ["unknown-1"] }] }"#;
+        assert!(Policy::parse(policy.as_bytes()).is_err());
+
+        let policy = r#"{ "trusted_keys": ["not a key"] }"#;
+        assert!(Policy::parse(policy.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn rules_match_in_order() {
+        let policy = policy(&NixKeypair::generate("trusted-1").unwrap());
+        let subject = |name: &str, nar_size| Subject {
+            name: name.to_owned(),
+            nar_size,
+            ..Default::default()
+        };
+
+        assert!(policy.allows(
+            Operation::Push,
+            Backend::Gha,
+            &subject("hello-2.12.1", Some(100))
+        ));
+        assert!(!policy.allows(
+            Operation::Push,
+            Backend::FlakeHub,
+            &subject("tool-internal-1.0", Some(100))
+        ));
+        assert!(!policy.allows(
+            Operation::Push,
+            Backend::Gha,
+            &subject("hello-2.12.1", Some(1000))
+        ));
+
+        // An unknown size is not at least `min_size`.
+        assert!(policy.allows(
+            Operation::Push,
+            Backend::Gha,
+            &subject("hello-2.12.1", None)
+        ));
+    }
+
+    #[test]
+    fn sri_nar_hashes_are_converted() {
+        assert_eq!(
+            nar_hash_base32("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").as_deref(),
+            Some(NAR_HASH)
+        );
+        assert_eq!(nar_hash_base32(NAR_HASH).as_deref(), Some(NAR_HASH));
+        assert_eq!(nar_hash_base32("md5:abc"), None);
+    }
+}
@@ -0,0 +1,184 @@
+//! Preheating the narinfo caches.
+//!
+//! `POST /api/preheat` lets a workflow tell us early on what it's going to
+//! build. While earlier steps run, we walk the closure of those paths through
+//! the narinfos in the GitHub Actions Cache, so that by the time Nix asks for
+//! them the answers (positive or negative) are already cached.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use futures::stream::StreamExt as _;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+use crate::narinfo_cache::Lookup;
+use crate::State;
+
+/// How many narinfos to look up at once.
+const PARALLELISM: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PreheatRequest {
+    /// Flake installables whose outputs to preheat, e.g. `.#packages.x86_64-linux.default`.
+    #[serde(default)]
+    pub installables: Vec<String>,
+
+    /// Store paths to preheat.
+    #[serde(default)]
+    pub store_paths: Vec<PathBuf>,
+}
+
+/// Looks up the closures of the requested paths, filling the narinfo caches.
+pub async fn run(state: State, request: PreheatRequest) -> Result<()> {
+    if state.gha_cache.is_none() {
+        return Ok(());
+    }
+
+    let mut roots = request.store_paths;
+    for installable in &request.installables {
+        roots.extend(query_outputs(installable).await?);
+    }
+
+    let mut seen = HashSet::new();
+    let mut level: Vec<String> = roots
+        .iter()
+        .filter_map(|path| store_path_hash(path))
+        .map(ToOwned::to_owned)
+        .collect();
+    let mut missing = 0;
+
+    while !level.is_empty() {
+        level.retain(|hash| seen.insert(hash.clone()));
+
+        let lookups: Vec<_> = futures::stream::iter(level)
+            .map(|hash| {
+                let state = state.clone();
+                async move { lookup_references(&state, &hash).await }
+            })
+            .buffer_unordered(PARALLELISM)
+            .collect()
+            .await;
+
+        level = Vec::new();
+        for lookup in lookups {
+            match lookup {
+                Ok(Some(references)) => level.extend(references),
+                Ok(None) => missing += 1,
+                Err(err) => tracing::debug!("Failed to preheat a narinfo: {}", err),
+            }
+        }
+    }
+
+    tracing::info!(
+        "Preheated {} narinfos, {} of which aren't in the GitHub Actions Cache",
+        seen.len(),
+        missing
+    );
+
+    Ok(())
+}
+
+/// Looks up the narinfo of a path, returning the hashes of its references, or
+/// `None` if the GitHub Actions Cache doesn't have it.
+async fn lookup_references(state: &State, store_path_hash: &str) -> Result<Option<Vec<String>>> {
+    let Some(gha_cache) = &state.gha_cache else {
+        return Ok(None);
+    };
+
+    if state
+        .narinfo_negative_cache
+        .read()
+        .await
+        .contains(store_path_hash)
+    {
+        return Ok(None);
+    }
+
+    let url = match state.narinfo_cache.lookup(store_path_hash).await {
+        Lookup::Fresh(url) | Lookup::Stale(url) => url,
+        Lookup::Miss => {
+            let key = format!("{}.narinfo", store_path_hash);
+            match gha_cache.api.get_file_url(&[&key]).await? {
+                Some(url) => {
+                    state
+                        .narinfo_cache
+                        .insert(store_path_hash.to_owned(), url.clone())
+                        .await;
+                    state.metrics.narinfos_preheated.incr();
+                    url
+                }
+                None => {
+                    state
+                        .narinfo_negative_cache
+                        .write()
+                        .await
+                        .insert(store_path_hash.to_owned());
+                    return Ok(None);
+                }
+            }
+        }
+    };
+
+    let narinfo = crate::http_client::client()
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Error::Internal(format!("Downloading {store_path_hash}.narinfo: {e}")))?
+        .text()
+        .await
+        .map_err(|e| Error::Internal(format!("Downloading {store_path_hash}.narinfo: {e}")))?;
+
+    let references = narinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("References:"))
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(|reference| reference.split_once('-'))
+        .map(|(hash, _)| hash.to_owned())
+        .collect();
+
+    Ok(Some(references))
+}
+
+/// Evaluates a flake installable, returning its output paths.
+async fn query_outputs(installable: &str) -> Result<Vec<PathBuf>> {
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "derivation",
+            "show",
+        ])
+        .arg(installable)
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix derivation show".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Internal(format!(
+            "nix derivation show failed for {}: {}",
+            installable,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let derivations: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&output.stdout)
+            .map_err(|e| Error::Internal(format!("Parsing nix derivation show: {e}")))?;
+
+    // Content-addressed outputs don't have a path until they're built.
+    Ok(derivations
+        .values()
+        .filter_map(|derivation| derivation.get("outputs")?.as_object())
+        .flat_map(|outputs| outputs.values())
+        .filter_map(|output| Some(PathBuf::from(output.get("path")?.as_str()?)))
+        .collect())
+}
+
+fn store_path_hash(path: &Path) -> Option<&str> {
+    let (hash, _) = path.file_name()?.to_str()?.split_once('-')?;
+    Some(hash)
+}
@@ -0,0 +1,78 @@
+//! Giving up on paths that keep failing.
+//!
+//! A failed upload is retried when the path is enqueued again, e.g. by the
+//! store diff at workflow-finish. A path that fails `--quarantine-after` times
+//! (or keeps running into `--upload-timeout`) is quarantined instead: it isn't
+//! tried again for the rest of the job, and is reported at workflow-finish.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+struct Failures {
+    count: u32,
+    last_error: String,
+}
+
+pub struct Quarantine {
+    /// After how many failures a path is quarantined.
+    max_failures: u32,
+
+    failures: std::sync::Mutex<HashMap<PathBuf, Failures>>,
+}
+
+impl Quarantine {
+    pub fn new(max_failures: u32) -> Self {
+        Self {
+            max_failures,
+            failures: Default::default(),
+        }
+    }
+
+    /// Records a failed upload, returning whether the path is now quarantined.
+    pub fn record_failure(&self, store_path: &Path, error: String) -> bool {
+        let mut failures = self.lock();
+        let entry = failures.entry(store_path.to_owned()).or_insert(Failures {
+            count: 0,
+            last_error: String::new(),
+        });
+        entry.count += 1;
+        entry.last_error = error;
+
+        entry.count == self.max_failures
+    }
+
+    /// Forgets the failures of a path that was uploaded after all.
+    pub fn record_success(&self, store_path: &Path) {
+        self.lock().remove(store_path);
+    }
+
+    /// Whether the upload of a path failed before, but may be retried.
+    pub fn has_failed(&self, store_path: &Path) -> bool {
+        self.lock()
+            .get(store_path)
+            .is_some_and(|entry| entry.count < self.max_failures)
+    }
+
+    /// Whether a path failed too often to be tried again.
+    pub fn contains(&self, store_path: &Path) -> bool {
+        self.lock()
+            .get(store_path)
+            .is_some_and(|entry| entry.count >= self.max_failures)
+    }
+
+    /// The quarantined paths, with the error of their last attempt.
+    pub fn paths(&self) -> Vec<(PathBuf, String)> {
+        let mut paths: Vec<_> = self
+            .lock()
+            .iter()
+            .filter(|(_, entry)| entry.count >= self.max_failures)
+            .map(|(store_path, entry)| (store_path.clone(), entry.last_error.clone()))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Failures>> {
+        self.failures.lock().expect("quarantine mutex was poisoned")
+    }
+}
@@ -0,0 +1,232 @@
+//! Introspection of the upload queue.
+//!
+//! Keeps track of the store paths a backend still has to upload, so that
+//! `GET /api/queue` can tell why a path hasn't shown up in the cache yet.
+//!
+//! The queue also applies backpressure: once more than `--max-queued-uploads`
+//! paths are waiting, `--enqueue-overflow` decides whether new paths wait for
+//! room, are rejected, or push out the oldest queued paths.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::error::{Error, Result};
+
+/// What to do with new paths when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EnqueueOverflow {
+    /// Wait until there is room, which throttles the post-build hook and thus the build.
+    Block,
+
+    /// Refuse the new paths with HTTP 429.
+    Reject,
+
+    /// Drop the paths that have been queued the longest to make room.
+    DropOldest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UploadState {
+    /// Waiting for the worker to get to it.
+    Queued,
+
+    /// Being uploaded right now.
+    Uploading,
+}
+
+/// A store path that hasn't been uploaded yet.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueEntry {
+    pub store_path: PathBuf,
+    pub state: UploadState,
+
+    /// The size of the uncompressed NAR, once the upload has started.
+    pub nar_size: Option<u64>,
+
+    /// How long ago the path was queued.
+    pub queued_secs: u64,
+}
+
+struct Entry {
+    state: UploadState,
+    nar_size: Option<u64>,
+    queued_at: Instant,
+
+    /// Whether the path was handed to the pipeline.
+    claimed: bool,
+}
+
+pub struct UploadQueue {
+    entries: std::sync::Mutex<HashMap<PathBuf, Entry>>,
+
+    /// How many paths may be pending before `overflow` applies.
+    high_water_mark: usize,
+
+    overflow: EnqueueOverflow,
+
+    /// Woken whenever a path leaves the queue.
+    drained: Notify,
+}
+
+impl UploadQueue {
+    pub fn new(high_water_mark: usize, overflow: EnqueueOverflow) -> Self {
+        Self {
+            entries: Default::default(),
+            high_water_mark,
+            overflow,
+            drained: Notify::new(),
+        }
+    }
+
+    /// Makes room for `incoming` new paths, according to the overflow policy.
+    ///
+    /// Returns how many queued paths were dropped to do so.
+    pub async fn make_room(&self, incoming: usize) -> Result<usize> {
+        match self.overflow {
+            EnqueueOverflow::Block => {
+                let mut waited = false;
+                loop {
+                    // Registered before checking, so a path finishing in between isn't missed.
+                    let drained = self.drained.notified();
+                    if self.lock().len() < self.high_water_mark {
+                        return Ok(0);
+                    }
+                    if !waited {
+                        tracing::debug!("The upload queue is full, waiting for room");
+                        waited = true;
+                    }
+                    drained.await;
+                }
+            }
+            EnqueueOverflow::Reject => {
+                if self.lock().len() < self.high_water_mark {
+                    Ok(0)
+                } else {
+                    Err(Error::QueueFull)
+                }
+            }
+            EnqueueOverflow::DropOldest => {
+                let mut entries = self.lock();
+                let excess = (entries.len() + incoming).saturating_sub(self.high_water_mark);
+                if excess == 0 {
+                    return Ok(0);
+                }
+
+                // Uploads in flight are left alone.
+                let mut queued: Vec<_> = entries
+                    .iter()
+                    .filter(|(_, entry)| entry.state == UploadState::Queued && !entry.claimed)
+                    .map(|(store_path, entry)| (entry.queued_at, store_path.clone()))
+                    .collect();
+                queued.sort();
+
+                let dropped = excess.min(queued.len());
+                for (_, store_path) in queued.into_iter().take(dropped) {
+                    tracing::debug!(
+                        "The upload queue is full, dropping '{}'",
+                        store_path.display()
+                    );
+                    entries.remove(&store_path);
+                }
+
+                if dropped > 0 {
+                    self.drained.notify_waiters();
+                }
+
+                Ok(dropped)
+            }
+        }
+    }
+
+    /// Hands a queued path to the pipeline.
+    ///
+    /// Returns `false` if the path was dropped to make room, or was handed over already.
+    pub fn claim(&self, store_path: &Path) -> bool {
+        match self.lock().get_mut(store_path) {
+            Some(entry) if !entry.claimed => {
+                entry.claimed = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records that a path was queued. Paths that are already queued are left alone.
+    pub fn push(&self, store_path: PathBuf) {
+        self.lock().entry(store_path).or_insert_with(|| Entry {
+            state: UploadState::Queued,
+            nar_size: None,
+            queued_at: Instant::now(),
+            claimed: false,
+        });
+    }
+
+    /// Records that the upload of a path has started.
+    pub fn start(&self, store_path: &Path, nar_size: u64) {
+        let mut entries = self.lock();
+        let entry = entries
+            .entry(store_path.to_owned())
+            .or_insert_with(|| Entry {
+                state: UploadState::Queued,
+                nar_size: None,
+                queued_at: Instant::now(),
+                claimed: false,
+            });
+        entry.state = UploadState::Uploading;
+        entry.nar_size = Some(nar_size);
+    }
+
+    /// Forgets about a path, whether its upload succeeded, failed or was skipped.
+    pub fn finish(&self, store_path: &Path) {
+        if self.lock().remove(store_path).is_some() {
+            self.drained.notify_waiters();
+        }
+    }
+
+    /// Waits until none of `store_paths` is pending anymore.
+    pub async fn wait_for(&self, mut store_paths: HashSet<PathBuf>) {
+        loop {
+            let drained = self.drained.notified();
+            {
+                let entries = self.lock();
+                store_paths.retain(|store_path| entries.contains_key(store_path));
+            }
+            if store_paths.is_empty() {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    /// The paths that are still pending, uploads in flight first.
+    pub fn snapshot(&self) -> Vec<QueueEntry> {
+        let mut snapshot: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(store_path, entry)| QueueEntry {
+                store_path: store_path.clone(),
+                state: entry.state,
+                nar_size: entry.nar_size,
+                queued_secs: entry.queued_at.elapsed().as_secs(),
+            })
+            .collect();
+
+        snapshot.sort_by(|a, b| {
+            (a.state != UploadState::Uploading, &a.store_path)
+                .cmp(&(b.state != UploadState::Uploading, &b.store_path))
+        });
+
+        snapshot
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Entry>> {
+        self.entries
+            .lock()
+            .expect("upload queue mutex was poisoned")
+    }
+}
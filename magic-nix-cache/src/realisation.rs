@@ -0,0 +1,70 @@
+//! Realisations of content-addressed derivations.
+//!
+//! With `ca-derivations`, Nix can't compute the output paths of a derivation
+//! up front. It asks binary caches for the derivation's realisations instead,
+//! which they serve as `/realisations/<drv hash>!<output>.doi`.
+
+use std::path::Path;
+
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+
+/// A realisation, as Nix serializes it into a `.doi` file.
+pub struct Realisation {
+    /// The derivation output, e.g. `sha256:<hash>!out`.
+    pub id: String,
+
+    /// The whole realisation, uploaded as-is.
+    pub json: serde_json::Value,
+}
+
+impl Realisation {
+    /// The key under which the realisation is stored in the GitHub Actions Cache.
+    pub fn key(&self) -> String {
+        key(&format!("{}.doi", self.id))
+    }
+}
+
+/// Turns the file name of a `.doi` into its GitHub Actions Cache key.
+pub fn key(doi: &str) -> String {
+    format!("realisations/{doi}")
+}
+
+/// Asks the local Nix for the realisations of all outputs of `drv_path`.
+///
+/// Outputs that aren't content-addressed don't have realisations and are skipped.
+pub async fn query(drv_path: &Path) -> Result<Vec<Realisation>> {
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command ca-derivations",
+            "realisation",
+            "info",
+            "--json",
+        ])
+        .arg(format!("{}^*", drv_path.display()))
+        .output()
+        .await
+        .map_err(|e| Error::Io(e, "Running nix realisation info".to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::Internal(format!(
+            "nix realisation info failed for {}: {}",
+            drv_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::Internal(format!("Parsing nix realisation info: {e}")))?;
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|json| {
+            // Input-addressed outputs show up as `{"opaquePath": ...}`.
+            let id = json.get("id")?.as_str()?.to_owned();
+            Some(Realisation { id, json })
+        })
+        .collect())
+}
@@ -0,0 +1,105 @@
+//! Changing settings without a restart.
+//!
+//! Restarting a long-lived daemon on a self-hosted runner throws away its
+//! upload queue. Instead, on SIGHUP or `POST /api/reload`, the daemon parses
+//! its command line and `--config-file` again, and applies the settings that
+//! can change while it runs:
+//! - the push filters (`--push-systems`, `--skip-fixed-output` and
+//!   `--push-fixed-output`);
+//! - `--remote-rate-limit`;
+//! - `--compression-workers` and `--gha-upload-jobs`;
+//! - `--log-filter`.
+//!
+//! Changes to other settings take effect on the next start.
+
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use clap::Parser as _;
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::Registry;
+
+use crate::env::Environment;
+use crate::error::{Error, Result};
+use crate::remote::RemoteAccess;
+use crate::{Args, Backend, State};
+
+/// Swaps the filter of the log subscriber, once logging is set up.
+pub static LOG_FILTER: OnceLock<Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// What a reload needs besides the rest of the state.
+#[derive(Default)]
+pub struct Reloader {
+    /// Where to read arguments from, if anywhere.
+    pub config_file: Option<PathBuf>,
+
+    /// The remote listener's access control, once it is up.
+    pub remote_access: OnceLock<Arc<RemoteAccess>>,
+}
+
+/// Reads the configuration again and applies what can change at runtime.
+pub async fn reload(state: &State) -> Result<()> {
+    let argv = match &state.reloader.config_file {
+        Some(config_file) => crate::config::args_with_file(config_file)?,
+        None => std::env::args_os().collect(),
+    };
+    let args = Args::try_parse_from(argv).map_err(|e| Error::Config(e.to_string()))?;
+    args.validate(Environment::determine())?;
+
+    *state
+        .push_filter
+        .write()
+        .expect("push filter lock was poisoned") = args.push_filter();
+
+    if let Some(remote_access) = state.reloader.remote_access.get() {
+        remote_access.set_rate_limit(args.remote_rate_limit);
+    }
+
+    if let Some(gha_cache) = &state.gha_cache {
+        gha_cache
+            .resize(args.compression_workers, args.upload_jobs(Backend::Gha))
+            .await?;
+    }
+
+    set_log_filter(args.log_filter.as_deref())?;
+
+    tracing::info!("Reloaded the configuration");
+
+    Ok(())
+}
+
+/// Filters the log with `directives`, or the default filter if `None`.
+pub fn set_log_filter(directives: Option<&str>) -> Result<()> {
+    let filter = match directives {
+        Some(directives) => EnvFilter::try_new(directives)
+            .map_err(|e| Error::Config(format!("invalid --log-filter: {e}")))?,
+        None => crate::default_log_filter(),
+    };
+
+    if let Some(handle) = LOG_FILTER.get() {
+        handle
+            .reload(filter)
+            .map_err(|e| Error::Internal(format!("Changing the log filter: {e}")))?;
+    }
+
+    Ok(())
+}
+
+/// Reloads the configuration whenever we get a SIGHUP.
+#[cfg(unix)]
+pub fn spawn_sighup_handler(state: State) -> Result<()> {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(|e| Error::Io(e, "Listening for SIGHUP".to_owned()))?;
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            tracing::info!("Got SIGHUP, reloading the configuration");
+            if let Err(err) = reload(&state).await {
+                tracing::warn!("Failed to reload the configuration: {}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! Serving the binary cache to other machines.
+//!
+//! The remote listener only exposes the read side of the binary cache. Clients
+//! authenticate with a shared token, either as a bearer token or as the password
+//! of HTTP basic auth (which is what Nix sends for `netrc` entries), and are rate
+//! limited per IP address.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, Extension, Request},
+    http::{header::AUTHORIZATION, Method},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use base64::Engine as _;
+
+use crate::error::{Error, Result};
+use crate::util::constant_time_eq;
+
+pub struct RemoteAccess {
+    /// The token clients need to present.
+    token: String,
+
+    /// Per-client request budget.
+    rate_limiter: RateLimiter,
+}
+
+impl RemoteAccess {
+    /// Reads the access token from `token_file` and allows each client
+    /// `requests_per_second` requests (0 means unlimited).
+    pub async fn load(token_file: &Path, requests_per_second: u32) -> Result<Self> {
+        let token = tokio::fs::read_to_string(token_file)
+            .await
+            .map_err(|e| Error::Io(e, format!("Reading {}", token_file.display())))?
+            .trim()
+            .to_owned();
+
+        if token.is_empty() {
+            return Err(Error::Config(format!(
+                "the remote access token in {} is empty",
+                token_file.display()
+            )));
+        }
+
+        Ok(Self {
+            token,
+            rate_limiter: RateLimiter::new(requests_per_second),
+        })
+    }
+
+    /// Allows each client `requests_per_second` requests from now on (0 means unlimited).
+    pub fn set_rate_limit(&self, requests_per_second: u32) {
+        self.rate_limiter
+            .requests_per_second
+            .store(requests_per_second, Ordering::Relaxed);
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let Some(header) = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+        else {
+            return false;
+        };
+
+        if let Some(token) = header.strip_prefix("Bearer ") {
+            return constant_time_eq(token.as_bytes(), self.token.as_bytes());
+        }
+
+        if let Some(credentials) = header.strip_prefix("Basic ") {
+            let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(credentials) else {
+                return false;
+            };
+
+            // The login is ignored, only the password has to match.
+            return match decoded.iter().position(|&b| b == b':') {
+                Some(colon) => constant_time_eq(&decoded[colon + 1..], self.token.as_bytes()),
+                None => false,
+            };
+        }
+
+        false
+    }
+}
+
+pub fn get_router(remote: Arc<RemoteAccess>) -> Router {
+    crate::binary_cache::get_router()
+        .layer(middleware::from_fn(authorize))
+        .layer(Extension(remote))
+}
+
+async fn authorize(
+    Extension(remote): Extension<Arc<RemoteAccess>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return Err(Error::MethodNotAllowed);
+    }
+
+    // Before authenticating, so that guessing the token is rate limited too.
+    if !remote.rate_limiter.check(client.ip()) {
+        tracing::debug!("Rate limiting {}", client);
+        return Err(Error::RateLimited);
+    }
+
+    if !remote.is_authorized(&request) {
+        tracing::debug!("Rejecting unauthorized request from {}", client);
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// How many clients we keep buckets for before dropping the idle ones.
+const MAX_BUCKETS: usize = 4096;
+
+/// How long it takes an empty bucket to fill up again, after which it is no
+/// different from a new one.
+const REFILL_TIME: Duration = Duration::from_secs(1);
+
+/// A token bucket per client, refilled at `requests_per_second` tokens per second.
+struct RateLimiter {
+    requests_per_second: AtomicU32,
+    buckets: std::sync::Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_client: HashMap<IpAddr, Bucket>,
+
+    /// When idle buckets were last dropped.
+    pruned: Option<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: u32) -> Self {
+        Self {
+            requests_per_second: AtomicU32::new(requests_per_second),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token from the client's bucket, returning whether the request may proceed.
+    fn check(&self, client: IpAddr) -> bool {
+        let rate = f64::from(self.requests_per_second.load(Ordering::Relaxed));
+        if rate == 0.0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .expect("rate limiter mutex was poisoned");
+
+        if buckets.by_client.len() >= MAX_BUCKETS
+            && buckets
+                .pruned
+                .map_or(true, |pruned| now.duration_since(pruned) >= REFILL_TIME)
+        {
+            buckets
+                .by_client
+                .retain(|_, bucket| now.duration_since(bucket.updated) < REFILL_TIME);
+            buckets.pruned = Some(now);
+        }
+
+        let bucket = buckets.by_client.entry(client).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Resuming uploads an earlier daemon didn't finish.
+//!
+//! When the runner is killed while the daemon flushes its queue at the end of
+//! a workflow, the paths that were still waiting are never uploaded. On
+//! persistent runners they are still in the store though, so we keep a journal
+//! of the pending paths on disk while uploading, and the next daemon queues
+//! them before it serves anything else. The journal is removed once the queue
+//! is drained.
+//!
+//! Like the dedupe index, journals are kept per scope, so that a job never
+//! resumes the uploads of another repository or `--cache-version`. A daemon
+//! holds a lock on the journal of its scope while it runs, and one that finds
+//! it taken by another daemon on the same machine does without.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+
+/// How often the journal is brought up to date.
+pub const WRITE_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Journal {
+    path: PathBuf,
+
+    /// What we last wrote, to skip writes when nothing changed.
+    written: tokio::sync::Mutex<Option<BTreeSet<PathBuf>>>,
+
+    /// Held for as long as we use the journal.
+    _lock: std::fs::File,
+}
+
+impl Journal {
+    /// Opens the journal of `scope` next to `path`, or returns `None` if it
+    /// can't be locked.
+    pub fn open(path: &Path, scope: &str) -> Option<Self> {
+        let path = scoped_path(path, scope);
+
+        match lock(&path) {
+            Ok(lock) => Some(Self {
+                path,
+                written: Default::default(),
+                _lock: lock,
+            }),
+            Err(err) => {
+                tracing::warn!(
+                    "Not recording the pending uploads in {}: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Reads the paths an earlier daemon didn't upload, leaving out those that
+    /// are gone from the store.
+    pub async fn load(&self) -> Vec<PathBuf> {
+        let pending = match read_paths(&self.path).await {
+            Ok(pending) => pending,
+            Err(err) => {
+                tracing::warn!(
+                    "Ignoring the pending uploads at {}: {}",
+                    self.path.display(),
+                    err
+                );
+                return Vec::new();
+            }
+        };
+
+        pending.into_iter().filter(|path| path.exists()).collect()
+    }
+
+    /// Records the paths that are still to be uploaded.
+    pub async fn write(&self, pending: BTreeSet<PathBuf>) -> Result<()> {
+        let mut written = self.written.lock().await;
+        if written.as_ref() == Some(&pending) {
+            return Ok(());
+        }
+
+        if pending.is_empty() {
+            self.remove().await?;
+        } else {
+            let serialized = serde_json::to_vec(&pending)
+                .map_err(|e| Error::Internal(format!("Serializing the pending uploads: {e}")))?;
+
+            if let Some(parent) = self.path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| Error::Io(e, format!("Creating {}", parent.display())))?;
+            }
+
+            // Write next to the real file and rename, so a killed runner never leaves a truncated journal.
+            let path_tmp = self.path.with_extension("tmp");
+            tokio::fs::write(&path_tmp, serialized)
+                .await
+                .map_err(|e| Error::Io(e, format!("Writing {}", path_tmp.display())))?;
+            tokio::fs::rename(&path_tmp, &self.path)
+                .await
+                .map_err(|e| Error::Io(e, format!("Renaming {}", path_tmp.display())))?;
+        }
+
+        *written = Some(pending);
+
+        Ok(())
+    }
+
+    async fn remove(&self) -> Result<()> {
+        match tokio::fs::remove_file(&self.path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::Io(e, format!("Removing {}", self.path.display()))),
+        }
+    }
+}
+
+/// Where the journal of `scope` is kept, e.g. `pending-uploads-<hash>.json` for `pending-uploads.json`.
+fn scoped_path(path: &Path, scope: &str) -> PathBuf {
+    let hash = format!("{:x}", Sha256::digest(scope.as_bytes()));
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut file_name = format!("{stem}-{}", &hash[..16]);
+    if let Some(extension) = path.extension() {
+        file_name.push('.');
+        file_name.push_str(&extension.to_string_lossy());
+    }
+
+    path.with_file_name(file_name)
+}
+
+/// Takes the lock on the journal at `path`, which is released when the returned file is closed,
+/// including when the daemon is killed.
+#[cfg(unix)]
+fn lock(path: &Path) -> Result<std::fs::File> {
+    use std::os::fd::AsRawFd as _;
+
+    let file = open_lock_file(path)?;
+
+    nix::fcntl::flock(
+        file.as_raw_fd(),
+        nix::fcntl::FlockArg::LockExclusiveNonblock,
+    )
+    .map_err(|e| match e {
+        nix::errno::Errno::EWOULDBLOCK => Error::Internal("another daemon is using it".to_owned()),
+        e => Error::Io(e.into(), format!("Locking {}", path.display())),
+    })?;
+
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn lock(path: &Path) -> Result<std::fs::File> {
+    open_lock_file(path)
+}
+
+fn open_lock_file(path: &Path) -> Result<std::fs::File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| Error::Io(e, format!("Creating {}", parent.display())))?;
+    }
+
+    let lock_path = path.with_extension("lock");
+    std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| Error::Io(e, format!("Opening {}", lock_path.display())))
+}
+
+async fn read_paths(path: &Path) -> Result<Vec<PathBuf>> {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::Io(e, format!("Reading {}", path.display()))),
+    };
+
+    serde_json::from_slice(&contents)
+        .map_err(|e| Error::Internal(format!("Parsing the pending uploads: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journals_are_scoped() {
+        let path = Path::new("/state/magic-nix-cache/pending-uploads.json");
+
+        let a = scoped_path(path, "owner/repo\0v1");
+        let b = scoped_path(path, "owner/repo\0v2");
+        assert_ne!(a, b);
+        assert_eq!(a, scoped_path(path, "owner/repo\0v1"));
+
+        assert_eq!(a.parent(), path.parent());
+        let file_name = a.file_name().unwrap().to_string_lossy();
+        assert!(file_name.starts_with("pending-uploads-"));
+        assert!(file_name.ends_with(".json"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn journals_are_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pending-uploads.json");
+
+        let journal = Journal::open(&path, "a").unwrap();
+        assert!(Journal::open(&path, "a").is_none());
+        assert!(Journal::open(&path, "b").is_some());
+
+        drop(journal);
+        assert!(Journal::open(&path, "a").is_some());
+    }
+
+    #[tokio::test]
+    async fn pending_paths_are_written_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pending-uploads.json");
+
+        let present = dir.path().join("present");
+        std::fs::write(&present, "").unwrap();
+        let gone = dir.path().join("gone");
+
+        let journal = Journal::open(&path, "a").unwrap();
+        journal
+            .write(BTreeSet::from([present.clone(), gone]))
+            .await
+            .unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path, "a").unwrap();
+        assert_eq!(journal.load().await, vec![present]);
+        assert!(Journal::open(&path, "b").unwrap().load().await.is_empty());
+
+        journal.write(BTreeSet::new()).await.unwrap();
+        assert!(!scoped_path(&path, "a").exists());
+    }
+}
@@ -0,0 +1,441 @@
+//! Keeping credentials out of the cache.
+//!
+//! Build sandboxes occasionally leak files like `.env` into their outputs, and
+//! pushing those to a cache that other jobs and forks read from is a breach.
+//! With `--secret-scan`, the NAR of each path is scanned for things that look
+//! like credentials before it's pushed to any backend. `warn` only logs what was
+//! found; `block` also keeps the path out of the cache.
+//!
+//! Every path of a closure is scanned, by the backend's upload workers right
+//! before the upload, so enqueueing never waits for a scan. Each path is only
+//! scanned once, however many backends push it.
+//!
+//! The rules are deliberately simple: well-known token formats, private key
+//! headers, and random-looking values assigned to names like `password`. More
+//! can be added with `--secret-scan-pattern`.
+
+use std::collections::HashMap;
+
+use attic::nix_store::{NixStore, StorePath};
+use futures::stream::TryStreamExt as _;
+use regex::bytes::Regex;
+
+use crate::error::{Error, Result};
+use crate::telemetry;
+
+/// How much of the previous chunk of a NAR is scanned again, so that a secret
+/// split across two chunks is still found.
+const OVERLAP: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SecretScan {
+    /// Don't scan.
+    Off,
+
+    /// Log the paths that look like they contain secrets, but push them.
+    Warn,
+
+    /// Don't push the paths that look like they contain secrets.
+    Block,
+}
+
+/// Something that looks like a credential.
+pub trait Rule: Send + Sync {
+    /// The name of the rule, which is logged instead of what it matched.
+    fn name(&self) -> &str;
+
+    /// Whether `haystack` contains something this rule is looking for.
+    fn is_match(&self, haystack: &[u8]) -> bool;
+}
+
+/// A rule matching a regular expression.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    name: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn new(name: &str, regex: &str) -> std::result::Result<Self, regex::Error> {
+        Ok(Self {
+            name: name.to_owned(),
+            regex: Regex::new(regex)?,
+        })
+    }
+
+    /// Parses a `--secret-scan-pattern` of the form `NAME=REGEX`.
+    pub fn parse(arg: &str) -> std::result::Result<Self, String> {
+        let (name, regex) = arg
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=REGEX, got '{arg}'"))?;
+
+        Self::new(name, regex).map_err(|e| format!("invalid pattern for rule '{name}': {e}"))
+    }
+}
+
+impl Rule for Pattern {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_match(&self, haystack: &[u8]) -> bool {
+        self.regex.is_match(haystack)
+    }
+}
+
+/// Random-looking values assigned to names like `password` or `api_key`.
+///
+/// Looking at the entropy of the value keeps placeholders such as
+/// `password = "changeme"` and references such as `token: ${TOKEN}` from
+/// matching.
+struct HighEntropyAssignment {
+    regex: Regex,
+
+    /// In bits per byte.
+    min_entropy: f64,
+}
+
+impl HighEntropyAssignment {
+    fn new() -> Self {
+        Self {
+            regex: Regex::new(
+                r#"(?i)(?:secret|token|password|passwd|api_?key|access_?key)["']?\s*[:=]\s*["']?([A-Za-z0-9+/_=-]{20,})"#,
+            )
+            .expect("the high entropy assignment pattern is valid"),
+            min_entropy: 4.0,
+        }
+    }
+}
+
+impl Rule for HighEntropyAssignment {
+    fn name(&self) -> &str {
+        "high-entropy-assignment"
+    }
+
+    fn is_match(&self, haystack: &[u8]) -> bool {
+        self.regex
+            .captures_iter(haystack)
+            .filter_map(|captures| captures.get(1))
+            .any(|value| entropy(value.as_bytes()) >= self.min_entropy)
+    }
+}
+
+/// The Shannon entropy of `bytes`, in bits per byte.
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for b in bytes {
+        counts[*b as usize] += 1;
+    }
+
+    let len = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// The rules that are always checked.
+fn builtin_rules() -> Vec<Box<dyn Rule>> {
+    let patterns = [
+        ("aws-access-key-id", r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b"),
+        (
+            "private-key",
+            r"-----BEGIN (?:(?:RSA|DSA|EC|OPENSSH|ENCRYPTED|PGP) )?PRIVATE KEY(?: BLOCK)?-----",
+        ),
+        ("github-token", r"\bgh[pousr]_[A-Za-z0-9]{36}\b"),
+        ("slack-token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+    ];
+
+    let mut rules: Vec<Box<dyn Rule>> = patterns
+        .into_iter()
+        .map(|(name, regex)| {
+            Box::new(Pattern::new(name, regex).expect("the built-in patterns are valid"))
+                as Box<dyn Rule>
+        })
+        .collect();
+    rules.push(Box::new(HighEntropyAssignment::new()));
+
+    rules
+}
+
+/// Decides which store paths are safe to push.
+pub struct SecretScanner {
+    mode: SecretScan,
+    rules: Vec<Box<dyn Rule>>,
+
+    /// Whether each path that was scanned may be pushed, by store path hash.
+    verdicts: std::sync::Mutex<HashMap<String, bool>>,
+}
+
+impl Default for SecretScanner {
+    fn default() -> Self {
+        Self {
+            mode: SecretScan::Off,
+            rules: Vec::new(),
+            verdicts: Default::default(),
+        }
+    }
+}
+
+impl std::fmt::Debug for SecretScanner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretScanner")
+            .field("mode", &self.mode)
+            .field(
+                "rules",
+                &self.rules.iter().map(|r| r.name()).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl SecretScanner {
+    pub fn new(mode: SecretScan, extra_rules: Vec<Box<dyn Rule>>) -> Self {
+        let mut rules = builtin_rules();
+        rules.extend(extra_rules);
+
+        Self {
+            mode,
+            rules,
+            verdicts: Default::default(),
+        }
+    }
+
+    /// Whether `store_path` may be pushed, scanning it the first time a backend asks.
+    pub async fn allows(
+        &self,
+        store: &NixStore,
+        metrics: &telemetry::TelemetryReport,
+        store_path: &StorePath,
+    ) -> bool {
+        if self.mode == SecretScan::Off {
+            return true;
+        }
+
+        let store_path_hash = store_path.to_hash().as_str().to_owned();
+        if let Some(allowed) = self.lock_verdicts().get(&store_path_hash) {
+            return *allowed;
+        }
+
+        let allowed = self.check(store, metrics, store_path).await;
+        self.lock_verdicts().insert(store_path_hash, allowed);
+
+        allowed
+    }
+
+    async fn check(
+        &self,
+        store: &NixStore,
+        metrics: &telemetry::TelemetryReport,
+        store_path: &StorePath,
+    ) -> bool {
+        let full_path = store.get_full_path(store_path);
+
+        match self.scan(store, store_path).await {
+            Ok(None) => return true,
+            Ok(Some(rule)) => {
+                metrics.paths_with_secrets.incr();
+                tracing::warn!(
+                    "'{}' looks like it contains a secret (matched rule '{}')",
+                    full_path.display(),
+                    rule
+                );
+            }
+            // Don't push what we couldn't look at when blocking.
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to scan '{}' for secrets: {}",
+                    full_path.display(),
+                    err
+                );
+            }
+        }
+
+        if self.mode == SecretScan::Block {
+            tracing::warn!("Not pushing '{}'", full_path.display());
+            metrics.paths_blocked_secrets.incr();
+            false
+        } else {
+            true
+        }
+    }
+
+    fn lock_verdicts(&self) -> std::sync::MutexGuard<'_, HashMap<String, bool>> {
+        self.verdicts
+            .lock()
+            .expect("secret scan verdicts mutex was poisoned")
+    }
+
+    /// Returns the name of the first rule that matches the NAR of `path`, if any.
+    async fn scan(&self, store: &NixStore, path: &StorePath) -> Result<Option<String>> {
+        let mut nar_stream = store.nar_from_path(path.clone());
+        let mut window = Window::new(&self.rules);
+
+        while let Some(chunk) = nar_stream.try_next().await.map_err(|e| {
+            Error::Internal(format!(
+                "Reading the NAR of {}: {e}",
+                store.get_full_path(path).display()
+            ))
+        })? {
+            if let Some(rule) = window.push(chunk.as_ref()) {
+                return Ok(Some(rule.to_owned()));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// The end of what was scanned so far, which the next chunk is appended to.
+struct Window<'a> {
+    rules: &'a [Box<dyn Rule>],
+    bytes: Vec<u8>,
+}
+
+impl<'a> Window<'a> {
+    fn new(rules: &'a [Box<dyn Rule>]) -> Self {
+        Self {
+            rules,
+            bytes: Vec::new(),
+        }
+    }
+
+    /// Scans `chunk`, returning the name of the first rule that matches.
+    fn push(&mut self, chunk: &[u8]) -> Option<&'a str> {
+        self.bytes.extend_from_slice(chunk);
+
+        if let Some(rule) = self.rules.iter().find(|rule| rule.is_match(&self.bytes)) {
+            return Some(rule.name());
+        }
+
+        self.bytes.drain(..self.bytes.len().saturating_sub(OVERLAP));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scanner() -> SecretScanner {
+        SecretScanner::new(SecretScan::Block, Vec::new())
+    }
+
+    fn matching_rule(scanner: &SecretScanner, haystack: &str) -> Option<String> {
+        Window::new(&scanner.rules)
+            .push(haystack.as_bytes())
+            .map(ToOwned::to_owned)
+    }
+
+    // The secrets are put together at runtime, so that this file doesn't look
+    // like it leaks any.
+    fn github_token() -> String {
+        format!("gh{}_{}", "p", "aB3dE5fG7h".repeat(4).split_at(36).0)
+    }
+
+    #[test]
+    fn builtin_rules_match() {
+        let scanner = scanner();
+
+        let cases = [
+            (
+                format!("aws_access_key_id = AKIA{}", "IOSFODNN7EXAMPLE"),
+                "aws-access-key-id",
+            ),
+            (
+                format!("-----BEGIN OPENSSH {} KEY-----", "PRIVATE"),
+                "private-key",
+            ),
+            (format!("-----BEGIN {} KEY-----", "PRIVATE"), "private-key"),
+            (format!("GITHUB_TOKEN={}", github_token()), "github-token"),
+            (
+                format!("SLACK={}-1234567890-abcdefghij", "xoxb"),
+                "slack-token",
+            ),
+            (
+                format!("password = \"{}\"", "aB3dE5fG7hJ9kL1mN2pQ4rS6tU8vW0x"),
+                "high-entropy-assignment",
+            ),
+            (
+                format!("\"api_key\": \"{}\"", "Zq8/Xw2+Rt5_Yu7-Ii9=Oo1Pp3Aa"),
+                "high-entropy-assignment",
+            ),
+        ];
+
+        for (haystack, rule) in cases {
+            assert_eq!(
+                matching_rule(&scanner, &haystack).as_deref(),
+                Some(rule),
+                "{haystack}"
+            );
+        }
+    }
+
+    #[test]
+    fn placeholders_dont_match() {
+        let scanner = scanner();
+
+        let haystacks = [
+            "password = \"changeme\"",
+            "token: ${GITHUB_TOKEN}",
+            "secret = \"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx\"",
+            "api_key = \"abababababababababababababababab\"",
+            "public key: -----BEGIN PUBLIC KEY-----",
+            "AKIA is how AWS access keys start",
+            "gh_ and ghp_ are prefixes of GitHub tokens",
+        ];
+
+        for haystack in haystacks {
+            assert_eq!(matching_rule(&scanner, haystack), None, "{haystack}");
+        }
+    }
+
+    #[test]
+    fn secrets_split_across_chunks_match() {
+        let scanner = scanner();
+        let token = github_token();
+        let (head, tail) = token.split_at(10);
+
+        let mut window = Window::new(&scanner.rules);
+        let padding = " ".repeat(3 * OVERLAP);
+
+        assert_eq!(window.push(format!("{padding}{head}").as_bytes()), None);
+        assert_eq!(
+            window.push(format!("{tail}{padding}").as_bytes()),
+            Some("github-token")
+        );
+    }
+
+    #[test]
+    fn window_only_keeps_the_overlap() {
+        let scanner = scanner();
+        let mut window = Window::new(&scanner.rules);
+
+        assert_eq!(window.push("x".repeat(3 * OVERLAP).as_bytes()), None);
+        assert_eq!(window.bytes.len(), OVERLAP);
+    }
+
+    #[test]
+    fn extra_patterns_are_checked() {
+        let pattern = Pattern::parse(r"internal-token=\bitk_[0-9a-f]{8}\b").unwrap();
+        let scanner = SecretScanner::new(SecretScan::Warn, vec![Box::new(pattern)]);
+
+        assert_eq!(
+            matching_rule(&scanner, "TOKEN=itk_0123abcd").as_deref(),
+            Some("internal-token")
+        );
+        assert!(Pattern::parse("no-equals-sign").is_err());
+        assert!(Pattern::parse("broken=(").is_err());
+    }
+
+    #[test]
+    fn entropy_is_in_bits_per_byte() {
+        assert_eq!(entropy(b"aaaa"), 0.0);
+        assert_eq!(entropy(b"abab"), 1.0);
+        assert_eq!(entropy(b"abcd"), 2.0);
+    }
+}
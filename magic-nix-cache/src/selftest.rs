@@ -0,0 +1,180 @@
+//! Startup self-test.
+//!
+//! Pushes a tiny store path to the configured backends and reads its narinfo
+//! back, so that broken credentials or networking are reported at startup
+//! rather than at the first real push.
+
+use std::path::PathBuf;
+
+use attic::nix_store::StorePath;
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::error::{Error, ErrorKind};
+use crate::State;
+
+/// The contents of the synthetic store path. Keeping them fixed means every
+/// run tests the same store path instead of littering the store.
+const SELFTEST_CONTENTS: &str = "magic-nix-cache self-test\n";
+
+#[derive(Error, Debug)]
+pub enum SelfTestError {
+    #[error("self-test failed: the local Nix store is not usable: {0}")]
+    Store(String),
+
+    #[error("self-test failed: the cache rejected our credentials: {0}")]
+    Auth(String),
+
+    #[error("self-test failed: cannot reach the cache: {0}")]
+    Network(String),
+
+    #[error("self-test failed: the cache returned something we didn't upload: {0}")]
+    Verification(String),
+
+    #[error("self-test failed: {0}")]
+    Other(String),
+}
+
+impl From<Error> for SelfTestError {
+    fn from(err: Error) -> Self {
+        use gha_cache::api::Error as ApiError;
+
+        match &err {
+            Error::Api(ApiError::ApiError { status, .. })
+                if *status == reqwest::StatusCode::UNAUTHORIZED
+                    || *status == reqwest::StatusCode::FORBIDDEN =>
+            {
+                Self::Auth(err.to_string())
+            }
+            Error::Api(ApiError::RequestError(_)) | Error::FlakeHubHttp(_) => {
+                Self::Network(err.to_string())
+            }
+            Error::Attic(_) | Error::Io(..) => Self::Store(err.to_string()),
+            _ => Self::Other(err.to_string()),
+        }
+    }
+}
+
+impl SelfTestError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Store(_) => ErrorKind::Store,
+            Self::Auth(_) => ErrorKind::AuthFailed,
+            Self::Network(_) => ErrorKind::Network,
+            Self::Verification(_) | Self::Other(_) => ErrorKind::Internal,
+        }
+    }
+}
+
+pub async fn run(state: &State) -> Result<(), SelfTestError> {
+    let Some(gha_cache) = &state.gha_cache else {
+        tracing::info!("Self-test: no backend that supports it is enabled, skipping");
+        return Ok(());
+    };
+
+    let full_path = add_synthetic_path().await?;
+    let store_path = state
+        .store
+        .follow_store_path(&full_path)
+        .map_err(Error::Attic)?;
+
+    tracing::info!("Self-test: pushing {}", full_path.display());
+    gha_cache
+        .upload_now(state.store.clone(), &store_path)
+        .await?;
+
+    tracing::info!("Self-test: fetching the narinfo back");
+    let key = format!("{}.narinfo", store_path.to_hash().as_str());
+    let url = gha_cache
+        .api
+        .get_file_url(&[&key])
+        .await
+        .map_err(Error::Api)?
+        .ok_or_else(|| SelfTestError::Verification(format!("{key} is missing after upload")))?;
+
+    let narinfo = crate::http_client::client()
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| SelfTestError::Network(e.to_string()))?
+        .text()
+        .await
+        .map_err(|e| SelfTestError::Network(e.to_string()))?;
+
+    verify_narinfo(state, &store_path, &narinfo).await?;
+
+    tracing::info!("Self-test passed");
+
+    Ok(())
+}
+
+/// Adds the synthetic path to the local store, returning its full path.
+async fn add_synthetic_path() -> Result<PathBuf, SelfTestError> {
+    let dir = tempfile::tempdir().map_err(|e| SelfTestError::Store(e.to_string()))?;
+    let file = dir.path().join("magic-nix-cache-selftest");
+    tokio::fs::write(&file, SELFTEST_CONTENTS)
+        .await
+        .map_err(|e| SelfTestError::Store(e.to_string()))?;
+
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "store",
+            "add-file",
+        ])
+        .arg(&file)
+        .output()
+        .await
+        .map_err(|e| SelfTestError::Store(format!("running nix: {e}")))?;
+
+    if !output.status.success() {
+        return Err(SelfTestError::Store(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim(),
+    ))
+}
+
+async fn verify_narinfo(
+    state: &State,
+    store_path: &StorePath,
+    narinfo: &str,
+) -> Result<(), SelfTestError> {
+    let path_info = state
+        .store
+        .query_path_info(store_path.clone())
+        .await
+        .map_err(Error::Attic)?;
+
+    let field = |name: &str| {
+        narinfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+            .map(str::trim)
+    };
+
+    let expected_store_path = state.store.get_full_path(store_path);
+    if field("StorePath") != expected_store_path.to_str() {
+        return Err(SelfTestError::Verification(format!(
+            "expected StorePath {}, got {:?}",
+            expected_store_path.display(),
+            field("StorePath")
+        )));
+    }
+
+    let expected_nar_size = path_info.nar_size.to_string();
+    if field("NarSize") != Some(expected_nar_size.as_str()) {
+        return Err(SelfTestError::Verification(format!(
+            "expected NarSize {}, got {:?}",
+            expected_nar_size,
+            field("NarSize")
+        )));
+    }
+
+    Ok(())
+}
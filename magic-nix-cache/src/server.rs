@@ -0,0 +1,260 @@
+//! Embedding the binary cache in other programs.
+//!
+//! The daemon sets everything up from its command line. Programs that want to
+//! serve the GitHub Actions Cache as a binary cache themselves use a
+//! [`ServerBuilder`] instead, which leaves nix.conf, post-build hooks and
+//! listening on sockets to the caller. FlakeHub isn't available this way.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use attic::nix_store::NixStore;
+use axum::{extract::Extension, middleware, routing::get, Router};
+use gha_cache::Credentials;
+use tokio::sync::RwLock;
+
+use crate::error::{Error, Result};
+use crate::queue::{EnqueueOverflow, QueueEntry};
+use crate::{
+    api, backends, binary_cache, events, flakehub_proxy, gha, index, narinfo_cache, telemetry,
+    tenants, watchdog,
+};
+use crate::{Backend, State, StateInner};
+
+/// The routes served by the daemon, without the state.
+pub(crate) fn routes() -> Router {
+    let routes = Router::new()
+        .route("/", get(root))
+        .merge(api::get_router())
+        .merge(binary_cache::get_router())
+        .merge(flakehub_proxy::get_router());
+
+    Router::new()
+        .nest(
+            tenants::SCOPE_ROUTE,
+            routes
+                .clone()
+                .layer(middleware::from_fn(tenants::select_by_path)),
+        )
+        .merge(routes)
+        .layer(middleware::from_fn(tenants::select))
+}
+
+async fn root() -> &'static str {
+    "cache the world 🚀"
+}
+
+/// Configures a [`Server`].
+pub struct ServerBuilder {
+    credentials: Credentials,
+    namespace: gha::CacheNamespace,
+    upstream: Option<String>,
+    index_file: Option<PathBuf>,
+    substituter_priority: u32,
+    upload_jobs: usize,
+    max_queued_uploads: usize,
+    enqueue_overflow: EnqueueOverflow,
+}
+
+impl ServerBuilder {
+    /// Starts configuring a server for the GitHub Actions Cache that `credentials` give access to.
+    pub fn new(credentials: Credentials) -> Self {
+        Self {
+            credentials,
+            namespace: gha::CacheNamespace::default(),
+            upstream: None,
+            index_file: None,
+            substituter_priority: 41,
+            upload_jobs: 4,
+            max_queued_uploads: 10_000,
+            enqueue_overflow: EnqueueOverflow::Block,
+        }
+    }
+
+    /// Keeps the entries of this server apart from those written with other versions.
+    pub fn cache_version(mut self, cache_version: String) -> Self {
+        self.namespace.cache_version = Some(cache_version);
+        self
+    }
+
+    /// Mixes a salt into the cache version, to invalidate what was cached with another one.
+    pub fn cache_version_salt(mut self, salt: String) -> Self {
+        self.namespace.salt = Some(salt);
+        self
+    }
+
+    /// Prepends a prefix to the key of everything stored, to partition the cache.
+    pub fn cache_key_prefix(mut self, key_prefix: String) -> Self {
+        self.namespace.key_prefix = Some(key_prefix);
+        self
+    }
+
+    /// Redirects requests for paths we don't have to this binary cache.
+    pub fn upstream(mut self, upstream: String) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// Persists the index of uploaded paths here, to notice evictions across restarts.
+    pub fn index_file(mut self, index_file: PathBuf) -> Self {
+        self.index_file = Some(index_file);
+        self
+    }
+
+    /// The priority advertised in `nix-cache-info`.
+    pub fn substituter_priority(mut self, substituter_priority: u32) -> Self {
+        self.substituter_priority = substituter_priority;
+        self
+    }
+
+    /// How many compressed store paths to upload in parallel.
+    pub fn upload_jobs(mut self, upload_jobs: usize) -> Self {
+        self.upload_jobs = upload_jobs.max(1);
+        self
+    }
+
+    /// How many store paths may wait for upload, and what to do with new ones beyond that.
+    pub fn queue_limit(mut self, max_queued_uploads: usize, overflow: EnqueueOverflow) -> Self {
+        self.max_queued_uploads = max_queued_uploads.max(1);
+        self.enqueue_overflow = overflow;
+        self
+    }
+
+    /// Connects to the local Nix store and starts the upload workers.
+    pub async fn build(self) -> Result<Server> {
+        let store = Arc::new(NixStore::connect()?);
+        let metrics = Arc::new(telemetry::TelemetryReport::new());
+        let events = events::EventBus::default();
+        let narinfo_negative_cache = Arc::new(RwLock::new(HashSet::new()));
+        let disk_watchdog = watchdog::DiskSpaceWatchdog::spawn(Vec::new(), 0, metrics.clone());
+        let index = Arc::new(index::UploadIndex::load(self.index_file).await);
+
+        let gha_cache = gha::GhaCache::new(
+            self.credentials,
+            &self.namespace,
+            store.clone(),
+            metrics.clone(),
+            events.clone(),
+            narinfo_negative_cache.clone(),
+            index,
+            disk_watchdog.clone(),
+            None,
+            gha::Pipeline {
+                compression_workers: std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1),
+                upload_jobs: self.upload_jobs,
+                upload_limit: None,
+                upload_deltas: false,
+                upload_build_logs: false,
+                upload_listings: false,
+                pack_threshold: None,
+                signing_keys: None,
+                verify_after_push: false,
+                dry_run: false,
+                max_queued_uploads: self.max_queued_uploads,
+                enqueue_overflow: self.enqueue_overflow,
+                upload_timeout: Some(Duration::from_secs(3600)),
+                quarantine_after: 3,
+                dedupe_index: None,
+                pending_uploads_file: None,
+                budget: None,
+                secret_scanner: Default::default(),
+            },
+        )?;
+
+        let narinfo_cache = narinfo_cache::NarinfoCache::load(
+            Duration::from_secs(300),
+            Duration::from_secs(3600),
+            None,
+        )
+        .await;
+
+        let backends = backends::Backends::default();
+        backends.set(Backend::Gha, backends::BackendStatus::Ready);
+
+        let state = Arc::new(StateInner {
+            gha_cache: Some(gha_cache),
+            upstream: self.upstream,
+            backends,
+            substituter_priority: self.substituter_priority,
+            ..StateInner::new(
+                store,
+                metrics,
+                events,
+                narinfo_cache,
+                narinfo_negative_cache,
+                disk_watchdog,
+            )
+        });
+
+        Ok(Server { state })
+    }
+}
+
+/// A binary cache backed by the GitHub Actions Cache.
+pub struct Server {
+    state: State,
+}
+
+impl Server {
+    /// The binary cache and `/api/*` routes, ready to be served.
+    pub fn router(&self) -> Router {
+        routes().layer(Extension(self.state.clone()))
+    }
+
+    /// A handle for pushing store paths to the cache.
+    pub fn push_queue(&self) -> PushQueue {
+        PushQueue {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Waits for pending uploads to finish.
+    pub async fn shutdown(&self) -> Result<()> {
+        if let Some(gha_cache) = &self.state.gha_cache {
+            gha_cache.shutdown().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Schedules store paths for upload.
+#[derive(Clone)]
+pub struct PushQueue {
+    state: State,
+}
+
+impl PushQueue {
+    /// Queues `store_paths`, along with their closures, for upload.
+    pub async fn enqueue(&self, store_paths: &[PathBuf]) -> Result<()> {
+        let store_paths = store_paths
+            .iter()
+            .map(|path| {
+                self.state
+                    .store
+                    .follow_store_path(path)
+                    .map_err(Error::Attic)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        api::enqueue_paths(&self.state, store_paths).await
+    }
+
+    /// Queues the realisations of a derivation for upload, once its outputs are.
+    pub async fn enqueue_derivation(&self, drv_path: PathBuf) -> Result<()> {
+        api::enqueue_derivation(&self.state, drv_path).await
+    }
+
+    /// The store paths that are queued or being uploaded.
+    pub fn pending(&self) -> Vec<QueueEntry> {
+        self.state
+            .gha_cache
+            .as_ref()
+            .map(|gha_cache| gha_cache.pending_paths())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,74 @@
+//! Signing uploaded narinfos.
+//!
+//! With `--signing-key-file`, each narinfo we upload is signed, so that the
+//! cache can be used without `trusted=1`. To rotate keys, pass the option once
+//! per key: the first one is the primary key and the others add secondary
+//! signatures, so that consumers trusting either the old or the new key accept
+//! the paths.
+
+use std::path::PathBuf;
+
+use attic::signing::NixKeypair;
+use attic_server::narinfo::NarInfo;
+
+use crate::error::{Error, Result};
+
+pub struct SigningKeys {
+    primary: NixKeypair,
+    secondary: Vec<NixKeypair>,
+}
+
+impl std::fmt::Debug for SigningKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKeys")
+            .field("primary", &self.primary.export_public_key())
+            .field(
+                "secondary",
+                &self
+                    .secondary
+                    .iter()
+                    .map(NixKeypair::export_public_key)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl SigningKeys {
+    /// Loads the secret keys in `paths`, the first of which is the primary one.
+    pub async fn load(paths: &[PathBuf]) -> Result<Option<Self>> {
+        let mut keys = Vec::with_capacity(paths.len());
+        for path in paths {
+            let contents = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| Error::Io(e, format!("Reading the signing key {}", path.display())))?;
+            keys.push(NixKeypair::from_str(contents.trim())?);
+        }
+
+        let mut keys = keys.into_iter();
+        Ok(keys.next().map(|primary| Self {
+            primary,
+            secondary: keys.collect(),
+        }))
+    }
+
+    /// Signs `narinfo` with the primary key, returning the signatures of the
+    /// secondary keys, which have to be added as extra `Sig:` lines.
+    pub fn sign(&self, narinfo: &mut NarInfo) -> Vec<String> {
+        let fingerprint = narinfo.fingerprint();
+        narinfo.signature = Some(self.primary.sign(&fingerprint));
+
+        self.secondary
+            .iter()
+            .map(|key| key.sign(&fingerprint))
+            .collect()
+    }
+
+    /// The public keys matching all configured keys, for `trusted-public-keys`.
+    pub fn public_keys(&self) -> Vec<String> {
+        std::iter::once(&self.primary)
+            .chain(&self.secondary)
+            .map(NixKeypair::export_public_key)
+            .collect()
+    }
+}
@@ -0,0 +1,168 @@
+//! Logging where narinfos come from.
+//!
+//! With `--log-substitutions`, every narinfo request is logged with whoever
+//! answered it, and how long that took. The requests are also tallied, and the
+//! tallies are summarized at workflow-finish. When CI rebuilt something it
+//! shouldn't have, the log shows whether Nix asked for it at all, and whether
+//! any cache had it.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::io::Write as _;
+use std::time::Duration;
+
+use crate::env::Environment;
+use crate::Backend;
+
+/// Who answered a narinfo request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Answer {
+    /// One of our backends had the narinfo.
+    Hit(Backend),
+
+    /// We sent Nix to the upstream cache, which may or may not have it.
+    Upstream,
+
+    /// Nobody had it.
+    Miss,
+
+    /// The request failed.
+    Failed,
+}
+
+impl Answer {
+    /// The order in which answers are summarized.
+    const ALL: [Answer; 5] = [
+        Answer::Hit(Backend::Gha),
+        Answer::Hit(Backend::FlakeHub),
+        Answer::Upstream,
+        Answer::Miss,
+        Answer::Failed,
+    ];
+}
+
+impl Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Hit(Backend::Gha) => write!(f, "hit in the GitHub Actions Cache"),
+            Self::Hit(Backend::FlakeHub) => write!(f, "hit in FlakeHub"),
+            Self::Upstream => write!(f, "sent upstream"),
+            Self::Miss => write!(f, "miss"),
+            Self::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Tally {
+    requests: u64,
+    total_latency: Duration,
+    max_latency: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct SubstitutionLog {
+    enabled: bool,
+
+    tallies: std::sync::Mutex<HashMap<Answer, Tally>>,
+}
+
+impl SubstitutionLog {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            tallies: Default::default(),
+        }
+    }
+
+    /// Records who answered the request for the narinfo of `store_path_hash`.
+    pub fn record(&self, store_path_hash: &str, answer: Answer, latency: Duration) {
+        if !self.enabled {
+            return;
+        }
+
+        tracing::info!(
+            "narinfo {}: {} ({} ms)",
+            store_path_hash,
+            answer,
+            latency.as_millis()
+        );
+
+        let mut tallies = self.lock();
+        let tally = tallies.entry(answer).or_default();
+        tally.requests += 1;
+        tally.total_latency += latency;
+        tally.max_latency = tally.max_latency.max(latency);
+    }
+
+    /// Summarizes the narinfo requests in the log, and in the job summary on GitHub Actions.
+    pub fn report(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        let tallies = self.lock().clone();
+        let rows: Vec<(Answer, Tally)> = Answer::ALL
+            .into_iter()
+            .filter_map(|answer| Some((answer, *tallies.get(&answer)?)))
+            .collect();
+
+        tracing::info!("Narinfo requests:");
+        for (answer, tally) in &rows {
+            tracing::info!(
+                "  {}: {} (average {} ms, at most {} ms)",
+                answer,
+                tally.requests,
+                average_ms(tally),
+                tally.max_latency.as_millis()
+            );
+        }
+
+        if Environment::determine().is_github_actions() {
+            if let Err(err) = write_step_summary(&rows) {
+                tracing::warn!("Failed to write the job summary: {}", err);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Answer, Tally>> {
+        self.tallies
+            .lock()
+            .expect("substitution tallies mutex was poisoned")
+    }
+}
+
+fn average_ms(tally: &Tally) -> u128 {
+    tally.total_latency.as_millis() / u128::from(tally.requests.max(1))
+}
+
+fn write_step_summary(rows: &[(Answer, Tally)]) -> std::io::Result<()> {
+    let Some(summary_file) = std::env::var_os("GITHUB_STEP_SUMMARY") else {
+        return Ok(());
+    };
+
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(summary_file)?;
+
+    writeln!(file, "### Magic Nix Cache substitutions\n")?;
+    writeln!(
+        file,
+        "| Answer | Requests | Average latency | Max latency |"
+    )?;
+    writeln!(file, "| --- | ---: | ---: | ---: |")?;
+    for (answer, tally) in rows {
+        writeln!(
+            file,
+            "| {} | {} | {} ms | {} ms |",
+            answer,
+            tally.requests,
+            average_ms(tally),
+            tally.max_latency.as_millis()
+        )?;
+    }
+    writeln!(file)?;
+
+    Ok(())
+}
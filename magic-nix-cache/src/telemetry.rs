@@ -16,18 +16,68 @@ pub struct TelemetryReport {
     elapsed_seconds: Metric,
 
     pub narinfos_served: Metric,
+    pub narinfos_cache_hits: Metric,
     pub narinfos_sent_upstream: Metric,
     pub narinfos_negative_cache_hits: Metric,
     pub narinfos_negative_cache_misses: Metric,
     pub narinfos_uploaded: Metric,
+    pub narinfos_evicted: Metric,
+    pub narinfos_preheated: Metric,
 
     pub nars_served: Metric,
     pub nars_sent_upstream: Metric,
     pub nars_uploaded: Metric,
+    pub nar_bytes_uploaded: Metric,
+    pub nars_served_from_deltas: Metric,
+    pub deltas_uploaded: Metric,
+    pub nars_served_from_packs: Metric,
+    pub nars_packed: Metric,
+    pub packs_uploaded: Metric,
+
+    pub realisations_served: Metric,
+    pub realisations_uploaded: Metric,
+
+    pub build_logs_served: Metric,
+    pub build_logs_uploaded: Metric,
+
+    pub listings_served: Metric,
+    pub listings_uploaded: Metric,
+    pub paths_forwarded_to_flakehub: Metric,
+
+    pub flakehub_requests_proxied: Metric,
+    pub narinfos_served_from_flakehub: Metric,
+    pub flakehub_fallbacks: Metric,
 
     pub num_original_paths: Metric,
     pub num_final_paths: Metric,
     pub num_new_paths: Metric,
+    pub flake_inputs_enqueued: Metric,
+
+    pub uploads_paused_low_disk: Metric,
+    pub uploads_skipped_deadline: Metric,
+    pub uploads_skipped_budget: Metric,
+    pub uploads_failed_verification: Metric,
+    pub uploads_quarantined: Metric,
+    pub uploads_skipped_quarantined: Metric,
+    pub uploads_skipped_dedupe: Metric,
+    pub uploads_skipped_persisted: Metric,
+    pub uploads_dropped_queue_full: Metric,
+    pub uploads_resumed: Metric,
+    pub enqueues_rejected_queue_full: Metric,
+    pub paths_skipped_other_system: Metric,
+    pub paths_skipped_fixed_output: Metric,
+    pub paths_with_secrets: Metric,
+    pub paths_blocked_secrets: Metric,
+    pub paths_denied_policy: Metric,
+    pub narinfos_denied_policy: Metric,
+    pub paths_fetched_from_builders: Metric,
+
+    pub dry_run_nars: Metric,
+    pub dry_run_nar_bytes: Metric,
+
+    pub hooks_run: Metric,
+    pub hooks_failed: Metric,
+    pub hooks_dropped: Metric,
 }
 
 #[derive(Debug, Default, serde::Serialize)]
@@ -37,9 +87,17 @@ impl Metric {
         self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
 
+    pub fn add(&self, val: usize) {
+        self.0.fetch_add(val, std::sync::atomic::Ordering::Relaxed);
+    }
+
     pub fn set(&self, val: usize) {
         self.0.store(val, std::sync::atomic::Ordering::Relaxed);
     }
+
+    pub fn get(&self) -> usize {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
 }
 
 impl TelemetryReport {
@@ -71,7 +129,7 @@ impl TelemetryReport {
         }
 
         if let Ok(serialized) = serde_json::to_string_pretty(&self) {
-            let _ = reqwest::Client::new()
+            let _ = crate::http_client::client()
                 .post(endpoint)
                 .body(serialized)
                 .header("Content-Type", "application/json")
@@ -0,0 +1,281 @@
+//! Sharing one daemon between jobs.
+//!
+//! On persistent self-hosted runners, several jobs can talk to the same daemon.
+//! With `--multi-tenant`, requests carrying an `X-Magic-Nix-Cache-Scope` header
+//! (set by the Action, e.g. to the repository and branch) are served from a
+//! state of their own: their paths are pushed to a separate namespace of the
+//! GitHub Actions Cache, their metrics are kept and reported separately, and
+//! `GET /api/events` only shows their uploads.
+//!
+//! Nix can't send headers, so a job points Nix at `/scope/<scope>` under the
+//! daemon's address instead, with the scope percent-encoded. The post-build
+//! hook is installed once for the whole machine and can't tell jobs apart, so
+//! what it pushes goes to the daemon's own state, like every other request
+//! without a scope.
+//!
+//! Scopes are never given up before their workflow finishes, so at most
+//! [`MAX_SCOPES`] can be in use at once, and their names are restricted.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{Extension, RawPathParams, Request},
+    middleware::Next,
+    response::Response,
+};
+use gha_cache::Credentials;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::error::{Error, Result};
+use crate::{backends, builders, gha, index, narinfo_cache, telemetry};
+use crate::{Backend, State, StateInner};
+
+/// The header that selects the scope of a request.
+pub const SCOPE_HEADER: &str = "X-Magic-Nix-Cache-Scope";
+
+/// The route under which the daemon's routes are served for a scope.
+pub const SCOPE_ROUTE: &str = "/scope/:mnc_scope";
+
+/// How many scopes may be in use at once.
+pub const MAX_SCOPES: usize = 64;
+
+/// How long the name of a scope may be.
+const MAX_SCOPE_LEN: usize = 128;
+
+/// The states of the scopes that are in use, and what new ones are made from.
+pub struct Tenants {
+    credentials: Credentials,
+    namespace: gha::CacheNamespace,
+    pipeline: gha::Pipeline,
+    narinfo_cache_ttl: Duration,
+    narinfo_cache_stale: Duration,
+    diagnostic_endpoint: Option<String>,
+
+    states: Mutex<HashMap<String, State>>,
+}
+
+impl Tenants {
+    pub fn new(
+        credentials: Credentials,
+        namespace: gha::CacheNamespace,
+        pipeline: gha::Pipeline,
+        narinfo_cache_ttl: Duration,
+        narinfo_cache_stale: Duration,
+        diagnostic_endpoint: Option<String>,
+    ) -> Self {
+        Self {
+            credentials,
+            namespace,
+            pipeline,
+            narinfo_cache_ttl,
+            narinfo_cache_stale,
+            diagnostic_endpoint,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the state of `scope`, setting it up from `parent` on first use.
+    async fn get_or_create(&self, parent: &StateInner, scope: &str) -> Result<State> {
+        let mut states = self.states.lock().await;
+        if let Some(state) = states.get(scope) {
+            return Ok(state.clone());
+        }
+
+        if states.len() >= MAX_SCOPES {
+            tracing::warn!(
+                "Not setting up scope '{}': {} scopes are in use already",
+                scope,
+                states.len()
+            );
+            return Err(Error::TooManyScopes);
+        }
+
+        tracing::info!("Setting up scope '{}'", scope);
+
+        let events = parent.events.child();
+
+        let metrics = Arc::new(telemetry::TelemetryReport::new());
+        let narinfo_negative_cache = Arc::new(RwLock::new(HashSet::new()));
+
+        let gha_cache = gha::GhaCache::new(
+            self.credentials.clone(),
+            &self.namespace.scoped(scope),
+            parent.store.clone(),
+            metrics.clone(),
+            events.clone(),
+            narinfo_negative_cache.clone(),
+            Arc::new(index::UploadIndex::load(None).await),
+            parent.disk_watchdog.clone(),
+            None,
+            self.pipeline.clone(),
+        )?;
+
+        let narinfo_cache = narinfo_cache::NarinfoCache::load(
+            self.narinfo_cache_ttl,
+            self.narinfo_cache_stale,
+            None,
+        )
+        .await;
+
+        let backends = backends::Backends::default();
+        backends.set(Backend::Gha, backends::BackendStatus::Ready);
+
+        let state = Arc::new(StateInner {
+            gha_cache: Some(gha_cache),
+            upstream: parent.upstream.clone(),
+            backends,
+            delta_substitution: parent.delta_substitution,
+            substituter_priority: parent.substituter_priority,
+            push_filter: parent.push_filter.clone(),
+            secret_scanner: parent.secret_scanner.clone(),
+            policy: parent.policy.clone(),
+            substitutions: parent.substitutions.clone(),
+            hooks: parent.hooks.clone(),
+            dry_run: parent.dry_run,
+            fetcher: builders::Fetcher::new(parent.fetcher.remotes().to_vec()),
+            api_token: parent.api_token.clone(),
+            ..StateInner::new(
+                parent.store.clone(),
+                metrics,
+                events,
+                narinfo_cache,
+                narinfo_negative_cache,
+                parent.disk_watchdog.clone(),
+            )
+        });
+
+        states.insert(scope.to_owned(), state.clone());
+
+        Ok(state)
+    }
+
+    /// Forgets about a scope whose workflow finished, and reports its metrics.
+    async fn finish(&self, scope: &str) {
+        let Some(state) = self.states.lock().await.remove(scope) else {
+            return;
+        };
+
+        tracing::info!(
+            "Scope '{}' finished: uploaded {} paths, served {} narinfos",
+            scope,
+            state.metrics.nars_uploaded.get(),
+            state.metrics.narinfos_served.get()
+        );
+
+        if let Some(diagnostic_endpoint) = &self.diagnostic_endpoint {
+            state.metrics.send(diagnostic_endpoint).await;
+        }
+    }
+
+    /// Waits for the uploads of the scopes that didn't finish, when the daemon shuts down.
+    pub async fn shutdown(&self) {
+        let states: Vec<_> = self.states.lock().await.drain().collect();
+
+        for (scope, state) in states {
+            if let Some(gha_cache) = &state.gha_cache {
+                if let Err(err) = gha_cache.shutdown().await {
+                    tracing::error!(
+                        "Failed to finish GitHub Actions Cache uploads for scope '{}': {}",
+                        scope,
+                        err
+                    );
+                }
+            }
+
+            if let Some(diagnostic_endpoint) = &self.diagnostic_endpoint {
+                state.metrics.send(diagnostic_endpoint).await;
+            }
+        }
+    }
+}
+
+/// Serves requests carrying a scope header from the state of that scope.
+pub async fn select(
+    Extension(state): Extension<State>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    let scope = match request.headers().get(SCOPE_HEADER) {
+        Some(scope) => Some(
+            scope
+                .to_str()
+                .ok()
+                .and_then(valid_scope)
+                .ok_or(Error::BadRequest)?,
+        ),
+        None => None,
+    };
+
+    serve_scoped(state, scope, request, next).await
+}
+
+/// Serves requests under [`SCOPE_ROUTE`] from the state of the scope in their path.
+///
+/// A scope header was handled before, and wins.
+pub async fn select_by_path(
+    Extension(state): Extension<State>,
+    params: RawPathParams,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    let scope = params
+        .iter()
+        .find(|(name, _)| *name == "mnc_scope")
+        .map(|(_, value)| valid_scope(value).ok_or(Error::BadRequest))
+        .transpose()?;
+
+    serve_scoped(state, scope, request, next).await
+}
+
+async fn serve_scoped(
+    state: State,
+    scope: Option<String>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response> {
+    let (Some(tenants), Some(scope)) = (&state.tenants, scope) else {
+        return Ok(next.run(request).await);
+    };
+
+    let tenant = tenants.get_or_create(&state, &scope).await?;
+    let finishing = request.uri().path().ends_with("/api/workflow-finish");
+
+    request.extensions_mut().insert(tenant);
+    let response = next.run(request).await;
+
+    if finishing && response.status().is_success() {
+        tenants.finish(&scope).await;
+    }
+
+    Ok(response)
+}
+
+/// Returns `scope` if it's usable as the name of a scope, like `owner/repo@branch`.
+fn valid_scope(scope: &str) -> Option<String> {
+    let valid = !scope.is_empty()
+        && scope.len() <= MAX_SCOPE_LEN
+        && scope
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./@:+".contains(c));
+
+    valid.then(|| scope.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_are_restricted() {
+        assert_eq!(
+            valid_scope("owner/repo@main"),
+            Some("owner/repo@main".to_owned())
+        );
+        assert_eq!(valid_scope(""), None);
+        assert_eq!(valid_scope("owner/repo main"), None);
+        assert_eq!(valid_scope("owner/repo\n"), None);
+        assert_eq!(valid_scope(&"a".repeat(MAX_SCOPE_LEN + 1)), None);
+    }
+}
@@ -0,0 +1,185 @@
+//! Reaching cache servers through a proxy or an SSH bastion.
+//!
+//! `--proxy` sends the daemon's traffic to the outside world through a proxy.
+//! `socks5://`, `socks5h://` (which also resolves names on the far side) and
+//! `http(s)://` URLs are handed to reqwest as they are. With
+//! `ssh://user@bastion[:port]`, the daemon runs `ssh -D` itself, restarts it
+//! whenever it exits, and uses the SOCKS proxy it provides.
+//!
+//! attic builds its own HTTP client for FlakeHub pushes, which only reads
+//! `HTTPS_PROXY` and `HTTP_PROXY` from the daemon's environment. The daemon
+//! doesn't export `--proxy` there: changing the environment of a running
+//! multi-threaded process isn't sound, and Nix and the hooks would inherit it.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+
+use crate::error::{Error, Result};
+
+/// How long ssh gets to open the tunnel at startup.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many ports to try at startup, in case another process takes the one we
+/// picked before ssh binds it.
+const STARTUP_ATTEMPTS: u32 = 3;
+
+/// How long to wait before restarting ssh the first time after it exited.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// The delay doubles each time ssh exits again, up to this.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+/// The proxy that HTTP clients should use, and the ssh process behind it.
+pub struct Tunnel {
+    pub url: String,
+
+    /// Keeps ssh running, if the proxy is an SSH tunnel.
+    supervisor: Option<JoinHandle<()>>,
+}
+
+impl Tunnel {
+    /// Stops ssh, if it's running.
+    pub fn stop(&self) {
+        if let Some(supervisor) = &self.supervisor {
+            supervisor.abort();
+        }
+    }
+}
+
+/// Sets up the proxy in `--proxy`.
+pub async fn start(proxy: &str) -> Result<Tunnel> {
+    let scheme = proxy.split_once("://").map(|(scheme, _)| scheme);
+
+    match scheme {
+        Some("socks5" | "socks5h" | "http" | "https") => Ok(Tunnel {
+            url: proxy.to_owned(),
+            supervisor: None,
+        }),
+        Some("ssh") => ssh_tunnel(proxy).await,
+        _ => Err(Error::Config(format!(
+            "unsupported --proxy '{proxy}', expected a socks5://, socks5h://, http(s):// or ssh:// URL"
+        ))),
+    }
+}
+
+/// Starts `ssh -D` to `destination` on a free local port, and keeps it running.
+async fn ssh_tunnel(destination: &str) -> Result<Tunnel> {
+    let mut attempt = 1;
+    let (listen, child) = loop {
+        let listen = SocketAddr::from((Ipv4Addr::LOCALHOST, free_port()?));
+
+        tracing::info!("Opening an SSH tunnel to {} on {}", destination, listen);
+
+        let mut child = spawn_ssh(destination, listen)?;
+        if wait_until_listening(&mut child, listen).await? {
+            break (listen, child);
+        }
+
+        // With `ExitOnForwardFailure`, ssh also exits when the port was taken.
+        if attempt == STARTUP_ATTEMPTS {
+            return Err(Error::Config(format!(
+                "ssh exited before the tunnel to {destination} came up"
+            )));
+        }
+        tracing::warn!(
+            "ssh exited before the tunnel to {} came up, trying another port",
+            destination
+        );
+        attempt += 1;
+    };
+
+    let supervisor = tokio::task::spawn(supervise(destination.to_owned(), listen, child));
+
+    Ok(Tunnel {
+        url: format!("socks5h://{listen}"),
+        supervisor: Some(supervisor),
+    })
+}
+
+/// Restarts ssh whenever it exits, backing off while it keeps failing.
+///
+/// HTTP clients were handed the port already, so ssh is restarted on the same
+/// one: if another process took it in the meantime, ssh keeps exiting until
+/// it's free again.
+async fn supervise(destination: String, listen: SocketAddr, mut child: Child) {
+    let mut delay = RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        match child.wait().await {
+            Ok(status) => tracing::warn!(
+                "The SSH tunnel to {} exited ({}), restarting it",
+                destination,
+                status
+            ),
+            Err(err) => tracing::warn!("Failed to wait for ssh: {}", err),
+        }
+
+        // A tunnel that stayed up for a while isn't failing over and over.
+        if started.elapsed() > MAX_RESTART_DELAY {
+            delay = RESTART_DELAY;
+        }
+
+        child = loop {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+            match spawn_ssh(&destination, listen) {
+                Ok(child) => break child,
+                Err(err) => tracing::warn!("{}", err),
+            }
+        };
+    }
+}
+
+fn spawn_ssh(destination: &str, listen: SocketAddr) -> Result<Child> {
+    Command::new("ssh")
+        .arg("-N")
+        .arg("-D")
+        .arg(listen.to_string())
+        .args(["-o", "ExitOnForwardFailure=yes"])
+        .args(["-o", "ServerAliveInterval=15"])
+        .args(["-o", "ServerAliveCountMax=3"])
+        .args(["-o", "BatchMode=yes"])
+        .arg(destination)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::Io(e, "Running ssh".to_owned()))
+}
+
+/// Waits for ssh to listen on `listen`, returning false if it exits first.
+async fn wait_until_listening(child: &mut Child, listen: SocketAddr) -> Result<bool> {
+    let started = Instant::now();
+    loop {
+        if tokio::net::TcpStream::connect(listen).await.is_ok() {
+            return Ok(true);
+        }
+
+        let exited = child
+            .try_wait()
+            .map_err(|e| Error::Io(e, "Waiting for ssh".to_owned()))?;
+        if exited.is_some() {
+            return Ok(false);
+        }
+
+        if started.elapsed() > STARTUP_TIMEOUT {
+            return Err(Error::Config(format!(
+                "the SSH tunnel didn't come up within {} seconds",
+                STARTUP_TIMEOUT.as_secs()
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+/// A local port that nothing listens on at the moment.
+fn free_port() -> Result<u16> {
+    std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .map_err(|e| Error::Io(e, "Finding a free port for the SSH tunnel".to_owned()))
+}
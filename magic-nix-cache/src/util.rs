@@ -50,3 +50,25 @@ pub async fn get_store_paths(store: &NixStore) -> Result<HashSet<PathBuf>> {
     }
     Ok(paths)
 }
+
+/// Compares two secrets without leaking where they differ through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Quotes `s` so that `sh` reads it back as a single word.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_keeps_words_together() {
+        assert_eq!(shell_quote("/tmp/token"), "'/tmp/token'");
+        assert_eq!(shell_quote("/tmp/my token"), "'/tmp/my token'");
+        assert_eq!(shell_quote("a'; rm -rf /; '"), r"'a'\''; rm -rf /; '\'''");
+    }
+}
@@ -0,0 +1,139 @@
+//! Checking uploads by reading them back.
+//!
+//! The GitHub Actions Cache has been seen to truncate uploads under load
+//! without reporting an error, which only shows up days later as hash
+//! mismatches in the jobs that substitute the paths. With
+//! `--verify-after-push`, the NAR of each path is downloaded again and compared
+//! with what we sent before its narinfo is uploaded, so that a corrupt NAR is
+//! never published. The narinfo is read back after its upload.
+
+use futures::StreamExt as _;
+use gha_cache::Api;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+
+use crate::error::{Error, Result};
+
+/// The size and SHA-256 hash of an upload.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Digest256 {
+    size: u64,
+    hash: [u8; 32],
+}
+
+impl std::fmt::Display for Digest256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes, sha256 {}", self.size, hex(&self.hash))
+    }
+}
+
+/// Hashes a file about to be uploaded, leaving it rewound.
+pub async fn digest_file(file: &mut tokio::fs::File) -> Result<Digest256> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .map_err(|e| Error::Io(e, "Hashing the compressed NAR".to_owned()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+
+    file.rewind()
+        .await
+        .map_err(|e| Error::Io(e, "Rewinding the compressed NAR".to_owned()))?;
+
+    Ok(Digest256 {
+        size,
+        hash: hasher.finalize().into(),
+    })
+}
+
+/// Checks that the narinfo under `narinfo_key` is the one we uploaded.
+pub async fn check_narinfo(api: &Api, narinfo_key: &str, narinfo: &str) -> Result<()> {
+    let uploaded_narinfo = download(api, narinfo_key).await?;
+
+    for field in ["StorePath", "URL", "NarHash", "NarSize"] {
+        let expected = narinfo_field(narinfo, field);
+        let actual = narinfo_field(&String::from_utf8_lossy(&uploaded_narinfo), field);
+        if actual != expected {
+            return Err(Error::Internal(format!(
+                "{narinfo_key} has {field} {actual:?} instead of {expected:?}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the NAR under `nar_key` matches `nar_digest`.
+pub async fn check_nar(api: &Api, nar_key: &str, nar_digest: &Digest256) -> Result<()> {
+    let url = file_url(api, nar_key).await?;
+    let mut stream = crate::http_client::client()
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Error::Internal(format!("Downloading {nar_key}: {e}")))?
+        .bytes_stream();
+
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| Error::Internal(format!("Downloading {nar_key}: {e}")))?;
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+    }
+
+    let uploaded_digest = Digest256 {
+        size,
+        hash: hasher.finalize().into(),
+    };
+
+    if uploaded_digest != *nar_digest {
+        return Err(Error::Internal(format!(
+            "{nar_key} is corrupt: uploaded {nar_digest}, read back {uploaded_digest}"
+        )));
+    }
+
+    Ok(())
+}
+
+async fn file_url(api: &Api, key: &str) -> Result<String> {
+    api.get_file_url(&[key])
+        .await?
+        .ok_or_else(|| Error::Internal(format!("{key} is missing after the upload")))
+}
+
+async fn download(api: &Api, key: &str) -> Result<Vec<u8>> {
+    let url = file_url(api, key).await?;
+
+    let body = crate::http_client::client()
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| Error::Internal(format!("Downloading {key}: {e}")))?
+        .bytes()
+        .await
+        .map_err(|e| Error::Internal(format!("Downloading {key}: {e}")))?;
+
+    Ok(body.to_vec())
+}
+
+fn narinfo_field<'a>(narinfo: &'a str, name: &str) -> Option<&'a str> {
+    narinfo
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+        .map(str::trim)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
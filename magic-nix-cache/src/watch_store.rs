@@ -0,0 +1,133 @@
+//! Watching the store for new paths.
+//!
+//! Where a post-build hook can't be installed (e.g. nix.conf is managed
+//! elsewhere, or builds happen on remote builders), `--watch-store` polls the
+//! Nix store instead, like `cachix watch-store`, and enqueues every path that
+//! became valid since the daemon started. Paths that Nix substituted from our
+//! own backends are left out, like a post-build hook would.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::error::Result;
+use crate::util::get_store_paths;
+use crate::State;
+
+/// How often the store is checked for new paths.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The hashes of the store paths whose narinfos our backends served.
+#[derive(Debug, Default)]
+pub struct Substituted(std::sync::Mutex<HashSet<String>>);
+
+impl Substituted {
+    pub fn record(&self, store_path_hash: &str) {
+        self.lock().insert(store_path_hash.to_owned());
+    }
+
+    /// Returns whether the path was served by our backends, forgetting it.
+    fn take(&self, store_path_hash: &str) -> bool {
+        self.lock().remove(store_path_hash)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.0.lock().expect("substituted paths mutex was poisoned")
+    }
+}
+
+/// Starts watching the store. Paths that exist already are not enqueued.
+pub async fn spawn(state: State) -> Result<()> {
+    let known = get_store_paths(&state.store).await?;
+
+    tokio::task::spawn(watch(state, known));
+
+    Ok(())
+}
+
+async fn watch(state: State, mut known: HashSet<PathBuf>) {
+    let store_dir = state.store.store_dir().to_owned();
+    let mut last_modified = modified(&store_dir);
+
+    // New paths that haven't been registered as valid yet.
+    let mut pending = HashSet::new();
+
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        // Adding a path to the store changes the mtime of the store directory,
+        // which saves listing it when nothing happened.
+        let now_modified = modified(&store_dir);
+        if now_modified == last_modified && pending.is_empty() {
+            continue;
+        }
+        last_modified = now_modified;
+
+        match get_store_paths(&state.store).await {
+            Ok(paths) => {
+                pending.extend(paths.difference(&known).cloned());
+                pending.retain(|path| paths.contains(path));
+                known = paths;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to list the store: {}", err);
+                continue;
+            }
+        }
+
+        // Lock files and the like are never going to become valid.
+        pending.retain(|full_path| {
+            !full_path.extension().is_some_and(|ext| ext == "lock")
+                && state.store.follow_store_path(full_path).is_ok()
+        });
+
+        let mut ready = Vec::new();
+        for full_path in &pending {
+            let Ok(store_path) = state.store.follow_store_path(full_path) else {
+                continue;
+            };
+
+            // Paths that are being built or substituted show up before they're valid.
+            if state
+                .store
+                .query_path_info(store_path.clone())
+                .await
+                .is_ok()
+            {
+                ready.push((full_path.clone(), store_path));
+            }
+        }
+
+        if ready.is_empty() {
+            continue;
+        }
+
+        for (full_path, _) in &ready {
+            pending.remove(full_path);
+        }
+
+        // They're in the cache already.
+        if let Some(substituted) = &state.substituted {
+            ready.retain(|(_, store_path)| !substituted.take(store_path.to_hash().as_str()));
+            if ready.is_empty() {
+                continue;
+            }
+        }
+
+        tracing::info!("Enqueueing {} new store paths", ready.len());
+
+        let store_paths = ready
+            .into_iter()
+            .map(|(_, store_path)| store_path)
+            .collect();
+        if let Err(err) = crate::api::enqueue_paths(&state, store_paths).await {
+            tracing::error!("Failed to enqueue new store paths: {}", err);
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
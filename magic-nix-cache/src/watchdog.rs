@@ -0,0 +1,110 @@
+//! Disk space watchdog.
+//!
+//! Compressing and uploading store paths needs scratch space. When one of the
+//! watched mount points runs low, new uploads are paused until space frees up,
+//! so that we don't take the user's build down with us.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::telemetry;
+
+/// How often free space is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct DiskSpaceWatchdog {
+    paused: watch::Receiver<bool>,
+}
+
+impl DiskSpaceWatchdog {
+    /// Starts watching `mount_points`, pausing uploads when any of them has less
+    /// than `min_free_bytes` available.
+    ///
+    /// A threshold of zero disables the watchdog.
+    pub fn spawn(
+        mount_points: Vec<PathBuf>,
+        min_free_bytes: u64,
+        metrics: Arc<telemetry::TelemetryReport>,
+    ) -> Self {
+        let (paused_tx, paused) = watch::channel(false);
+
+        if min_free_bytes > 0 && !mount_points.is_empty() {
+            tokio::task::spawn(async move {
+                loop {
+                    let low =
+                        mount_points
+                            .iter()
+                            .find_map(|mount_point| match free_space(mount_point) {
+                                Ok(free) if free < min_free_bytes => Some((mount_point, free)),
+                                Ok(_) => None,
+                                Err(err) => {
+                                    tracing::debug!(
+                                        "Cannot determine free space on {}: {}",
+                                        mount_point.display(),
+                                        err
+                                    );
+                                    None
+                                }
+                            });
+
+                    let was_paused = *paused_tx.borrow();
+                    match low {
+                        Some((mount_point, free)) if !was_paused => {
+                            tracing::warn!(
+                                "Only {} MiB free on {}, pausing uploads until space frees up",
+                                free / (1024 * 1024),
+                                mount_point.display()
+                            );
+                            metrics.uploads_paused_low_disk.incr();
+                            paused_tx.send_replace(true);
+                        }
+                        None if was_paused => {
+                            tracing::info!("Disk space recovered, resuming uploads");
+                            paused_tx.send_replace(false);
+                        }
+                        _ => {}
+                    }
+
+                    tokio::time::sleep(CHECK_INTERVAL).await;
+                }
+            });
+        }
+
+        Self { paused }
+    }
+
+    /// Whether uploads are currently paused.
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Waits until uploads are no longer paused.
+    pub async fn wait_for_space(&self) {
+        let mut paused = self.paused.clone();
+        while *paused.borrow_and_update() {
+            if paused.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Returns the number of bytes available to unprivileged users on the file system containing `path`.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn free_space(path: &Path) -> std::io::Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "free space checks are only supported on Unix",
+    ))
+}